@@ -1,6 +1,25 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    env, fs,
+    path::{Path, PathBuf},
+};
 
-use crate::telegram::{DialogSummary, MessageSummary};
+use crate::{
+    editor::{EditAction, TextEditor},
+    markup::{self, MarkupMode},
+    telegram::{
+        ChatFolder, DialogKind, DialogSummary, EntityKind, FormattedText, GENERAL_TOPIC_ID,
+        ImagePreview, LastMessage, MediaInfo, MessageSearchResult, MessageSummary, ReactionCount,
+        TopicSummary, keep_chosen_reactions,
+    },
+};
+
+const OLDER_MESSAGES_PREFETCH_LINES: usize = 5;
+const MAX_IMAGE_PREVIEWS: usize = 64;
+/// Reactions offered by the reaction picker, in Telegram's default order.
+pub const REACTION_CHOICES: &[&str] = &[
+    "👍", "👎", "❤", "🔥", "🥰", "👏", "😁", "🤔", "😢", "🎉", "🙏",
+];
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum FocusArea {
@@ -17,12 +36,183 @@ pub enum SortMode {
     Alphabetical,
 }
 
+/// A tab over the chat list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FolderTab {
+    /// Every chat outside the archive.
+    #[default]
+    All,
+    /// A folder of the user, by id.
+    Folder(i32),
+    Archived,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum UiMode {
     #[default]
     Normal,
     Compose,
     Search,
+    ConfirmDelete,
+    MessageSearch,
+    AttachFile,
+    PickReaction,
+    ForwardPicker,
+    /// Visual selection of a range of messages in the message pane.
+    SelectMessages,
+    ConfirmPin,
+    TopicPicker,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageSearchScope {
+    Chat(i64),
+    AllChats,
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageSearch {
+    pub scope: MessageSearchScope,
+    pub query: String,
+    /// Query the current `results` belong to; `None` until a search has finished.
+    pub results_query: Option<String>,
+    pub results: Vec<MessageSearchResult>,
+    pub selected: usize,
+    pub is_searching: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Download {
+    pub dialog_id: i64,
+    pub message_id: i32,
+    /// Empty until the Telegram task reports the first progress.
+    pub file_name: String,
+    pub downloaded: u64,
+    pub total: Option<u64>,
+}
+
+impl Download {
+    pub fn ratio(&self) -> Option<f64> {
+        let total = self.total.filter(|total| *total > 0)?;
+        Some((self.downloaded as f64 / total as f64).min(1.0))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AttachPicker {
+    pub path: String,
+    /// Entries matching the path after an ambiguous Tab completion.
+    pub completions: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upload {
+    pub dialog_id: i64,
+    pub file_name: String,
+    /// Put back into the compose box when the file is not sent.
+    pub caption: String,
+    pub uploaded: u64,
+    pub total: Option<u64>,
+}
+
+impl Upload {
+    pub fn ratio(&self) -> Option<f64> {
+        let total = self.total.filter(|total| *total > 0)?;
+        Some((self.uploaded as f64 / total as f64).min(1.0))
+    }
+}
+
+/// A file picked in the attach picker, ready to be sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    pub dialog_id: i64,
+    pub path: PathBuf,
+    pub caption: FormattedText,
+    pub reply_to: Option<i32>,
+}
+
+/// What a cancel key press refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transfer {
+    Download { dialog_id: i64, message_id: i32 },
+    Upload,
+}
+
+/// What pressing Enter in the message search should do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SearchSubmission {
+    Search {
+        dialog_id: Option<i64>,
+        query: String,
+    },
+    Open {
+        dialog_id: i64,
+        message_id: i32,
+    },
+}
+
+/// Destination chat picker for forwarding messages.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForwardPicker {
+    pub message_ids: Vec<i32>,
+    /// Fuzzy filter over the chat titles.
+    pub query: String,
+    /// Index into `AppState::forward_candidates`.
+    pub selected: usize,
+    pub drop_author: bool,
+    pub drop_captions: bool,
+}
+
+/// Messages picked for forwarding, ready to be sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Forward {
+    pub from_dialog_id: i64,
+    pub message_ids: Vec<i32>,
+    pub to_dialog_id: i64,
+    pub drop_author: bool,
+    pub drop_captions: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReactionPicker {
+    pub message_id: i32,
+    /// Index into `REACTION_CHOICES`.
+    pub selected: usize,
+}
+
+/// Comments of a channel post shown in place of the channel. The discussion group becomes the
+/// selected chat, while the chat list keeps the channel selected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommentThread {
+    pub channel_id: i64,
+    pub post_id: i32,
+    pub discussion_id: i64,
+    pub top_id: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopicPicker {
+    pub dialog_id: i64,
+    /// Index into "All topics" followed by the topics of the chat.
+    pub selected: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingPin {
+    pub message_id: i32,
+    pub unpin: bool,
+    /// Alerts every member of the chat; only used when pinning.
+    pub notify: bool,
+}
+
+/// Message pane geometry from the last rendered frame, in wrapped lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MessageViewport {
+    pub content_lines: usize,
+    pub height: usize,
+    pub scroll_from_bottom: usize,
+    pub first_visible_message_id: Option<i32>,
+    pub last_visible_message_id: Option<i32>,
 }
 
 #[derive(Debug, Default)]
@@ -33,16 +223,68 @@ pub struct AppState {
     pub new_message_count_by_dialog: HashMap<i64, usize>,
     pub is_loading_dialogs: bool,
     pub is_loading_messages: bool,
+    pub is_loading_older_messages: bool,
     pub is_sending_message: bool,
     pub last_error: Option<String>,
     pub should_quit: bool,
     pub focus: FocusArea,
     pub sort_mode: SortMode,
+    pub folders: Vec<ChatFolder>,
+    pub active_folder: FolderTab,
     pub ui_mode: UiMode,
     pub search_query: String,
-    pub compose_text: String,
+    pub compose: TextEditor,
+    /// Drafts by chat; the chat being composed in has its current text in `compose`.
+    pub drafts: HashMap<i64, String>,
+    /// Chat the compose text belongs to.
+    compose_dialog_id: Option<i64>,
+    pub markup_mode: MarkupMode,
+    /// The mode to go back to once an edit that needed another mode is over.
+    markup_mode_before_edit: Option<MarkupMode>,
+    /// Shows the compose text as it will be sent.
+    pub compose_preview: bool,
     pub message_scroll_from_bottom: usize,
     pub pending_new_messages_for_selected: usize,
+    pub message_viewport: MessageViewport,
+    pub history_exhausted_dialogs: HashSet<i64>,
+    pub selected_message_id: Option<i32>,
+    pub reveal_selected_message: bool,
+    pub reply_to_message_id: Option<i32>,
+    pub editing_message_id: Option<i32>,
+    pub pending_delete_message_ids: Vec<i32>,
+    pub reaction_picker: Option<ReactionPicker>,
+    /// Messages of the selected chat marked for a bulk action.
+    pub marked_message_ids: BTreeSet<i32>,
+    /// Where the visual selection started; the range runs to the selected message.
+    pub selection_anchor: Option<i32>,
+    pub forward: Option<ForwardPicker>,
+    /// Pinned messages by dialog, newest first.
+    pub pinned_by_dialog: HashMap<i64, Vec<MessageSummary>>,
+    /// Which pin of the selected chat the pinned strip shows.
+    pub pinned_index: usize,
+    pub pending_pin: Option<PendingPin>,
+    pub topics_by_dialog: HashMap<i64, Vec<TopicSummary>>,
+    /// Topic shown for a forum chat; without one, all topics are shown together.
+    pub topic_by_dialog: HashMap<i64, i32>,
+    pub topic_picker: Option<TopicPicker>,
+    pub thread: Option<CommentThread>,
+    pub read_ack_by_dialog: HashMap<i64, i32>,
+    pub read_ack_by_topic: HashMap<(i64, i32), i32>,
+    pub offline: bool,
+    pub message_search: Option<MessageSearch>,
+    /// Chats showing a window of older history that does not reach the newest message.
+    pub detached_history_dialogs: HashSet<i64>,
+    /// Active downloads, oldest first.
+    pub downloads: Vec<Download>,
+    pub attach: Option<AttachPicker>,
+    pub upload: Option<Upload>,
+    /// One-off notice for the status line, cleared on the next key press.
+    pub status_message: Option<String>,
+    pub image_previews: HashMap<(i64, i32), ImagePreview>,
+    /// Messages whose spoilers are shown, by dialog and message id.
+    pub revealed_spoilers: HashSet<(i64, i32)>,
+    /// Requested previews, oldest first; the oldest are dropped beyond `MAX_IMAGE_PREVIEWS`.
+    preview_requests: VecDeque<(i64, i32)>,
 }
 
 impl AppState {
@@ -55,16 +297,69 @@ impl AppState {
     }
 
     pub fn on_dialogs_loaded(&mut self, dialogs: Vec<DialogSummary>) {
+        let known_drafts: HashMap<i64, String> = self
+            .dialogs
+            .iter()
+            .map(|dialog| (dialog.id, dialog.draft.clone()))
+            .collect();
         self.dialogs = dialogs;
-        self.new_message_count_by_dialog
-            .retain(|dialog_id, _| self.dialogs.iter().any(|dialog| dialog.id == *dialog_id));
+        // Local drafts stay unless the cloud draft changed since the last load.
+        for (dialog_id, draft) in self
+            .dialogs
+            .iter()
+            .filter(|dialog| known_drafts.get(&dialog.id) != Some(&dialog.draft))
+            .map(|dialog| (dialog.id, dialog.draft.clone()))
+            .collect::<Vec<_>>()
+        {
+            self.adopt_cloud_draft(dialog_id, draft);
+        }
+        // Server unread counts already include anything counted locally since the last load.
+        self.new_message_count_by_dialog.clear();
+        for dialog in &mut self.dialogs {
+            // The dialog list may have been fetched before our latest read acknowledgement landed.
+            if let Some(&acked) = self.read_ack_by_dialog.get(&dialog.id)
+                && acked > dialog.read_inbox_max_id
+            {
+                dialog.read_inbox_max_id = acked;
+                dialog.unread_count = 0;
+                dialog.unread_mentions_count = 0;
+            }
+        }
         self.is_loading_dialogs = false;
+        self.ensure_folder();
+        self.ensure_selection();
+    }
+
+    /// Shows cached chats until the first server answer; never overrides what is already loaded.
+    pub fn on_cached_dialogs(&mut self, dialogs: Vec<DialogSummary>) {
+        if !self.dialogs.is_empty() {
+            return;
+        }
+
+        self.dialogs = dialogs;
+        for (dialog_id, draft) in self
+            .dialogs
+            .iter()
+            .map(|dialog| (dialog.id, dialog.draft.clone()))
+            .collect::<Vec<_>>()
+        {
+            self.adopt_cloud_draft(dialog_id, draft);
+        }
         self.ensure_selection();
     }
 
+    pub fn on_cached_messages(&mut self, dialog_id: i64, messages: Vec<MessageSummary>) {
+        let loaded = self.messages_by_dialog.entry(dialog_id).or_default();
+        if loaded.is_empty() {
+            *loaded = messages;
+        }
+    }
+
     pub fn on_messages_loaded(&mut self, dialog_id: i64, messages: Vec<MessageSummary>) {
         self.messages_by_dialog.insert(dialog_id, messages);
         self.new_message_count_by_dialog.remove(&dialog_id);
+        self.history_exhausted_dialogs.remove(&dialog_id);
+        self.detached_history_dialogs.remove(&dialog_id);
         self.is_loading_messages = false;
         if Some(dialog_id) == self.selected_dialog_id {
             self.message_scroll_from_bottom = 0;
@@ -72,20 +367,239 @@ impl AppState {
         }
     }
 
+    /// A page of a thread that is no longer shown in its chat is dropped; it would mix into
+    /// the history.
+    pub fn on_older_messages_loaded(
+        &mut self,
+        dialog_id: i64,
+        thread_id: Option<i32>,
+        older: Vec<MessageSummary>,
+    ) {
+        self.is_loading_older_messages = false;
+        if thread_id != self.topic_by_dialog.get(&dialog_id).copied() {
+            return;
+        }
+
+        let messages = self.messages_by_dialog.entry(dialog_id).or_default();
+        let oldest_id = messages.first().map(|message| message.id);
+        let mut older: Vec<MessageSummary> = older
+            .into_iter()
+            .filter(|message| oldest_id.is_none_or(|oldest| message.id < oldest))
+            .collect();
+
+        if older.is_empty() {
+            self.history_exhausted_dialogs.insert(dialog_id);
+            return;
+        }
+
+        // Scroll position is bottom-relative, so prepending keeps the viewport in place.
+        older.append(messages);
+        *messages = older;
+    }
+
+    /// Returns the dialog and oldest loaded message id when the view is close enough to the top
+    /// of the loaded history that the previous page should be fetched.
+    pub fn older_messages_anchor(&self) -> Option<(i64, i32)> {
+        if self.is_loading_messages || self.is_loading_older_messages {
+            return None;
+        }
+
+        let dialog_id = self.selected_dialog_id?;
+        if self.history_exhausted_dialogs.contains(&dialog_id) {
+            return None;
+        }
+
+        let oldest_id = self.selected_dialog_messages().first()?.id;
+        let max_scroll = self
+            .message_viewport
+            .content_lines
+            .saturating_sub(self.message_viewport.height);
+        let lines_above = max_scroll.saturating_sub(self.message_scroll_from_bottom);
+        if lines_above > OLDER_MESSAGES_PREFETCH_LINES {
+            return None;
+        }
+
+        Some((dialog_id, oldest_id))
+    }
+
     pub fn on_message_sent(&mut self, dialog_id: i64, message: MessageSummary) {
+        self.note_last_message(dialog_id, &message);
         self.append_message_if_missing(dialog_id, message);
         self.is_sending_message = false;
-        self.compose_text.clear();
+        self.compose.push_history();
+        self.compose.clear();
+        self.drafts.remove(&dialog_id);
+        self.reply_to_message_id = None;
         self.last_error = None;
     }
 
+    pub fn on_message_edited(&mut self, dialog_id: i64, message: MessageSummary) {
+        if let Some(dialog) = self
+            .dialogs
+            .iter_mut()
+            .find(|dialog| dialog.id == dialog_id)
+            && dialog
+                .last_message
+                .as_ref()
+                .is_some_and(|last| last.id == message.id)
+        {
+            dialog.last_message = Some(LastMessage::from(&message));
+        }
+        if let Some(existing) = self
+            .messages_by_dialog
+            .get_mut(&dialog_id)
+            .and_then(|messages| messages.iter_mut().find(|m| m.id == message.id))
+        {
+            let recent = existing
+                .comments
+                .as_mut()
+                .map(|comments| std::mem::take(&mut comments.recent));
+            *existing = message.clone();
+            // Edits from updates do not say who commented last.
+            if let (Some(comments), Some(recent)) = (&mut existing.comments, recent)
+                && comments.recent.is_empty()
+            {
+                comments.recent = recent;
+            }
+        }
+        if let Some(pinned) = self
+            .pinned_by_dialog
+            .get_mut(&dialog_id)
+            .and_then(|pinned| pinned.iter_mut().find(|m| m.id == message.id))
+        {
+            *pinned = message.clone();
+        }
+
+        // Only our own pending edit finishes the edit; the same message edited elsewhere does not.
+        if self.is_sending_message
+            && Some(dialog_id) == self.selected_dialog_id
+            && self.editing_message_id == Some(message.id)
+        {
+            self.is_sending_message = false;
+            self.end_edit();
+            self.last_error = None;
+        }
+    }
+
+    /// `chosen_known` is false when `reactions` does not say which reactions are ours; the
+    /// previous choice is kept then.
+    pub fn on_reactions_changed(
+        &mut self,
+        dialog_id: i64,
+        message_id: i32,
+        mut reactions: Vec<ReactionCount>,
+        chosen_known: bool,
+    ) {
+        let Some(message) = self
+            .messages_by_dialog
+            .get_mut(&dialog_id)
+            .and_then(|messages| messages.iter_mut().find(|m| m.id == message_id))
+        else {
+            return;
+        };
+
+        if !chosen_known {
+            keep_chosen_reactions(&message.reactions, &mut reactions);
+        }
+        message.reactions = reactions;
+    }
+
+    pub fn on_messages_deleted(&mut self, dialog_id: i64, message_ids: &[i32]) {
+        if let Some(messages) = self.messages_by_dialog.get_mut(&dialog_id) {
+            messages.retain(|message| !message_ids.contains(&message.id));
+        }
+        self.remove_pins(dialog_id, message_ids);
+        self.forget_last_message(dialog_id, message_ids);
+
+        if Some(dialog_id) != self.selected_dialog_id {
+            return;
+        }
+
+        if self
+            .selected_message_id
+            .is_some_and(|id| message_ids.contains(&id))
+        {
+            self.clear_message_selection();
+        }
+        if self
+            .reply_to_message_id
+            .is_some_and(|id| message_ids.contains(&id))
+        {
+            self.reply_to_message_id = None;
+        }
+        if self
+            .editing_message_id
+            .is_some_and(|id| message_ids.contains(&id))
+        {
+            self.cancel_edit();
+        }
+    }
+
+    /// Deletions made elsewhere stay visible, struck through, instead of silently vanishing.
+    pub fn on_incoming_messages_deleted(&mut self, dialog_ids: &[i64], message_ids: &[i32]) {
+        for dialog_id in dialog_ids {
+            if let Some(messages) = self.messages_by_dialog.get_mut(dialog_id) {
+                for message in messages
+                    .iter_mut()
+                    .filter(|message| message_ids.contains(&message.id))
+                {
+                    message.deleted = true;
+                }
+            }
+            self.remove_pins(*dialog_id, message_ids);
+            self.forget_last_message(*dialog_id, message_ids);
+        }
+
+        if !self
+            .selected_dialog_id
+            .is_some_and(|id| dialog_ids.contains(&id))
+        {
+            return;
+        }
+
+        if self
+            .reply_to_message_id
+            .is_some_and(|id| message_ids.contains(&id))
+        {
+            self.reply_to_message_id = None;
+        }
+        if self
+            .editing_message_id
+            .is_some_and(|id| message_ids.contains(&id))
+        {
+            self.cancel_edit();
+        }
+        if self
+            .pending_delete_message_ids
+            .iter()
+            .any(|id| message_ids.contains(id))
+        {
+            self.cancel_delete();
+        }
+    }
+
+    pub fn on_outgoing_message(&mut self, dialog_id: i64, message: MessageSummary) {
+        self.note_last_message(dialog_id, &message);
+        self.append_message_if_missing(dialog_id, message);
+    }
+
     pub fn on_incoming_message(&mut self, dialog_id: i64, message: MessageSummary) {
-        if !self.append_message_if_missing(dialog_id, message) {
+        self.note_last_message(dialog_id, &message);
+        let topic_id = message.topic_id.unwrap_or(GENERAL_TOPIC_ID);
+        let in_open_topic = Some(dialog_id) == self.selected_dialog_id
+            && self.topic_by_dialog.get(&dialog_id) == Some(&topic_id);
+        if !in_open_topic && let Some(topic) = self.topic_mut(dialog_id, topic_id) {
+            topic.unread_count = topic.unread_count.saturating_add(1);
+        }
+
+        // Appending to a window of older history would leave a gap, so the message is only counted.
+        let detached = self.detached_history_dialogs.contains(&dialog_id);
+        if !detached && !self.append_message_if_missing(dialog_id, message) {
             return;
         }
 
         if Some(dialog_id) == self.selected_dialog_id {
-            if self.message_scroll_from_bottom > 0 {
+            if detached || self.message_scroll_from_bottom > 0 {
                 self.pending_new_messages_for_selected =
                     self.pending_new_messages_for_selected.saturating_add(1);
             }
@@ -98,6 +612,113 @@ impl AppState {
         }
     }
 
+    /// Returns the next read acknowledgement to send: the selected chat and the newest message
+    /// currently on screen, if it is newer than what is already read.
+    pub fn pending_read_ack(&self) -> Option<(i64, i32)> {
+        if self.offline {
+            return None;
+        }
+
+        let dialog = self.selected_dialog()?;
+        let last_visible = self.message_viewport.last_visible_message_id?;
+        let acked = match self.selected_topic_id() {
+            Some(topic_id) => self
+                .read_ack_by_topic
+                .get(&(dialog.id, topic_id))
+                .copied()
+                .unwrap_or_default()
+                .max(
+                    self.selected_topic()
+                        .map(|topic| topic.read_inbox_max_id)
+                        .unwrap_or_default(),
+                ),
+            None => self
+                .read_ack_by_dialog
+                .get(&dialog.id)
+                .copied()
+                .unwrap_or_default()
+                .max(dialog.read_inbox_max_id),
+        };
+
+        (last_visible > acked).then_some((dialog.id, last_visible))
+    }
+
+    /// Topics are read on their own; the unread count of the chat comes with the next refresh.
+    pub fn on_read_ack_sent(&mut self, dialog_id: i64, max_id: i32) {
+        if let Some(&topic_id) = self.topic_by_dialog.get(&dialog_id) {
+            self.read_ack_by_topic.insert((dialog_id, topic_id), max_id);
+            self.on_thread_read(dialog_id, topic_id, max_id);
+            return;
+        }
+
+        self.read_ack_by_dialog.insert(dialog_id, max_id);
+        let still_unread = self
+            .messages_by_dialog
+            .get(&dialog_id)
+            .map(|messages| {
+                messages
+                    .iter()
+                    .filter(|message| !message.outgoing && message.id > max_id)
+                    .count()
+            })
+            .unwrap_or_default();
+        self.on_inbox_read(dialog_id, max_id, still_unread);
+    }
+
+    pub fn on_inbox_read(&mut self, dialog_id: i64, max_id: i32, still_unread_count: usize) {
+        self.new_message_count_by_dialog.remove(&dialog_id);
+        if let Some(dialog) = self.dialog_mut(dialog_id) {
+            dialog.read_inbox_max_id = dialog.read_inbox_max_id.max(max_id);
+            dialog.unread_count = still_unread_count;
+            if still_unread_count == 0 {
+                dialog.unread_mentions_count = 0;
+            }
+        }
+    }
+
+    pub fn on_thread_read(&mut self, dialog_id: i64, thread_id: i32, max_id: i32) {
+        let still_unread = (self.topic_by_dialog.get(&dialog_id) == Some(&thread_id)).then(|| {
+            self.messages_by_dialog
+                .get(&dialog_id)
+                .map(|messages| {
+                    messages
+                        .iter()
+                        .filter(|message| !message.outgoing && message.id > max_id)
+                        .count()
+                })
+                .unwrap_or_default()
+        });
+        let Some(topic) = self.topic_mut(dialog_id, thread_id) else {
+            return;
+        };
+        topic.read_inbox_max_id = topic.read_inbox_max_id.max(max_id);
+        if let Some(still_unread) = still_unread {
+            topic.unread_count = still_unread;
+        } else if max_id >= topic.top_message {
+            topic.unread_count = 0;
+        }
+        if topic.unread_count == 0 {
+            topic.unread_mentions_count = 0;
+        }
+    }
+
+    pub fn on_outbox_read(&mut self, dialog_id: i64, max_id: i32) {
+        if let Some(dialog) = self.dialog_mut(dialog_id) {
+            dialog.read_outbox_max_id = dialog.read_outbox_max_id.max(max_id);
+        }
+    }
+
+    /// Server-side unread count plus messages that arrived since the dialogs were last loaded.
+    pub fn dialog_unread_count(&self, dialog_id: i64) -> usize {
+        let server_count = self
+            .dialogs
+            .iter()
+            .find(|dialog| dialog.id == dialog_id)
+            .map(|dialog| dialog.unread_count)
+            .unwrap_or_default();
+        server_count.saturating_add(self.dialog_new_message_count(dialog_id))
+    }
+
     pub fn dialog_new_message_count(&self, dialog_id: i64) -> usize {
         self.new_message_count_by_dialog
             .get(&dialog_id)
@@ -131,13 +752,14 @@ impl AppState {
             return false;
         }
 
-        let Some(current_id) = self.selected_dialog_id else {
+        let Some(current_id) = self.list_dialog_id() else {
             self.selected_dialog_id = Some(visible[0]);
             self.pending_new_messages_for_selected = 0;
             return true;
         };
 
         let Some(pos) = visible.iter().position(|id| *id == current_id) else {
+            self.leave_thread();
             self.selected_dialog_id = Some(visible[0]);
             self.pending_new_messages_for_selected = 0;
             return true;
@@ -147,9 +769,11 @@ impl AppState {
             return false;
         }
 
+        self.leave_thread();
         self.selected_dialog_id = Some(visible[pos - 1]);
         self.message_scroll_from_bottom = 0;
         self.pending_new_messages_for_selected = 0;
+        self.clear_message_cursor();
         true
     }
 
@@ -159,13 +783,14 @@ impl AppState {
             return false;
         }
 
-        let Some(current_id) = self.selected_dialog_id else {
+        let Some(current_id) = self.list_dialog_id() else {
             self.selected_dialog_id = Some(visible[0]);
             self.pending_new_messages_for_selected = 0;
             return true;
         };
 
         let Some(pos) = visible.iter().position(|id| *id == current_id) else {
+            self.leave_thread();
             self.selected_dialog_id = Some(visible[0]);
             self.pending_new_messages_for_selected = 0;
             return true;
@@ -175,17 +800,26 @@ impl AppState {
             return false;
         }
 
+        self.leave_thread();
         self.selected_dialog_id = Some(visible[pos + 1]);
         self.message_scroll_from_bottom = 0;
         self.pending_new_messages_for_selected = 0;
+        self.clear_message_cursor();
         true
     }
 
+    /// Chats of the active folder; a chat search looks through all of them.
     pub fn visible_dialogs(&self) -> Vec<&DialogSummary> {
         let mut dialogs: Vec<&DialogSummary> = self
             .dialogs
             .iter()
-            .filter(|dialog| self.matches_query(dialog))
+            .filter(|dialog| {
+                if self.search_query.is_empty() {
+                    self.in_folder(self.active_folder, dialog)
+                } else {
+                    self.matches_query(dialog)
+                }
+            })
             .collect();
 
         if self.sort_mode == SortMode::Alphabetical {
@@ -201,7 +835,7 @@ impl AppState {
     }
 
     pub fn selected_visible_index(&self) -> Option<usize> {
-        let selected_id = self.selected_dialog_id?;
+        let selected_id = self.list_dialog_id()?;
         self.visible_dialog_ids()
             .iter()
             .position(|id| *id == selected_id)
@@ -247,6 +881,73 @@ impl AppState {
         }
     }
 
+    pub fn on_folders_loaded(&mut self, folders: Vec<ChatFolder>) {
+        self.folders = folders;
+        self.ensure_folder();
+        self.ensure_selection();
+    }
+
+    /// "All", the user's folders, and "Archived" while there are archived chats.
+    pub fn folder_tabs(&self) -> Vec<FolderTab> {
+        let mut tabs = vec![FolderTab::All];
+        tabs.extend(
+            self.folders
+                .iter()
+                .map(|folder| FolderTab::Folder(folder.id)),
+        );
+        if self.dialogs.iter().any(|dialog| dialog.archived) {
+            tabs.push(FolderTab::Archived);
+        }
+        tabs
+    }
+
+    pub fn folder_title(&self, tab: FolderTab) -> &str {
+        match tab {
+            FolderTab::All => "All",
+            FolderTab::Folder(id) => self
+                .folders
+                .iter()
+                .find(|folder| folder.id == id)
+                .map_or("Folder", |folder| folder.title.as_str()),
+            FolderTab::Archived => "Archived",
+        }
+    }
+
+    /// Unread messages in the folder. Muted chats are left out, as in other Telegram apps.
+    pub fn folder_unread_count(&self, tab: FolderTab) -> usize {
+        self.dialogs
+            .iter()
+            .filter(|dialog| !dialog.muted && self.in_folder(tab, dialog))
+            .map(|dialog| self.dialog_unread_count(dialog.id))
+            .sum()
+    }
+
+    /// Shows the folder at this tab position.
+    pub fn select_folder(&mut self, index: usize) {
+        if let Some(&tab) = self.folder_tabs().get(index) {
+            self.active_folder = tab;
+            self.ensure_selection();
+        }
+    }
+
+    pub fn select_next_folder(&mut self) {
+        let tabs = self.folder_tabs();
+        let index = tabs
+            .iter()
+            .position(|tab| *tab == self.active_folder)
+            .map_or(0, |index| (index + 1) % tabs.len());
+        self.select_folder(index);
+    }
+
+    pub fn select_prev_folder(&mut self) {
+        let tabs = self.folder_tabs();
+        let index = tabs
+            .iter()
+            .position(|tab| *tab == self.active_folder)
+            .map_or(0, |index| (index + tabs.len() - 1) % tabs.len());
+        self.select_folder(index);
+    }
+
     pub fn toggle_sort_mode(&mut self) {
         self.sort_mode = match self.sort_mode {
             SortMode::Recent => SortMode::Alphabetical,
@@ -257,276 +958,3249 @@ impl AppState {
 
     pub fn insert_char(&mut self, ch: char) {
         match self.ui_mode {
-            UiMode::Compose => self.compose_text.push(ch),
+            UiMode::Compose => self.compose.insert_char(ch),
             UiMode::Search => {
                 self.search_query.push(ch);
                 self.ensure_selection();
             }
-            UiMode::Normal => {}
+            UiMode::MessageSearch => {
+                if let Some(search) = &mut self.message_search {
+                    search.query.push(ch);
+                }
+            }
+            UiMode::AttachFile => {
+                if let Some(picker) = &mut self.attach {
+                    picker.path.push(ch);
+                    picker.completions.clear();
+                }
+            }
+            UiMode::ForwardPicker => {
+                if let Some(picker) = &mut self.forward {
+                    picker.query.push(ch);
+                    picker.selected = 0;
+                }
+            }
+            UiMode::Normal
+            | UiMode::ConfirmDelete
+            | UiMode::ConfirmPin
+            | UiMode::TopicPicker
+            | UiMode::PickReaction
+            | UiMode::SelectMessages => {}
+        }
+    }
+
+    pub fn edit_compose(&mut self, action: EditAction) {
+        if self.ui_mode == UiMode::Compose {
+            self.compose.apply(action);
         }
     }
 
     pub fn backspace(&mut self) {
         match self.ui_mode {
             UiMode::Compose => {
-                self.compose_text.pop();
+                self.compose.backspace();
             }
             UiMode::Search => {
                 self.search_query.pop();
                 self.ensure_selection();
             }
-            UiMode::Normal => {}
+            UiMode::MessageSearch => {
+                if let Some(search) = &mut self.message_search {
+                    search.query.pop();
+                }
+            }
+            UiMode::AttachFile => {
+                if let Some(picker) = &mut self.attach {
+                    picker.path.pop();
+                    picker.completions.clear();
+                }
+            }
+            UiMode::ForwardPicker => {
+                if let Some(picker) = &mut self.forward {
+                    picker.query.pop();
+                    picker.selected = 0;
+                }
+            }
+            UiMode::Normal
+            | UiMode::ConfirmDelete
+            | UiMode::ConfirmPin
+            | UiMode::TopicPicker
+            | UiMode::PickReaction
+            | UiMode::SelectMessages => {}
         }
     }
 
-    pub fn scroll_messages_up(&mut self) {
-        self.message_scroll_from_bottom = self.message_scroll_from_bottom.saturating_add(1);
-    }
+    /// Shows or hides the spoilers of the selected message.
+    pub fn toggle_spoilers(&mut self) {
+        let Some((dialog_id, message)) = self.selected_dialog_id.zip(self.selected_message())
+        else {
+            return;
+        };
+        if !message
+            .entities
+            .iter()
+            .any(|entity| entity.kind == EntityKind::Spoiler)
+        {
+            return;
+        }
 
-    pub fn scroll_messages_down(&mut self) {
-        if self.message_scroll_from_bottom > 0 {
-            self.message_scroll_from_bottom -= 1;
-            if self.message_scroll_from_bottom == 0 {
-                self.pending_new_messages_for_selected = 0;
-            }
+        let key = (dialog_id, message.id);
+        if !self.revealed_spoilers.remove(&key) {
+            self.revealed_spoilers.insert(key);
         }
     }
 
-    fn matches_query(&self, dialog: &DialogSummary) -> bool {
-        if self.search_query.is_empty() {
-            return true;
+    pub fn spoilers_revealed(&self, message_id: i32) -> bool {
+        self.selected_dialog_id
+            .is_some_and(|dialog_id| self.revealed_spoilers.contains(&(dialog_id, message_id)))
+    }
+
+    /// Registers a download of the selected message's media and returns what to download.
+    pub fn start_download(&mut self) -> Option<(i64, i32)> {
+        let dialog_id = self.selected_dialog_id?;
+        let message_id = self
+            .selected_message()
+            .filter(|message| {
+                !message.deleted && message.media.as_ref().is_some_and(MediaInfo::is_file)
+            })?
+            .id;
+        if self
+            .downloads
+            .iter()
+            .any(|download| download.dialog_id == dialog_id && download.message_id == message_id)
+        {
+            return None;
         }
 
-        dialog
-            .title
-            .to_lowercase()
-            .contains(&self.search_query.to_lowercase())
+        self.downloads.push(Download {
+            dialog_id,
+            message_id,
+            file_name: String::new(),
+            downloaded: 0,
+            total: None,
+        });
+        Some((dialog_id, message_id))
     }
 
-    fn visible_dialog_ids(&self) -> Vec<i64> {
-        self.visible_dialogs()
-            .iter()
-            .map(|dialog| dialog.id)
-            .collect()
+    /// The transfer a cancel key press refers to: the selected message's download, else the
+    /// upload, else the newest download.
+    pub fn transfer_to_cancel(&self) -> Option<Transfer> {
+        let selected = self
+            .selected_dialog_id
+            .zip(self.selected_message_id)
+            .filter(|&(dialog_id, message_id)| {
+                self.downloads.iter().any(|download| {
+                    download.dialog_id == dialog_id && download.message_id == message_id
+                })
+            });
+        let download = |(dialog_id, message_id)| Transfer::Download {
+            dialog_id,
+            message_id,
+        };
+        selected
+            .map(download)
+            .or(self.upload.as_ref().map(|_| Transfer::Upload))
+            .or_else(|| {
+                self.downloads
+                    .last()
+                    .map(|latest| download((latest.dialog_id, latest.message_id)))
+            })
     }
 
-    fn append_message_if_missing(&mut self, dialog_id: i64, message: MessageSummary) -> bool {
-        let messages = self.messages_by_dialog.entry(dialog_id).or_default();
-        if messages.iter().any(|existing| existing.id == message.id) {
-            return false;
+    pub fn on_download_progress(
+        &mut self,
+        dialog_id: i64,
+        message_id: i32,
+        file_name: String,
+        downloaded: u64,
+        total: Option<u64>,
+    ) {
+        if let Some(download) = self.download_mut(dialog_id, message_id) {
+            download.file_name = file_name;
+            download.downloaded = downloaded;
+            download.total = total;
         }
-        messages.push(message);
-        true
     }
 
-    fn ensure_selection(&mut self) {
-        let visible = self.visible_dialog_ids();
-        if visible.is_empty() {
-            self.selected_dialog_id = None;
-            return;
+    pub fn on_download_finished(&mut self, dialog_id: i64, message_id: i32, path: &Path) {
+        self.remove_download(dialog_id, message_id);
+        self.status_message = Some(format!("Saved {}", path.display()));
+    }
+
+    pub fn on_download_cancelled(&mut self, dialog_id: i64, message_id: i32) {
+        if let Some(download) = self.remove_download(dialog_id, message_id) {
+            self.status_message = Some(format!("Cancelled download {}", download.file_name));
         }
+    }
 
-        if !self
-            .selected_dialog_id
-            .is_some_and(|id| visible.contains(&id))
+    pub fn on_download_failed(&mut self, dialog_id: i64, message_id: i32, error: String) {
+        self.remove_download(dialog_id, message_id);
+        self.last_error = Some(format!("Download failed: {error}"));
+    }
+
+    pub fn start_attach(&mut self) {
+        if self.selected_dialog_id.is_none() {
+            self.last_error = Some("No chat selected".to_string());
+        } else if self.offline {
+            self.last_error = Some("Offline mode is read-only".to_string());
+        } else if self.editing_message_id.is_some() {
+            self.last_error = Some("Files cannot be attached to an edit".to_string());
+        } else if self.upload.is_some() {
+            self.last_error = Some("Another file is still uploading".to_string());
+        } else {
+            self.attach = Some(AttachPicker::default());
+            self.ui_mode = UiMode::AttachFile;
+            self.focus = FocusArea::Input;
+        }
+    }
+
+    pub fn close_attach(&mut self) {
+        self.attach = None;
+        self.ui_mode = UiMode::Compose;
+    }
+
+    pub fn complete_attach_path(&mut self) {
+        if let Some(picker) = &mut self.attach {
+            let (path, completions) = complete_path(&picker.path);
+            picker.path = path;
+            picker.completions = completions;
+        }
+    }
+
+    /// Takes the picked file, with the compose text as its caption, and starts tracking the upload.
+    pub fn submit_attachment(&mut self) -> Option<Attachment> {
+        let dialog_id = self.selected_dialog_id?;
+        let path = self.attach.as_ref()?.path.trim();
+        if path.is_empty() {
+            self.last_error = Some("Type the path of a file to send".to_string());
+            return None;
+        }
+
+        let path = expand_home(path);
+        if !path.is_file() {
+            self.last_error = Some(format!("{} is not a file", path.display()));
+            return None;
+        }
+
+        let caption = self.compose.text().trim().to_string();
+        let formatted = self.formatted_compose();
+        self.compose.clear();
+        self.attach = None;
+        self.ui_mode = UiMode::Compose;
+        self.last_error = None;
+        self.upload = Some(Upload {
+            dialog_id,
+            file_name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            caption: caption.clone(),
+            uploaded: 0,
+            total: None,
+        });
+        let reply_to = self.send_reply_to();
+        self.reply_to_message_id = None;
+        Some(Attachment {
+            dialog_id,
+            path,
+            caption: formatted,
+            reply_to,
+        })
+    }
+
+    pub fn on_upload_progress(&mut self, dialog_id: i64, uploaded: u64, total: u64) {
+        if let Some(upload) = self
+            .upload
+            .as_mut()
+            .filter(|upload| upload.dialog_id == dialog_id)
         {
-            self.selected_dialog_id = Some(visible[0]);
-            self.message_scroll_from_bottom = 0;
+            upload.uploaded = uploaded;
+            upload.total = Some(total);
+        }
+    }
+
+    pub fn on_upload_finished(&mut self, dialog_id: i64, message: MessageSummary) {
+        if let Some(upload) = self.upload.take() {
+            self.status_message = Some(format!("Sent {}", upload.file_name));
+        }
+        self.note_last_message(dialog_id, &message);
+        self.append_message_if_missing(dialog_id, message);
+    }
+
+    pub fn on_upload_cancelled(&mut self, dialog_id: i64) {
+        if let Some(upload) = self.restore_upload_caption(dialog_id) {
+            self.status_message = Some(format!("Cancelled upload {}", upload.file_name));
+        }
+    }
+
+    pub fn on_upload_failed(&mut self, dialog_id: i64, error: String) {
+        self.restore_upload_caption(dialog_id);
+        self.last_error = Some(format!("Upload failed: {error}"));
+    }
+
+    /// Photos on screen in the selected chat whose previews have not been requested yet.
+    pub fn previews_to_load(&mut self) -> Vec<(i64, i32)> {
+        let viewport = self.message_viewport;
+        let (Some(dialog_id), Some(first), Some(last), false) = (
+            self.selected_dialog_id,
+            viewport.first_visible_message_id,
+            viewport.last_visible_message_id,
+            self.offline,
+        ) else {
+            return Vec::new();
+        };
+
+        let wanted: Vec<(i64, i32)> = self
+            .selected_dialog_messages()
+            .iter()
+            .filter(|message| {
+                (first..=last).contains(&message.id)
+                    && !message.deleted
+                    && matches!(message.media, Some(MediaInfo::Photo { .. }))
+            })
+            .map(|message| (dialog_id, message.id))
+            .filter(|key| !self.preview_requests.contains(key))
+            .collect();
+        for &key in &wanted {
+            self.preview_requests.push_back(key);
+            if self.preview_requests.len() > MAX_IMAGE_PREVIEWS
+                && let Some(evicted) = self.preview_requests.pop_front()
+            {
+                self.image_previews.remove(&evicted);
+            }
+        }
+        wanted
+    }
+
+    pub fn on_image_preview_loaded(
+        &mut self,
+        dialog_id: i64,
+        message_id: i32,
+        preview: ImagePreview,
+    ) {
+        // Requests evicted while loading are not cached.
+        if self.preview_requests.contains(&(dialog_id, message_id)) {
+            self.image_previews.insert((dialog_id, message_id), preview);
+        }
+    }
+
+    pub fn start_message_search(&mut self, all_chats: bool) -> bool {
+        let scope = if all_chats {
+            MessageSearchScope::AllChats
+        } else {
+            match self.selected_dialog_id {
+                Some(dialog_id) => MessageSearchScope::Chat(dialog_id),
+                None => return false,
+            }
+        };
+
+        self.message_search = Some(MessageSearch {
+            scope,
+            query: String::new(),
+            results_query: None,
+            results: Vec::new(),
+            selected: 0,
+            is_searching: false,
+        });
+        self.ui_mode = UiMode::MessageSearch;
+        true
+    }
+
+    pub fn close_message_search(&mut self) {
+        self.message_search = None;
+        self.ui_mode = UiMode::Normal;
+    }
+
+    pub fn toggle_message_search_scope(&mut self) {
+        let selected_dialog_id = self.selected_dialog_id;
+        let Some(search) = &mut self.message_search else {
+            return;
+        };
+
+        search.scope = match (search.scope, selected_dialog_id) {
+            (MessageSearchScope::AllChats, Some(dialog_id)) => MessageSearchScope::Chat(dialog_id),
+            _ => MessageSearchScope::AllChats,
+        };
+        search.results_query = None;
+        search.results.clear();
+        search.selected = 0;
+    }
+
+    pub fn select_prev_search_result(&mut self) {
+        if let Some(search) = &mut self.message_search {
+            search.selected = search.selected.saturating_sub(1);
+        }
+    }
+
+    pub fn select_next_search_result(&mut self) {
+        if let Some(search) = &mut self.message_search
+            && search.selected + 1 < search.results.len()
+        {
+            search.selected += 1;
+        }
+    }
+
+    /// Enter runs the typed query, or opens the selected result once results for that exact
+    /// query are on screen.
+    pub fn submit_message_search(&mut self) -> Option<SearchSubmission> {
+        let search = self.message_search.as_mut()?;
+        let query = search.query.trim().to_string();
+        if query.is_empty() || search.is_searching {
+            return None;
+        }
+
+        if search.results_query.as_deref() == Some(query.as_str()) {
+            let result = search.results.get(search.selected)?;
+            return Some(SearchSubmission::Open {
+                dialog_id: result.dialog_id,
+                message_id: result.message.id,
+            });
+        }
+
+        search.is_searching = true;
+        let dialog_id = match search.scope {
+            MessageSearchScope::Chat(dialog_id) => Some(dialog_id),
+            MessageSearchScope::AllChats => None,
+        };
+        Some(SearchSubmission::Search { dialog_id, query })
+    }
+
+    pub fn on_search_results_loaded(&mut self, query: String, results: Vec<MessageSearchResult>) {
+        let Some(search) = &mut self.message_search else {
+            return;
+        };
+
+        search.is_searching = false;
+        search.results_query = Some(query);
+        search.results = results;
+        search.selected = 0;
+    }
+
+    /// Switches to the chat of a search result and marks the message for loading. Returns
+    /// `false` when the chat is not in the chat list.
+    pub fn open_message(&mut self, dialog_id: i64, message_id: i32) -> bool {
+        let Some(archived) = self
+            .dialogs
+            .iter()
+            .find(|dialog| dialog.id == dialog_id)
+            .map(|dialog| dialog.archived)
+        else {
+            self.last_error = Some("Chat of this message is not in the chat list".to_string());
+            return false;
+        };
+
+        self.close_message_search();
+        if !self.visible_dialog_ids().contains(&dialog_id) {
+            self.search_query.clear();
+        }
+        if !self.visible_dialog_ids().contains(&dialog_id) {
+            self.active_folder = if archived {
+                FolderTab::Archived
+            } else {
+                FolderTab::All
+            };
+        }
+        self.leave_thread();
+        // The message may be in any topic, so the history around it spans all of them.
+        self.topic_by_dialog.remove(&dialog_id);
+        self.selected_dialog_id = Some(dialog_id);
+        self.message_scroll_from_bottom = 0;
+        self.pending_new_messages_for_selected = 0;
+        self.clear_message_cursor();
+        self.selected_message_id = Some(message_id);
+        self.focus = FocusArea::Messages;
+        self.is_loading_messages = true;
+        true
+    }
+
+    pub fn on_messages_around_loaded(
+        &mut self,
+        dialog_id: i64,
+        message_id: i32,
+        messages: Vec<MessageSummary>,
+        has_newer: bool,
+    ) {
+        if Some(dialog_id) != self.selected_dialog_id {
+            return;
+        }
+
+        self.messages_by_dialog.insert(dialog_id, messages);
+        self.history_exhausted_dialogs.remove(&dialog_id);
+        if has_newer {
+            self.detached_history_dialogs.insert(dialog_id);
+        } else {
+            self.detached_history_dialogs.remove(&dialog_id);
+        }
+        self.is_loading_messages = false;
+        self.message_scroll_from_bottom = 0;
+        self.pending_new_messages_for_selected = 0;
+        self.selected_message_id = Some(message_id);
+        self.reveal_selected_message = true;
+    }
+
+    /// True when the selected chat shows older history and the view is already at its bottom,
+    /// so scrolling further down should jump to the newest messages.
+    pub fn wants_latest_messages(&self) -> bool {
+        !self.is_loading_messages
+            && self.message_scroll_from_bottom == 0
+            && self
+                .selected_dialog_id
+                .is_some_and(|id| self.detached_history_dialogs.contains(&id))
+    }
+
+    pub fn scroll_messages_up(&mut self) {
+        self.message_scroll_from_bottom = self.message_scroll_from_bottom.saturating_add(1);
+    }
+
+    pub fn scroll_messages_down(&mut self) {
+        if self.message_scroll_from_bottom > 0 {
+            self.message_scroll_from_bottom -= 1;
+            if self.message_scroll_from_bottom == 0 {
+                self.pending_new_messages_for_selected = 0;
+            }
+        }
+    }
+
+    pub fn apply_message_viewport(&mut self, viewport: MessageViewport) {
+        self.message_viewport = viewport;
+        self.message_scroll_from_bottom = viewport.scroll_from_bottom;
+        if self.message_scroll_from_bottom == 0 {
             self.pending_new_messages_for_selected = 0;
         }
-    }
-}
+        self.reveal_selected_message = false;
+    }
+
+    pub fn select_prev_message(&mut self) {
+        let messages = self.selected_dialog_messages();
+        let next = match self.selected_message_index() {
+            Some(index) => messages[index.saturating_sub(1)].id,
+            None => match messages.last() {
+                Some(message) => message.id,
+                None => return,
+            },
+        };
+        self.selected_message_id = Some(next);
+        self.reveal_selected_message = true;
+    }
+
+    pub fn select_next_message(&mut self) {
+        let messages = self.selected_dialog_messages();
+        let next = match self.selected_message_index() {
+            Some(index) if index + 1 < messages.len() => messages[index + 1].id,
+            Some(_) => return,
+            None => match messages.last() {
+                Some(message) => message.id,
+                None => return,
+            },
+        };
+        self.selected_message_id = Some(next);
+        self.reveal_selected_message = true;
+    }
+
+    pub fn selected_message_index(&self) -> Option<usize> {
+        let selected_id = self.selected_message_id?;
+        self.selected_dialog_messages()
+            .iter()
+            .position(|message| message.id == selected_id)
+    }
+
+    pub fn selected_message(&self) -> Option<&MessageSummary> {
+        self.selected_message_index()
+            .map(|index| &self.selected_dialog_messages()[index])
+    }
+
+    pub fn clear_message_selection(&mut self) {
+        self.selected_message_id = None;
+        self.reveal_selected_message = false;
+        self.marked_message_ids.clear();
+    }
+
+    /// Marks or unmarks the selected message for a bulk action.
+    pub fn toggle_message_mark(&mut self) {
+        let Some(message_id) = self
+            .selected_message()
+            .filter(|message| !message.deleted)
+            .map(|message| message.id)
+        else {
+            return;
+        };
+
+        if !self.marked_message_ids.remove(&message_id) {
+            self.marked_message_ids.insert(message_id);
+        }
+    }
+
+    /// Starts a visual selection at the selected message, or at the newest one.
+    pub fn start_message_selection(&mut self) -> bool {
+        if self.selected_message_id.is_none() {
+            self.select_prev_message();
+        }
+        let Some(message_id) = self.selected_message_id else {
+            return false;
+        };
+
+        self.selection_anchor = Some(message_id);
+        self.ui_mode = UiMode::SelectMessages;
+        true
+    }
+
+    /// Leaves the visual selection and drops the range and the marks.
+    pub fn cancel_message_selection(&mut self) {
+        self.selection_anchor = None;
+        self.marked_message_ids.clear();
+        self.ui_mode = UiMode::Normal;
+    }
+
+    /// Whether a message is marked or inside the visual selection.
+    pub fn is_message_selected(&self, message_id: i32) -> bool {
+        self.marked_message_ids.contains(&message_id) || self.selection_range_contains(message_id)
+    }
+
+    /// Marked and visually selected messages in chat order, or the selected message when
+    /// nothing is marked. Deleted messages are left out.
+    pub fn target_message_ids(&self) -> Vec<i32> {
+        let messages = self.selected_dialog_messages();
+        if self.marked_message_ids.is_empty() && self.selection_anchor.is_none() {
+            return self
+                .selected_message()
+                .filter(|message| !message.deleted)
+                .map(|message| message.id)
+                .into_iter()
+                .collect();
+        }
+
+        messages
+            .iter()
+            .filter(|message| !message.deleted && self.is_message_selected(message.id))
+            .map(|message| message.id)
+            .collect()
+    }
+
+    /// Ends the visual selection and returns the target messages for copying or exporting.
+    pub fn take_target_messages(&mut self) -> Vec<MessageSummary> {
+        let ids = self.target_message_ids();
+        self.cancel_message_selection();
+        self.selected_dialog_messages()
+            .iter()
+            .filter(|message| ids.contains(&message.id))
+            .cloned()
+            .collect()
+    }
+
+    /// Opens the forward picker for the target messages.
+    pub fn start_forward(&mut self) -> bool {
+        let message_ids = self.target_message_ids();
+        if message_ids.is_empty() {
+            return false;
+        }
+        self.freeze_selection_range();
+
+        self.forward = Some(ForwardPicker {
+            message_ids,
+            ..ForwardPicker::default()
+        });
+        self.ui_mode = UiMode::ForwardPicker;
+        true
+    }
+
+    pub fn close_forward_picker(&mut self) {
+        self.forward = None;
+        self.ui_mode = UiMode::Normal;
+    }
+
+    /// Visible chats matching the picker query, best match first.
+    pub fn forward_candidates(&self) -> Vec<&DialogSummary> {
+        let query = self
+            .forward
+            .as_ref()
+            .map(|picker| picker.query.as_str())
+            .unwrap_or_default();
+        let mut scored: Vec<(i64, &DialogSummary)> = self
+            .visible_dialogs()
+            .into_iter()
+            .filter_map(|dialog| Some((fuzzy_score(query, &dialog.title)?, dialog)))
+            .collect();
+        // Stable, so equally good matches keep the chat list order.
+        scored.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+        scored.into_iter().map(|(_, dialog)| dialog).collect()
+    }
+
+    pub fn select_prev_forward_target(&mut self) {
+        if let Some(picker) = &mut self.forward {
+            picker.selected = picker.selected.saturating_sub(1);
+        }
+    }
+
+    pub fn select_next_forward_target(&mut self) {
+        let count = self.forward_candidates().len();
+        if let Some(picker) = &mut self.forward
+            && picker.selected + 1 < count
+        {
+            picker.selected += 1;
+        }
+    }
+
+    /// Turning the author off also keeps the captions.
+    pub fn toggle_forward_drop_author(&mut self) {
+        if let Some(picker) = &mut self.forward {
+            picker.drop_author = !picker.drop_author;
+            picker.drop_captions &= picker.drop_author;
+        }
+    }
+
+    /// Captions can only be dropped from copies, so this also drops the author.
+    pub fn toggle_forward_drop_captions(&mut self) {
+        if let Some(picker) = &mut self.forward {
+            picker.drop_captions = !picker.drop_captions;
+            picker.drop_author |= picker.drop_captions;
+        }
+    }
+
+    /// Closes the picker and returns the forward to send, if a chat matches the query.
+    pub fn submit_forward(&mut self) -> Option<Forward> {
+        let to_dialog_id = self
+            .forward_candidates()
+            .get(self.forward.as_ref()?.selected)?
+            .id;
+        let picker = self.forward.take()?;
+        self.ui_mode = UiMode::Normal;
+        self.marked_message_ids.clear();
+        Some(Forward {
+            from_dialog_id: self.selected_dialog_id?,
+            message_ids: picker.message_ids,
+            to_dialog_id,
+            drop_author: picker.drop_author,
+            drop_captions: picker.drop_captions,
+        })
+    }
+
+    pub fn on_messages_forwarded(&mut self, dialog_id: i64, messages: Vec<MessageSummary>) {
+        let count = messages.len();
+        for message in &messages {
+            self.note_last_message(dialog_id, message);
+        }
+        // Chats that were never opened load the messages with the rest of their history.
+        if self.messages_by_dialog.contains_key(&dialog_id)
+            && !self.detached_history_dialogs.contains(&dialog_id)
+        {
+            for message in messages {
+                self.append_message_if_missing(dialog_id, message);
+            }
+        }
+
+        let title = self
+            .dialogs
+            .iter()
+            .find(|dialog| dialog.id == dialog_id)
+            .map(|dialog| dialog.title.as_str())
+            .unwrap_or("chat");
+        self.status_message = Some(format!("Forwarded {} to {title}", message_count(count)));
+    }
+
+    pub fn start_reply(&mut self) -> bool {
+        let Some(message_id) = self
+            .selected_message()
+            .filter(|message| !message.deleted)
+            .map(|message| message.id)
+        else {
+            return false;
+        };
+
+        self.cancel_edit();
+        self.reply_to_message_id = Some(message_id);
+        self.enter_compose();
+        true
+    }
+
+    pub fn cancel_reply(&mut self) {
+        self.reply_to_message_id = None;
+    }
+
+    pub fn start_edit(&mut self) -> bool {
+        let Some(message) = self
+            .selected_message()
+            .filter(|message| message.outgoing && !message.deleted)
+        else {
+            return false;
+        };
+        let message_id = message.id;
+        // The text goes back into markup so that sending it keeps the formatting.
+        let mode = markup::mode_for(&message.entities, self.markup_mode);
+        let text = markup::render(&message.text, &message.entities, mode);
+
+        // The draft comes back once the edit is done.
+        self.stash_compose_draft();
+        self.editing_message_id = Some(message_id);
+        self.reply_to_message_id = None;
+        if mode != self.markup_mode {
+            self.markup_mode_before_edit = Some(self.markup_mode);
+            self.markup_mode = mode;
+        }
+        self.compose.set_text(text);
+        self.enter_compose();
+        true
+    }
+
+    /// The compose text with its markup turned into entities.
+    pub fn formatted_compose(&self) -> FormattedText {
+        markup::parse(self.compose.text().trim(), self.markup_mode)
+    }
+
+    pub fn cycle_markup_mode(&mut self) {
+        self.markup_mode = self.markup_mode.next();
+    }
+
+    pub fn toggle_compose_preview(&mut self) {
+        self.compose_preview = !self.compose_preview;
+    }
+
+    pub fn cancel_edit(&mut self) {
+        if self.editing_message_id.is_some() {
+            self.end_edit();
+        }
+    }
+
+    fn end_edit(&mut self) {
+        self.editing_message_id = None;
+        if let Some(mode) = self.markup_mode_before_edit.take() {
+            self.markup_mode = mode;
+        }
+        self.restore_compose_draft();
+    }
+
+    /// Keeps the compose text with the chat it was typed in: once another chat is selected,
+    /// the text becomes the previous chat's draft and the new chat's draft is loaded. Returns
+    /// the previous chat's draft when it differs from the cloud draft.
+    pub fn sync_compose_draft(&mut self) -> Option<(i64, String)> {
+        if self.compose_dialog_id == self.selected_dialog_id {
+            return None;
+        }
+
+        self.cancel_edit();
+        let unsaved = self.unsaved_draft();
+        self.compose_dialog_id = self.selected_dialog_id;
+        self.restore_compose_draft();
+        unsaved
+    }
+
+    /// The draft of the chat being composed in, when it differs from the cloud draft.
+    pub fn unsaved_draft(&mut self) -> Option<(i64, String)> {
+        self.stash_compose_draft();
+        let dialog_id = self.compose_dialog_id?;
+        let draft = self.drafts.get(&dialog_id).cloned().unwrap_or_default();
+        let cloud = self
+            .dialogs
+            .iter()
+            .find(|dialog| dialog.id == dialog_id)?
+            .draft
+            .as_str();
+        (draft != cloud).then_some((dialog_id, draft))
+    }
+
+    pub fn on_draft_changed(&mut self, dialog_id: i64, text: String) {
+        if let Some(dialog) = self
+            .dialogs
+            .iter_mut()
+            .find(|dialog| dialog.id == dialog_id)
+        {
+            dialog.draft = text.clone();
+        }
+        self.adopt_cloud_draft(dialog_id, text);
+    }
+
+    pub fn has_draft(&self, dialog_id: i64) -> bool {
+        if Some(dialog_id) == self.compose_dialog_id && self.editing_message_id.is_none() {
+            !self.compose.text().trim().is_empty()
+        } else {
+            self.drafts.contains_key(&dialog_id)
+        }
+    }
+
+    pub fn editing_target(&self) -> Option<&MessageSummary> {
+        let editing_id = self.editing_message_id?;
+        self.selected_dialog_messages()
+            .iter()
+            .find(|message| message.id == editing_id)
+    }
+
+    /// Asks for confirmation to delete the target messages.
+    pub fn start_delete(&mut self) -> bool {
+        let message_ids = self.target_message_ids();
+        if message_ids.is_empty() {
+            return false;
+        }
+        self.freeze_selection_range();
+
+        self.pending_delete_message_ids = message_ids;
+        self.ui_mode = UiMode::ConfirmDelete;
+        true
+    }
+
+    pub fn cancel_delete(&mut self) {
+        self.pending_delete_message_ids.clear();
+        self.ui_mode = UiMode::Normal;
+    }
+
+    /// Channels and supergroups only delete messages for everyone.
+    pub fn can_delete_for_me(&self) -> bool {
+        self.selected_dialog().is_some_and(|dialog| {
+            !matches!(dialog.kind, DialogKind::Supergroup | DialogKind::Channel)
+        })
+    }
+
+    /// Leaves the delete confirmation and returns what should be deleted, if anything.
+    pub fn take_pending_delete(&mut self) -> Option<(i64, Vec<i32>)> {
+        let message_ids = std::mem::take(&mut self.pending_delete_message_ids);
+        self.ui_mode = UiMode::Normal;
+        if message_ids.is_empty() {
+            return None;
+        }
+        self.marked_message_ids.clear();
+        Some((self.selected_dialog_id?, message_ids))
+    }
+
+    pub fn delete_targets(&self) -> Vec<&MessageSummary> {
+        self.selected_dialog_messages()
+            .iter()
+            .filter(|message| self.pending_delete_message_ids.contains(&message.id))
+            .collect()
+    }
+
+    pub fn reply_target(&self) -> Option<&MessageSummary> {
+        let reply_id = self.reply_to_message_id?;
+        self.selected_dialog_messages()
+            .iter()
+            .find(|message| message.id == reply_id)
+    }
+
+    /// Opens the reaction picker on the selected message, starting at our current reaction.
+    pub fn start_reaction_picker(&mut self) -> bool {
+        let Some(message) = self.selected_message().filter(|message| !message.deleted) else {
+            return false;
+        };
+
+        let selected = message
+            .reactions
+            .iter()
+            .filter(|reaction| reaction.chosen)
+            .find_map(|reaction| {
+                REACTION_CHOICES
+                    .iter()
+                    .position(|choice| *choice == reaction.emoji)
+            })
+            .unwrap_or(0);
+        self.reaction_picker = Some(ReactionPicker {
+            message_id: message.id,
+            selected,
+        });
+        self.ui_mode = UiMode::PickReaction;
+        true
+    }
+
+    pub fn close_reaction_picker(&mut self) {
+        self.reaction_picker = None;
+        self.ui_mode = UiMode::Normal;
+    }
+
+    pub fn select_prev_reaction(&mut self) {
+        if let Some(picker) = &mut self.reaction_picker {
+            picker.selected = picker.selected.saturating_sub(1);
+        }
+    }
+
+    pub fn select_next_reaction(&mut self) {
+        if let Some(picker) = &mut self.reaction_picker
+            && picker.selected + 1 < REACTION_CHOICES.len()
+        {
+            picker.selected += 1;
+        }
+    }
+
+    /// Selects a reaction by index; out-of-range indexes are ignored.
+    pub fn pick_reaction(&mut self, index: usize) -> bool {
+        match &mut self.reaction_picker {
+            Some(picker) if index < REACTION_CHOICES.len() => {
+                picker.selected = index;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Closes the picker and returns the chat, message and new reaction to send. Picking the
+    /// reaction we already left removes it, which is `None`.
+    pub fn submit_reaction(&mut self) -> Option<(i64, i32, Option<String>)> {
+        let picker = self.reaction_picker.take()?;
+        self.ui_mode = UiMode::Normal;
+        let emoji = REACTION_CHOICES[picker.selected];
+        let already_chosen = self
+            .selected_dialog_messages()
+            .iter()
+            .find(|message| message.id == picker.message_id)?
+            .reactions
+            .iter()
+            .any(|reaction| reaction.chosen && reaction.emoji == emoji);
+        Some((
+            self.selected_dialog_id?,
+            picker.message_id,
+            (!already_chosen).then(|| emoji.to_string()),
+        ))
+    }
+
+    pub fn reaction_target(&self) -> Option<&MessageSummary> {
+        let message_id = self.reaction_picker?.message_id;
+        self.selected_dialog_messages()
+            .iter()
+            .find(|message| message.id == message_id)
+    }
+
+    pub fn on_pinned_messages_loaded(&mut self, dialog_id: i64, messages: Vec<MessageSummary>) {
+        self.pinned_by_dialog.insert(dialog_id, messages);
+        if Some(dialog_id) == self.selected_dialog_id {
+            self.pinned_index = 0;
+        }
+    }
+
+    /// Applies pins and unpins to chats whose pins are loaded. Returns `true` when a new pin is
+    /// not in the loaded history, so the pins of the chat have to be loaded again.
+    pub fn on_pinned_messages_changed(
+        &mut self,
+        dialog_id: i64,
+        message_ids: &[i32],
+        pinned: bool,
+    ) -> bool {
+        if !pinned {
+            self.remove_pins(dialog_id, message_ids);
+            return false;
+        }
+
+        let Some(pins) = self.pinned_by_dialog.get_mut(&dialog_id) else {
+            return false;
+        };
+        let loaded = self
+            .messages_by_dialog
+            .get(&dialog_id)
+            .map(Vec::as_slice)
+            .unwrap_or(&[]);
+        let mut missing = false;
+        for message_id in message_ids {
+            if pins.iter().any(|pin| pin.id == *message_id) {
+                continue;
+            }
+            match loaded.iter().find(|message| message.id == *message_id) {
+                Some(message) => pins.push(message.clone()),
+                None => missing = true,
+            }
+        }
+        pins.sort_by_key(|pin| std::cmp::Reverse(pin.id));
+        if Some(dialog_id) == self.selected_dialog_id {
+            self.pinned_index = 0;
+        }
+        missing
+    }
+
+    pub fn pinned_messages(&self) -> &[MessageSummary] {
+        self.selected_dialog_id
+            .and_then(|dialog_id| self.pinned_by_dialog.get(&dialog_id))
+            .map(Vec::as_slice)
+            .unwrap_or(&[])
+    }
+
+    /// The pin shown in the pinned strip, with its index among the pins of the selected chat.
+    pub fn shown_pin(&self) -> Option<(usize, &MessageSummary)> {
+        let pins = self.pinned_messages();
+        if pins.is_empty() {
+            return None;
+        }
+        let index = self.pinned_index % pins.len();
+        Some((index, &pins[index]))
+    }
+
+    pub fn is_pinned(&self, message_id: i32) -> bool {
+        self.pinned_messages()
+            .iter()
+            .any(|pin| pin.id == message_id)
+    }
+
+    /// Jumps to the pin shown in the strip and moves the strip on to the next older pin. Returns
+    /// the message to load when it is not in the loaded history.
+    pub fn open_pinned_message(&mut self) -> Option<(i64, i32)> {
+        let dialog_id = self.selected_dialog_id?;
+        let (index, pin) = self.shown_pin()?;
+        let message_id = pin.id;
+        self.pinned_index = index + 1;
+
+        if self
+            .selected_dialog_messages()
+            .iter()
+            .any(|message| message.id == message_id)
+        {
+            self.clear_message_cursor();
+            self.selected_message_id = Some(message_id);
+            self.reveal_selected_message = true;
+            self.focus = FocusArea::Messages;
+            return None;
+        }
+        self.open_message(dialog_id, message_id)
+            .then_some((dialog_id, message_id))
+    }
+
+    /// Asks for confirmation to pin the selected message, or to unpin it when it is pinned.
+    pub fn start_pin(&mut self) -> bool {
+        let Some(message) = self.selected_message().filter(|message| !message.deleted) else {
+            return false;
+        };
+
+        self.pending_pin = Some(PendingPin {
+            message_id: message.id,
+            unpin: self.is_pinned(message.id),
+            notify: true,
+        });
+        self.ui_mode = UiMode::ConfirmPin;
+        true
+    }
+
+    pub fn toggle_pin_notify(&mut self) {
+        if let Some(pending) = &mut self.pending_pin
+            && !pending.unpin
+        {
+            pending.notify = !pending.notify;
+        }
+    }
+
+    pub fn cancel_pin(&mut self) {
+        self.pending_pin = None;
+        self.ui_mode = UiMode::Normal;
+    }
+
+    /// Leaves the pin confirmation and returns the chat and the pin change to send.
+    pub fn take_pending_pin(&mut self) -> Option<(i64, PendingPin)> {
+        let pending = self.pending_pin.take()?;
+        self.ui_mode = UiMode::Normal;
+        Some((self.selected_dialog_id?, pending))
+    }
+
+    pub fn pin_target(&self) -> Option<&MessageSummary> {
+        let message_id = self.pending_pin?.message_id;
+        self.selected_dialog_messages()
+            .iter()
+            .find(|message| message.id == message_id)
+    }
+
+    pub fn selected_topic_id(&self) -> Option<i32> {
+        self.topic_by_dialog.get(&self.selected_dialog_id?).copied()
+    }
+
+    pub fn selected_topic(&self) -> Option<&TopicSummary> {
+        let topic_id = self.selected_topic_id()?;
+        self.topics_by_dialog
+            .get(&self.selected_dialog_id?)?
+            .iter()
+            .find(|topic| topic.id == topic_id)
+    }
+
+    /// What a new message replies to: the reply target, else the open topic, since messages
+    /// are placed in a topic by replying to its first message. The General topic needs none.
+    pub fn send_reply_to(&self) -> Option<i32> {
+        self.reply_to_message_id.or_else(|| {
+            self.selected_topic_id()
+                .filter(|id| *id != GENERAL_TOPIC_ID)
+        })
+    }
+
+    pub fn on_topics_loaded(&mut self, dialog_id: i64, topics: Vec<TopicSummary>) {
+        if let Some(picker) = &mut self.topic_picker
+            && picker.dialog_id == dialog_id
+        {
+            picker.selected = picker.selected.min(topics.len());
+        }
+        self.topics_by_dialog.insert(dialog_id, topics);
+    }
+
+    /// The channel post whose comments can be opened: the selected message, if it has comments.
+    pub fn thread_target(&self) -> Option<(i64, i32)> {
+        let dialog_id = self.selected_dialog_id?;
+        let message = self
+            .selected_message()
+            .filter(|message| message.comments.is_some() && !message.deleted)?;
+        Some((dialog_id, message.id))
+    }
+
+    /// Shows the comments of a post, unless another chat was selected while they were found.
+    /// Returns `true` when the thread has to be loaded.
+    pub fn on_thread_opened(
+        &mut self,
+        channel_id: i64,
+        post_id: i32,
+        discussion_id: i64,
+        top_id: i32,
+    ) -> bool {
+        if Some(channel_id) != self.selected_dialog_id {
+            return false;
+        }
+
+        self.thread = Some(CommentThread {
+            channel_id,
+            post_id,
+            discussion_id,
+            top_id,
+        });
+        self.topic_by_dialog.insert(discussion_id, top_id);
+        self.messages_by_dialog.remove(&discussion_id);
+        self.history_exhausted_dialogs.remove(&discussion_id);
+        self.detached_history_dialogs.remove(&discussion_id);
+        self.selected_dialog_id = Some(discussion_id);
+        self.message_scroll_from_bottom = 0;
+        self.pending_new_messages_for_selected = 0;
+        self.clear_message_cursor();
+        self.focus = FocusArea::Messages;
+        true
+    }
+
+    /// Goes back from the comments to the channel, with the commented post selected.
+    pub fn close_thread(&mut self) -> bool {
+        let Some(thread) = self.leave_thread() else {
+            return false;
+        };
+        self.selected_dialog_id = Some(thread.channel_id);
+        self.message_scroll_from_bottom = 0;
+        self.pending_new_messages_for_selected = 0;
+        self.clear_message_cursor();
+        self.selected_message_id = Some(thread.post_id);
+        self.reveal_selected_message = true;
+        true
+    }
+
+    /// The thread is forgotten along with its messages, so the discussion group opens as a
+    /// whole when it is picked from the chat list.
+    fn leave_thread(&mut self) -> Option<CommentThread> {
+        let thread = self.thread.take()?;
+        self.topic_by_dialog.remove(&thread.discussion_id);
+        self.messages_by_dialog.remove(&thread.discussion_id);
+        self.history_exhausted_dialogs.remove(&thread.discussion_id);
+        self.detached_history_dialogs.remove(&thread.discussion_id);
+        Some(thread)
+    }
+
+    /// The chat highlighted in the chat list: the channel while its comments are shown.
+    fn list_dialog_id(&self) -> Option<i64> {
+        self.thread
+            .map(|thread| thread.channel_id)
+            .or(self.selected_dialog_id)
+    }
+
+    /// Opens the topic list of the selected forum chat. Returns the chat whose topics should be
+    /// loaded, or `None` when the chat is not a forum.
+    pub fn start_topic_picker(&mut self) -> Option<i64> {
+        let dialog = self.selected_dialog().filter(|dialog| dialog.forum)?;
+        let dialog_id = dialog.id;
+        let selected = self
+            .selected_topic_id()
+            .and_then(|topic_id| {
+                self.topic_choices(dialog_id)
+                    .iter()
+                    .position(|topic| topic.id == topic_id)
+            })
+            .map_or(0, |index| index + 1);
+        self.topic_picker = Some(TopicPicker {
+            dialog_id,
+            selected,
+        });
+        self.ui_mode = UiMode::TopicPicker;
+        Some(dialog_id)
+    }
+
+    pub fn close_topic_picker(&mut self) {
+        self.topic_picker = None;
+        self.ui_mode = UiMode::Normal;
+    }
+
+    pub fn topic_choices(&self, dialog_id: i64) -> &[TopicSummary] {
+        self.topics_by_dialog
+            .get(&dialog_id)
+            .map(Vec::as_slice)
+            .unwrap_or(&[])
+    }
+
+    pub fn select_prev_topic(&mut self) {
+        if let Some(picker) = &mut self.topic_picker {
+            picker.selected = picker.selected.saturating_sub(1);
+        }
+    }
+
+    pub fn select_next_topic(&mut self) {
+        let Some(picker) = self.topic_picker else {
+            return;
+        };
+        if picker.selected < self.topic_choices(picker.dialog_id).len()
+            && let Some(picker) = &mut self.topic_picker
+        {
+            picker.selected += 1;
+        }
+    }
+
+    /// Shows the picked topic, or all topics for the first entry. Returns `true` when the
+    /// history of the chat has to be loaded again.
+    pub fn submit_topic(&mut self) -> bool {
+        let Some(picker) = self.topic_picker.take() else {
+            return false;
+        };
+        self.ui_mode = UiMode::Normal;
+        let topic_id = picker
+            .selected
+            .checked_sub(1)
+            .and_then(|index| self.topic_choices(picker.dialog_id).get(index))
+            .map(|topic| topic.id);
+        if topic_id == self.topic_by_dialog.get(&picker.dialog_id).copied() {
+            return false;
+        }
+
+        match topic_id {
+            Some(topic_id) => self.topic_by_dialog.insert(picker.dialog_id, topic_id),
+            None => self.topic_by_dialog.remove(&picker.dialog_id),
+        };
+        self.messages_by_dialog.remove(&picker.dialog_id);
+        self.history_exhausted_dialogs.remove(&picker.dialog_id);
+        self.detached_history_dialogs.remove(&picker.dialog_id);
+        self.message_scroll_from_bottom = 0;
+        self.pending_new_messages_for_selected = 0;
+        self.clear_message_cursor();
+        true
+    }
+
+    fn topic_mut(&mut self, dialog_id: i64, topic_id: i32) -> Option<&mut TopicSummary> {
+        self.topics_by_dialog
+            .get_mut(&dialog_id)?
+            .iter_mut()
+            .find(|topic| topic.id == topic_id)
+    }
+
+    fn remove_pins(&mut self, dialog_id: i64, message_ids: &[i32]) {
+        if let Some(pins) = self.pinned_by_dialog.get_mut(&dialog_id) {
+            pins.retain(|pin| !message_ids.contains(&pin.id));
+        }
+    }
+
+    fn selection_range_contains(&self, message_id: i32) -> bool {
+        let (Some(anchor), Some(cursor)) = (self.selection_anchor, self.selected_message_id) else {
+            return false;
+        };
+        let messages = self.selected_dialog_messages();
+        let index_of = |id| messages.iter().position(|message| message.id == id);
+        match (index_of(anchor), index_of(cursor), index_of(message_id)) {
+            (Some(anchor), Some(cursor), Some(index)) => {
+                (anchor.min(cursor)..=anchor.max(cursor)).contains(&index)
+            }
+            _ => false,
+        }
+    }
+
+    /// Turns the visual range into marks, so a cancelled action keeps the selection.
+    fn freeze_selection_range(&mut self) {
+        let ids: Vec<i32> = self
+            .selected_dialog_messages()
+            .iter()
+            .map(|message| message.id)
+            .filter(|id| self.selection_range_contains(*id))
+            .collect();
+        self.marked_message_ids.extend(ids);
+        self.selection_anchor = None;
+    }
+
+    fn in_folder(&self, tab: FolderTab, dialog: &DialogSummary) -> bool {
+        let folder = match tab {
+            FolderTab::All => return !dialog.archived,
+            FolderTab::Archived => return dialog.archived,
+            FolderTab::Folder(id) => match self.folders.iter().find(|folder| folder.id == id) {
+                Some(folder) => folder,
+                None => return false,
+            },
+        };
+        if folder.exclude.contains(&dialog.id) {
+            return false;
+        }
+        if folder.include.contains(&dialog.id) {
+            return true;
+        }
+        if (folder.exclude_archived && dialog.archived)
+            || (folder.exclude_muted && dialog.muted)
+            || (folder.exclude_read && self.dialog_unread_count(dialog.id) == 0)
+        {
+            return false;
+        }
+        match dialog.kind {
+            DialogKind::User if dialog.contact => folder.contacts,
+            DialogKind::User => folder.non_contacts,
+            DialogKind::Bot => folder.bots,
+            DialogKind::Group | DialogKind::Supergroup => folder.groups,
+            DialogKind::Channel => folder.broadcasts,
+        }
+    }
+
+    /// Falls back to "All" when the active folder went away.
+    fn ensure_folder(&mut self) {
+        if !self.folder_tabs().contains(&self.active_folder) {
+            self.active_folder = FolderTab::All;
+        }
+    }
+
+    fn matches_query(&self, dialog: &DialogSummary) -> bool {
+        if self.search_query.is_empty() {
+            return true;
+        }
+
+        dialog
+            .title
+            .to_lowercase()
+            .contains(&self.search_query.to_lowercase())
+    }
+
+    fn dialog_mut(&mut self, dialog_id: i64) -> Option<&mut DialogSummary> {
+        self.dialogs
+            .iter_mut()
+            .find(|dialog| dialog.id == dialog_id)
+    }
+
+    fn download_mut(&mut self, dialog_id: i64, message_id: i32) -> Option<&mut Download> {
+        self.downloads
+            .iter_mut()
+            .find(|download| download.dialog_id == dialog_id && download.message_id == message_id)
+    }
+
+    fn remove_download(&mut self, dialog_id: i64, message_id: i32) -> Option<Download> {
+        let index = self.downloads.iter().position(|download| {
+            download.dialog_id == dialog_id && download.message_id == message_id
+        })?;
+        Some(self.downloads.remove(index))
+    }
+
+    /// Gives the caption of a file that was not sent back to its chat: into the compose box
+    /// while that chat is open, else into the chat's draft.
+    fn restore_upload_caption(&mut self, dialog_id: i64) -> Option<Upload> {
+        let upload = self
+            .upload
+            .take_if(|upload| upload.dialog_id == dialog_id)?;
+        if Some(dialog_id) == self.compose_dialog_id {
+            if self.compose.is_empty() {
+                self.compose.set_text(upload.caption.clone());
+            }
+        } else if !upload.caption.is_empty() {
+            self.drafts
+                .entry(dialog_id)
+                .or_insert_with(|| upload.caption.clone());
+        }
+        Some(upload)
+    }
+
+    fn visible_dialog_ids(&self) -> Vec<i64> {
+        self.visible_dialogs()
+            .iter()
+            .map(|dialog| dialog.id)
+            .collect()
+    }
+
+    /// Keeps the chat list preview on the newest message of the chat.
+    fn note_last_message(&mut self, dialog_id: i64, message: &MessageSummary) {
+        if let Some(dialog) = self
+            .dialogs
+            .iter_mut()
+            .find(|dialog| dialog.id == dialog_id)
+            && dialog
+                .last_message
+                .as_ref()
+                .is_none_or(|last| last.id <= message.id)
+        {
+            dialog.last_message = Some(LastMessage::from(message));
+        }
+    }
+
+    /// Falls back to the newest loaded message when the previewed one is deleted.
+    fn forget_last_message(&mut self, dialog_id: i64, message_ids: &[i32]) {
+        let Some(dialog) = self
+            .dialogs
+            .iter_mut()
+            .find(|dialog| dialog.id == dialog_id)
+        else {
+            return;
+        };
+        if !dialog
+            .last_message
+            .as_ref()
+            .is_some_and(|last| message_ids.contains(&last.id))
+        {
+            return;
+        }
+        dialog.last_message = self
+            .messages_by_dialog
+            .get(&dialog_id)
+            .and_then(|messages| {
+                messages
+                    .iter()
+                    .filter(|message| !message.deleted && !message_ids.contains(&message.id))
+                    .max_by_key(|message| message.id)
+            })
+            .map(LastMessage::from);
+    }
+
+    /// Messages of other topics than the one shown are left out.
+    fn append_message_if_missing(&mut self, dialog_id: i64, message: MessageSummary) -> bool {
+        if self
+            .topic_by_dialog
+            .get(&dialog_id)
+            .is_some_and(|topic_id| message.topic_id.unwrap_or(GENERAL_TOPIC_ID) != *topic_id)
+        {
+            return false;
+        }
+        let messages = self.messages_by_dialog.entry(dialog_id).or_default();
+        if messages.iter().any(|existing| existing.id == message.id) {
+            return false;
+        }
+        messages.push(message);
+        true
+    }
+
+    fn ensure_selection(&mut self) {
+        let visible = self.visible_dialog_ids();
+        if visible.is_empty() {
+            self.leave_thread();
+            self.selected_dialog_id = None;
+            return;
+        }
+
+        if !self
+            .list_dialog_id()
+            .is_some_and(|id| visible.contains(&id))
+        {
+            self.leave_thread();
+            self.selected_dialog_id = Some(visible[0]);
+            self.message_scroll_from_bottom = 0;
+            self.pending_new_messages_for_selected = 0;
+            self.clear_message_cursor();
+        }
+    }
+
+    /// Takes a cloud draft as the chat's draft. Text being typed in the chat is never replaced.
+    fn adopt_cloud_draft(&mut self, dialog_id: i64, text: String) {
+        let composing = Some(dialog_id) == self.compose_dialog_id;
+        if composing
+            && (self.editing_message_id.is_some() || !self.compose.text().trim().is_empty())
+        {
+            return;
+        }
+        if composing {
+            self.compose.set_text(text.clone());
+        }
+        if text.is_empty() {
+            self.drafts.remove(&dialog_id);
+        } else {
+            self.drafts.insert(dialog_id, text);
+        }
+    }
+
+    fn stash_compose_draft(&mut self) {
+        let Some(dialog_id) = self.compose_dialog_id else {
+            return;
+        };
+        if self.editing_message_id.is_some() {
+            return;
+        }
+        let text = self.compose.text().trim_end();
+        if text.is_empty() {
+            self.drafts.remove(&dialog_id);
+        } else {
+            self.drafts.insert(dialog_id, text.to_string());
+        }
+    }
+
+    fn restore_compose_draft(&mut self) {
+        let draft = self
+            .compose_dialog_id
+            .and_then(|dialog_id| self.drafts.get(&dialog_id))
+            .cloned()
+            .unwrap_or_default();
+        self.compose.set_text(draft);
+    }
+
+    fn clear_message_cursor(&mut self) {
+        self.selected_message_id = None;
+        self.reveal_selected_message = false;
+        self.marked_message_ids.clear();
+        self.selection_anchor = None;
+        self.reply_to_message_id = None;
+        self.cancel_edit();
+        if !self.pending_delete_message_ids.is_empty() {
+            self.cancel_delete();
+        }
+    }
+}
+
+/// "1 message" or "`count` messages", for status lines.
+pub fn message_count(count: usize) -> String {
+    if count == 1 {
+        "1 message".to_string()
+    } else {
+        format!("{count} messages")
+    }
+}
+
+/// Scores `text` against a fuzzy `query` whose characters must appear in order, ignoring case.
+/// Consecutive characters and word starts score higher; `None` means no match.
+fn fuzzy_score(query: &str, text: &str) -> Option<i64> {
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let mut score = 0;
+    let mut position = 0;
+    let mut previous_match: Option<usize> = None;
+    for query_char in query
+        .to_lowercase()
+        .chars()
+        .filter(|ch| !ch.is_whitespace())
+    {
+        let offset = text[position..].iter().position(|ch| *ch == query_char)?;
+        let index = position + offset;
+        score += 1;
+        if previous_match.is_some_and(|previous| previous + 1 == index) {
+            score += 5;
+        }
+        if index == 0 || !text[index - 1].is_alphanumeric() {
+            score += 3;
+        }
+        score -= offset as i64;
+        previous_match = Some(index);
+        position = index + 1;
+    }
+    Some(score)
+}
+
+/// Completes the last component of `input` against the filesystem like a shell does on Tab.
+/// Returns the new input and, when the completion is ambiguous, the matching entry names.
+fn complete_path(input: &str) -> (String, Vec<String>) {
+    let (dir, prefix) = match input.rfind('/') {
+        Some(index) => input.split_at(index + 1),
+        None => ("", input),
+    };
+    let Ok(entries) = fs::read_dir(if dir.is_empty() {
+        PathBuf::from(".")
+    } else {
+        expand_home(dir)
+    }) else {
+        return (input.to_string(), Vec::new());
+    };
+
+    let mut matches: Vec<String> = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let mut name = entry.file_name().into_string().ok()?;
+            let hidden = name.starts_with('.') && !prefix.starts_with('.');
+            if hidden || !name.starts_with(prefix) {
+                return None;
+            }
+            if entry.path().is_dir() {
+                name.push('/');
+            }
+            Some(name)
+        })
+        .collect();
+    matches.sort();
+
+    match matches.as_slice() {
+        [] => (input.to_string(), Vec::new()),
+        [only] => (format!("{dir}{only}"), Vec::new()),
+        [first, rest @ ..] => {
+            let common = rest.iter().fold(first.len(), |len, name| {
+                first[..len]
+                    .char_indices()
+                    .zip(name.chars())
+                    .find(|((_, a), b)| a != b)
+                    .map_or(len.min(name.len()), |((index, _), _)| index)
+            });
+            (format!("{dir}{}", &first[..common]), matches)
+        }
+    }
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    let rest = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => rest.trim_start_matches('/'),
+        _ => return PathBuf::from(path),
+    };
+    match env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join(rest),
+        None => PathBuf::from(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telegram::{Comments, TextEntity};
+
+    fn dialog(id: i64, title: &str) -> DialogSummary {
+        DialogSummary {
+            id,
+            title: title.to_string(),
+            ..DialogSummary::default()
+        }
+    }
+
+    fn dialogs() -> Vec<DialogSummary> {
+        vec![dialog(1, "a"), dialog(2, "b")]
+    }
+
+    fn message(id: i32, text: &str) -> MessageSummary {
+        MessageSummary {
+            id,
+            from: "x".to_string(),
+            text: text.to_string(),
+            entities: Vec::new(),
+            date: "now".to_string(),
+            reply_to: None,
+            topic_id: None,
+            outgoing: false,
+            mentioned: false,
+            edited: false,
+            deleted: false,
+            reactions: Vec::new(),
+            comments: None,
+            media: None,
+        }
+    }
+
+    fn photo(id: i32) -> MessageSummary {
+        MessageSummary {
+            media: Some(MediaInfo::Photo {
+                width: 800,
+                height: 600,
+                size: 1024,
+            }),
+            ..message(id, "")
+        }
+    }
+
+    #[test]
+    fn selection_bounds_are_clamped() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+
+        assert!(!app.select_prev());
+        assert_eq!(app.selected_dialog_id(), Some(1));
+
+        assert!(app.select_next());
+        assert_eq!(app.selected_dialog_id(), Some(2));
+
+        assert!(!app.select_next());
+        assert_eq!(app.selected_dialog_id(), Some(2));
+    }
+
+    #[test]
+    fn selected_dialog_id_updates_with_selection() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+
+        assert_eq!(app.selected_dialog_id(), Some(1));
+        app.select_next();
+        assert_eq!(app.selected_dialog_id(), Some(2));
+    }
+
+    #[test]
+    fn messages_are_stored_by_dialog() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_messages_loaded(1, vec![message(1, "hello")]);
+
+        assert_eq!(app.selected_dialog_messages().len(), 1);
+        app.select_next();
+        assert!(app.selected_dialog_messages().is_empty());
+    }
+
+    #[test]
+    fn search_filters_visible_dialogs() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+
+        app.start_search();
+        app.insert_char('b');
+
+        assert_eq!(app.visible_dialogs().len(), 1);
+        assert_eq!(app.selected_dialog_id(), Some(2));
+    }
+
+    #[test]
+    fn sorting_can_toggle_to_alphabetical() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(vec![dialog(1, "zulu"), dialog(2, "alpha")]);
+
+        app.toggle_sort_mode();
+        let visible = app.visible_dialogs();
+        assert_eq!(visible[0].title, "alpha");
+        assert_eq!(visible[1].title, "zulu");
+    }
+
+    #[test]
+    fn folders_filter_chats_by_rules_and_peers() {
+        let mut app = AppState::new();
+        let mut channel = dialog(3, "news");
+        channel.kind = DialogKind::Channel;
+        channel.unread_count = 4;
+        let mut archived = dialog(4, "old");
+        archived.archived = true;
+        let mut muted = dialog(5, "noisy");
+        muted.kind = DialogKind::Group;
+        muted.muted = true;
+        muted.unread_count = 7;
+        app.on_dialogs_loaded(vec![
+            dialog(1, "a"),
+            dialog(2, "b"),
+            channel,
+            archived,
+            muted,
+        ]);
+        app.on_folders_loaded(vec![ChatFolder {
+            id: 2,
+            title: "Feeds".to_string(),
+            groups: true,
+            broadcasts: true,
+            exclude_muted: true,
+            include: vec![2],
+            ..ChatFolder::default()
+        }]);
+
+        let tabs = app.folder_tabs();
+        assert_eq!(
+            tabs,
+            vec![FolderTab::All, FolderTab::Folder(2), FolderTab::Archived]
+        );
+        assert_eq!(app.folder_title(tabs[1]), "Feeds");
+        assert_eq!(app.visible_dialogs().len(), 4);
+
+        app.select_next_folder();
+        let ids: Vec<i64> = app
+            .visible_dialogs()
+            .iter()
+            .map(|dialog| dialog.id)
+            .collect();
+        assert_eq!(ids, vec![2, 3]);
+        assert_eq!(app.folder_unread_count(FolderTab::Folder(2)), 4);
+        assert_eq!(app.folder_unread_count(FolderTab::All), 4);
+
+        app.select_folder(2);
+        assert_eq!(app.selected_dialog_id(), Some(4));
+        app.select_next_folder();
+        assert_eq!(app.active_folder, FolderTab::All);
+
+        app.select_folder(1);
+        app.start_search();
+        app.insert_char('o');
+        let ids: Vec<i64> = app
+            .visible_dialogs()
+            .iter()
+            .map(|dialog| dialog.id)
+            .collect();
+        assert_eq!(ids, vec![4, 5]);
+    }
+
+    #[test]
+    fn removed_folder_falls_back_to_all_chats() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_folders_loaded(vec![ChatFolder {
+            id: 7,
+            title: "Work".to_string(),
+            include: vec![2],
+            ..ChatFolder::default()
+        }]);
+        app.select_folder(1);
+        assert_eq!(app.selected_dialog_id(), Some(2));
+
+        app.on_folders_loaded(Vec::new());
+
+        assert_eq!(app.active_folder, FolderTab::All);
+        assert_eq!(app.visible_dialogs().len(), 2);
+    }
+
+    #[test]
+    fn message_scroll_is_bottom_relative() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_messages_loaded(
+            1,
+            vec![message(1, "one"), message(2, "two"), message(3, "three")],
+        );
+
+        assert_eq!(app.message_scroll_from_bottom, 0);
+        app.scroll_messages_up();
+        assert_eq!(app.message_scroll_from_bottom, 1);
+        app.scroll_messages_down();
+        assert_eq!(app.message_scroll_from_bottom, 0);
+    }
+
+    #[test]
+    fn message_scroll_resets_when_selecting_another_chat() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_messages_loaded(1, vec![message(1, "hello")]);
+        app.message_scroll_from_bottom = 1;
+
+        app.select_next();
+
+        assert_eq!(app.message_scroll_from_bottom, 0);
+    }
+
+    #[test]
+    fn chat_preview_follows_the_newest_message() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_messages_loaded(1, vec![message(1, "one"), message(2, "two")]);
+        let preview = |app: &AppState| {
+            app.dialogs[0]
+                .last_message
+                .as_ref()
+                .map(|last| last.text.clone())
+        };
+
+        app.on_incoming_message(1, message(3, "three"));
+        assert_eq!(preview(&app).as_deref(), Some("three"));
+        app.on_incoming_message(1, message(1, "one"));
+        assert_eq!(preview(&app).as_deref(), Some("three"));
+
+        app.on_message_edited(1, message(3, "three!"));
+        assert_eq!(preview(&app).as_deref(), Some("three!"));
+
+        app.on_messages_deleted(1, &[3]);
+        assert_eq!(preview(&app).as_deref(), Some("two"));
+        app.on_incoming_messages_deleted(&[1], &[1, 2]);
+        assert_eq!(preview(&app), None);
+    }
+
+    #[test]
+    fn incoming_non_selected_chat_increments_badge() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+
+        app.on_incoming_message(2, message(10, "hello"));
+
+        assert_eq!(app.dialog_new_message_count(2), 1);
+        assert_eq!(app.pending_new_messages_for_selected, 0);
+    }
+
+    #[test]
+    fn incoming_selected_chat_while_at_bottom_does_not_set_badges() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+
+        app.on_incoming_message(1, message(10, "hello"));
+
+        assert_eq!(app.dialog_new_message_count(1), 0);
+        assert_eq!(app.pending_new_messages_for_selected, 0);
+    }
+
+    #[test]
+    fn incoming_selected_chat_while_scrolled_up_sets_pending_indicator() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_messages_loaded(1, vec![message(1, "first")]);
+        app.scroll_messages_up();
+
+        app.on_incoming_message(1, message(10, "hello"));
+
+        assert_eq!(app.pending_new_messages_for_selected, 1);
+        assert_eq!(app.dialog_new_message_count(1), 0);
+    }
+
+    #[test]
+    fn pending_indicator_clears_when_scrolled_to_bottom() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_messages_loaded(1, vec![message(1, "first")]);
+        app.scroll_messages_up();
+        app.on_incoming_message(1, message(10, "hello"));
+
+        app.scroll_messages_down();
+
+        assert_eq!(app.pending_new_messages_for_selected, 0);
+    }
+
+    #[test]
+    fn dialog_badge_clears_when_messages_loaded() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_incoming_message(2, message(10, "hello"));
+        app.select_next();
+
+        app.on_messages_loaded(2, vec![message(10, "hello"), message(11, "new")]);
+
+        assert_eq!(app.dialog_new_message_count(2), 0);
+    }
+
+    #[test]
+    fn duplicate_message_id_is_not_appended_twice() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+
+        app.on_message_sent(1, message(42, "hello"));
+        app.on_incoming_message(1, message(42, "hello"));
+
+        assert_eq!(app.selected_dialog_messages().len(), 1);
+    }
+
+    #[test]
+    fn older_messages_are_prepended_without_moving_scroll() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_messages_loaded(1, vec![message(10, "ten"), message(11, "eleven")]);
+        app.scroll_messages_up();
+        app.is_loading_older_messages = true;
+
+        app.on_older_messages_loaded(1, None, vec![message(8, "eight"), message(9, "nine")]);
+
+        let ids: Vec<i32> = app
+            .selected_dialog_messages()
+            .iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(ids, vec![8, 9, 10, 11]);
+        assert_eq!(app.message_scroll_from_bottom, 1);
+        assert!(!app.is_loading_older_messages);
+    }
+
+    #[test]
+    fn empty_older_page_stops_pagination() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_messages_loaded(1, vec![message(10, "ten")]);
+
+        assert_eq!(app.older_messages_anchor(), Some((1, 10)));
+        app.on_older_messages_loaded(1, None, Vec::new());
+
+        assert_eq!(app.older_messages_anchor(), None);
+    }
+
+    #[test]
+    fn older_page_of_a_thread_no_longer_shown_is_dropped() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_messages_loaded(2, vec![message(10, "ten")]);
+        app.topic_by_dialog.insert(2, 7);
+        app.is_loading_older_messages = true;
+
+        app.on_older_messages_loaded(2, Some(5), vec![message(8, "other topic")]);
+        app.on_older_messages_loaded(2, None, vec![message(9, "whole chat")]);
+
+        assert_eq!(app.selected_dialog_id(), Some(1));
+        assert_eq!(app.messages_by_dialog[&2].len(), 1);
+        assert!(!app.is_loading_older_messages);
+
+        app.on_older_messages_loaded(2, Some(7), vec![message(9, "same topic")]);
+        assert_eq!(app.messages_by_dialog[&2].len(), 2);
+    }
+
+    #[test]
+    fn older_messages_are_requested_only_near_top() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_messages_loaded(1, vec![message(10, "ten")]);
+        app.message_viewport = MessageViewport {
+            content_lines: 40,
+            height: 10,
+            scroll_from_bottom: 0,
+            first_visible_message_id: None,
+            last_visible_message_id: None,
+        };
+
+        assert_eq!(app.older_messages_anchor(), None);
+
+        app.message_scroll_from_bottom = 26;
+        assert_eq!(app.older_messages_anchor(), Some((1, 10)));
+
+        app.is_loading_older_messages = true;
+        assert_eq!(app.older_messages_anchor(), None);
+    }
+
+    #[test]
+    fn message_cursor_starts_at_newest_and_is_clamped() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_messages_loaded(1, vec![message(1, "one"), message(2, "two")]);
+
+        app.select_prev_message();
+        assert_eq!(app.selected_message_id, Some(2));
+        app.select_prev_message();
+        app.select_prev_message();
+        assert_eq!(app.selected_message_id, Some(1));
+        app.select_next_message();
+        app.select_next_message();
+        assert_eq!(app.selected_message_id, Some(2));
+    }
+
+    #[test]
+    fn reply_targets_selected_message_and_clears_after_send() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_messages_loaded(1, vec![message(1, "one"), message(2, "two")]);
+
+        assert!(!app.start_reply());
+        app.select_prev_message();
+        assert!(app.start_reply());
+        assert_eq!(app.ui_mode, UiMode::Compose);
+        assert_eq!(app.reply_target().map(|m| m.id), Some(2));
+
+        app.on_message_sent(1, message(3, "answer"));
+        assert_eq!(app.reply_to_message_id, None);
+    }
+
+    #[test]
+    fn message_cursor_resets_when_selecting_another_chat() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_messages_loaded(1, vec![message(1, "one")]);
+        app.select_prev_message();
+        app.start_reply();
+
+        app.select_next();
+
+        assert_eq!(app.selected_message_id, None);
+        assert_eq!(app.reply_to_message_id, None);
+    }
+
+    #[test]
+    fn drafts_follow_the_selected_chat() {
+        let mut app = AppState::new();
+        let mut loaded = dialogs();
+        loaded[1].draft = "cloud".to_string();
+        app.on_dialogs_loaded(loaded);
+        assert_eq!(app.sync_compose_draft(), None);
+        assert!(app.compose.is_empty());
+
+        app.compose.set_text("hello ");
+        app.select_next();
+        assert_eq!(app.sync_compose_draft(), Some((1, "hello".to_string())));
+        assert_eq!(app.compose.text(), "cloud");
+        assert!(app.has_draft(1));
+        assert!(app.has_draft(2));
+
+        app.compose.clear();
+        assert!(!app.has_draft(2));
+        app.select_prev();
+        assert_eq!(app.sync_compose_draft(), Some((2, String::new())));
+        assert_eq!(app.compose.text(), "hello");
+        assert_eq!(app.sync_compose_draft(), None);
+
+        // Saved drafts match the cloud copy and are not sent again.
+        app.on_draft_changed(1, "hello".to_string());
+        assert_eq!(app.unsaved_draft(), None);
+    }
+
+    #[test]
+    fn cloud_drafts_never_replace_text_being_typed() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.sync_compose_draft();
+        app.compose.set_text("local");
+
+        app.on_draft_changed(1, "remote".to_string());
+        app.on_draft_changed(2, "other device".to_string());
+        assert_eq!(app.compose.text(), "local");
+        assert_eq!(app.drafts.get(&2).map(String::as_str), Some("other device"));
+        assert_eq!(app.unsaved_draft(), Some((1, "local".to_string())));
+
+        // A reload only takes cloud drafts that changed since the last one.
+        app.drafts.insert(2, "edited here".to_string());
+        let mut reloaded = dialogs();
+        reloaded[0].draft = "remote".to_string();
+        reloaded[1].draft = "other device".to_string();
+        app.on_dialogs_loaded(reloaded);
+        assert_eq!(app.drafts.get(&2).map(String::as_str), Some("edited here"));
+
+        app.compose.clear();
+        let mut reloaded = dialogs();
+        reloaded[0].draft = "newer".to_string();
+        app.on_dialogs_loaded(reloaded);
+        assert_eq!(app.compose.text(), "newer");
+        assert!(!app.drafts.contains_key(&2));
+    }
+
+    #[test]
+    fn editing_a_message_keeps_the_draft() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        let mut own = message(2, "typo");
+        own.outgoing = true;
+        app.on_messages_loaded(1, vec![own]);
+        app.sync_compose_draft();
+        app.compose.set_text("draft");
+
+        app.select_prev_message();
+        assert!(app.start_edit());
+        assert_eq!(app.compose.text(), "typo");
+        assert!(app.has_draft(1));
+        app.cancel_edit();
+        assert_eq!(app.compose.text(), "draft");
+    }
+
+    #[test]
+    fn edit_prefills_compose_only_for_own_messages() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        let mut own = message(2, "typo");
+        own.outgoing = true;
+        app.on_messages_loaded(1, vec![message(1, "theirs"), own]);
+
+        app.select_prev_message();
+        assert!(app.start_edit());
+        assert_eq!(app.compose.text(), "typo");
+        assert_eq!(app.ui_mode, UiMode::Compose);
+
+        app.exit_compose();
+        app.select_prev_message();
+        app.cancel_edit();
+        assert!(!app.start_edit());
+        assert!(app.compose.is_empty());
+    }
+
+    #[test]
+    fn editing_keeps_the_message_formatting() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        let mut own = message(2, "2*3 is six, really");
+        own.outgoing = true;
+        own.entities = vec![
+            TextEntity {
+                kind: EntityKind::Bold,
+                range: 7..10,
+            },
+            TextEntity {
+                kind: EntityKind::Code,
+                range: 12..18,
+            },
+        ];
+        app.on_messages_loaded(1, vec![own.clone()]);
+
+        app.select_prev_message();
+        assert!(app.start_edit());
+        assert_eq!(app.compose.text(), "2\\*3 is **six**, `really`");
+        let formatted = app.formatted_compose();
+        assert_eq!(formatted.text, own.text);
+        assert_eq!(formatted.entities, own.entities);
+    }
+
+    #[test]
+    fn editing_switches_to_html_for_formatting_markdown_lacks() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        let mut own = message(2, "a<b");
+        own.outgoing = true;
+        own.entities = vec![TextEntity {
+            kind: EntityKind::Underline,
+            range: 0..1,
+        }];
+        app.on_messages_loaded(1, vec![own.clone()]);
+
+        app.select_prev_message();
+        assert!(app.start_edit());
+        assert_eq!(app.markup_mode, MarkupMode::Html);
+        assert_eq!(app.compose.text(), "<u>a</u>&lt;b");
+        assert_eq!(app.formatted_compose().entities, own.entities);
+
+        app.cancel_edit();
+        assert_eq!(app.markup_mode, MarkupMode::Markdown);
+    }
+
+    #[test]
+    fn edited_message_is_replaced_in_place() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        let mut own = message(2, "typo");
+        own.outgoing = true;
+        app.on_messages_loaded(1, vec![message(1, "one"), own.clone()]);
+        app.select_prev_message();
+        app.start_edit();
+        app.is_sending_message = true;
+
+        own.text = "fixed".to_string();
+        app.on_message_edited(1, own);
+
+        let texts: Vec<&str> = app
+            .selected_dialog_messages()
+            .iter()
+            .map(|m| m.text.as_str())
+            .collect();
+        assert_eq!(texts, vec!["one", "fixed"]);
+        assert_eq!(app.editing_message_id, None);
+        assert!(app.compose.is_empty());
+        assert!(!app.is_sending_message);
+    }
+
+    #[test]
+    fn confirmed_delete_removes_message_and_selection() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_messages_loaded(1, vec![message(1, "one"), message(2, "two")]);
+        app.select_prev_message();
+
+        assert!(app.start_delete());
+        assert_eq!(app.ui_mode, UiMode::ConfirmDelete);
+        let (dialog_id, ids) = app.take_pending_delete().expect("pending delete");
+        assert_eq!(app.ui_mode, UiMode::Normal);
+
+        app.on_messages_deleted(dialog_id, &ids);
+
+        assert_eq!(app.selected_dialog_messages().len(), 1);
+        assert_eq!(app.selected_message_id, None);
+    }
+
+    #[test]
+    fn channels_only_offer_delete_for_everyone() {
+        let mut app = AppState::new();
+        let mut supergroup = dialog(-1_000_000_000_043, "supergroup");
+        supergroup.kind = DialogKind::Supergroup;
+        let mut channel = dialog(-1_000_000_000_042, "channel");
+        channel.kind = DialogKind::Channel;
+        let mut group = dialog(-42, "group");
+        group.kind = DialogKind::Group;
+        app.on_dialogs_loaded(vec![group, supergroup, channel]);
+
+        assert!(app.can_delete_for_me());
+        app.select_next();
+        assert!(!app.can_delete_for_me());
+        app.select_next();
+        assert!(!app.can_delete_for_me());
+    }
+
+    #[test]
+    fn remote_edit_updates_message_and_marks_it_edited() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_messages_loaded(1, vec![message(1, "before")]);
+
+        let mut edited = message(1, "after");
+        edited.edited = true;
+        app.on_message_edited(1, edited);
+
+        let updated = &app.selected_dialog_messages()[0];
+        assert_eq!(updated.text, "after");
+        assert!(updated.edited);
+    }
+
+    #[test]
+    fn remote_deletion_marks_messages_and_cancels_reply() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_messages_loaded(1, vec![message(1, "one"), message(2, "two")]);
+        app.on_messages_loaded(2, vec![message(2, "other chat")]);
+        app.select_prev_message();
+        app.start_reply();
+
+        app.on_incoming_messages_deleted(&[1], &[2]);
+
+        assert!(app.selected_dialog_messages()[1].deleted);
+        assert!(!app.selected_dialog_messages()[0].deleted);
+        assert!(!app.messages_by_dialog[&2][0].deleted);
+        assert_eq!(app.reply_to_message_id, None);
+        assert!(!app.start_reply());
+    }
+
+    #[test]
+    fn outgoing_message_from_other_device_does_not_set_badges() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+
+        app.on_outgoing_message(2, message(10, "from phone"));
+
+        assert_eq!(app.messages_by_dialog[&2].len(), 1);
+        assert_eq!(app.dialog_new_message_count(2), 0);
+    }
+
+    #[test]
+    fn outgoing_update_and_send_confirmation_are_deduplicated() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.compose.set_text("hello");
+
+        app.on_outgoing_message(1, message(42, "hello"));
+        app.on_message_sent(1, message(42, "hello"));
+
+        assert_eq!(app.selected_dialog_messages().len(), 1);
+        assert!(app.compose.is_empty());
+    }
+
+    #[test]
+    fn unread_badge_combines_server_and_local_counts() {
+        let mut app = AppState::new();
+        let mut unread = dialog(2, "b");
+        unread.unread_count = 3;
+        app.on_dialogs_loaded(vec![dialog(1, "a"), unread]);
+
+        app.on_incoming_message(2, message(10, "hello"));
+        assert_eq!(app.dialog_unread_count(2), 4);
+
+        let mut reloaded = dialog(2, "b");
+        reloaded.unread_count = 4;
+        app.on_dialogs_loaded(vec![dialog(1, "a"), reloaded]);
+        assert_eq!(app.dialog_unread_count(2), 4);
+    }
+
+    #[test]
+    fn read_ack_is_requested_for_newest_visible_message_once() {
+        let mut app = AppState::new();
+        let mut unread = dialog(1, "a");
+        unread.unread_count = 2;
+        unread.unread_mentions_count = 1;
+        unread.read_inbox_max_id = 1;
+        app.on_dialogs_loaded(vec![unread]);
+        app.on_messages_loaded(1, vec![message(1, "one"), message(2, "two")]);
+        app.message_viewport.last_visible_message_id = Some(2);
+
+        assert_eq!(app.pending_read_ack(), Some((1, 2)));
+        app.on_read_ack_sent(1, 2);
+
+        assert_eq!(app.pending_read_ack(), None);
+        assert_eq!(app.dialog_unread_count(1), 0);
+        assert_eq!(app.dialogs[0].unread_mentions_count, 0);
+    }
+
+    #[test]
+    fn stale_dialog_reload_does_not_resurrect_read_messages() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_read_ack_sent(1, 5);
+
+        let mut stale = dialog(1, "a");
+        stale.unread_count = 2;
+        stale.read_inbox_max_id = 3;
+        app.on_dialogs_loaded(vec![stale, dialog(2, "b")]);
+
+        assert_eq!(app.dialog_unread_count(1), 0);
+    }
+
+    #[test]
+    fn outbox_read_only_moves_forward() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+
+        app.on_outbox_read(1, 7);
+        app.on_outbox_read(1, 3);
+
+        assert_eq!(app.dialogs[0].read_outbox_max_id, 7);
+    }
+
+    #[test]
+    fn cached_dialogs_show_until_server_answers() {
+        let mut app = AppState::new();
+        app.on_cached_dialogs(vec![dialog(3, "cached")]);
+
+        assert!(app.is_loading_dialogs);
+        assert_eq!(app.selected_dialog_id(), Some(3));
+
+        app.on_dialogs_loaded(dialogs());
+        app.on_cached_dialogs(vec![dialog(3, "cached")]);
+
+        assert!(!app.is_loading_dialogs);
+        assert_eq!(app.dialogs.len(), 2);
+        assert_eq!(app.selected_dialog_id(), Some(1));
+    }
+
+    #[test]
+    fn cached_messages_do_not_replace_loaded_history() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_cached_messages(1, vec![message(1, "cached")]);
+
+        assert_eq!(app.selected_dialog_messages()[0].text, "cached");
+
+        app.on_messages_loaded(1, vec![message(1, "fresh")]);
+        app.on_cached_messages(1, vec![message(1, "cached")]);
+
+        assert_eq!(app.selected_dialog_messages()[0].text, "fresh");
+    }
+
+    #[test]
+    fn offline_mode_sends_no_read_acks() {
+        let mut app = AppState::new();
+        app.offline = true;
+        app.on_dialogs_loaded(dialogs());
+        app.on_messages_loaded(1, vec![message(1, "one")]);
+        app.message_viewport.last_visible_message_id = Some(1);
+
+        assert_eq!(app.pending_read_ack(), None);
+    }
+
+    fn search_result(dialog_id: i64, message_id: i32) -> MessageSearchResult {
+        MessageSearchResult {
+            dialog_id,
+            dialog_title: "b".to_string(),
+            message: message(message_id, "found"),
+        }
+    }
+
+    #[test]
+    fn enter_searches_first_then_opens_selected_result() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        assert!(app.start_message_search(false));
+        for ch in "found".chars() {
+            app.insert_char(ch);
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        assert_eq!(
+            app.submit_message_search(),
+            Some(SearchSubmission::Search {
+                dialog_id: Some(1),
+                query: "found".to_string()
+            })
+        );
+        assert_eq!(app.submit_message_search(), None);
 
-    fn dialogs() -> Vec<DialogSummary> {
-        vec![
-            DialogSummary {
-                id: 1,
-                title: "a".to_string(),
-            },
-            DialogSummary {
-                id: 2,
-                title: "b".to_string(),
-            },
-        ]
+        app.on_search_results_loaded(
+            "found".to_string(),
+            vec![search_result(2, 10), search_result(2, 20)],
+        );
+        app.select_next_search_result();
+        app.select_next_search_result();
+
+        assert_eq!(
+            app.submit_message_search(),
+            Some(SearchSubmission::Open {
+                dialog_id: 2,
+                message_id: 20
+            })
+        );
     }
 
-    fn message(id: i32, text: &str) -> MessageSummary {
-        MessageSummary {
-            id,
-            from: "x".to_string(),
-            text: text.to_string(),
-            date: "now".to_string(),
-        }
+    #[test]
+    fn editing_query_after_results_searches_again() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.start_message_search(true);
+        app.insert_char('a');
+        app.submit_message_search();
+        app.on_search_results_loaded("a".to_string(), vec![search_result(2, 10)]);
+        app.insert_char('b');
+
+        assert_eq!(
+            app.submit_message_search(),
+            Some(SearchSubmission::Search {
+                dialog_id: None,
+                query: "ab".to_string()
+            })
+        );
     }
 
     #[test]
-    fn selection_bounds_are_clamped() {
+    fn scope_toggles_between_chat_and_all_chats() {
         let mut app = AppState::new();
         app.on_dialogs_loaded(dialogs());
+        app.start_message_search(false);
 
-        assert!(!app.select_prev());
-        assert_eq!(app.selected_dialog_id(), Some(1));
+        app.toggle_message_search_scope();
+        assert_eq!(
+            app.message_search.as_ref().map(|search| search.scope),
+            Some(MessageSearchScope::AllChats)
+        );
 
-        assert!(app.select_next());
-        assert_eq!(app.selected_dialog_id(), Some(2));
+        app.toggle_message_search_scope();
+        assert_eq!(
+            app.message_search.as_ref().map(|search| search.scope),
+            Some(MessageSearchScope::Chat(1))
+        );
+    }
 
-        assert!(!app.select_next());
+    #[test]
+    fn opening_result_selects_chat_and_message_around_it() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.start_search();
+        app.insert_char('a');
+        app.start_message_search(true);
+
+        assert!(app.open_message(2, 20));
+        assert_eq!(app.ui_mode, UiMode::Normal);
+        assert!(app.search_query.is_empty());
         assert_eq!(app.selected_dialog_id(), Some(2));
+        assert!(app.is_loading_messages);
+
+        app.on_messages_around_loaded(
+            2,
+            20,
+            vec![
+                message(19, "before"),
+                message(20, "found"),
+                message(21, "after"),
+            ],
+            true,
+        );
+
+        assert_eq!(app.selected_message().map(|m| m.id), Some(20));
+        assert!(app.reveal_selected_message);
+        assert!(app.wants_latest_messages());
     }
 
     #[test]
-    fn selected_dialog_id_updates_with_selection() {
+    fn incoming_messages_are_not_appended_to_older_history() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.open_message(1, 20);
+        app.on_messages_around_loaded(1, 20, vec![message(20, "found")], true);
+
+        app.on_incoming_message(1, message(99, "new"));
+
+        assert_eq!(app.selected_dialog_messages().len(), 1);
+        assert_eq!(app.pending_new_messages_for_selected, 1);
+
+        app.on_messages_loaded(1, vec![message(99, "new")]);
+        assert!(!app.wants_latest_messages());
+    }
+
+    #[test]
+    fn opening_result_from_unknown_chat_fails() {
         let mut app = AppState::new();
         app.on_dialogs_loaded(dialogs());
 
+        assert!(!app.open_message(42, 1));
         assert_eq!(app.selected_dialog_id(), Some(1));
-        app.select_next();
-        assert_eq!(app.selected_dialog_id(), Some(2));
+        assert!(app.last_error.is_some());
     }
 
     #[test]
-    fn messages_are_stored_by_dialog() {
+    fn download_tracks_progress_until_finished() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_messages_loaded(1, vec![photo(1), message(2, "text")]);
+        app.select_prev_message();
+        assert_eq!(app.start_download(), None);
+
+        app.select_prev_message();
+        assert_eq!(app.start_download(), Some((1, 1)));
+        assert_eq!(app.start_download(), None);
+
+        app.on_download_progress(1, 1, "report.pdf".to_string(), 25, Some(100));
+        assert_eq!(app.downloads[0].file_name, "report.pdf");
+        assert_eq!(app.downloads[0].ratio(), Some(0.25));
+
+        app.on_download_finished(1, 1, Path::new("downloads/report.pdf"));
+        assert!(app.downloads.is_empty());
+        assert_eq!(
+            app.status_message.as_deref(),
+            Some("Saved downloads/report.pdf")
+        );
+    }
+
+    #[test]
+    fn cancel_prefers_selected_message_download() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        app.on_messages_loaded(1, vec![photo(1), photo(2)]);
+        app.select_prev_message();
+        app.start_download();
+        app.select_prev_message();
+        app.start_download();
+        let download = |message_id| Transfer::Download {
+            dialog_id: 1,
+            message_id,
+        };
+
+        assert_eq!(app.transfer_to_cancel(), Some(download(1)));
+
+        app.select_next_message();
+        assert_eq!(app.transfer_to_cancel(), Some(download(2)));
+
+        app.clear_message_selection();
+        assert_eq!(app.transfer_to_cancel(), Some(download(1)));
+
+        app.upload = Some(Upload {
+            dialog_id: 1,
+            file_name: "a.txt".to_string(),
+            caption: String::new(),
+            uploaded: 0,
+            total: None,
+        });
+        assert_eq!(app.transfer_to_cancel(), Some(Transfer::Upload));
+        app.on_upload_cancelled(1);
+
+        app.on_download_cancelled(1, 1);
+        app.on_download_failed(1, 2, "boom".to_string());
+        assert_eq!(app.transfer_to_cancel(), None);
+        assert!(app.last_error.is_some());
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("tele-rust-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn path_completion_extends_unique_and_common_prefixes() {
+        let dir = temp_dir("complete");
+        fs::write(dir.join("report-2024.pdf"), "").unwrap();
+        fs::write(dir.join("report-2025.pdf"), "").unwrap();
+        fs::write(dir.join(".hidden"), "").unwrap();
+        fs::create_dir(dir.join("photos")).unwrap();
+        let base = format!("{}/", dir.display());
+
+        assert_eq!(
+            complete_path(&format!("{base}ph")),
+            (format!("{base}photos/"), Vec::new())
+        );
+        assert_eq!(
+            complete_path(&format!("{base}re")),
+            (
+                format!("{base}report-202"),
+                vec!["report-2024.pdf".to_string(), "report-2025.pdf".to_string()]
+            )
+        );
+        assert_eq!(complete_path(&base).1.len(), 3);
+        assert_eq!(
+            complete_path(&format!("{base}missing")),
+            (format!("{base}missing"), Vec::new())
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn attachment_takes_compose_text_as_caption() {
+        let dir = temp_dir("attach");
+        let file = dir.join("cat.png");
+        fs::write(&file, "png").unwrap();
+
         let mut app = AppState::new();
         app.on_dialogs_loaded(dialogs());
         app.on_messages_loaded(1, vec![message(1, "hello")]);
+        app.select_prev_message();
+        app.start_reply();
+        app.enter_compose();
+        app.compose.set_text(" **look** ");
+        app.start_attach();
+        assert_eq!(app.ui_mode, UiMode::AttachFile);
 
-        assert_eq!(app.selected_dialog_messages().len(), 1);
-        app.select_next();
-        assert!(app.selected_dialog_messages().is_empty());
+        for ch in dir.join("missing").display().to_string().chars() {
+            app.insert_char(ch);
+        }
+        assert_eq!(app.submit_attachment(), None);
+        assert!(app.last_error.is_some());
+
+        app.attach.as_mut().unwrap().path = file.display().to_string();
+        assert_eq!(
+            app.submit_attachment(),
+            Some(Attachment {
+                dialog_id: 1,
+                path: file.clone(),
+                caption: FormattedText {
+                    text: "look".to_string(),
+                    entities: vec![TextEntity {
+                        kind: EntityKind::Bold,
+                        range: 0..4,
+                    }],
+                },
+                reply_to: Some(1),
+            })
+        );
+        assert_eq!(app.ui_mode, UiMode::Compose);
+        assert!(app.compose.is_empty());
+        assert_eq!(app.upload.as_ref().unwrap().file_name, "cat.png");
+        assert_eq!(app.upload.as_ref().unwrap().caption, "**look**");
+
+        app.start_attach();
+        assert_eq!(app.ui_mode, UiMode::Compose);
+        assert_eq!(
+            app.last_error.as_deref(),
+            Some("Another file is still uploading")
+        );
+
+        app.on_upload_progress(1, 512, 2048);
+        assert_eq!(app.upload.as_ref().unwrap().ratio(), Some(0.25));
+
+        app.on_upload_finished(1, message(2, "look"));
+        assert!(app.upload.is_none());
+        assert_eq!(app.selected_dialog_messages().len(), 2);
+        assert_eq!(app.status_message.as_deref(), Some("Sent cat.png"));
+
+        fs::remove_dir_all(dir).unwrap();
     }
 
     #[test]
-    fn search_filters_visible_dialogs() {
+    fn compose_text_is_parsed_with_the_markup_mode() {
+        let mut app = AppState::new();
+        app.compose.set_text(" <b>hi</b> **there** ");
+
+        assert_eq!(
+            app.formatted_compose(),
+            FormattedText {
+                text: "<b>hi</b> there".to_string(),
+                entities: vec![TextEntity {
+                    kind: EntityKind::Bold,
+                    range: 10..15,
+                }],
+            }
+        );
+
+        app.cycle_markup_mode();
+        assert_eq!(app.markup_mode, MarkupMode::Html);
+        assert_eq!(app.formatted_compose().text, "hi **there**");
+
+        app.cycle_markup_mode();
+        assert_eq!(app.markup_mode, MarkupMode::Plain);
+        assert_eq!(
+            app.formatted_compose(),
+            FormattedText {
+                text: "<b>hi</b> **there**".to_string(),
+                entities: Vec::new(),
+            }
+        );
+
+        app.toggle_compose_preview();
+        assert!(app.compose_preview);
+    }
+
+    #[test]
+    fn failed_upload_restores_caption() {
         let mut app = AppState::new();
         app.on_dialogs_loaded(dialogs());
+        app.sync_compose_draft();
+        let upload = |dialog_id| Upload {
+            dialog_id,
+            file_name: "a.txt".to_string(),
+            caption: "caption".to_string(),
+            uploaded: 0,
+            total: None,
+        };
+        app.upload = Some(upload(1));
 
-        app.start_search();
-        app.insert_char('b');
+        app.on_upload_failed(2, "FILE_PARTS_INVALID".to_string());
+        assert!(app.upload.is_some());
+        app.on_upload_failed(1, "FILE_PARTS_INVALID".to_string());
 
-        assert_eq!(app.visible_dialogs().len(), 1);
-        assert_eq!(app.selected_dialog_id(), Some(2));
+        assert!(app.upload.is_none());
+        assert_eq!(app.compose.text(), "caption");
+        assert!(app.last_error.is_some());
+
+        // A chat that is no longer open gets the caption as its draft instead.
+        app.compose.clear();
+        app.upload = Some(upload(2));
+        app.on_upload_cancelled(2);
+
+        assert_eq!(app.compose.text(), "");
+        assert_eq!(app.drafts.get(&2).map(String::as_str), Some("caption"));
+        assert!(app.has_draft(2));
     }
 
     #[test]
-    fn sorting_can_toggle_to_alphabetical() {
+    fn attach_is_unavailable_offline() {
         let mut app = AppState::new();
-        app.on_dialogs_loaded(vec![
-            DialogSummary {
-                id: 1,
-                title: "zulu".to_string(),
-            },
-            DialogSummary {
-                id: 2,
-                title: "alpha".to_string(),
-            },
-        ]);
+        app.on_dialogs_loaded(dialogs());
+        app.offline = true;
 
-        app.toggle_sort_mode();
-        let visible = app.visible_dialogs();
-        assert_eq!(visible[0].title, "alpha");
-        assert_eq!(visible[1].title, "zulu");
+        app.start_attach();
+
+        assert!(app.attach.is_none());
+        assert_eq!(app.ui_mode, UiMode::Normal);
     }
 
     #[test]
-    fn message_scroll_is_bottom_relative() {
+    fn previews_are_requested_once_for_visible_photos() {
         let mut app = AppState::new();
         app.on_dialogs_loaded(dialogs());
-        app.on_messages_loaded(
-            1,
-            vec![message(1, "one"), message(2, "two"), message(3, "three")],
-        );
+        app.on_messages_loaded(1, vec![photo(1), message(2, "text"), photo(3), photo(4)]);
+        app.message_viewport.first_visible_message_id = Some(2);
+        app.message_viewport.last_visible_message_id = Some(3);
 
-        assert_eq!(app.message_scroll_from_bottom, 0);
-        app.scroll_messages_up();
-        assert_eq!(app.message_scroll_from_bottom, 1);
-        app.scroll_messages_down();
-        assert_eq!(app.message_scroll_from_bottom, 0);
+        assert_eq!(app.previews_to_load(), vec![(1, 3)]);
+        assert!(app.previews_to_load().is_empty());
+
+        let preview = ImagePreview {
+            width: 1,
+            height: 1,
+            rgb: vec![0, 0, 0],
+            source: Vec::new(),
+        };
+        app.on_image_preview_loaded(1, 3, preview.clone());
+        app.on_image_preview_loaded(1, 4, preview);
+        assert!(app.image_previews.contains_key(&(1, 3)));
+        assert!(!app.image_previews.contains_key(&(1, 4)));
+
+        app.offline = true;
+        app.message_viewport.first_visible_message_id = Some(1);
+        assert!(app.previews_to_load().is_empty());
     }
 
     #[test]
-    fn message_scroll_resets_when_selecting_another_chat() {
+    fn spoilers_toggle_only_on_messages_with_spoilers() {
         let mut app = AppState::new();
         app.on_dialogs_loaded(dialogs());
-        app.on_messages_loaded(1, vec![message(1, "hello")]);
-        app.message_scroll_from_bottom = 1;
+        let secret = MessageSummary {
+            entities: vec![TextEntity {
+                kind: EntityKind::Spoiler,
+                range: 0..6,
+            }],
+            ..message(2, "secret")
+        };
+        app.on_messages_loaded(1, vec![message(1, "plain"), secret]);
 
-        app.select_next();
+        app.selected_message_id = Some(1);
+        app.toggle_spoilers();
+        assert!(app.revealed_spoilers.is_empty());
 
-        assert_eq!(app.message_scroll_from_bottom, 0);
+        app.selected_message_id = Some(2);
+        app.toggle_spoilers();
+        assert!(app.spoilers_revealed(2));
+        app.toggle_spoilers();
+        assert!(!app.spoilers_revealed(2));
+    }
+
+    fn reaction(emoji: &str, count: u32, chosen: bool) -> ReactionCount {
+        ReactionCount {
+            emoji: emoji.to_string(),
+            count,
+            chosen,
+        }
     }
 
     #[test]
-    fn incoming_non_selected_chat_increments_badge() {
+    fn reaction_picker_toggles_our_reaction() {
         let mut app = AppState::new();
         app.on_dialogs_loaded(dialogs());
+        let liked = MessageSummary {
+            reactions: vec![reaction("❤", 2, true)],
+            ..message(2, "liked")
+        };
+        app.on_messages_loaded(1, vec![message(1, "plain"), liked]);
 
-        app.on_incoming_message(2, message(10, "hello"));
+        app.selected_message_id = Some(2);
+        assert!(app.start_reaction_picker());
+        assert_eq!(app.ui_mode, UiMode::PickReaction);
+        assert_eq!(app.reaction_picker.map(|picker| picker.selected), Some(2));
+        assert_eq!(app.submit_reaction(), Some((1, 2, None)));
+        assert_eq!(app.ui_mode, UiMode::Normal);
 
-        assert_eq!(app.dialog_new_message_count(2), 1);
-        assert_eq!(app.pending_new_messages_for_selected, 0);
+        app.selected_message_id = Some(1);
+        assert!(app.start_reaction_picker());
+        app.select_next_reaction();
+        assert!(!app.pick_reaction(REACTION_CHOICES.len()));
+        assert_eq!(
+            app.submit_reaction(),
+            Some((1, 1, Some(REACTION_CHOICES[1].to_string())))
+        );
     }
 
     #[test]
-    fn incoming_selected_chat_while_at_bottom_does_not_set_badges() {
+    fn reaction_updates_without_our_choice_keep_it() {
         let mut app = AppState::new();
         app.on_dialogs_loaded(dialogs());
+        let liked = MessageSummary {
+            reactions: vec![reaction("👍", 1, true)],
+            ..message(1, "liked")
+        };
+        app.on_messages_loaded(1, vec![liked]);
 
-        app.on_incoming_message(1, message(10, "hello"));
+        app.on_reactions_changed(
+            1,
+            1,
+            vec![reaction("👍", 2, false), reaction("🔥", 1, false)],
+            false,
+        );
+        assert_eq!(
+            app.selected_dialog_messages()[0].reactions,
+            [reaction("👍", 2, true), reaction("🔥", 1, false)]
+        );
 
-        assert_eq!(app.dialog_new_message_count(1), 0);
-        assert_eq!(app.pending_new_messages_for_selected, 0);
+        app.on_reactions_changed(1, 1, vec![reaction("🔥", 1, false)], true);
+        assert_eq!(
+            app.selected_dialog_messages()[0].reactions,
+            [reaction("🔥", 1, false)]
+        );
     }
 
     #[test]
-    fn incoming_selected_chat_while_scrolled_up_sets_pending_indicator() {
+    fn fuzzy_score_prefers_word_starts_and_runs() {
+        assert!(fuzzy_score("wk", "Work chat").is_some());
+        assert_eq!(fuzzy_score("kw", "Work chat"), None);
+        assert_eq!(fuzzy_score("", "anything"), Some(0));
+        assert!(
+            fuzzy_score("wch", "Work chat").expect("match")
+                > fuzzy_score("wch", "Weather watch").expect("match")
+        );
+        assert!(fuzzy_score("ЧАТ", "рабочий чат").is_some());
+    }
+
+    #[test]
+    fn forward_sends_marked_messages_to_the_picked_chat() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(vec![
+            dialog(1, "Family"),
+            dialog(2, "Work chat"),
+            dialog(3, "Weather watch"),
+        ]);
+        app.on_messages_loaded(
+            1,
+            vec![message(1, "one"), message(2, "two"), message(3, "three")],
+        );
+
+        app.selected_message_id = Some(3);
+        app.toggle_message_mark();
+        app.selected_message_id = Some(1);
+        app.toggle_message_mark();
+        assert!(app.start_forward());
+        for ch in "wch".chars() {
+            app.insert_char(ch);
+        }
+        let candidates: Vec<i64> = app
+            .forward_candidates()
+            .iter()
+            .map(|dialog| dialog.id)
+            .collect();
+        assert_eq!(candidates, [2, 3]);
+
+        app.toggle_forward_drop_captions();
+        assert_eq!(
+            app.submit_forward(),
+            Some(Forward {
+                from_dialog_id: 1,
+                message_ids: vec![1, 3],
+                to_dialog_id: 2,
+                drop_author: true,
+                drop_captions: true,
+            })
+        );
+        assert_eq!(app.ui_mode, UiMode::Normal);
+        assert!(app.marked_message_ids.is_empty());
+    }
+
+    #[test]
+    fn forwarded_messages_only_join_loaded_chats() {
         let mut app = AppState::new();
         app.on_dialogs_loaded(dialogs());
-        app.on_messages_loaded(1, vec![message(1, "first")]);
-        app.scroll_messages_up();
+        app.on_messages_loaded(1, vec![message(1, "one")]);
 
-        app.on_incoming_message(1, message(10, "hello"));
+        app.on_messages_forwarded(1, vec![message(2, "copy")]);
+        app.on_messages_forwarded(2, vec![message(7, "copy")]);
 
-        assert_eq!(app.pending_new_messages_for_selected, 1);
-        assert_eq!(app.dialog_new_message_count(1), 0);
+        assert_eq!(app.selected_dialog_messages().len(), 2);
+        assert!(!app.messages_by_dialog.contains_key(&2));
+        assert_eq!(
+            app.status_message.as_deref(),
+            Some("Forwarded 1 message to b")
+        );
     }
 
     #[test]
-    fn pending_indicator_clears_when_scrolled_to_bottom() {
+    fn visual_selection_combines_range_and_marks() {
         let mut app = AppState::new();
         app.on_dialogs_loaded(dialogs());
-        app.on_messages_loaded(1, vec![message(1, "first")]);
-        app.scroll_messages_up();
-        app.on_incoming_message(1, message(10, "hello"));
+        app.on_messages_loaded(1, (1..=6).map(|id| message(id, "text")).collect());
 
-        app.scroll_messages_down();
+        app.selected_message_id = Some(5);
+        assert!(app.start_message_selection());
+        app.select_prev_message();
+        app.select_prev_message();
+        app.selected_message_id = Some(1);
+        app.toggle_message_mark();
+        app.selected_message_id = Some(3);
+        assert_eq!(app.target_message_ids(), [1, 3, 4, 5]);
 
-        assert_eq!(app.pending_new_messages_for_selected, 0);
+        assert!(app.start_delete());
+        assert_eq!(app.delete_targets().len(), 4);
+        app.cancel_delete();
+        // The range became marks, so the selection survives a cancelled action.
+        assert_eq!(app.target_message_ids(), [1, 3, 4, 5]);
+
+        let copied = app.take_target_messages();
+        assert_eq!(copied.len(), 4);
+        assert!(app.marked_message_ids.is_empty());
+        assert_eq!(app.target_message_ids(), [3]);
     }
 
     #[test]
-    fn dialog_badge_clears_when_messages_loaded() {
+    fn pinned_strip_cycles_and_jumps_to_pins() {
         let mut app = AppState::new();
         app.on_dialogs_loaded(dialogs());
-        app.on_incoming_message(2, message(10, "hello"));
-        app.select_next();
+        app.on_messages_loaded(1, (10..=12).map(|id| message(id, "text")).collect());
+        app.on_pinned_messages_loaded(1, vec![message(11, "new"), message(3, "old")]);
 
-        app.on_messages_loaded(2, vec![message(10, "hello"), message(11, "new")]);
+        assert_eq!(
+            app.shown_pin().map(|(index, pin)| (index, pin.id)),
+            Some((0, 11))
+        );
+        assert_eq!(app.open_pinned_message(), None);
+        assert_eq!(app.selected_message_id, Some(11));
+        assert!(app.reveal_selected_message);
 
-        assert_eq!(app.dialog_new_message_count(2), 0);
+        assert_eq!(
+            app.shown_pin().map(|(index, pin)| (index, pin.id)),
+            Some((1, 3))
+        );
+        assert_eq!(app.open_pinned_message(), Some((1, 3)));
+        assert!(app.is_loading_messages);
+        assert_eq!(
+            app.shown_pin().map(|(index, pin)| (index, pin.id)),
+            Some((0, 11))
+        );
     }
 
     #[test]
-    fn duplicate_message_id_is_not_appended_twice() {
+    fn pin_updates_apply_to_loaded_pins() {
         let mut app = AppState::new();
         app.on_dialogs_loaded(dialogs());
+        app.on_messages_loaded(1, (10..=12).map(|id| message(id, "text")).collect());
+        app.on_pinned_messages_loaded(1, vec![message(10, "text")]);
 
-        app.on_message_sent(1, message(42, "hello"));
-        app.on_incoming_message(1, message(42, "hello"));
+        assert!(!app.on_pinned_messages_changed(1, &[12], true));
+        assert!(app.on_pinned_messages_changed(1, &[5], true));
+        assert!(!app.on_pinned_messages_changed(2, &[5], true));
+        let pins: Vec<i32> = app.pinned_messages().iter().map(|pin| pin.id).collect();
+        assert_eq!(pins, [12, 10]);
 
-        assert_eq!(app.selected_dialog_messages().len(), 1);
+        app.selected_message_id = Some(12);
+        assert!(app.start_pin());
+        assert_eq!(app.ui_mode, UiMode::ConfirmPin);
+        app.toggle_pin_notify();
+        assert_eq!(
+            app.take_pending_pin(),
+            Some((
+                1,
+                PendingPin {
+                    message_id: 12,
+                    unpin: true,
+                    notify: true,
+                }
+            ))
+        );
+
+        app.on_pinned_messages_changed(1, &[12], false);
+        app.on_messages_deleted(1, &[10]);
+        assert!(app.pinned_messages().is_empty());
+    }
+
+    fn topic(id: i32, title: &str) -> TopicSummary {
+        TopicSummary {
+            id,
+            title: title.to_owned(),
+            top_message: id,
+            unread_count: 0,
+            unread_mentions_count: 0,
+            read_inbox_max_id: id,
+        }
+    }
+
+    fn forum_app() -> AppState {
+        let mut app = AppState::new();
+        let mut forum = dialog(1, "forum");
+        forum.forum = true;
+        app.on_dialogs_loaded(vec![forum, dialog(2, "plain")]);
+        app.on_topics_loaded(
+            1,
+            vec![topic(GENERAL_TOPIC_ID, "General"), topic(7, "Releases")],
+        );
+        app
+    }
+
+    #[test]
+    fn picking_a_topic_scopes_history_and_replies() {
+        let mut app = forum_app();
+        app.on_messages_loaded(1, vec![message(1, "hello")]);
+
+        assert_eq!(app.start_topic_picker(), Some(1));
+        assert_eq!(app.ui_mode, UiMode::TopicPicker);
+        app.select_next_topic();
+        app.select_next_topic();
+        app.select_next_topic();
+        assert!(app.submit_topic());
+        assert_eq!(app.selected_topic_id(), Some(7));
+        assert!(!app.messages_by_dialog.contains_key(&1));
+        assert_eq!(app.send_reply_to(), Some(7));
+
+        app.on_messages_loaded(1, Vec::new());
+        let mut elsewhere = message(20, "general");
+        elsewhere.topic_id = None;
+        app.on_incoming_message(1, elsewhere);
+        let mut here = message(21, "release");
+        here.topic_id = Some(7);
+        app.on_incoming_message(1, here);
+        let ids: Vec<i32> = app
+            .selected_dialog_messages()
+            .iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(ids, [21]);
+        assert_eq!(app.topic_choices(1)[0].unread_count, 1);
+        assert_eq!(app.topic_choices(1)[1].unread_count, 0);
+
+        app.start_topic_picker();
+        assert_eq!(app.topic_picker.map(|picker| picker.selected), Some(2));
+        app.select_prev_topic();
+        app.select_prev_topic();
+        assert!(app.submit_topic());
+        assert_eq!(app.selected_topic_id(), None);
+
+        app.select_next();
+        assert_eq!(app.start_topic_picker(), None);
+    }
+
+    #[test]
+    fn comments_open_in_place_of_the_channel_and_lead_back_to_the_post() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(dialogs());
+        let post = MessageSummary {
+            comments: Some(Comments {
+                count: 2,
+                recent: vec!["Bob".to_string()],
+            }),
+            ..message(5, "post")
+        };
+        app.on_messages_loaded(1, vec![message(4, "plain"), post]);
+        app.select_next_message();
+        assert_eq!(app.thread_target(), Some((1, 5)));
+
+        assert!(app.on_thread_opened(1, 5, -100, 70));
+        assert_eq!(app.selected_dialog_id(), Some(-100));
+        assert_eq!(app.selected_topic_id(), Some(70));
+        assert_eq!(app.selected_visible_index(), Some(0));
+        assert_eq!(app.send_reply_to(), Some(70));
+        app.on_dialogs_loaded(dialogs());
+        assert_eq!(app.selected_dialog_id(), Some(-100));
+
+        let mut edited = message(5, "post, edited");
+        edited.comments = Some(Comments {
+            count: 3,
+            recent: Vec::new(),
+        });
+        app.on_message_edited(1, edited);
+        assert!(app.close_thread());
+        assert_eq!(app.selected_dialog_id(), Some(1));
+        assert_eq!(app.selected_message_id, Some(5));
+        assert_eq!(
+            app.selected_message().and_then(|m| m.comments.clone()),
+            Some(Comments {
+                count: 3,
+                recent: vec!["Bob".to_string()],
+            })
+        );
+        assert!(!app.topic_by_dialog.contains_key(&-100));
+
+        assert!(app.on_thread_opened(1, 5, -100, 70));
+        app.select_next();
+        assert_eq!(app.selected_dialog_id(), Some(2));
+        assert_eq!(app.thread, None);
+        assert!(!app.on_thread_opened(1, 5, -100, 70));
+    }
+
+    #[test]
+    fn reading_a_topic_tracks_its_own_read_state() {
+        let mut app = forum_app();
+        app.topic_by_dialog.insert(1, 7);
+        if let Some(topic) = app.topic_mut(1, 7) {
+            topic.top_message = 30;
+            topic.unread_count = 2;
+        }
+        app.on_messages_loaded(1, (28..=30).map(|id| message(id, "text")).collect());
+        app.message_viewport.last_visible_message_id = Some(30);
+
+        assert_eq!(app.pending_read_ack(), Some((1, 30)));
+        app.on_read_ack_sent(1, 30);
+        assert_eq!(app.pending_read_ack(), None);
+        assert_eq!(app.selected_topic().map(|t| t.unread_count), Some(0));
+        assert_eq!(app.read_ack_by_dialog.get(&1), None);
+
+        app.on_thread_read(1, GENERAL_TOPIC_ID, 40);
+        assert_eq!(app.topic_choices(1)[0].read_inbox_max_id, 40);
     }
 }
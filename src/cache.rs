@@ -0,0 +1,1866 @@
+use std::{path::Path, str::FromStr};
+
+use anyhow::{Context, anyhow};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::telegram::{
+    ChatFolder, Comments, DialogKind, DialogSummary, EntityKind, GENERAL_TOPIC_ID, LastMessage,
+    MESSAGES_AROUND_HALF_PAGE, MediaInfo, MessageSearchResult, MessageSummary,
+    OLDER_MESSAGES_PAGE_SIZE, ReactionCount, SEARCH_RESULTS_LIMIT, TelegramEvent, TelegramRequest,
+    TextEntity, keep_chosen_reactions,
+};
+
+pub const CACHE_FILE: &str = "telegram.cache";
+const SCHEMA_VERSION: i64 = 9;
+const MESSAGES_PER_DIALOG: i64 = 500;
+/// Separators of the flattened media and entity columns.
+const FIELD_SEPARATOR: char = '\u{1f}';
+const LIST_SEPARATOR: char = '\u{1e}';
+
+/// Local copy of the chat list and recent history, used to paint the UI before the server
+/// answers and to browse chats while offline.
+pub struct MessageCache {
+    connection: sqlite::Connection,
+}
+
+impl MessageCache {
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let connection = sqlite::Connection::open(path).context("open message cache")?;
+        let cache = Self { connection };
+        cache.init().context("initialize message cache")?;
+        Ok(cache)
+    }
+
+    fn init(&self) -> sqlite::Result<()> {
+        let mut statement = self.connection.prepare("PRAGMA user_version")?;
+        statement.next()?;
+        let version = statement.read::<i64, _>(0)?;
+        drop(statement);
+        if version == SCHEMA_VERSION {
+            return Ok(());
+        }
+
+        // The cache can always be refilled from the server, so an outdated schema is dropped.
+        self.connection.execute(
+            "DROP TABLE IF EXISTS dialog;
+            DROP TABLE IF EXISTS message;
+            DROP TABLE IF EXISTS folder;
+            CREATE TABLE dialog (
+                id INTEGER NOT NULL,
+                position INTEGER NOT NULL,
+                title TEXT NOT NULL,
+                unread_count INTEGER NOT NULL,
+                unread_mentions_count INTEGER NOT NULL,
+                read_inbox_max_id INTEGER NOT NULL,
+                read_outbox_max_id INTEGER NOT NULL,
+                draft TEXT NOT NULL,
+                forum INTEGER NOT NULL,
+                kind TEXT NOT NULL,
+                contact INTEGER NOT NULL,
+                muted INTEGER NOT NULL,
+                archived INTEGER NOT NULL,
+                pinned INTEGER NOT NULL,
+                last_message_id INTEGER,
+                last_sender TEXT,
+                last_text TEXT,
+                last_entities TEXT,
+                last_media TEXT,
+                last_date TEXT,
+                last_outgoing INTEGER,
+                last_mentioned INTEGER,
+                PRIMARY KEY (id));
+            CREATE TABLE folder (
+                id INTEGER NOT NULL,
+                position INTEGER NOT NULL,
+                title TEXT NOT NULL,
+                rules TEXT NOT NULL,
+                include_peers TEXT NOT NULL,
+                exclude_peers TEXT NOT NULL,
+                PRIMARY KEY (id));
+            CREATE TABLE message (
+                dialog_id INTEGER NOT NULL,
+                id INTEGER NOT NULL,
+                sender TEXT NOT NULL,
+                text TEXT NOT NULL,
+                date TEXT NOT NULL,
+                reply_to INTEGER,
+                topic_id INTEGER,
+                outgoing INTEGER NOT NULL,
+                mentioned INTEGER NOT NULL,
+                edited INTEGER NOT NULL,
+                deleted INTEGER NOT NULL,
+                media TEXT,
+                entities TEXT NOT NULL,
+                reactions TEXT NOT NULL,
+                comments TEXT,
+                PRIMARY KEY (dialog_id, id));",
+        )?;
+        // Can't bind PRAGMA parameters, but `SCHEMA_VERSION` is not user-controlled input.
+        self.connection
+            .execute(format!("PRAGMA user_version = {SCHEMA_VERSION}"))
+    }
+
+    pub fn load_dialogs(&self) -> anyhow::Result<Vec<DialogSummary>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT * FROM dialog ORDER BY position")?;
+        let mut dialogs = Vec::new();
+        while statement.next()? == sqlite::State::Row {
+            dialogs.push(DialogSummary {
+                id: statement.read::<i64, _>("id")?,
+                title: statement.read::<String, _>("title")?,
+                unread_count: statement.read::<i64, _>("unread_count")? as usize,
+                unread_mentions_count: statement.read::<i64, _>("unread_mentions_count")? as usize,
+                read_inbox_max_id: statement.read::<i64, _>("read_inbox_max_id")? as i32,
+                read_outbox_max_id: statement.read::<i64, _>("read_outbox_max_id")? as i32,
+                draft: statement.read::<String, _>("draft")?,
+                forum: statement.read::<i64, _>("forum")? != 0,
+                kind: decode_kind(&statement.read::<String, _>("kind")?),
+                contact: statement.read::<i64, _>("contact")? != 0,
+                muted: statement.read::<i64, _>("muted")? != 0,
+                archived: statement.read::<i64, _>("archived")? != 0,
+                pinned: statement.read::<i64, _>("pinned")? != 0,
+                last_message: read_last_message(&statement)?,
+            });
+        }
+        Ok(dialogs)
+    }
+
+    pub fn load_folders(&self) -> anyhow::Result<Vec<ChatFolder>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT * FROM folder ORDER BY position")?;
+        let mut folders = Vec::new();
+        while statement.next()? == sqlite::State::Row {
+            let mut folder = ChatFolder {
+                id: statement.read::<i64, _>("id")? as i32,
+                title: statement.read::<String, _>("title")?,
+                include: decode_ids(&statement.read::<String, _>("include_peers")?),
+                exclude: decode_ids(&statement.read::<String, _>("exclude_peers")?),
+                ..ChatFolder::default()
+            };
+            decode_rules(&statement.read::<String, _>("rules")?, &mut folder);
+            folders.push(folder);
+        }
+        Ok(folders)
+    }
+
+    /// Newest `limit` cached messages of a chat, oldest first. `thread_id` keeps only the
+    /// messages of that forum topic.
+    pub fn load_messages(
+        &self,
+        dialog_id: i64,
+        limit: usize,
+        thread_id: Option<i32>,
+    ) -> anyhow::Result<Vec<MessageSummary>> {
+        self.load_messages_before(dialog_id, i32::MAX, limit, thread_id)
+    }
+
+    pub fn load_older_messages(
+        &self,
+        dialog_id: i64,
+        before_id: i32,
+        thread_id: Option<i32>,
+    ) -> anyhow::Result<Vec<MessageSummary>> {
+        self.load_messages_before(dialog_id, before_id, OLDER_MESSAGES_PAGE_SIZE, thread_id)
+    }
+
+    fn load_messages_before(
+        &self,
+        dialog_id: i64,
+        before_id: i32,
+        limit: usize,
+        thread_id: Option<i32>,
+    ) -> anyhow::Result<Vec<MessageSummary>> {
+        let mut statement = self.connection.prepare(
+            "SELECT * FROM message WHERE dialog_id = :dialog_id AND id < :before_id
+                AND (:thread_id IS NULL OR coalesce(topic_id, :general_topic_id) = :thread_id)
+            ORDER BY id DESC LIMIT :limit",
+        )?;
+        statement.bind((":dialog_id", dialog_id))?;
+        statement.bind((":before_id", i64::from(before_id)))?;
+        statement.bind((":thread_id", thread_id.map(i64::from)))?;
+        statement.bind((":general_topic_id", i64::from(GENERAL_TOPIC_ID)))?;
+        statement.bind((":limit", i64::try_from(limit).unwrap_or(i64::MAX)))?;
+
+        let mut messages = Vec::new();
+        while statement.next()? == sqlite::State::Row {
+            messages.push(read_message(&statement)?);
+        }
+
+        messages.reverse();
+        Ok(messages)
+    }
+
+    fn load_messages_after(
+        &self,
+        dialog_id: i64,
+        after_id: i32,
+        limit: usize,
+    ) -> anyhow::Result<Vec<MessageSummary>> {
+        let mut statement = self.connection.prepare(
+            "SELECT * FROM message WHERE dialog_id = :dialog_id AND id > :after_id
+            ORDER BY id LIMIT :limit",
+        )?;
+        statement.bind((":dialog_id", dialog_id))?;
+        statement.bind((":after_id", i64::from(after_id)))?;
+        statement.bind((":limit", i64::try_from(limit).unwrap_or(i64::MAX)))?;
+
+        let mut messages = Vec::new();
+        while statement.next()? == sqlite::State::Row {
+            messages.push(read_message(&statement)?);
+        }
+        Ok(messages)
+    }
+
+    /// Same shape as the server answer: the message, up to a half page on each side of it, and
+    /// whether even newer messages are cached.
+    pub fn load_messages_around(
+        &self,
+        dialog_id: i64,
+        message_id: i32,
+    ) -> anyhow::Result<(Vec<MessageSummary>, bool)> {
+        let mut messages =
+            self.load_messages_before(dialog_id, message_id + 1, MESSAGES_AROUND_HALF_PAGE, None)?;
+        let newer =
+            self.load_messages_after(dialog_id, message_id, MESSAGES_AROUND_HALF_PAGE + 1)?;
+        let has_newer = newer.len() > MESSAGES_AROUND_HALF_PAGE;
+        messages.extend(newer.into_iter().take(MESSAGES_AROUND_HALF_PAGE));
+        Ok((messages, has_newer))
+    }
+
+    /// Case-insensitive substring search over cached messages, newest first.
+    pub fn search_messages(
+        &self,
+        dialog_id: Option<i64>,
+        query: &str,
+    ) -> anyhow::Result<Vec<MessageSearchResult>> {
+        let mut statement = self.connection.prepare(
+            "SELECT message.*, dialog.title AS dialog_title FROM message
+            JOIN dialog ON dialog.id = message.dialog_id
+            WHERE :dialog_id IS NULL OR message.dialog_id = :dialog_id
+            ORDER BY message.date DESC, message.id DESC",
+        )?;
+        statement.bind((":dialog_id", dialog_id))?;
+
+        // SQLite's `lower()` only folds ASCII, so matching happens on our side.
+        let query = query.to_lowercase();
+        let mut results = Vec::new();
+        while results.len() < SEARCH_RESULTS_LIMIT && statement.next()? == sqlite::State::Row {
+            let message = read_message(&statement)?;
+            if !message.text.to_lowercase().contains(&query) {
+                continue;
+            }
+            results.push(MessageSearchResult {
+                dialog_id: statement.read::<i64, _>("dialog_id")?,
+                dialog_title: statement.read::<String, _>("dialog_title")?,
+                message,
+            });
+        }
+        Ok(results)
+    }
+
+    /// Writes an event from the Telegram task through to the cache.
+    pub fn record_event(&self, event: &TelegramEvent) -> anyhow::Result<()> {
+        match event {
+            TelegramEvent::DialogsLoaded(dialogs) => self.save_dialogs(dialogs),
+            TelegramEvent::FoldersLoaded(folders) => self.save_folders(folders),
+            TelegramEvent::MessagesLoaded {
+                dialog_id,
+                thread_id: None,
+                messages,
+            } => self.save_recent_messages(*dialog_id, messages),
+            // A page of one thread says nothing about the messages of other threads in between.
+            TelegramEvent::MessagesLoaded {
+                dialog_id,
+                messages,
+                ..
+            }
+            | TelegramEvent::OlderMessagesLoaded {
+                dialog_id,
+                messages,
+                ..
+            }
+            | TelegramEvent::MessagesForwarded {
+                dialog_id,
+                messages,
+            } => self.transaction(|| {
+                for message in messages {
+                    self.upsert_message(*dialog_id, message)?;
+                }
+                if let Some(newest) = messages.iter().max_by_key(|message| message.id) {
+                    self.update_last_message(*dialog_id, newest)?;
+                }
+                self.prune_messages(*dialog_id)
+            }),
+            TelegramEvent::MessageSent {
+                dialog_id,
+                message,
+            }
+            | TelegramEvent::IncomingMessage {
+                dialog_id,
+                message,
+            }
+            | TelegramEvent::OutgoingMessage {
+                dialog_id,
+                message,
+            }
+            | TelegramEvent::UploadFinished {
+                dialog_id,
+                message,
+            } => self.transaction(|| {
+                self.upsert_message(*dialog_id, message)?;
+                self.update_last_message(*dialog_id, message)?;
+                self.prune_messages(*dialog_id)
+            }),
+            TelegramEvent::MessageEdited {
+                dialog_id,
+                message,
+            }
+            | TelegramEvent::IncomingMessageEdited {
+                dialog_id,
+                message,
+            } => self.transaction(|| {
+                self.update_message(*dialog_id, message)?;
+                self.update_last_message(*dialog_id, message)
+            }),
+            TelegramEvent::MessagesDeleted {
+                dialog_id,
+                message_ids,
+            } => self.transaction(|| {
+                for message_id in message_ids {
+                    self.execute_with(
+                        "DELETE FROM message WHERE dialog_id = :dialog_id AND id = :id",
+                        &[
+                            (":dialog_id", sqlite::Value::Integer(*dialog_id)),
+                            (":id", sqlite::Value::Integer(i64::from(*message_id))),
+                        ],
+                    )?;
+                }
+                Ok(())
+            }),
+            TelegramEvent::IncomingMessagesDeleted {
+                dialog_ids,
+                message_ids,
+            } => self.transaction(|| {
+                for dialog_id in dialog_ids {
+                    for message_id in message_ids {
+                        self.execute_with(
+                            "UPDATE message SET deleted = 1 WHERE dialog_id = :dialog_id AND id = :id",
+                            &[
+                                (":dialog_id", sqlite::Value::Integer(*dialog_id)),
+                                (":id", sqlite::Value::Integer(i64::from(*message_id))),
+                            ],
+                        )?;
+                    }
+                }
+                Ok(())
+            }),
+            TelegramEvent::InboxRead {
+                dialog_id,
+                max_id,
+                still_unread_count,
+            } => self.execute_with(
+                "UPDATE dialog SET
+                    read_inbox_max_id = MAX(read_inbox_max_id, :max_id),
+                    unread_count = :unread_count,
+                    unread_mentions_count =
+                        CASE WHEN :unread_count = 0 THEN 0 ELSE unread_mentions_count END
+                WHERE id = :dialog_id",
+                &[
+                    (":dialog_id", sqlite::Value::Integer(*dialog_id)),
+                    (":max_id", sqlite::Value::Integer(i64::from(*max_id))),
+                    (
+                        ":unread_count",
+                        sqlite::Value::Integer(*still_unread_count as i64),
+                    ),
+                ],
+            ),
+            TelegramEvent::OutboxRead { dialog_id, max_id } => self.execute_with(
+                "UPDATE dialog SET read_outbox_max_id = MAX(read_outbox_max_id, :max_id)
+                WHERE id = :dialog_id",
+                &[
+                    (":dialog_id", sqlite::Value::Integer(*dialog_id)),
+                    (":max_id", sqlite::Value::Integer(i64::from(*max_id))),
+                ],
+            ),
+            TelegramEvent::MessagesAroundLoaded {
+                dialog_id,
+                messages,
+                ..
+            } => self.transaction(|| {
+                for message in messages {
+                    self.upsert_message(*dialog_id, message)?;
+                }
+                self.prune_messages(*dialog_id)
+            }),
+            TelegramEvent::DraftChanged { dialog_id, text } => self.execute_with(
+                "UPDATE dialog SET draft = :draft WHERE id = :dialog_id",
+                &[
+                    (":dialog_id", sqlite::Value::Integer(*dialog_id)),
+                    (":draft", sqlite::Value::String(text.clone())),
+                ],
+            ),
+            TelegramEvent::ReactionsChanged {
+                dialog_id,
+                message_id,
+                reactions,
+                chosen_known,
+            } => self.update_reactions(*dialog_id, *message_id, reactions, *chosen_known),
+            TelegramEvent::SearchResultsLoaded { .. }
+            | TelegramEvent::PinnedMessagesLoaded { .. }
+            | TelegramEvent::TopicsLoaded { .. }
+            | TelegramEvent::ThreadOpened { .. }
+            | TelegramEvent::ThreadRead { .. }
+            | TelegramEvent::PinnedMessagesChanged { .. }
+            | TelegramEvent::DownloadProgress { .. }
+            | TelegramEvent::DownloadFinished { .. }
+            | TelegramEvent::DownloadCancelled { .. }
+            | TelegramEvent::DownloadFailed { .. }
+            | TelegramEvent::UploadProgress { .. }
+            | TelegramEvent::UploadCancelled { .. }
+            | TelegramEvent::UploadFailed { .. }
+            | TelegramEvent::ImagePreviewLoaded { .. }
+            | TelegramEvent::Error(_) => Ok(()),
+        }
+    }
+
+    fn save_dialogs(&self, dialogs: &[DialogSummary]) -> anyhow::Result<()> {
+        self.transaction(|| {
+            self.connection.execute("DELETE FROM dialog")?;
+            for (position, dialog) in dialogs.iter().enumerate() {
+                self.execute_with(
+                    "INSERT INTO dialog VALUES (
+                        :id, :position, :title, :unread_count, :unread_mentions_count,
+                        :read_inbox_max_id, :read_outbox_max_id, :draft, :forum, :kind, :contact,
+                        :muted, :archived, :pinned, :last_message_id, :last_sender, :last_text,
+                        :last_entities, :last_media, :last_date, :last_outgoing, :last_mentioned)",
+                    &[
+                        (":id", sqlite::Value::Integer(dialog.id)),
+                        (":position", sqlite::Value::Integer(position as i64)),
+                        (":title", sqlite::Value::String(dialog.title.clone())),
+                        (
+                            ":unread_count",
+                            sqlite::Value::Integer(dialog.unread_count as i64),
+                        ),
+                        (
+                            ":unread_mentions_count",
+                            sqlite::Value::Integer(dialog.unread_mentions_count as i64),
+                        ),
+                        (
+                            ":read_inbox_max_id",
+                            sqlite::Value::Integer(i64::from(dialog.read_inbox_max_id)),
+                        ),
+                        (
+                            ":read_outbox_max_id",
+                            sqlite::Value::Integer(i64::from(dialog.read_outbox_max_id)),
+                        ),
+                        (":draft", sqlite::Value::String(dialog.draft.clone())),
+                        (":forum", sqlite::Value::Integer(i64::from(dialog.forum))),
+                        (
+                            ":kind",
+                            sqlite::Value::String(encode_kind(dialog.kind).to_string()),
+                        ),
+                        (
+                            ":contact",
+                            sqlite::Value::Integer(i64::from(dialog.contact)),
+                        ),
+                        (":muted", sqlite::Value::Integer(i64::from(dialog.muted))),
+                        (
+                            ":archived",
+                            sqlite::Value::Integer(i64::from(dialog.archived)),
+                        ),
+                        (":pinned", sqlite::Value::Integer(i64::from(dialog.pinned))),
+                    ]
+                    .into_iter()
+                    .chain(last_message_bindings(dialog.last_message.as_ref()))
+                    .collect::<Vec<_>>(),
+                )?;
+            }
+            self.connection
+                .execute("DELETE FROM message WHERE dialog_id NOT IN (SELECT id FROM dialog)")?;
+            Ok(())
+        })
+    }
+
+    fn save_folders(&self, folders: &[ChatFolder]) -> anyhow::Result<()> {
+        self.transaction(|| {
+            self.connection.execute("DELETE FROM folder")?;
+            for (position, folder) in folders.iter().enumerate() {
+                self.execute_with(
+                    "INSERT INTO folder VALUES (
+                        :id, :position, :title, :rules, :include_peers, :exclude_peers)",
+                    &[
+                        (":id", sqlite::Value::Integer(i64::from(folder.id))),
+                        (":position", sqlite::Value::Integer(position as i64)),
+                        (":title", sqlite::Value::String(folder.title.clone())),
+                        (":rules", sqlite::Value::String(encode_rules(folder))),
+                        (
+                            ":include_peers",
+                            sqlite::Value::String(encode_ids(&folder.include)),
+                        ),
+                        (
+                            ":exclude_peers",
+                            sqlite::Value::String(encode_ids(&folder.exclude)),
+                        ),
+                    ],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    /// `messages` is the newest page of a chat, so cached messages inside that range which the
+    /// server no longer returned were deleted while we were away.
+    fn save_recent_messages(
+        &self,
+        dialog_id: i64,
+        messages: &[MessageSummary],
+    ) -> anyhow::Result<()> {
+        self.transaction(|| {
+            let oldest_id = messages.first().map(|message| message.id).unwrap_or(0);
+            self.execute_with(
+                "DELETE FROM message WHERE dialog_id = :dialog_id AND id >= :oldest_id",
+                &[
+                    (":dialog_id", sqlite::Value::Integer(dialog_id)),
+                    (":oldest_id", sqlite::Value::Integer(i64::from(oldest_id))),
+                ],
+            )?;
+            for message in messages {
+                self.upsert_message(dialog_id, message)?;
+            }
+            self.prune_messages(dialog_id)
+        })
+    }
+
+    fn upsert_message(&self, dialog_id: i64, message: &MessageSummary) -> anyhow::Result<()> {
+        self.execute_with(
+            "INSERT OR REPLACE INTO message VALUES (
+                :dialog_id, :id, :sender, :text, :date, :reply_to, :topic_id, :outgoing, :mentioned,
+                :edited, :deleted, :media, :entities, :reactions, :comments)",
+            &message_bindings(dialog_id, message),
+        )
+    }
+
+    /// Edits only touch messages that are already cached, so they never punch holes into the
+    /// cached history.
+    fn update_message(&self, dialog_id: i64, message: &MessageSummary) -> anyhow::Result<()> {
+        self.execute_with(
+            "UPDATE message SET
+                sender = :sender, text = :text, date = :date, reply_to = :reply_to,
+                topic_id = :topic_id, outgoing = :outgoing, mentioned = :mentioned, edited = :edited,
+                deleted = :deleted, media = :media,
+                entities = :entities, reactions = :reactions, comments = :comments
+            WHERE dialog_id = :dialog_id AND id = :id",
+            &message_bindings(dialog_id, message),
+        )
+    }
+
+    /// Moves the chat preview to `message` unless the chat already shows a newer one.
+    fn update_last_message(&self, dialog_id: i64, message: &MessageSummary) -> anyhow::Result<()> {
+        let mut bindings = last_message_bindings(Some(&LastMessage::from(message)));
+        bindings.push((":dialog_id", sqlite::Value::Integer(dialog_id)));
+        self.execute_with(
+            "UPDATE dialog SET
+                last_message_id = :last_message_id, last_sender = :last_sender,
+                last_text = :last_text, last_entities = :last_entities, last_media = :last_media,
+                last_date = :last_date, last_outgoing = :last_outgoing,
+                last_mentioned = :last_mentioned
+            WHERE id = :dialog_id
+                AND (last_message_id IS NULL OR last_message_id <= :last_message_id)",
+            &bindings,
+        )
+    }
+
+    fn update_reactions(
+        &self,
+        dialog_id: i64,
+        message_id: i32,
+        reactions: &[ReactionCount],
+        chosen_known: bool,
+    ) -> anyhow::Result<()> {
+        let mut reactions = reactions.to_vec();
+        if !chosen_known {
+            let mut statement = self.connection.prepare(
+                "SELECT reactions FROM message WHERE dialog_id = :dialog_id AND id = :id",
+            )?;
+            statement.bind((":dialog_id", dialog_id))?;
+            statement.bind((":id", i64::from(message_id)))?;
+            if statement.next()? == sqlite::State::Row {
+                let previous = decode_reactions(&statement.read::<String, _>("reactions")?);
+                keep_chosen_reactions(&previous, &mut reactions);
+            }
+        }
+
+        self.execute_with(
+            "UPDATE message SET reactions = :reactions WHERE dialog_id = :dialog_id AND id = :id",
+            &[
+                (":dialog_id", sqlite::Value::Integer(dialog_id)),
+                (":id", sqlite::Value::Integer(i64::from(message_id))),
+                (
+                    ":reactions",
+                    sqlite::Value::String(encode_reactions(&reactions)),
+                ),
+            ],
+        )
+    }
+
+    fn prune_messages(&self, dialog_id: i64) -> anyhow::Result<()> {
+        self.execute_with(
+            "DELETE FROM message WHERE dialog_id = :dialog_id AND id NOT IN (
+                SELECT id FROM message WHERE dialog_id = :dialog_id ORDER BY id DESC LIMIT :keep)",
+            &[
+                (":dialog_id", sqlite::Value::Integer(dialog_id)),
+                (":keep", sqlite::Value::Integer(MESSAGES_PER_DIALOG)),
+            ],
+        )
+    }
+
+    fn execute_with(&self, query: &str, bindings: &[(&str, sqlite::Value)]) -> anyhow::Result<()> {
+        let mut statement = self.connection.prepare(query)?;
+        statement.bind(bindings)?;
+        while statement.next()? == sqlite::State::Row {}
+        Ok(())
+    }
+
+    fn transaction(&self, body: impl FnOnce() -> anyhow::Result<()>) -> anyhow::Result<()> {
+        self.connection.execute("BEGIN TRANSACTION")?;
+        match body() {
+            Ok(()) => {
+                self.connection.execute("COMMIT")?;
+                Ok(())
+            }
+            Err(err) => {
+                let _ = self.connection.execute("ROLLBACK");
+                Err(err)
+            }
+        }
+    }
+}
+
+fn read_message(statement: &sqlite::Statement<'_>) -> sqlite::Result<MessageSummary> {
+    let text = statement.read::<String, _>("text")?;
+    let entities = decode_entities(&statement.read::<String, _>("entities")?)
+        .into_iter()
+        .filter(|entity| text.get(entity.range.clone()).is_some())
+        .collect();
+    Ok(MessageSummary {
+        id: statement.read::<i64, _>("id")? as i32,
+        from: statement.read::<String, _>("sender")?,
+        text,
+        entities,
+        date: statement.read::<String, _>("date")?,
+        reply_to: statement
+            .read::<Option<i64>, _>("reply_to")?
+            .map(|id| id as i32),
+        topic_id: statement
+            .read::<Option<i64>, _>("topic_id")?
+            .map(|id| id as i32),
+        outgoing: statement.read::<i64, _>("outgoing")? != 0,
+        mentioned: statement.read::<i64, _>("mentioned")? != 0,
+        edited: statement.read::<i64, _>("edited")? != 0,
+        deleted: statement.read::<i64, _>("deleted")? != 0,
+        media: statement
+            .read::<Option<String>, _>("media")?
+            .map(|media| decode_media(&media).unwrap_or(MediaInfo::Unsupported)),
+        reactions: decode_reactions(&statement.read::<String, _>("reactions")?),
+        comments: statement
+            .read::<Option<String>, _>("comments")?
+            .and_then(|comments| decode_comments(&comments)),
+    })
+}
+
+fn message_bindings(
+    dialog_id: i64,
+    message: &MessageSummary,
+) -> Vec<(&'static str, sqlite::Value)> {
+    vec![
+        (":dialog_id", sqlite::Value::Integer(dialog_id)),
+        (":id", sqlite::Value::Integer(i64::from(message.id))),
+        (":sender", sqlite::Value::String(message.from.clone())),
+        (":text", sqlite::Value::String(message.text.clone())),
+        (":date", sqlite::Value::String(message.date.clone())),
+        (
+            ":reply_to",
+            message
+                .reply_to
+                .map(|id| sqlite::Value::Integer(i64::from(id)))
+                .unwrap_or(sqlite::Value::Null),
+        ),
+        (
+            ":topic_id",
+            message
+                .topic_id
+                .map(|id| sqlite::Value::Integer(i64::from(id)))
+                .unwrap_or(sqlite::Value::Null),
+        ),
+        (
+            ":outgoing",
+            sqlite::Value::Integer(i64::from(message.outgoing)),
+        ),
+        (
+            ":mentioned",
+            sqlite::Value::Integer(i64::from(message.mentioned)),
+        ),
+        (":edited", sqlite::Value::Integer(i64::from(message.edited))),
+        (
+            ":deleted",
+            sqlite::Value::Integer(i64::from(message.deleted)),
+        ),
+        (
+            ":media",
+            message
+                .media
+                .as_ref()
+                .map(|media| sqlite::Value::String(encode_media(media)))
+                .unwrap_or(sqlite::Value::Null),
+        ),
+        (
+            ":entities",
+            sqlite::Value::String(encode_entities(&message.entities)),
+        ),
+        (
+            ":reactions",
+            sqlite::Value::String(encode_reactions(&message.reactions)),
+        ),
+        (
+            ":comments",
+            message
+                .comments
+                .as_ref()
+                .map(|comments| sqlite::Value::String(encode_comments(comments)))
+                .unwrap_or(sqlite::Value::Null),
+        ),
+    ]
+}
+
+/// The preview columns of a chat are all null when it has no messages.
+fn read_last_message(statement: &sqlite::Statement<'_>) -> sqlite::Result<Option<LastMessage>> {
+    let Some(id) = statement.read::<Option<i64>, _>("last_message_id")? else {
+        return Ok(None);
+    };
+    let text = statement
+        .read::<Option<String>, _>("last_text")?
+        .unwrap_or_default();
+    let entities = decode_entities(
+        &statement
+            .read::<Option<String>, _>("last_entities")?
+            .unwrap_or_default(),
+    )
+    .into_iter()
+    .filter(|entity| text.get(entity.range.clone()).is_some())
+    .collect();
+    Ok(Some(LastMessage {
+        id: id as i32,
+        from: statement
+            .read::<Option<String>, _>("last_sender")?
+            .unwrap_or_default(),
+        text,
+        entities,
+        media: statement
+            .read::<Option<String>, _>("last_media")?
+            .map(|media| decode_media(&media).unwrap_or(MediaInfo::Unsupported)),
+        date: statement
+            .read::<Option<String>, _>("last_date")?
+            .unwrap_or_default(),
+        outgoing: statement
+            .read::<Option<i64>, _>("last_outgoing")?
+            .is_some_and(|outgoing| outgoing != 0),
+        mentioned: statement
+            .read::<Option<i64>, _>("last_mentioned")?
+            .is_some_and(|mentioned| mentioned != 0),
+    }))
+}
+
+fn last_message_bindings(message: Option<&LastMessage>) -> Vec<(&'static str, sqlite::Value)> {
+    let Some(message) = message else {
+        return [
+            ":last_message_id",
+            ":last_sender",
+            ":last_text",
+            ":last_entities",
+            ":last_media",
+            ":last_date",
+            ":last_outgoing",
+            ":last_mentioned",
+        ]
+        .into_iter()
+        .map(|name| (name, sqlite::Value::Null))
+        .collect();
+    };
+    vec![
+        (
+            ":last_message_id",
+            sqlite::Value::Integer(i64::from(message.id)),
+        ),
+        (":last_sender", sqlite::Value::String(message.from.clone())),
+        (":last_text", sqlite::Value::String(message.text.clone())),
+        (
+            ":last_entities",
+            sqlite::Value::String(encode_entities(&message.entities)),
+        ),
+        (
+            ":last_media",
+            message
+                .media
+                .as_ref()
+                .map(|media| sqlite::Value::String(encode_media(media)))
+                .unwrap_or(sqlite::Value::Null),
+        ),
+        (":last_date", sqlite::Value::String(message.date.clone())),
+        (
+            ":last_outgoing",
+            sqlite::Value::Integer(i64::from(message.outgoing)),
+        ),
+        (
+            ":last_mentioned",
+            sqlite::Value::Integer(i64::from(message.mentioned)),
+        ),
+    ]
+}
+
+fn encode_entities(entities: &[TextEntity]) -> String {
+    let text = |text: &str| text.replace([FIELD_SEPARATOR, LIST_SEPARATOR], " ");
+    entities
+        .iter()
+        .map(|entity| {
+            let (tag, extra) = match &entity.kind {
+                EntityKind::Bold => ("bold", None),
+                EntityKind::Italic => ("italic", None),
+                EntityKind::Underline => ("underline", None),
+                EntityKind::Strikethrough => ("strike", None),
+                EntityKind::Spoiler => ("spoiler", None),
+                EntityKind::Code => ("code", None),
+                EntityKind::Pre { language } => ("pre", Some(text(language))),
+                EntityKind::TextUrl { url } => ("text_url", Some(text(url))),
+                EntityKind::Url => ("url", None),
+                EntityKind::Mention => ("mention", None),
+                EntityKind::Blockquote => ("blockquote", None),
+            };
+            let mut fields = vec![
+                tag.to_string(),
+                entity.range.start.to_string(),
+                entity.range.end.to_string(),
+            ];
+            fields.extend(extra);
+            fields.join(&FIELD_SEPARATOR.to_string())
+        })
+        .collect::<Vec<_>>()
+        .join(&LIST_SEPARATOR.to_string())
+}
+
+/// Entities that do not parse are dropped; the text is still shown without them.
+fn decode_entities(encoded: &str) -> Vec<TextEntity> {
+    encoded
+        .split(LIST_SEPARATOR)
+        .filter_map(|entity| {
+            let fields: Vec<&str> = entity.split(FIELD_SEPARATOR).collect();
+            let (kind, start, end) = match fields.as_slice() {
+                [tag, start, end] => {
+                    let kind = match *tag {
+                        "bold" => EntityKind::Bold,
+                        "italic" => EntityKind::Italic,
+                        "underline" => EntityKind::Underline,
+                        "strike" => EntityKind::Strikethrough,
+                        "spoiler" => EntityKind::Spoiler,
+                        "code" => EntityKind::Code,
+                        "url" => EntityKind::Url,
+                        "mention" => EntityKind::Mention,
+                        "blockquote" => EntityKind::Blockquote,
+                        _ => return None,
+                    };
+                    (kind, start, end)
+                }
+                ["pre", start, end, language] => (
+                    EntityKind::Pre {
+                        language: language.to_string(),
+                    },
+                    start,
+                    end,
+                ),
+                ["text_url", start, end, url] => (
+                    EntityKind::TextUrl {
+                        url: url.to_string(),
+                    },
+                    start,
+                    end,
+                ),
+                _ => return None,
+            };
+            Some(TextEntity {
+                kind,
+                range: start.parse().ok()?..end.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+fn encode_reactions(reactions: &[ReactionCount]) -> String {
+    reactions
+        .iter()
+        .map(|reaction| {
+            [
+                reaction
+                    .emoji
+                    .replace([FIELD_SEPARATOR, LIST_SEPARATOR], " "),
+                reaction.count.to_string(),
+                i32::from(reaction.chosen).to_string(),
+            ]
+            .join(&FIELD_SEPARATOR.to_string())
+        })
+        .collect::<Vec<_>>()
+        .join(&LIST_SEPARATOR.to_string())
+}
+
+fn decode_reactions(encoded: &str) -> Vec<ReactionCount> {
+    encoded
+        .split(LIST_SEPARATOR)
+        .filter_map(|reaction| {
+            let fields: Vec<&str> = reaction.split(FIELD_SEPARATOR).collect();
+            let [emoji, count, chosen] = fields.as_slice() else {
+                return None;
+            };
+            Some(ReactionCount {
+                emoji: emoji.to_string(),
+                count: count.parse().ok()?,
+                chosen: *chosen == "1",
+            })
+        })
+        .collect()
+}
+
+/// The names of the rules a folder has set.
+fn encode_rules(folder: &ChatFolder) -> String {
+    [
+        ("contacts", folder.contacts),
+        ("non_contacts", folder.non_contacts),
+        ("groups", folder.groups),
+        ("broadcasts", folder.broadcasts),
+        ("bots", folder.bots),
+        ("exclude_muted", folder.exclude_muted),
+        ("exclude_read", folder.exclude_read),
+        ("exclude_archived", folder.exclude_archived),
+    ]
+    .into_iter()
+    .filter_map(|(name, set)| set.then_some(name))
+    .collect::<Vec<_>>()
+    .join(&FIELD_SEPARATOR.to_string())
+}
+
+fn decode_rules(encoded: &str, folder: &mut ChatFolder) {
+    for rule in encoded.split(FIELD_SEPARATOR) {
+        let flag = match rule {
+            "contacts" => &mut folder.contacts,
+            "non_contacts" => &mut folder.non_contacts,
+            "groups" => &mut folder.groups,
+            "broadcasts" => &mut folder.broadcasts,
+            "bots" => &mut folder.bots,
+            "exclude_muted" => &mut folder.exclude_muted,
+            "exclude_read" => &mut folder.exclude_read,
+            "exclude_archived" => &mut folder.exclude_archived,
+            _ => continue,
+        };
+        *flag = true;
+    }
+}
+
+fn encode_ids(ids: &[i64]) -> String {
+    ids.iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(&FIELD_SEPARATOR.to_string())
+}
+
+fn decode_ids(encoded: &str) -> Vec<i64> {
+    encoded
+        .split(FIELD_SEPARATOR)
+        .filter_map(|id| id.parse().ok())
+        .collect()
+}
+
+fn encode_kind(kind: DialogKind) -> &'static str {
+    match kind {
+        DialogKind::User => "user",
+        DialogKind::Bot => "bot",
+        DialogKind::Group => "group",
+        DialogKind::Supergroup => "supergroup",
+        DialogKind::Channel => "channel",
+    }
+}
+
+fn decode_kind(encoded: &str) -> DialogKind {
+    match encoded {
+        "bot" => DialogKind::Bot,
+        "group" => DialogKind::Group,
+        "supergroup" => DialogKind::Supergroup,
+        "channel" => DialogKind::Channel,
+        _ => DialogKind::User,
+    }
+}
+
+/// The count followed by the names of the latest commenters.
+fn encode_comments(comments: &Comments) -> String {
+    std::iter::once(comments.count.to_string())
+        .chain(
+            comments
+                .recent
+                .iter()
+                .map(|name| name.replace([FIELD_SEPARATOR, LIST_SEPARATOR], " ")),
+        )
+        .collect::<Vec<_>>()
+        .join(&FIELD_SEPARATOR.to_string())
+}
+
+fn decode_comments(encoded: &str) -> Option<Comments> {
+    let mut fields = encoded.split(FIELD_SEPARATOR);
+    Some(Comments {
+        count: fields.next()?.parse().ok()?,
+        recent: fields.map(ToOwned::to_owned).collect(),
+    })
+}
+
+/// Flattens media into a tag followed by its fields; the cache never queries them.
+fn encode_media(media: &MediaInfo) -> String {
+    let text = |text: &str| text.replace([FIELD_SEPARATOR, LIST_SEPARATOR], " ");
+    let fields = match media {
+        MediaInfo::Photo {
+            width,
+            height,
+            size,
+        } => vec![
+            "photo".to_string(),
+            width.to_string(),
+            height.to_string(),
+            size.to_string(),
+        ],
+        MediaInfo::Video {
+            file_name,
+            duration,
+            width,
+            height,
+            size,
+            round,
+        } => vec![
+            "video".to_string(),
+            text(file_name),
+            duration.to_string(),
+            width.to_string(),
+            height.to_string(),
+            size.to_string(),
+            round.to_string(),
+        ],
+        MediaInfo::Animation {
+            file_name,
+            width,
+            height,
+            size,
+        } => vec![
+            "animation".to_string(),
+            text(file_name),
+            width.to_string(),
+            height.to_string(),
+            size.to_string(),
+        ],
+        MediaInfo::Audio {
+            title,
+            performer,
+            duration,
+            size,
+        } => vec![
+            "audio".to_string(),
+            text(title),
+            text(performer),
+            duration.to_string(),
+            size.to_string(),
+        ],
+        MediaInfo::Voice { duration, size } => {
+            vec!["voice".to_string(), duration.to_string(), size.to_string()]
+        }
+        MediaInfo::Sticker { emoji, animated } => {
+            vec!["sticker".to_string(), text(emoji), animated.to_string()]
+        }
+        MediaInfo::Document {
+            file_name,
+            mime_type,
+            size,
+        } => vec![
+            "document".to_string(),
+            text(file_name),
+            text(mime_type),
+            size.to_string(),
+        ],
+        MediaInfo::Poll {
+            question,
+            options,
+            quiz,
+            closed,
+            total_voters,
+        } => vec![
+            "poll".to_string(),
+            text(question),
+            options
+                .iter()
+                .map(|option| text(option))
+                .collect::<Vec<_>>()
+                .join(&LIST_SEPARATOR.to_string()),
+            quiz.to_string(),
+            closed.to_string(),
+            total_voters.to_string(),
+        ],
+        MediaInfo::Location {
+            latitude,
+            longitude,
+            live,
+        } => vec![
+            "location".to_string(),
+            latitude.to_string(),
+            longitude.to_string(),
+            live.to_string(),
+        ],
+        MediaInfo::Venue {
+            title,
+            address,
+            latitude,
+            longitude,
+        } => vec![
+            "venue".to_string(),
+            text(title),
+            text(address),
+            latitude.to_string(),
+            longitude.to_string(),
+        ],
+        MediaInfo::Contact { name, phone } => {
+            vec!["contact".to_string(), text(name), text(phone)]
+        }
+        MediaInfo::Dice { emoji, value } => {
+            vec!["dice".to_string(), text(emoji), value.to_string()]
+        }
+        MediaInfo::WebPage {
+            url,
+            site_name,
+            title,
+        } => vec![
+            "webpage".to_string(),
+            text(url),
+            text(site_name),
+            text(title),
+        ],
+        MediaInfo::Unsupported => vec!["unsupported".to_string()],
+    };
+    fields.join(&FIELD_SEPARATOR.to_string())
+}
+
+fn decode_media(encoded: &str) -> Option<MediaInfo> {
+    fn parse<T: FromStr>(field: &str) -> Option<T> {
+        field.parse().ok()
+    }
+
+    let fields: Vec<&str> = encoded.split(FIELD_SEPARATOR).collect();
+    let media = match fields.as_slice() {
+        ["photo", width, height, size] => MediaInfo::Photo {
+            width: parse(width)?,
+            height: parse(height)?,
+            size: parse(size)?,
+        },
+        ["video", file_name, duration, width, height, size, round] => MediaInfo::Video {
+            file_name: file_name.to_string(),
+            duration: parse(duration)?,
+            width: parse(width)?,
+            height: parse(height)?,
+            size: parse(size)?,
+            round: parse(round)?,
+        },
+        ["animation", file_name, width, height, size] => MediaInfo::Animation {
+            file_name: file_name.to_string(),
+            width: parse(width)?,
+            height: parse(height)?,
+            size: parse(size)?,
+        },
+        ["audio", title, performer, duration, size] => MediaInfo::Audio {
+            title: title.to_string(),
+            performer: performer.to_string(),
+            duration: parse(duration)?,
+            size: parse(size)?,
+        },
+        ["voice", duration, size] => MediaInfo::Voice {
+            duration: parse(duration)?,
+            size: parse(size)?,
+        },
+        ["sticker", emoji, animated] => MediaInfo::Sticker {
+            emoji: emoji.to_string(),
+            animated: parse(animated)?,
+        },
+        ["document", file_name, mime_type, size] => MediaInfo::Document {
+            file_name: file_name.to_string(),
+            mime_type: mime_type.to_string(),
+            size: parse(size)?,
+        },
+        ["poll", question, options, quiz, closed, total_voters] => MediaInfo::Poll {
+            question: question.to_string(),
+            options: options
+                .split(LIST_SEPARATOR)
+                .filter(|option| !option.is_empty())
+                .map(ToOwned::to_owned)
+                .collect(),
+            quiz: parse(quiz)?,
+            closed: parse(closed)?,
+            total_voters: parse(total_voters)?,
+        },
+        ["location", latitude, longitude, live] => MediaInfo::Location {
+            latitude: parse(latitude)?,
+            longitude: parse(longitude)?,
+            live: parse(live)?,
+        },
+        ["venue", title, address, latitude, longitude] => MediaInfo::Venue {
+            title: title.to_string(),
+            address: address.to_string(),
+            latitude: parse(latitude)?,
+            longitude: parse(longitude)?,
+        },
+        ["contact", name, phone] => MediaInfo::Contact {
+            name: name.to_string(),
+            phone: phone.to_string(),
+        },
+        ["dice", emoji, value] => MediaInfo::Dice {
+            emoji: emoji.to_string(),
+            value: parse(value)?,
+        },
+        ["webpage", url, site_name, title] => MediaInfo::WebPage {
+            url: url.to_string(),
+            site_name: site_name.to_string(),
+            title: title.to_string(),
+        },
+        ["unsupported"] => MediaInfo::Unsupported,
+        _ => return None,
+    };
+    Some(media)
+}
+
+/// Stands in for the Telegram task when there is no connection: reads are answered from the
+/// cache and anything that would change server state fails.
+pub fn spawn_offline_task(
+    cache: MessageCache,
+    mut req_rx: mpsc::Receiver<TelegramRequest>,
+    event_tx: mpsc::Sender<TelegramEvent>,
+) -> JoinHandle<anyhow::Result<()>> {
+    tokio::spawn(async move {
+        while let Some(req) = req_rx.recv().await {
+            let result = match req {
+                TelegramRequest::LoadDialogs => {
+                    cache.load_dialogs().map(TelegramEvent::DialogsLoaded)
+                }
+                TelegramRequest::LoadFolders => {
+                    cache.load_folders().map(TelegramEvent::FoldersLoaded)
+                }
+                TelegramRequest::LoadMessages {
+                    dialog_id,
+                    limit,
+                    thread_id,
+                } => cache
+                    .load_messages(dialog_id, limit, thread_id)
+                    .map(|messages| TelegramEvent::MessagesLoaded {
+                        dialog_id,
+                        thread_id,
+                        messages,
+                    }),
+                TelegramRequest::LoadOlderMessages {
+                    dialog_id,
+                    before_id,
+                    thread_id,
+                } => cache
+                    .load_older_messages(dialog_id, before_id, thread_id)
+                    .map(|messages| TelegramEvent::OlderMessagesLoaded {
+                        dialog_id,
+                        thread_id,
+                        messages,
+                    }),
+                TelegramRequest::SendMessage { .. }
+                | TelegramRequest::EditMessage { .. }
+                | TelegramRequest::DeleteMessages { .. }
+                | TelegramRequest::ForwardMessages { .. }
+                | TelegramRequest::SendReaction { .. }
+                | TelegramRequest::PinMessage { .. }
+                | TelegramRequest::UnpinMessage { .. } => Err(anyhow!("offline mode is read-only")),
+                TelegramRequest::OpenThread { .. } => {
+                    Err(anyhow!("comments are not available offline"))
+                }
+                TelegramRequest::SearchMessages { dialog_id, query } => cache
+                    .search_messages(dialog_id, &query)
+                    .map(|results| TelegramEvent::SearchResultsLoaded { query, results }),
+                TelegramRequest::LoadMessagesAround {
+                    dialog_id,
+                    message_id,
+                } => cache.load_messages_around(dialog_id, message_id).map(
+                    |(messages, has_newer)| TelegramEvent::MessagesAroundLoaded {
+                        dialog_id,
+                        message_id,
+                        messages,
+                        has_newer,
+                    },
+                ),
+                TelegramRequest::DownloadMedia {
+                    dialog_id,
+                    message_id,
+                    ..
+                } => Ok(TelegramEvent::DownloadFailed {
+                    dialog_id,
+                    message_id,
+                    error: "media is not available offline".to_string(),
+                }),
+                TelegramRequest::SendFile { dialog_id, .. } => Ok(TelegramEvent::UploadFailed {
+                    dialog_id,
+                    error: "offline mode is read-only".to_string(),
+                }),
+                TelegramRequest::MarkRead { .. }
+                | TelegramRequest::CancelDownload { .. }
+                | TelegramRequest::CancelUpload
+                | TelegramRequest::LoadImagePreview { .. }
+                | TelegramRequest::SaveDraft { .. }
+                | TelegramRequest::LoadPinnedMessages { .. }
+                | TelegramRequest::LoadTopics { .. } => continue,
+                TelegramRequest::Shutdown => break,
+            };
+
+            let event = result.unwrap_or_else(|err| TelegramEvent::Error(err.to_string()));
+            if event_tx.send(event).await.is_err() {
+                break;
+            }
+        }
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dialog(id: i64, title: &str) -> DialogSummary {
+        DialogSummary {
+            id,
+            title: title.to_string(),
+            ..DialogSummary::default()
+        }
+    }
+
+    fn message(id: i32, text: &str) -> MessageSummary {
+        MessageSummary {
+            id,
+            from: "Alice".to_string(),
+            text: text.to_string(),
+            entities: Vec::new(),
+            date: "2024-01-01".to_string(),
+            reply_to: None,
+            topic_id: None,
+            outgoing: false,
+            mentioned: false,
+            edited: false,
+            deleted: false,
+            reactions: Vec::new(),
+            comments: None,
+            media: None,
+        }
+    }
+
+    fn ids(messages: &[MessageSummary]) -> Vec<i32> {
+        messages.iter().map(|message| message.id).collect()
+    }
+
+    #[test]
+    fn dialogs_round_trip_in_server_order() {
+        let cache = MessageCache::open(":memory:").expect("open cache");
+        let mut zed = dialog(2, "Zed");
+        zed.unread_count = 3;
+        zed.read_outbox_max_id = 7;
+
+        cache
+            .record_event(&TelegramEvent::DialogsLoaded(vec![zed, dialog(1, "Alice")]))
+            .expect("save dialogs");
+        let dialogs = cache.load_dialogs().expect("load dialogs");
+
+        assert_eq!(dialogs.iter().map(|d| d.id).collect::<Vec<_>>(), vec![2, 1]);
+        assert_eq!(dialogs[0].unread_count, 3);
+        assert_eq!(dialogs[0].read_outbox_max_id, 7);
+    }
+
+    #[test]
+    fn folders_and_chat_kinds_round_trip() {
+        let cache = MessageCache::open(":memory:").expect("open cache");
+        let mut news = dialog(-100, "News");
+        news.kind = DialogKind::Channel;
+        news.muted = true;
+        news.archived = true;
+        let folders = vec![
+            ChatFolder {
+                id: 4,
+                title: "Work".to_string(),
+                groups: true,
+                exclude_read: true,
+                include: vec![1, -100],
+                exclude: vec![7],
+                ..ChatFolder::default()
+            },
+            ChatFolder {
+                id: 2,
+                title: "Empty".to_string(),
+                ..ChatFolder::default()
+            },
+        ];
+        cache
+            .record_event(&TelegramEvent::DialogsLoaded(vec![news]))
+            .expect("save dialogs");
+        cache
+            .record_event(&TelegramEvent::FoldersLoaded(folders.clone()))
+            .expect("save folders");
+
+        let dialogs = cache.load_dialogs().expect("load dialogs");
+        assert_eq!(dialogs[0].kind, DialogKind::Channel);
+        assert!(dialogs[0].muted && dialogs[0].archived && !dialogs[0].contact);
+        assert_eq!(cache.load_folders().expect("load folders"), folders);
+    }
+
+    #[test]
+    fn draft_changes_update_the_cached_dialog() {
+        let cache = MessageCache::open(":memory:").expect("open cache");
+        let mut alice = dialog(1, "Alice");
+        alice.draft = "see you".to_string();
+        cache
+            .record_event(&TelegramEvent::DialogsLoaded(vec![alice, dialog(2, "Bob")]))
+            .expect("save dialogs");
+        cache
+            .record_event(&TelegramEvent::DraftChanged {
+                dialog_id: 2,
+                text: "hi".to_string(),
+            })
+            .expect("save draft");
+
+        let drafts: Vec<String> = cache
+            .load_dialogs()
+            .expect("load dialogs")
+            .into_iter()
+            .map(|dialog| dialog.draft)
+            .collect();
+        assert_eq!(drafts, vec!["see you", "hi"]);
+    }
+
+    #[test]
+    fn chat_previews_round_trip_and_follow_new_messages() {
+        let cache = MessageCache::open(":memory:").expect("open cache");
+        let mut alice = dialog(1, "Alice");
+        alice.pinned = true;
+        alice.last_message = Some(LastMessage {
+            id: 5,
+            from: "Alice".to_string(),
+            text: "see you".to_string(),
+            media: Some(MediaInfo::Sticker {
+                emoji: "👋".to_string(),
+                animated: false,
+            }),
+            date: "2024-01-01 10:00:00 UTC".to_string(),
+            mentioned: true,
+            ..LastMessage::default()
+        });
+        cache
+            .record_event(&TelegramEvent::DialogsLoaded(vec![
+                alice.clone(),
+                dialog(2, "Bob"),
+            ]))
+            .expect("save dialogs");
+
+        let loaded = cache.load_dialogs().expect("load dialogs");
+        assert!(loaded[0].pinned);
+        assert_eq!(loaded[0].last_message, alice.last_message);
+        assert_eq!(loaded[1].last_message, None);
+
+        for (dialog_id, id, text) in [(1, 4, "older"), (1, 6, "newer"), (2, 1, "hi")] {
+            cache
+                .record_event(&TelegramEvent::IncomingMessage {
+                    dialog_id,
+                    message: message(id, text),
+                })
+                .expect("save message");
+        }
+
+        let previews: Vec<Option<String>> = cache
+            .load_dialogs()
+            .expect("load dialogs")
+            .into_iter()
+            .map(|dialog| dialog.last_message.map(|last| last.text))
+            .collect();
+        assert_eq!(
+            previews,
+            vec![Some("newer".to_string()), Some("hi".to_string())]
+        );
+    }
+
+    #[test]
+    fn topic_pages_keep_other_topics_and_filter_by_topic() {
+        let cache = MessageCache::open(":memory:").expect("open cache");
+        let in_topic = |id: i32| MessageSummary {
+            topic_id: Some(5),
+            ..message(id, "topic")
+        };
+        cache
+            .record_event(&TelegramEvent::MessagesLoaded {
+                dialog_id: 1,
+                thread_id: None,
+                messages: vec![message(1, "general"), in_topic(2), message(3, "general")],
+            })
+            .expect("save messages");
+        cache
+            .record_event(&TelegramEvent::MessagesLoaded {
+                dialog_id: 1,
+                thread_id: Some(5),
+                messages: vec![in_topic(4)],
+            })
+            .expect("save topic messages");
+
+        let load = |thread_id| {
+            cache
+                .load_messages(1, 50, thread_id)
+                .map(|m| ids(&m))
+                .expect("load messages")
+        };
+        assert_eq!(load(None), vec![1, 2, 3, 4]);
+        assert_eq!(load(Some(5)), vec![2, 4]);
+        assert_eq!(load(Some(GENERAL_TOPIC_ID)), vec![1, 3]);
+    }
+
+    #[test]
+    fn recent_page_replaces_messages_deleted_while_away() {
+        let cache = MessageCache::open(":memory:").expect("open cache");
+        let mut reply = message(3, "three");
+        reply.reply_to = Some(1);
+        cache
+            .record_event(&TelegramEvent::MessagesLoaded {
+                dialog_id: 1,
+                thread_id: None,
+                messages: vec![message(1, "one"), message(2, "two"), reply],
+            })
+            .expect("save messages");
+        cache
+            .record_event(&TelegramEvent::MessagesLoaded {
+                dialog_id: 1,
+                thread_id: None,
+                messages: vec![message(2, "two"), message(4, "four")],
+            })
+            .expect("save messages");
+
+        let messages = cache.load_messages(1, 50, None).expect("load messages");
+
+        assert_eq!(ids(&messages), vec![1, 2, 4]);
+        assert_eq!(
+            cache.load_older_messages(1, 2, None).map(|m| ids(&m)).ok(),
+            Some(vec![1])
+        );
+    }
+
+    #[test]
+    fn edits_and_deletions_update_cached_messages_only() {
+        let cache = MessageCache::open(":memory:").expect("open cache");
+        cache
+            .record_event(&TelegramEvent::MessagesLoaded {
+                dialog_id: 1,
+                thread_id: None,
+                messages: vec![message(1, "one"), message(2, "two")],
+            })
+            .expect("save messages");
+
+        let mut edited = message(1, "one, edited");
+        edited.edited = true;
+        cache
+            .record_event(&TelegramEvent::IncomingMessageEdited {
+                dialog_id: 1,
+                message: edited,
+            })
+            .expect("edit message");
+        cache
+            .record_event(&TelegramEvent::IncomingMessageEdited {
+                dialog_id: 1,
+                message: message(9, "not cached"),
+            })
+            .expect("edit message");
+        cache
+            .record_event(&TelegramEvent::IncomingMessagesDeleted {
+                dialog_ids: vec![1],
+                message_ids: vec![2],
+            })
+            .expect("delete message");
+
+        let messages = cache.load_messages(1, 50, None).expect("load messages");
+
+        assert_eq!(ids(&messages), vec![1, 2]);
+        assert_eq!(messages[0].text, "one, edited");
+        assert!(messages[0].edited);
+        assert!(messages[1].deleted);
+    }
+
+    #[test]
+    fn history_is_pruned_to_newest_messages() {
+        let cache = MessageCache::open(":memory:").expect("open cache");
+        let page: Vec<MessageSummary> = (1..=MESSAGES_PER_DIALOG as i32 + 10)
+            .map(|id| message(id, "text"))
+            .collect();
+        cache
+            .record_event(&TelegramEvent::MessagesLoaded {
+                dialog_id: 1,
+                thread_id: None,
+                messages: page,
+            })
+            .expect("save messages");
+
+        let messages = cache
+            .load_messages(1, usize::MAX, None)
+            .expect("load messages");
+
+        assert_eq!(messages.len(), MESSAGES_PER_DIALOG as usize);
+        assert_eq!(messages.first().map(|m| m.id), Some(11));
+    }
+
+    #[test]
+    fn reaction_updates_keep_our_choice_when_left_out() {
+        let cache = MessageCache::open(":memory:").expect("open cache");
+        let reaction = |emoji: &str, count, chosen| ReactionCount {
+            emoji: emoji.to_string(),
+            count,
+            chosen,
+        };
+        cache
+            .record_event(&TelegramEvent::MessagesLoaded {
+                dialog_id: 1,
+                thread_id: None,
+                messages: vec![MessageSummary {
+                    reactions: vec![reaction("👍", 1, true)],
+                    ..message(1, "text")
+                }],
+            })
+            .expect("save messages");
+
+        cache
+            .record_event(&TelegramEvent::ReactionsChanged {
+                dialog_id: 1,
+                message_id: 1,
+                reactions: vec![reaction("👍", 2, false), reaction("🔥", 1, false)],
+                chosen_known: false,
+            })
+            .expect("update reactions");
+
+        let messages = cache.load_messages(1, 10, None).expect("load messages");
+        assert_eq!(
+            messages[0].reactions,
+            [reaction("👍", 2, true), reaction("🔥", 1, false)]
+        );
+    }
+
+    #[test]
+    fn search_matches_cached_text_case_insensitively() {
+        let cache = MessageCache::open(":memory:").expect("open cache");
+        cache
+            .record_event(&TelegramEvent::DialogsLoaded(vec![
+                dialog(1, "Alice"),
+                dialog(2, "Bob"),
+            ]))
+            .expect("save dialogs");
+        for (dialog_id, id, text) in [
+            (1, 1, "Привет, мир"),
+            (2, 1, "мир и дружба"),
+            (2, 2, "other"),
+        ] {
+            cache
+                .record_event(&TelegramEvent::IncomingMessage {
+                    dialog_id,
+                    message: message(id, text),
+                })
+                .expect("save message");
+        }
+
+        let everywhere = cache.search_messages(None, "МИР").expect("search");
+        let in_bob = cache.search_messages(Some(2), "мир").expect("search");
+
+        assert_eq!(everywhere.len(), 2);
+        assert_eq!(in_bob.len(), 1);
+        assert_eq!(in_bob[0].dialog_title, "Bob");
+    }
+
+    #[test]
+    fn messages_around_include_both_sides() {
+        let cache = MessageCache::open(":memory:").expect("open cache");
+        let page: Vec<MessageSummary> = (1..=100).map(|id| message(id, "text")).collect();
+        cache
+            .record_event(&TelegramEvent::MessagesLoaded {
+                dialog_id: 1,
+                thread_id: None,
+                messages: page,
+            })
+            .expect("save messages");
+
+        let (messages, has_newer) = cache.load_messages_around(1, 50).expect("load around");
+        let (latest, latest_has_newer) = cache.load_messages_around(1, 95).expect("load around");
+
+        assert_eq!(messages.first().map(|m| m.id), Some(26));
+        assert_eq!(messages.last().map(|m| m.id), Some(75));
+        assert!(has_newer);
+        assert_eq!(latest.last().map(|m| m.id), Some(100));
+        assert!(!latest_has_newer);
+    }
+
+    #[test]
+    fn media_survives_a_round_trip() {
+        let cache = MessageCache::open(":memory:").expect("open cache");
+        let media = [
+            MediaInfo::Photo {
+                width: 1280,
+                height: 720,
+                size: 1024,
+            },
+            MediaInfo::Video {
+                file_name: "clip.mp4".to_string(),
+                duration: 65,
+                width: 1920,
+                height: 1080,
+                size: 4096,
+                round: false,
+            },
+            MediaInfo::Poll {
+                question: "Lunch?".to_string(),
+                options: vec!["Pizza".to_string(), "Sushi".to_string()],
+                quiz: false,
+                closed: true,
+                total_voters: 3,
+            },
+            MediaInfo::Location {
+                latitude: 55.7558,
+                longitude: -37.6173,
+                live: true,
+            },
+            MediaInfo::Document {
+                file_name: "odd\u{1f}name.txt".to_string(),
+                mime_type: "text/plain".to_string(),
+                size: 0,
+            },
+            MediaInfo::Unsupported,
+        ];
+        let messages: Vec<MessageSummary> = media
+            .iter()
+            .zip(1..)
+            .map(|(media, id)| MessageSummary {
+                media: Some(media.clone()),
+                ..message(id, "")
+            })
+            .collect();
+
+        cache
+            .record_event(&TelegramEvent::MessagesLoaded {
+                dialog_id: 1,
+                thread_id: None,
+                messages,
+            })
+            .expect("save messages");
+
+        let loaded: Vec<Option<MediaInfo>> = cache
+            .load_messages(1, 10, None)
+            .expect("load messages")
+            .into_iter()
+            .map(|message| message.media)
+            .collect();
+        assert_eq!(loaded[0], Some(media[0].clone()));
+        assert_eq!(loaded[1], Some(media[1].clone()));
+        assert_eq!(loaded[2], Some(media[2].clone()));
+        assert_eq!(loaded[3], Some(media[3].clone()));
+        assert_eq!(
+            loaded[4],
+            Some(MediaInfo::Document {
+                file_name: "odd name.txt".to_string(),
+                mime_type: "text/plain".to_string(),
+                size: 0,
+            })
+        );
+        assert_eq!(loaded[5], Some(MediaInfo::Unsupported));
+        assert_eq!(decode_media("photo\u{1f}wide"), None);
+    }
+
+    #[test]
+    fn comments_survive_a_round_trip() {
+        let cache = MessageCache::open(":memory:").expect("open cache");
+        let comments = [
+            Comments {
+                count: 0,
+                recent: Vec::new(),
+            },
+            Comments {
+                count: 12,
+                recent: vec!["Alice".to_string(), "Bob".to_string()],
+            },
+        ];
+        let mut messages: Vec<MessageSummary> = comments
+            .iter()
+            .zip(1..)
+            .map(|(comments, id)| MessageSummary {
+                comments: Some(comments.clone()),
+                ..message(id, "post")
+            })
+            .collect();
+        messages.push(message(3, "plain"));
+        cache
+            .record_event(&TelegramEvent::MessagesLoaded {
+                dialog_id: 1,
+                thread_id: None,
+                messages,
+            })
+            .expect("save messages");
+
+        let loaded: Vec<Option<Comments>> = cache
+            .load_messages(1, 10, None)
+            .expect("load messages")
+            .into_iter()
+            .map(|message| message.comments)
+            .collect();
+        assert_eq!(
+            loaded,
+            vec![Some(comments[0].clone()), Some(comments[1].clone()), None]
+        );
+    }
+
+    #[test]
+    fn entities_round_trip_and_invalid_ranges_are_dropped() {
+        let cache = MessageCache::open(":memory:").expect("open cache");
+        let entities = vec![
+            TextEntity {
+                kind: EntityKind::Bold,
+                range: 0..4,
+            },
+            TextEntity {
+                kind: EntityKind::TextUrl {
+                    url: "https://example.com".to_string(),
+                },
+                range: 5..9,
+            },
+            TextEntity {
+                kind: EntityKind::Pre {
+                    language: "rust".to_string(),
+                },
+                range: 0..9,
+            },
+            TextEntity {
+                kind: EntityKind::Spoiler,
+                range: 5..40,
+            },
+        ];
+        cache
+            .record_event(&TelegramEvent::MessagesLoaded {
+                dialog_id: 1,
+                thread_id: None,
+                messages: vec![MessageSummary {
+                    entities: entities.clone(),
+                    ..message(1, "bold link")
+                }],
+            })
+            .expect("save messages");
+
+        let loaded = cache.load_messages(1, 10, None).expect("load messages");
+
+        assert_eq!(loaded[0].entities, entities[..3]);
+        assert!(decode_entities("").is_empty());
+        assert!(decode_entities("sparkle\u{1f}0\u{1f}1").is_empty());
+    }
+}
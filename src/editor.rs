@@ -0,0 +1,537 @@
+//! Multi-line text editing for the compose box: cursor, word motions, undo/redo and sent history.
+
+use std::{
+    collections::hash_map::RandomState,
+    env, fs,
+    hash::{BuildHasher, Hasher},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use anyhow::{Context, anyhow};
+use tokio::process::Command;
+
+const MAX_UNDO_STEPS: usize = 100;
+const FALLBACK_EDITOR: &str = "vi";
+const TEMP_FILE_ATTEMPTS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditAction {
+    InsertNewline,
+    Delete,
+    MoveLeft,
+    MoveRight,
+    /// Moves to the line above, or to the previous sent message from the first line.
+    MoveUp,
+    /// Moves to the line below, or to the next sent message from the last line.
+    MoveDown,
+    WordLeft,
+    WordRight,
+    LineStart,
+    LineEnd,
+    DeleteWordBefore,
+    DeleteToLineStart,
+    Undo,
+    Redo,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EditKind {
+    Insert,
+    Delete,
+    Replace,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Snapshot {
+    text: String,
+    cursor: usize,
+}
+
+/// Text with a cursor, kept as a byte offset on a character boundary.
+#[derive(Debug, Clone, Default)]
+pub struct TextEditor {
+    text: String,
+    cursor: usize,
+    undo: Vec<Snapshot>,
+    redo: Vec<Snapshot>,
+    /// Consecutive edits of the same kind are undone together.
+    last_edit: Option<EditKind>,
+    history: Vec<String>,
+    /// Index into `history` while browsing it, with the text typed before browsing started.
+    browsing: Option<(usize, String)>,
+}
+
+impl TextEditor {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    /// Replaces the text and puts the cursor at its end; undo starts afresh.
+    pub fn set_text(&mut self, text: impl Into<String>) {
+        self.text = text.into();
+        self.cursor = self.text.len();
+        self.undo.clear();
+        self.redo.clear();
+        self.last_edit = None;
+        self.browsing = None;
+    }
+
+    pub fn clear(&mut self) {
+        self.set_text(String::new());
+    }
+
+    /// Remembers the current text as sent, for Up/Down.
+    pub fn push_history(&mut self) {
+        self.browsing = None;
+        let text = self.text.trim();
+        if !text.is_empty() && self.history.last().map(String::as_str) != Some(text) {
+            self.history.push(text.to_string());
+        }
+    }
+
+    /// Replaces the whole text as one undoable step, with the cursor at the end.
+    pub fn replace_text(&mut self, text: String) {
+        self.record(EditKind::Replace, true);
+        self.text = text;
+        self.cursor = self.text.len();
+        self.browsing = None;
+    }
+
+    pub fn insert_char(&mut self, ch: char) {
+        // A word boundary starts a new undo step.
+        self.record(EditKind::Insert, ch.is_whitespace());
+        self.text.insert(self.cursor, ch);
+        self.cursor += ch.len_utf8();
+    }
+
+    pub fn backspace(&mut self) {
+        if let Some(start) = self.prev_boundary(self.cursor) {
+            self.delete_range(start, self.cursor);
+        }
+    }
+
+    pub fn apply(&mut self, action: EditAction) {
+        match action {
+            EditAction::InsertNewline => self.insert_char('\n'),
+            EditAction::Delete => {
+                if let Some(end) = self.next_boundary(self.cursor) {
+                    self.delete_range(self.cursor, end);
+                }
+            }
+            EditAction::MoveLeft => {
+                let cursor = self.prev_boundary(self.cursor).unwrap_or(0);
+                self.move_to(cursor);
+            }
+            EditAction::MoveRight => {
+                let cursor = self.next_boundary(self.cursor).unwrap_or(self.text.len());
+                self.move_to(cursor);
+            }
+            EditAction::MoveUp => self.move_vertically(false),
+            EditAction::MoveDown => self.move_vertically(true),
+            EditAction::WordLeft => self.move_to(self.word_start()),
+            EditAction::WordRight => self.move_to(self.word_end()),
+            EditAction::LineStart => self.move_to(self.line_start()),
+            EditAction::LineEnd => self.move_to(self.line_end()),
+            EditAction::DeleteWordBefore => self.delete_range(self.word_start(), self.cursor),
+            EditAction::DeleteToLineStart => self.delete_range(self.line_start(), self.cursor),
+            EditAction::Undo => self.restore(false),
+            EditAction::Redo => self.restore(true),
+        }
+    }
+
+    /// Line index and character column of the cursor.
+    pub fn cursor_line_col(&self) -> (usize, usize) {
+        let before = &self.text[..self.cursor];
+        let line = before.matches('\n').count();
+        let column = before[self.line_start()..].chars().count();
+        (line, column)
+    }
+
+    fn record(&mut self, kind: EditKind, new_step: bool) {
+        if new_step || self.last_edit != Some(kind) {
+            self.undo.push(self.snapshot());
+            if self.undo.len() > MAX_UNDO_STEPS {
+                self.undo.remove(0);
+            }
+        }
+        self.redo.clear();
+        self.last_edit = Some(kind);
+        self.browsing = None;
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            text: self.text.clone(),
+            cursor: self.cursor,
+        }
+    }
+
+    fn restore(&mut self, redo: bool) {
+        let (from, to) = if redo {
+            (&mut self.redo, &mut self.undo)
+        } else {
+            (&mut self.undo, &mut self.redo)
+        };
+        let Some(snapshot) = from.pop() else {
+            return;
+        };
+        to.push(Snapshot {
+            text: std::mem::replace(&mut self.text, snapshot.text),
+            cursor: std::mem::replace(&mut self.cursor, snapshot.cursor),
+        });
+        self.last_edit = None;
+        self.browsing = None;
+    }
+
+    fn delete_range(&mut self, start: usize, end: usize) {
+        if start >= end {
+            return;
+        }
+        self.record(EditKind::Delete, false);
+        self.text.replace_range(start..end, "");
+        self.cursor = start;
+    }
+
+    fn move_to(&mut self, cursor: usize) {
+        self.cursor = cursor;
+        self.last_edit = None;
+    }
+
+    fn move_vertically(&mut self, down: bool) {
+        let (line, column) = self.cursor_line_col();
+        let target = if down {
+            let Some(newline) = self.text[self.cursor..].find('\n') else {
+                self.next_history();
+                return;
+            };
+            self.cursor + newline + 1
+        } else {
+            if line == 0 {
+                self.previous_history();
+                return;
+            }
+            self.text[..self.line_start() - 1]
+                .rfind('\n')
+                .map_or(0, |newline| newline + 1)
+        };
+        let line_len = self.text[target..]
+            .find('\n')
+            .unwrap_or(self.text.len() - target);
+        let offset = self.text[target..target + line_len]
+            .char_indices()
+            .nth(column)
+            .map_or(line_len, |(offset, _)| offset);
+        self.move_to(target + offset);
+    }
+
+    fn previous_history(&mut self) {
+        let index = match &self.browsing {
+            None if !self.history.is_empty() => self.history.len() - 1,
+            Some((index, _)) if *index > 0 => index - 1,
+            _ => return,
+        };
+        let draft = match self.browsing.take() {
+            Some((_, draft)) => draft,
+            None => self.text.clone(),
+        };
+        self.replace_text(self.history[index].clone());
+        self.browsing = Some((index, draft));
+    }
+
+    fn next_history(&mut self) {
+        let Some((index, draft)) = self.browsing.take() else {
+            return;
+        };
+        if index + 1 < self.history.len() {
+            self.replace_text(self.history[index + 1].clone());
+            self.browsing = Some((index + 1, draft));
+        } else {
+            self.replace_text(draft);
+        }
+    }
+
+    fn prev_boundary(&self, offset: usize) -> Option<usize> {
+        self.text[..offset]
+            .char_indices()
+            .next_back()
+            .map(|(index, _)| index)
+    }
+
+    fn next_boundary(&self, offset: usize) -> Option<usize> {
+        self.text[offset..]
+            .chars()
+            .next()
+            .map(|ch| offset + ch.len_utf8())
+    }
+
+    fn line_start(&self) -> usize {
+        self.text[..self.cursor]
+            .rfind('\n')
+            .map_or(0, |newline| newline + 1)
+    }
+
+    fn line_end(&self) -> usize {
+        self.text[self.cursor..]
+            .find('\n')
+            .map_or(self.text.len(), |newline| self.cursor + newline)
+    }
+
+    /// Start of the word before the cursor, skipping whitespace first.
+    fn word_start(&self) -> usize {
+        let before = self.text[..self.cursor].trim_end();
+        before
+            .char_indices()
+            .rev()
+            .find(|(_, ch)| ch.is_whitespace())
+            .map_or(0, |(index, ch)| index + ch.len_utf8())
+    }
+
+    /// End of the word after the cursor, skipping whitespace first.
+    fn word_end(&self) -> usize {
+        let after = &self.text[self.cursor..];
+        let skipped = after.len() - after.trim_start().len();
+        let word = &after[skipped..];
+        let len = word.find(char::is_whitespace).unwrap_or(word.len());
+        self.cursor + skipped + len
+    }
+}
+
+/// `$VISUAL`, else `$EDITOR`, else `vi`.
+pub fn editor_command(var: impl Fn(&str) -> Option<String>) -> String {
+    ["VISUAL", "EDITOR"]
+        .into_iter()
+        .filter_map(var)
+        .find(|command| !command.trim().is_empty())
+        .unwrap_or_else(|| FALLBACK_EDITOR.to_string())
+}
+
+/// Opens `text` in `command` through a temporary file and returns what was saved. The command
+/// may carry arguments, such as `code --wait`.
+pub async fn edit_externally(command: &str, text: &str) -> anyhow::Result<String> {
+    let mut parts = command.split_whitespace();
+    let program = parts.next().context("editor command is empty")?;
+    let (path, mut file) = create_temp_file()?;
+    let written = file
+        .write_all(text.as_bytes())
+        .with_context(|| format!("failed to write {}", path.display()));
+    drop(file);
+    if let Err(err) = written {
+        let _ = fs::remove_file(&path);
+        return Err(err);
+    }
+
+    let edited = match Command::new(program).args(parts).arg(&path).status().await {
+        Ok(status) if status.success() => {
+            fs::read_to_string(&path).context("failed to read the edited message")
+        }
+        Ok(status) => Err(anyhow!("{command} exited with {status}")),
+        Err(err) => Err(anyhow!(err).context(format!("failed to run {command}"))),
+    };
+    let _ = fs::remove_file(&path);
+    // Editors end the file with a newline.
+    Ok(edited?.trim_end().to_string())
+}
+
+/// Creates a new file only we can read, under a random name. An existing file or symlink at
+/// the path is never opened, so other local users can neither read nor redirect the draft.
+fn create_temp_file() -> anyhow::Result<(PathBuf, fs::File)> {
+    let dir = env::temp_dir();
+    for _ in 0..TEMP_FILE_ATTEMPTS {
+        let random = RandomState::new().build_hasher().finish();
+        let path = dir.join(format!(
+            "tele-rust-compose-{}-{random:016x}.md",
+            std::process::id()
+        ));
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        match options.open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => {
+                return Err(anyhow!(err).context(format!("failed to create {}", path.display())));
+            }
+        }
+    }
+    Err(anyhow!(
+        "failed to create a temporary file in {}",
+        dir.display()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor(text: &str) -> TextEditor {
+        let mut editor = TextEditor::default();
+        editor.set_text(text);
+        editor
+    }
+
+    #[test]
+    fn inserts_and_deletes_at_the_cursor() {
+        let mut editor = editor("héllo");
+        editor.apply(EditAction::LineStart);
+        editor.apply(EditAction::MoveRight);
+        editor.apply(EditAction::MoveRight);
+        editor.insert_char('y');
+        assert_eq!(editor.text(), "héyllo");
+
+        editor.backspace();
+        editor.backspace();
+        assert_eq!(editor.text(), "hllo");
+        editor.apply(EditAction::Delete);
+        assert_eq!(editor.text(), "hlo");
+        assert_eq!(editor.cursor(), 1);
+    }
+
+    #[test]
+    fn word_motions_skip_whitespace() {
+        let mut editor = editor("one  two three");
+        editor.apply(EditAction::WordLeft);
+        assert_eq!(editor.cursor(), 9);
+        editor.apply(EditAction::WordLeft);
+        assert_eq!(editor.cursor(), 5);
+        editor.apply(EditAction::WordRight);
+        assert_eq!(editor.cursor(), 8);
+        editor.apply(EditAction::WordRight);
+        assert_eq!(editor.cursor(), 14);
+
+        editor.apply(EditAction::DeleteWordBefore);
+        assert_eq!(editor.text(), "one  two ");
+        editor.apply(EditAction::DeleteWordBefore);
+        assert_eq!(editor.text(), "one  ");
+    }
+
+    #[test]
+    fn lines_keep_the_column_when_moving_vertically() {
+        let mut editor = editor("first line\nab\nthird line");
+        assert_eq!(editor.cursor_line_col(), (2, 10));
+
+        editor.apply(EditAction::MoveUp);
+        assert_eq!(editor.cursor_line_col(), (1, 2));
+        editor.apply(EditAction::MoveUp);
+        assert_eq!(editor.cursor_line_col(), (0, 2));
+        editor.apply(EditAction::LineEnd);
+        editor.apply(EditAction::MoveDown);
+        assert_eq!(editor.cursor_line_col(), (1, 2));
+
+        editor.apply(EditAction::DeleteToLineStart);
+        assert_eq!(editor.text(), "first line\n\nthird line");
+        editor.apply(EditAction::InsertNewline);
+        assert_eq!(editor.text(), "first line\n\n\nthird line");
+        assert_eq!(editor.cursor_line_col(), (2, 0));
+    }
+
+    #[test]
+    fn undo_and_redo_restore_whole_words() {
+        let mut editor = TextEditor::default();
+        for ch in "hello world".chars() {
+            editor.insert_char(ch);
+        }
+        editor.backspace();
+        editor.backspace();
+
+        editor.apply(EditAction::Undo);
+        assert_eq!(editor.text(), "hello world");
+        editor.apply(EditAction::Undo);
+        assert_eq!(editor.text(), "hello");
+        editor.apply(EditAction::Undo);
+        assert_eq!(editor.text(), "");
+        editor.apply(EditAction::Undo);
+        assert_eq!(editor.text(), "");
+
+        editor.apply(EditAction::Redo);
+        editor.apply(EditAction::Redo);
+        assert_eq!(editor.text(), "hello world");
+        assert_eq!(editor.cursor(), 11);
+
+        editor.apply(EditAction::Undo);
+        editor.insert_char('!');
+        editor.apply(EditAction::Redo);
+        assert_eq!(editor.text(), "hello!");
+    }
+
+    #[test]
+    fn up_and_down_browse_sent_history_from_the_edges() {
+        let mut editor = TextEditor::default();
+        for sent in ["first", "second\nline", "second\nline", " "] {
+            editor.set_text(sent);
+            editor.push_history();
+        }
+        editor.set_text("draft");
+
+        editor.apply(EditAction::MoveUp);
+        assert_eq!(editor.text(), "second\nline");
+        // Inside a multi-line entry Up moves between its lines first.
+        editor.apply(EditAction::MoveUp);
+        assert_eq!(editor.text(), "second\nline");
+        editor.apply(EditAction::MoveUp);
+        assert_eq!(editor.text(), "first");
+        editor.apply(EditAction::MoveUp);
+        assert_eq!(editor.text(), "first");
+
+        editor.apply(EditAction::MoveDown);
+        assert_eq!(editor.text(), "second\nline");
+        editor.apply(EditAction::MoveDown);
+        assert_eq!(editor.text(), "draft");
+        editor.apply(EditAction::MoveDown);
+        assert_eq!(editor.text(), "draft");
+
+        editor.apply(EditAction::MoveUp);
+        editor.apply(EditAction::Undo);
+        assert_eq!(editor.text(), "draft");
+    }
+
+    #[test]
+    fn editor_command_prefers_visual_then_editor() {
+        let vars = |visual: &'static str, editor: &'static str| {
+            move |name: &str| match name {
+                "VISUAL" => Some(visual.to_string()),
+                "EDITOR" => Some(editor.to_string()),
+                _ => None,
+            }
+        };
+
+        assert_eq!(editor_command(vars("hx", "vim")), "hx");
+        assert_eq!(editor_command(vars(" ", "vim")), "vim");
+        assert_eq!(editor_command(|_| None), "vi");
+    }
+
+    #[tokio::test]
+    async fn external_editor_output_replaces_the_text() {
+        // A script rather than `sed -i`, whose arguments differ between GNU and BSD sed.
+        let script = env::temp_dir().join(format!("tele-rust-editor-{}.sh", std::process::id()));
+        fs::write(
+            &script,
+            "sed s/draft/final/ \"$1\" > \"$1.new\" && mv \"$1.new\" \"$1\"\n",
+        )
+        .expect("write editor script");
+        let edited = edit_externally(&format!("sh {}", script.display()), "a draft\n").await;
+        let _ = fs::remove_file(&script);
+        let edited = edited.expect("run editor script");
+        assert_eq!(edited, "a final");
+
+        let err = edit_externally("false", "text")
+            .await
+            .expect_err("false fails");
+        assert!(err.to_string().starts_with("false exited with"));
+
+        let mut editor = TextEditor::default();
+        editor.insert_char('a');
+        editor.replace_text(edited);
+        editor.apply(EditAction::Undo);
+        assert_eq!(editor.text(), "a");
+    }
+}
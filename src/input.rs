@@ -1,6 +1,9 @@
-use crossterm::event::{KeyCode, KeyEvent, KeyEventKind};
+use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
 
-use crate::app::{FocusArea, UiMode};
+use crate::{
+    app::{FocusArea, UiMode},
+    editor::EditAction,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AppCommand {
@@ -8,15 +11,67 @@ pub enum AppCommand {
     MoveDown,
     ScrollMessagesUp,
     ScrollMessagesDown,
+    SelectPrevMessage,
+    SelectNextMessage,
+    ReplyToMessage,
+    EditMessage,
+    DeleteMessage,
+    ConfirmDeleteForMe,
+    ConfirmDeleteForEveryone,
+    DownloadMedia,
+    ToggleSpoilers,
+    StartReaction,
+    PrevReaction,
+    NextReaction,
+    /// Sends the reaction at this index of the picker.
+    PickReaction(usize),
+    SubmitReaction,
+    ToggleMessageMark,
+    StartMessageSelection,
+    CopyMessages,
+    ExportMessages,
+    StartForward,
+    PrevForwardTarget,
+    NextForwardTarget,
+    ToggleForwardDropAuthor,
+    ToggleForwardDropCaptions,
+    SubmitForward,
+    OpenPinnedMessage,
+    StartPin,
+    TogglePinNotify,
+    ConfirmPin,
+    OpenTopics,
+    OpenThread,
+    PrevTopic,
+    NextTopic,
+    SubmitTopic,
+    CancelTransfer,
+    StartAttach,
+    ToggleComposePreview,
+    OpenExternalEditor,
+    CycleMarkupMode,
+    CompletePath,
+    SubmitAttachment,
     FocusNext,
     FocusPrev,
     EnterCompose,
     ExitComposeOrSearch,
     SubmitMessage,
     StartSearch,
+    StartMessageSearch,
+    StartGlobalSearch,
+    SubmitSearch,
+    PrevSearchResult,
+    NextSearchResult,
+    ToggleSearchScope,
     ToggleSortMode,
+    /// Shows the folder at this tab position.
+    SelectFolder(usize),
+    PrevFolder,
+    NextFolder,
     Backspace,
     InsertChar(char),
+    Edit(EditAction),
     Quit,
     None,
 }
@@ -25,6 +80,44 @@ const QUIT_HOTKEYS: &[char] = &['q', 'й'];
 const COMPOSE_HOTKEYS: &[char] = &['i', 'ш'];
 const SORT_HOTKEYS: &[char] = &['s', 'ы'];
 const SEARCH_HOTKEYS: &[char] = &['/', '.'];
+const MESSAGE_SEARCH_HOTKEYS: &[char] = &['?', ','];
+const GLOBAL_SEARCH_HOTKEYS: &[char] = &['g', 'п'];
+const MESSAGE_UP_HOTKEYS: &[char] = &['k', 'л'];
+const MESSAGE_DOWN_HOTKEYS: &[char] = &['j', 'о'];
+const REPLY_HOTKEYS: &[char] = &['r', 'к'];
+const EDIT_HOTKEYS: &[char] = &['e', 'у'];
+const DELETE_HOTKEYS: &[char] = &['d', 'в'];
+const SAVE_MEDIA_HOTKEYS: &[char] = &['s', 'ы'];
+const CANCEL_TRANSFER_HOTKEYS: &[char] = &['x', 'ч'];
+const SPOILER_HOTKEYS: &[char] = &['v', 'м'];
+const REACTION_HOTKEYS: &[char] = &['a', 'ф'];
+const MARK_HOTKEYS: &[char] = &['m', 'ь'];
+const FORWARD_HOTKEYS: &[char] = &['f', 'а'];
+/// Matched case-sensitively, since `v` toggles spoilers.
+const MESSAGE_SELECTION_HOTKEYS: &[char] = &['V', 'М'];
+const COPY_HOTKEYS: &[char] = &['y', 'н'];
+const EXPORT_HOTKEYS: &[char] = &['w', 'ц'];
+const PIN_HOTKEYS: &[char] = &['p', 'з'];
+const OPEN_PIN_HOTKEYS: &[char] = &['o', 'щ'];
+const TOPICS_HOTKEYS: &[char] = &['t', 'е'];
+const THREAD_HOTKEYS: &[char] = &['c', 'с'];
+const PREV_FOLDER_HOTKEYS: &[char] = &['[', 'х'];
+const NEXT_FOLDER_HOTKEYS: &[char] = &[']', 'ъ'];
+const PIN_NOTIFY_HOTKEYS: &[char] = &['n', 'т'];
+/// Used with Ctrl in the forward picker.
+const DROP_AUTHOR_HOTKEYS: &[char] = &['a', 'ф'];
+const DROP_CAPTIONS_HOTKEYS: &[char] = &['d', 'в'];
+/// Used with Ctrl in compose mode.
+const ATTACH_HOTKEYS: &[char] = &['o', 'щ'];
+const COMPOSE_PREVIEW_HOTKEYS: &[char] = &['p', 'з'];
+const MARKUP_MODE_HOTKEYS: &[char] = &['t', 'е'];
+const EXTERNAL_EDITOR_HOTKEYS: &[char] = &['e', 'у'];
+const DELETE_WORD_HOTKEYS: &[char] = &['w', 'ц'];
+const DELETE_LINE_HOTKEYS: &[char] = &['u', 'г'];
+const UNDO_HOTKEYS: &[char] = &['z', 'я'];
+const REDO_HOTKEYS: &[char] = &['y', 'н'];
+const DELETE_FOR_ME_HOTKEYS: &[char] = &['m', 'ь'];
+const DELETE_FOR_EVERYONE_HOTKEYS: &[char] = &['e', 'у'];
 
 pub fn is_quit_hotkey(key: KeyEvent) -> bool {
     is_hotkey_char(key, QUIT_HOTKEYS)
@@ -42,6 +135,110 @@ fn is_search_hotkey(key: KeyEvent) -> bool {
     is_hotkey_char(key, SEARCH_HOTKEYS)
 }
 
+fn is_message_search_hotkey(key: KeyEvent) -> bool {
+    is_hotkey_char(key, MESSAGE_SEARCH_HOTKEYS)
+}
+
+fn is_global_search_hotkey(key: KeyEvent) -> bool {
+    is_hotkey_char(key, GLOBAL_SEARCH_HOTKEYS)
+}
+
+fn is_message_up_hotkey(key: KeyEvent) -> bool {
+    is_hotkey_char(key, MESSAGE_UP_HOTKEYS)
+}
+
+fn is_message_down_hotkey(key: KeyEvent) -> bool {
+    is_hotkey_char(key, MESSAGE_DOWN_HOTKEYS)
+}
+
+fn is_reply_hotkey(key: KeyEvent) -> bool {
+    is_hotkey_char(key, REPLY_HOTKEYS)
+}
+
+fn is_edit_hotkey(key: KeyEvent) -> bool {
+    is_hotkey_char(key, EDIT_HOTKEYS)
+}
+
+fn is_delete_hotkey(key: KeyEvent) -> bool {
+    is_hotkey_char(key, DELETE_HOTKEYS)
+}
+
+fn is_save_media_hotkey(key: KeyEvent) -> bool {
+    is_hotkey_char(key, SAVE_MEDIA_HOTKEYS)
+}
+
+fn is_spoiler_hotkey(key: KeyEvent) -> bool {
+    is_hotkey_char(key, SPOILER_HOTKEYS)
+}
+
+fn is_reaction_hotkey(key: KeyEvent) -> bool {
+    is_hotkey_char(key, REACTION_HOTKEYS)
+}
+
+fn is_mark_hotkey(key: KeyEvent) -> bool {
+    is_hotkey_char(key, MARK_HOTKEYS)
+}
+
+fn is_forward_hotkey(key: KeyEvent) -> bool {
+    is_hotkey_char(key, FORWARD_HOTKEYS)
+}
+
+fn is_message_selection_hotkey(key: KeyEvent) -> bool {
+    matches!(key.code, KeyCode::Char(ch) if MESSAGE_SELECTION_HOTKEYS.contains(&ch))
+}
+
+fn is_copy_hotkey(key: KeyEvent) -> bool {
+    is_hotkey_char(key, COPY_HOTKEYS)
+}
+
+fn is_export_hotkey(key: KeyEvent) -> bool {
+    is_hotkey_char(key, EXPORT_HOTKEYS)
+}
+
+fn is_pin_hotkey(key: KeyEvent) -> bool {
+    is_hotkey_char(key, PIN_HOTKEYS)
+}
+
+fn is_open_pin_hotkey(key: KeyEvent) -> bool {
+    is_hotkey_char(key, OPEN_PIN_HOTKEYS)
+}
+
+fn is_topics_hotkey(key: KeyEvent) -> bool {
+    is_hotkey_char(key, TOPICS_HOTKEYS)
+}
+
+fn is_thread_hotkey(key: KeyEvent) -> bool {
+    is_hotkey_char(key, THREAD_HOTKEYS)
+}
+
+fn is_prev_folder_hotkey(key: KeyEvent) -> bool {
+    is_hotkey_char(key, PREV_FOLDER_HOTKEYS)
+}
+
+fn is_next_folder_hotkey(key: KeyEvent) -> bool {
+    is_hotkey_char(key, NEXT_FOLDER_HOTKEYS)
+}
+
+fn is_cancel_transfer_hotkey(key: KeyEvent) -> bool {
+    is_hotkey_char(key, CANCEL_TRANSFER_HOTKEYS)
+}
+
+fn is_attach_hotkey(key: KeyEvent) -> bool {
+    key.modifiers.contains(KeyModifiers::CONTROL) && is_hotkey_char(key, ATTACH_HOTKEYS)
+}
+
+fn is_compose_preview_hotkey(key: KeyEvent) -> bool {
+    key.modifiers.contains(KeyModifiers::CONTROL) && is_hotkey_char(key, COMPOSE_PREVIEW_HOTKEYS)
+}
+
+fn is_external_editor_hotkey(key: KeyEvent) -> bool {
+    key.modifiers.contains(KeyModifiers::CONTROL) && is_hotkey_char(key, EXTERNAL_EDITOR_HOTKEYS)
+}
+
+fn is_markup_mode_hotkey(key: KeyEvent) -> bool {
+    key.modifiers.contains(KeyModifiers::CONTROL) && is_hotkey_char(key, MARKUP_MODE_HOTKEYS)
+}
+
 fn is_hotkey_char(key: KeyEvent, hotkeys: &[char]) -> bool {
     match key.code {
         KeyCode::Char(ch) => hotkeys.contains(&ch.to_ascii_lowercase()),
@@ -54,6 +251,56 @@ pub fn map_key_event(key: KeyEvent, ui_mode: UiMode, focus: FocusArea) -> AppCom
         return AppCommand::None;
     }
 
+    if ui_mode == UiMode::ConfirmDelete {
+        return map_confirm_delete_key(key);
+    }
+
+    if ui_mode == UiMode::ConfirmPin {
+        return map_confirm_pin_key(key);
+    }
+
+    if ui_mode == UiMode::TopicPicker {
+        return map_topic_picker_key(key);
+    }
+
+    if ui_mode == UiMode::MessageSearch {
+        return map_message_search_key(key);
+    }
+
+    if ui_mode == UiMode::AttachFile {
+        return map_attach_file_key(key);
+    }
+
+    if ui_mode == UiMode::PickReaction {
+        return map_reaction_picker_key(key);
+    }
+
+    if ui_mode == UiMode::ForwardPicker {
+        return map_forward_picker_key(key);
+    }
+
+    if ui_mode == UiMode::SelectMessages {
+        return map_message_selection_key(key);
+    }
+
+    if ui_mode == UiMode::Compose {
+        if is_attach_hotkey(key) {
+            return AppCommand::StartAttach;
+        }
+        if is_compose_preview_hotkey(key) {
+            return AppCommand::ToggleComposePreview;
+        }
+        if is_markup_mode_hotkey(key) {
+            return AppCommand::CycleMarkupMode;
+        }
+        if is_external_editor_hotkey(key) {
+            return AppCommand::OpenExternalEditor;
+        }
+        if let Some(action) = map_edit_key(key, focus) {
+            return AppCommand::Edit(action);
+        }
+    }
+
     if key.code == KeyCode::BackTab {
         return AppCommand::FocusPrev;
     }
@@ -82,11 +329,140 @@ pub fn map_key_event(key: KeyEvent, ui_mode: UiMode, focus: FocusArea) -> AppCom
         KeyCode::Char(_) if is_search_hotkey(key) && ui_mode != UiMode::Compose => {
             AppCommand::StartSearch
         }
+        KeyCode::Char(_) if is_message_search_hotkey(key) && ui_mode == UiMode::Normal => {
+            AppCommand::StartMessageSearch
+        }
+        KeyCode::Char(_) if is_global_search_hotkey(key) && ui_mode == UiMode::Normal => {
+            AppCommand::StartGlobalSearch
+        }
         KeyCode::Char(_)
             if is_sort_hotkey(key) && focus == FocusArea::Chats && ui_mode != UiMode::Compose =>
         {
             AppCommand::ToggleSortMode
         }
+        KeyCode::Char(ch @ '1'..='9') if focus == FocusArea::Chats && ui_mode == UiMode::Normal => {
+            AppCommand::SelectFolder(ch as usize - '1' as usize)
+        }
+        KeyCode::Char(_)
+            if is_prev_folder_hotkey(key)
+                && focus == FocusArea::Chats
+                && ui_mode == UiMode::Normal =>
+        {
+            AppCommand::PrevFolder
+        }
+        KeyCode::Char(_)
+            if is_next_folder_hotkey(key)
+                && focus == FocusArea::Chats
+                && ui_mode == UiMode::Normal =>
+        {
+            AppCommand::NextFolder
+        }
+        KeyCode::Char(_)
+            if is_message_up_hotkey(key)
+                && focus == FocusArea::Messages
+                && ui_mode == UiMode::Normal =>
+        {
+            AppCommand::SelectPrevMessage
+        }
+        KeyCode::Char(_)
+            if is_message_down_hotkey(key)
+                && focus == FocusArea::Messages
+                && ui_mode == UiMode::Normal =>
+        {
+            AppCommand::SelectNextMessage
+        }
+        KeyCode::Char(_)
+            if is_reply_hotkey(key)
+                && focus == FocusArea::Messages
+                && ui_mode == UiMode::Normal =>
+        {
+            AppCommand::ReplyToMessage
+        }
+        KeyCode::Char(_)
+            if is_edit_hotkey(key) && focus == FocusArea::Messages && ui_mode == UiMode::Normal =>
+        {
+            AppCommand::EditMessage
+        }
+        KeyCode::Char(_)
+            if is_delete_hotkey(key)
+                && focus == FocusArea::Messages
+                && ui_mode == UiMode::Normal =>
+        {
+            AppCommand::DeleteMessage
+        }
+        KeyCode::Char(_)
+            if is_save_media_hotkey(key)
+                && focus == FocusArea::Messages
+                && ui_mode == UiMode::Normal =>
+        {
+            AppCommand::DownloadMedia
+        }
+        KeyCode::Char(_)
+            if is_message_selection_hotkey(key)
+                && focus == FocusArea::Messages
+                && ui_mode == UiMode::Normal =>
+        {
+            AppCommand::StartMessageSelection
+        }
+        KeyCode::Char(_)
+            if is_spoiler_hotkey(key)
+                && focus == FocusArea::Messages
+                && ui_mode == UiMode::Normal =>
+        {
+            AppCommand::ToggleSpoilers
+        }
+        KeyCode::Char(_)
+            if is_reaction_hotkey(key)
+                && focus == FocusArea::Messages
+                && ui_mode == UiMode::Normal =>
+        {
+            AppCommand::StartReaction
+        }
+        KeyCode::Char(_)
+            if is_mark_hotkey(key) && focus == FocusArea::Messages && ui_mode == UiMode::Normal =>
+        {
+            AppCommand::ToggleMessageMark
+        }
+        KeyCode::Char(_)
+            if is_forward_hotkey(key)
+                && focus == FocusArea::Messages
+                && ui_mode == UiMode::Normal =>
+        {
+            AppCommand::StartForward
+        }
+        KeyCode::Char(_)
+            if is_copy_hotkey(key) && focus == FocusArea::Messages && ui_mode == UiMode::Normal =>
+        {
+            AppCommand::CopyMessages
+        }
+        KeyCode::Char(_)
+            if is_export_hotkey(key)
+                && focus == FocusArea::Messages
+                && ui_mode == UiMode::Normal =>
+        {
+            AppCommand::ExportMessages
+        }
+        KeyCode::Char(_)
+            if is_pin_hotkey(key) && focus == FocusArea::Messages && ui_mode == UiMode::Normal =>
+        {
+            AppCommand::StartPin
+        }
+        KeyCode::Char(_) if is_open_pin_hotkey(key) && ui_mode == UiMode::Normal => {
+            AppCommand::OpenPinnedMessage
+        }
+        KeyCode::Char(_) if is_topics_hotkey(key) && ui_mode == UiMode::Normal => {
+            AppCommand::OpenTopics
+        }
+        KeyCode::Char(_)
+            if is_thread_hotkey(key)
+                && focus == FocusArea::Messages
+                && ui_mode == UiMode::Normal =>
+        {
+            AppCommand::OpenThread
+        }
+        KeyCode::Char(_) if is_cancel_transfer_hotkey(key) && ui_mode == UiMode::Normal => {
+            AppCommand::CancelTransfer
+        }
         KeyCode::Char(_) if is_compose_hotkey(key) && ui_mode != UiMode::Search => {
             AppCommand::EnterCompose
         }
@@ -96,6 +472,147 @@ pub fn map_key_event(key: KeyEvent, ui_mode: UiMode, focus: FocusArea) -> AppCom
     }
 }
 
+fn map_edit_key(key: KeyEvent, focus: FocusArea) -> Option<EditAction> {
+    let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+    let action = match key.code {
+        KeyCode::Enter
+            if key
+                .modifiers
+                .intersects(KeyModifiers::ALT | KeyModifiers::SHIFT) =>
+        {
+            EditAction::InsertNewline
+        }
+        KeyCode::Left if ctrl => EditAction::WordLeft,
+        KeyCode::Right if ctrl => EditAction::WordRight,
+        KeyCode::Left => EditAction::MoveLeft,
+        KeyCode::Right => EditAction::MoveRight,
+        // With another pane focused, Up/Down keep scrolling it.
+        KeyCode::Up if focus == FocusArea::Input => EditAction::MoveUp,
+        KeyCode::Down if focus == FocusArea::Input => EditAction::MoveDown,
+        KeyCode::Home => EditAction::LineStart,
+        KeyCode::End => EditAction::LineEnd,
+        KeyCode::Delete => EditAction::Delete,
+        KeyCode::Char(_) if ctrl && is_hotkey_char(key, DELETE_WORD_HOTKEYS) => {
+            EditAction::DeleteWordBefore
+        }
+        KeyCode::Char(_) if ctrl && is_hotkey_char(key, DELETE_LINE_HOTKEYS) => {
+            EditAction::DeleteToLineStart
+        }
+        KeyCode::Char(_) if ctrl && is_hotkey_char(key, UNDO_HOTKEYS) => EditAction::Undo,
+        KeyCode::Char(_) if ctrl && is_hotkey_char(key, REDO_HOTKEYS) => EditAction::Redo,
+        _ => return None,
+    };
+    Some(action)
+}
+
+fn map_confirm_delete_key(key: KeyEvent) -> AppCommand {
+    match key.code {
+        KeyCode::Esc => AppCommand::ExitComposeOrSearch,
+        KeyCode::Char(_) if is_hotkey_char(key, DELETE_FOR_ME_HOTKEYS) => {
+            AppCommand::ConfirmDeleteForMe
+        }
+        KeyCode::Char(_) if is_hotkey_char(key, DELETE_FOR_EVERYONE_HOTKEYS) => {
+            AppCommand::ConfirmDeleteForEveryone
+        }
+        _ => AppCommand::None,
+    }
+}
+
+fn map_confirm_pin_key(key: KeyEvent) -> AppCommand {
+    match key.code {
+        KeyCode::Esc => AppCommand::ExitComposeOrSearch,
+        KeyCode::Enter => AppCommand::ConfirmPin,
+        KeyCode::Char(_) if is_hotkey_char(key, PIN_NOTIFY_HOTKEYS) => AppCommand::TogglePinNotify,
+        _ => AppCommand::None,
+    }
+}
+
+fn map_topic_picker_key(key: KeyEvent) -> AppCommand {
+    match key.code {
+        KeyCode::Esc => AppCommand::ExitComposeOrSearch,
+        KeyCode::Enter => AppCommand::SubmitTopic,
+        KeyCode::Up => AppCommand::PrevTopic,
+        KeyCode::Down => AppCommand::NextTopic,
+        _ => AppCommand::None,
+    }
+}
+
+fn map_message_search_key(key: KeyEvent) -> AppCommand {
+    match key.code {
+        KeyCode::Esc => AppCommand::ExitComposeOrSearch,
+        KeyCode::Enter => AppCommand::SubmitSearch,
+        KeyCode::Up => AppCommand::PrevSearchResult,
+        KeyCode::Down => AppCommand::NextSearchResult,
+        KeyCode::Tab | KeyCode::BackTab => AppCommand::ToggleSearchScope,
+        KeyCode::Backspace => AppCommand::Backspace,
+        KeyCode::Char(ch) => AppCommand::InsertChar(ch),
+        _ => AppCommand::None,
+    }
+}
+
+fn map_attach_file_key(key: KeyEvent) -> AppCommand {
+    match key.code {
+        KeyCode::Esc => AppCommand::ExitComposeOrSearch,
+        KeyCode::Enter => AppCommand::SubmitAttachment,
+        KeyCode::Tab => AppCommand::CompletePath,
+        KeyCode::Backspace => AppCommand::Backspace,
+        KeyCode::Char(ch) => AppCommand::InsertChar(ch),
+        _ => AppCommand::None,
+    }
+}
+
+/// Digits pick a reaction directly, counting from 1.
+fn map_reaction_picker_key(key: KeyEvent) -> AppCommand {
+    match key.code {
+        KeyCode::Esc => AppCommand::ExitComposeOrSearch,
+        KeyCode::Enter => AppCommand::SubmitReaction,
+        KeyCode::Left | KeyCode::Up => AppCommand::PrevReaction,
+        KeyCode::Right | KeyCode::Down => AppCommand::NextReaction,
+        KeyCode::Char(ch) => match ch.to_digit(10) {
+            Some(digit @ 1..) => AppCommand::PickReaction(digit as usize - 1),
+            _ => AppCommand::None,
+        },
+        _ => AppCommand::None,
+    }
+}
+
+/// Movement extends the range; the bulk actions apply to the whole selection.
+fn map_message_selection_key(key: KeyEvent) -> AppCommand {
+    match key.code {
+        KeyCode::Esc => AppCommand::ExitComposeOrSearch,
+        KeyCode::Up => AppCommand::SelectPrevMessage,
+        KeyCode::Down => AppCommand::SelectNextMessage,
+        KeyCode::Char(' ') => AppCommand::ToggleMessageMark,
+        KeyCode::Char(_) if is_message_up_hotkey(key) => AppCommand::SelectPrevMessage,
+        KeyCode::Char(_) if is_message_down_hotkey(key) => AppCommand::SelectNextMessage,
+        KeyCode::Char(_) if is_mark_hotkey(key) => AppCommand::ToggleMessageMark,
+        KeyCode::Char(_) if is_delete_hotkey(key) => AppCommand::DeleteMessage,
+        KeyCode::Char(_) if is_forward_hotkey(key) => AppCommand::StartForward,
+        KeyCode::Char(_) if is_copy_hotkey(key) => AppCommand::CopyMessages,
+        KeyCode::Char(_) if is_export_hotkey(key) => AppCommand::ExportMessages,
+        _ => AppCommand::None,
+    }
+}
+
+fn map_forward_picker_key(key: KeyEvent) -> AppCommand {
+    let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+    match key.code {
+        KeyCode::Esc => AppCommand::ExitComposeOrSearch,
+        KeyCode::Enter => AppCommand::SubmitForward,
+        KeyCode::Up => AppCommand::PrevForwardTarget,
+        KeyCode::Down => AppCommand::NextForwardTarget,
+        KeyCode::Backspace => AppCommand::Backspace,
+        KeyCode::Char(_) if ctrl && is_hotkey_char(key, DROP_AUTHOR_HOTKEYS) => {
+            AppCommand::ToggleForwardDropAuthor
+        }
+        KeyCode::Char(_) if ctrl && is_hotkey_char(key, DROP_CAPTIONS_HOTKEYS) => {
+            AppCommand::ToggleForwardDropCaptions
+        }
+        KeyCode::Char(ch) => AppCommand::InsertChar(ch),
+        _ => AppCommand::None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -183,4 +700,476 @@ mod tests {
             AppCommand::InsertChar('й')
         );
     }
+
+    #[test]
+    fn message_cursor_hotkeys_apply_only_in_messages_pane() {
+        let up = KeyEvent::new(KeyCode::Char('k'), KeyModifiers::NONE);
+        let down_ru = KeyEvent::new(KeyCode::Char('о'), KeyModifiers::NONE);
+        let reply = KeyEvent::new(KeyCode::Char('r'), KeyModifiers::NONE);
+
+        assert_eq!(
+            map_key_event(up, UiMode::Normal, FocusArea::Messages),
+            AppCommand::SelectPrevMessage
+        );
+        assert_eq!(
+            map_key_event(down_ru, UiMode::Normal, FocusArea::Messages),
+            AppCommand::SelectNextMessage
+        );
+        assert_eq!(
+            map_key_event(reply, UiMode::Normal, FocusArea::Messages),
+            AppCommand::ReplyToMessage
+        );
+        assert_eq!(
+            map_key_event(reply, UiMode::Compose, FocusArea::Input),
+            AppCommand::InsertChar('r')
+        );
+        assert_eq!(
+            map_key_event(up, UiMode::Normal, FocusArea::Chats),
+            AppCommand::InsertChar('k')
+        );
+    }
+
+    #[test]
+    fn delete_confirmation_only_accepts_its_own_keys() {
+        let delete = KeyEvent::new(KeyCode::Char('d'), KeyModifiers::NONE);
+        let for_me = KeyEvent::new(KeyCode::Char('m'), KeyModifiers::NONE);
+        let for_everyone_ru = KeyEvent::new(KeyCode::Char('у'), KeyModifiers::NONE);
+        let quit = KeyEvent::new(KeyCode::Char('q'), KeyModifiers::NONE);
+        let esc = KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE);
+
+        assert_eq!(
+            map_key_event(delete, UiMode::Normal, FocusArea::Messages),
+            AppCommand::DeleteMessage
+        );
+        assert_eq!(
+            map_key_event(for_me, UiMode::ConfirmDelete, FocusArea::Messages),
+            AppCommand::ConfirmDeleteForMe
+        );
+        assert_eq!(
+            map_key_event(for_everyone_ru, UiMode::ConfirmDelete, FocusArea::Messages),
+            AppCommand::ConfirmDeleteForEveryone
+        );
+        assert_eq!(
+            map_key_event(quit, UiMode::ConfirmDelete, FocusArea::Messages),
+            AppCommand::None
+        );
+        assert_eq!(
+            map_key_event(esc, UiMode::ConfirmDelete, FocusArea::Messages),
+            AppCommand::ExitComposeOrSearch
+        );
+    }
+
+    #[test]
+    fn reaction_picker_captures_its_keys() {
+        let react_ru = KeyEvent::new(KeyCode::Char('ф'), KeyModifiers::NONE);
+        let right = KeyEvent::new(KeyCode::Right, KeyModifiers::NONE);
+        let third = KeyEvent::new(KeyCode::Char('3'), KeyModifiers::NONE);
+        let zero = KeyEvent::new(KeyCode::Char('0'), KeyModifiers::NONE);
+        let quit = KeyEvent::new(KeyCode::Char('q'), KeyModifiers::NONE);
+
+        assert_eq!(
+            map_key_event(react_ru, UiMode::Normal, FocusArea::Messages),
+            AppCommand::StartReaction
+        );
+        assert_eq!(
+            map_key_event(right, UiMode::PickReaction, FocusArea::Messages),
+            AppCommand::NextReaction
+        );
+        assert_eq!(
+            map_key_event(third, UiMode::PickReaction, FocusArea::Messages),
+            AppCommand::PickReaction(2)
+        );
+        assert_eq!(
+            map_key_event(zero, UiMode::PickReaction, FocusArea::Messages),
+            AppCommand::None
+        );
+        assert_eq!(
+            map_key_event(quit, UiMode::PickReaction, FocusArea::Messages),
+            AppCommand::None
+        );
+    }
+
+    #[test]
+    fn forward_picker_types_a_query_and_toggles_options() {
+        let forward_ru = KeyEvent::new(KeyCode::Char('а'), KeyModifiers::NONE);
+        let letter = KeyEvent::new(KeyCode::Char('q'), KeyModifiers::NONE);
+        let drop_author = KeyEvent::new(KeyCode::Char('a'), KeyModifiers::CONTROL);
+        let drop_captions_ru = KeyEvent::new(KeyCode::Char('в'), KeyModifiers::CONTROL);
+
+        assert_eq!(
+            map_key_event(forward_ru, UiMode::Normal, FocusArea::Messages),
+            AppCommand::StartForward
+        );
+        assert_eq!(
+            map_key_event(letter, UiMode::ForwardPicker, FocusArea::Messages),
+            AppCommand::InsertChar('q')
+        );
+        assert_eq!(
+            map_key_event(drop_author, UiMode::ForwardPicker, FocusArea::Messages),
+            AppCommand::ToggleForwardDropAuthor
+        );
+        assert_eq!(
+            map_key_event(drop_captions_ru, UiMode::ForwardPicker, FocusArea::Messages),
+            AppCommand::ToggleForwardDropCaptions
+        );
+    }
+
+    #[test]
+    fn message_selection_is_entered_with_capital_v_only() {
+        let visual = KeyEvent::new(KeyCode::Char('V'), KeyModifiers::SHIFT);
+        let spoiler = KeyEvent::new(KeyCode::Char('v'), KeyModifiers::NONE);
+        let down_ru = KeyEvent::new(KeyCode::Char('о'), KeyModifiers::NONE);
+        let copy = KeyEvent::new(KeyCode::Char('y'), KeyModifiers::NONE);
+        let quit = KeyEvent::new(KeyCode::Char('q'), KeyModifiers::NONE);
+
+        assert_eq!(
+            map_key_event(visual, UiMode::Normal, FocusArea::Messages),
+            AppCommand::StartMessageSelection
+        );
+        assert_eq!(
+            map_key_event(spoiler, UiMode::Normal, FocusArea::Messages),
+            AppCommand::ToggleSpoilers
+        );
+        assert_eq!(
+            map_key_event(down_ru, UiMode::SelectMessages, FocusArea::Messages),
+            AppCommand::SelectNextMessage
+        );
+        assert_eq!(
+            map_key_event(copy, UiMode::SelectMessages, FocusArea::Messages),
+            AppCommand::CopyMessages
+        );
+        assert_eq!(
+            map_key_event(quit, UiMode::SelectMessages, FocusArea::Messages),
+            AppCommand::None
+        );
+    }
+
+    #[test]
+    fn message_search_keys_are_captured_by_search_mode() {
+        let find = KeyEvent::new(KeyCode::Char('?'), KeyModifiers::SHIFT);
+        let global_ru = KeyEvent::new(KeyCode::Char('п'), KeyModifiers::NONE);
+        let quit = KeyEvent::new(KeyCode::Char('q'), KeyModifiers::NONE);
+        let enter = KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE);
+        let tab = KeyEvent::new(KeyCode::Tab, KeyModifiers::NONE);
+        let down = KeyEvent::new(KeyCode::Down, KeyModifiers::NONE);
+
+        assert_eq!(
+            map_key_event(find, UiMode::Normal, FocusArea::Messages),
+            AppCommand::StartMessageSearch
+        );
+        assert_eq!(
+            map_key_event(global_ru, UiMode::Normal, FocusArea::Chats),
+            AppCommand::StartGlobalSearch
+        );
+        assert_eq!(
+            map_key_event(global_ru, UiMode::Compose, FocusArea::Input),
+            AppCommand::InsertChar('п')
+        );
+        assert_eq!(
+            map_key_event(quit, UiMode::MessageSearch, FocusArea::Chats),
+            AppCommand::InsertChar('q')
+        );
+        assert_eq!(
+            map_key_event(enter, UiMode::MessageSearch, FocusArea::Chats),
+            AppCommand::SubmitSearch
+        );
+        assert_eq!(
+            map_key_event(tab, UiMode::MessageSearch, FocusArea::Chats),
+            AppCommand::ToggleSearchScope
+        );
+        assert_eq!(
+            map_key_event(down, UiMode::MessageSearch, FocusArea::Chats),
+            AppCommand::NextSearchResult
+        );
+    }
+
+    #[test]
+    fn save_media_shares_key_with_sort_by_pane() {
+        let save = KeyEvent::new(KeyCode::Char('s'), KeyModifiers::NONE);
+        let cancel_ru = KeyEvent::new(KeyCode::Char('ч'), KeyModifiers::NONE);
+
+        assert_eq!(
+            map_key_event(save, UiMode::Normal, FocusArea::Chats),
+            AppCommand::ToggleSortMode
+        );
+        assert_eq!(
+            map_key_event(save, UiMode::Normal, FocusArea::Messages),
+            AppCommand::DownloadMedia
+        );
+        assert_eq!(
+            map_key_event(cancel_ru, UiMode::Normal, FocusArea::Chats),
+            AppCommand::CancelTransfer
+        );
+        assert_eq!(
+            map_key_event(cancel_ru, UiMode::Compose, FocusArea::Input),
+            AppCommand::InsertChar('ч')
+        );
+    }
+
+    #[test]
+    fn attach_picker_is_opened_from_compose_with_ctrl() {
+        let attach = KeyEvent::new(KeyCode::Char('o'), KeyModifiers::CONTROL);
+        let attach_ru = KeyEvent::new(KeyCode::Char('щ'), KeyModifiers::CONTROL);
+        let plain = KeyEvent::new(KeyCode::Char('o'), KeyModifiers::NONE);
+        let tab = KeyEvent::new(KeyCode::Tab, KeyModifiers::NONE);
+        let enter = KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE);
+
+        assert_eq!(
+            map_key_event(attach, UiMode::Compose, FocusArea::Input),
+            AppCommand::StartAttach
+        );
+        assert_eq!(
+            map_key_event(attach_ru, UiMode::Compose, FocusArea::Input),
+            AppCommand::StartAttach
+        );
+        assert_eq!(
+            map_key_event(plain, UiMode::Compose, FocusArea::Input),
+            AppCommand::InsertChar('o')
+        );
+        assert_eq!(
+            map_key_event(tab, UiMode::AttachFile, FocusArea::Input),
+            AppCommand::CompletePath
+        );
+        assert_eq!(
+            map_key_event(enter, UiMode::AttachFile, FocusArea::Input),
+            AppCommand::SubmitAttachment
+        );
+    }
+
+    #[test]
+    fn compose_preview_markup_mode_and_editor_use_ctrl() {
+        let preview = KeyEvent::new(KeyCode::Char('p'), KeyModifiers::CONTROL);
+        let preview_ru = KeyEvent::new(KeyCode::Char('з'), KeyModifiers::CONTROL);
+        let mode = KeyEvent::new(KeyCode::Char('t'), KeyModifiers::CONTROL);
+        let mode_ru = KeyEvent::new(KeyCode::Char('е'), KeyModifiers::CONTROL);
+        let editor = KeyEvent::new(KeyCode::Char('e'), KeyModifiers::CONTROL);
+        let editor_ru = KeyEvent::new(KeyCode::Char('у'), KeyModifiers::CONTROL);
+        let plain = KeyEvent::new(KeyCode::Char('p'), KeyModifiers::NONE);
+
+        assert_eq!(
+            map_key_event(preview, UiMode::Compose, FocusArea::Input),
+            AppCommand::ToggleComposePreview
+        );
+        assert_eq!(
+            map_key_event(preview_ru, UiMode::Compose, FocusArea::Input),
+            AppCommand::ToggleComposePreview
+        );
+        assert_eq!(
+            map_key_event(mode, UiMode::Compose, FocusArea::Input),
+            AppCommand::CycleMarkupMode
+        );
+        assert_eq!(
+            map_key_event(mode_ru, UiMode::Compose, FocusArea::Input),
+            AppCommand::CycleMarkupMode
+        );
+        assert_eq!(
+            map_key_event(editor, UiMode::Compose, FocusArea::Input),
+            AppCommand::OpenExternalEditor
+        );
+        assert_eq!(
+            map_key_event(editor_ru, UiMode::Compose, FocusArea::Input),
+            AppCommand::OpenExternalEditor
+        );
+        assert_eq!(
+            map_key_event(plain, UiMode::Compose, FocusArea::Input),
+            AppCommand::InsertChar('p')
+        );
+    }
+
+    #[test]
+    fn compose_mode_maps_editing_keys() {
+        let key = |code, modifiers| {
+            map_key_event(
+                KeyEvent::new(code, modifiers),
+                UiMode::Compose,
+                FocusArea::Input,
+            )
+        };
+
+        assert_eq!(
+            key(KeyCode::Enter, KeyModifiers::NONE),
+            AppCommand::SubmitMessage
+        );
+        assert_eq!(
+            key(KeyCode::Enter, KeyModifiers::ALT),
+            AppCommand::Edit(EditAction::InsertNewline)
+        );
+        assert_eq!(
+            key(KeyCode::Enter, KeyModifiers::SHIFT),
+            AppCommand::Edit(EditAction::InsertNewline)
+        );
+        assert_eq!(
+            key(KeyCode::Left, KeyModifiers::CONTROL),
+            AppCommand::Edit(EditAction::WordLeft)
+        );
+        assert_eq!(
+            key(KeyCode::Home, KeyModifiers::NONE),
+            AppCommand::Edit(EditAction::LineStart)
+        );
+        assert_eq!(
+            key(KeyCode::Up, KeyModifiers::NONE),
+            AppCommand::Edit(EditAction::MoveUp)
+        );
+        assert_eq!(
+            key(KeyCode::Char('w'), KeyModifiers::CONTROL),
+            AppCommand::Edit(EditAction::DeleteWordBefore)
+        );
+        assert_eq!(
+            key(KeyCode::Char('г'), KeyModifiers::CONTROL),
+            AppCommand::Edit(EditAction::DeleteToLineStart)
+        );
+        assert_eq!(
+            key(KeyCode::Char('я'), KeyModifiers::CONTROL),
+            AppCommand::Edit(EditAction::Undo)
+        );
+        assert_eq!(
+            key(KeyCode::Char('y'), KeyModifiers::CONTROL),
+            AppCommand::Edit(EditAction::Redo)
+        );
+        assert_eq!(
+            map_key_event(
+                KeyEvent::new(KeyCode::Up, KeyModifiers::NONE),
+                UiMode::Compose,
+                FocusArea::Messages
+            ),
+            AppCommand::ScrollMessagesUp
+        );
+        assert_eq!(
+            map_key_event(
+                KeyEvent::new(KeyCode::Left, KeyModifiers::NONE),
+                UiMode::Normal,
+                FocusArea::Input
+            ),
+            AppCommand::None
+        );
+    }
+
+    #[test]
+    fn pin_hotkeys_support_russian_aliases() {
+        let key = |ch, ui_mode, focus| {
+            map_key_event(
+                KeyEvent::new(KeyCode::Char(ch), KeyModifiers::NONE),
+                ui_mode,
+                focus,
+            )
+        };
+
+        assert_eq!(
+            key('p', UiMode::Normal, FocusArea::Messages),
+            AppCommand::StartPin
+        );
+        assert_eq!(
+            key('з', UiMode::Normal, FocusArea::Messages),
+            AppCommand::StartPin
+        );
+        assert_eq!(
+            key('p', UiMode::Normal, FocusArea::Chats),
+            AppCommand::InsertChar('p')
+        );
+        assert_eq!(
+            key('щ', UiMode::Normal, FocusArea::Chats),
+            AppCommand::OpenPinnedMessage
+        );
+        assert_eq!(
+            key('т', UiMode::ConfirmPin, FocusArea::Messages),
+            AppCommand::TogglePinNotify
+        );
+        assert_eq!(
+            map_key_event(
+                KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE),
+                UiMode::ConfirmPin,
+                FocusArea::Messages
+            ),
+            AppCommand::ConfirmPin
+        );
+    }
+
+    #[test]
+    fn topic_and_comment_keys_open_their_views() {
+        let key = |code: KeyCode, ui_mode: UiMode| {
+            map_key_event(
+                KeyEvent::new(code, KeyModifiers::NONE),
+                ui_mode,
+                FocusArea::Chats,
+            )
+        };
+
+        assert_eq!(
+            key(KeyCode::Char('t'), UiMode::Normal),
+            AppCommand::OpenTopics
+        );
+        assert_eq!(
+            key(KeyCode::Char('е'), UiMode::Normal),
+            AppCommand::OpenTopics
+        );
+        assert_eq!(
+            key(KeyCode::Char('t'), UiMode::Search),
+            AppCommand::InsertChar('t')
+        );
+        assert_eq!(
+            key(KeyCode::Char('c'), UiMode::Normal),
+            AppCommand::InsertChar('c')
+        );
+        assert_eq!(
+            map_key_event(
+                KeyEvent::new(KeyCode::Char('с'), KeyModifiers::NONE),
+                UiMode::Normal,
+                FocusArea::Messages
+            ),
+            AppCommand::OpenThread
+        );
+        assert_eq!(
+            key(KeyCode::Down, UiMode::TopicPicker),
+            AppCommand::NextTopic
+        );
+        assert_eq!(key(KeyCode::Up, UiMode::TopicPicker), AppCommand::PrevTopic);
+        assert_eq!(
+            key(KeyCode::Enter, UiMode::TopicPicker),
+            AppCommand::SubmitTopic
+        );
+        assert_eq!(
+            key(KeyCode::Char('q'), UiMode::TopicPicker),
+            AppCommand::None
+        );
+    }
+
+    #[test]
+    fn folder_keys_switch_tabs_in_the_chat_list() {
+        let key = |ch: char, ui_mode: UiMode, focus: FocusArea| {
+            map_key_event(
+                KeyEvent::new(KeyCode::Char(ch), KeyModifiers::NONE),
+                ui_mode,
+                focus,
+            )
+        };
+
+        assert_eq!(
+            key('1', UiMode::Normal, FocusArea::Chats),
+            AppCommand::SelectFolder(0)
+        );
+        assert_eq!(
+            key('9', UiMode::Normal, FocusArea::Chats),
+            AppCommand::SelectFolder(8)
+        );
+        assert_eq!(
+            key('0', UiMode::Normal, FocusArea::Chats),
+            AppCommand::InsertChar('0')
+        );
+        assert_eq!(
+            key('2', UiMode::Search, FocusArea::Chats),
+            AppCommand::InsertChar('2')
+        );
+        assert_eq!(
+            key(']', UiMode::Normal, FocusArea::Chats),
+            AppCommand::NextFolder
+        );
+        assert_eq!(
+            key('х', UiMode::Normal, FocusArea::Chats),
+            AppCommand::PrevFolder
+        );
+        assert_eq!(
+            key('[', UiMode::Normal, FocusArea::Messages),
+            AppCommand::InsertChar('[')
+        );
+    }
 }
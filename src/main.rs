@@ -1,19 +1,40 @@
 mod app;
+mod cache;
+mod editor;
 mod input;
+mod markup;
 mod telegram;
 mod tui;
 
-use std::time::Duration;
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::Context;
-use app::AppState;
+use app::{AppState, SearchSubmission, Transfer, message_count};
+use cache::{CACHE_FILE, MessageCache, spawn_offline_task};
 use crossterm::event::{Event as CrosstermEvent, EventStream, KeyCode, KeyEvent, KeyModifiers};
 use futures::StreamExt;
 use input::{AppCommand, is_quit_hotkey, map_key_event};
-use telegram::{AuthFlow, AuthStatus, TelegramEvent, TelegramRequest, spawn_telegram_task};
-use tokio::{sync::mpsc, time::interval};
-use tracing::error;
-use tui::{AuthView, TerminalGuard, draw, draw_auth};
+use telegram::{
+    AuthFlow, AuthStatus, DELETE_FOR_ME_UNSUPPORTED, MessageSummary, TelegramEvent,
+    TelegramRequest, spawn_telegram_task,
+};
+use tokio::{
+    sync::mpsc,
+    time::{interval, timeout},
+};
+use tracing::{error, warn};
+use tui::{AuthView, GraphicsProtocol, ImageLayer, TerminalGuard, draw, draw_auth};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+enum Startup {
+    Online(Box<AuthFlow>, AuthStatus),
+    Offline,
+}
 
 #[derive(Debug, Clone)]
 enum AuthScreen {
@@ -37,47 +58,90 @@ async fn main() -> anyhow::Result<()> {
         .try_init()
         .ok();
 
-    let mut auth_flow = AuthFlow::connect_from_env()
-        .await
-        .context("failed to initialize Telegram client")?;
+    let cache = open_cache();
+    let cached_dialogs = cache
+        .as_ref()
+        .and_then(|cache| cache.load_dialogs().ok())
+        .unwrap_or_default();
 
-    let mut terminal_guard = TerminalGuard::new().context("failed to initialize terminal")?;
+    let startup = start_session(!cached_dialogs.is_empty()).await?;
 
-    if let AuthStatus::Authorized = auth_flow.current_status().await? {
-        // Existing session is valid; skip login form.
-    } else {
-        let authorized = run_auth_loop(terminal_guard.terminal_mut(), &mut auth_flow).await?;
-        if !authorized {
-            return Ok(());
-        }
-    }
+    let mut terminal_guard = TerminalGuard::new().context("failed to initialize terminal")?;
 
-    let (client, updates_rx) = auth_flow.into_client()?;
     let (req_tx, req_rx) = mpsc::channel(32);
     let (event_tx, mut event_rx) = mpsc::channel(64);
 
-    let telegram_handle = spawn_telegram_task(client, updates_rx, req_rx, event_tx);
+    let (telegram_handle, offline) = match startup {
+        Startup::Online(mut auth_flow, status) => {
+            if !matches!(status, AuthStatus::Authorized) {
+                let authorized =
+                    run_auth_loop(terminal_guard.terminal_mut(), &mut auth_flow).await?;
+                if !authorized {
+                    return Ok(());
+                }
+            }
+
+            let (client, updates_rx) = auth_flow.into_client()?;
+            (
+                spawn_telegram_task(client, updates_rx, req_rx, event_tx),
+                false,
+            )
+        }
+        Startup::Offline => {
+            let offline_cache = MessageCache::open(CACHE_FILE)?;
+            (spawn_offline_task(offline_cache, req_rx, event_tx), true)
+        }
+    };
 
     req_tx
         .send(TelegramRequest::LoadDialogs)
         .await
         .context("failed to request initial dialog load")?;
+    req_tx
+        .send(TelegramRequest::LoadFolders)
+        .await
+        .context("failed to request chat folders")?;
 
+    let download_dir = telegram::download_dir();
     let mut app = AppState::new();
+    app.offline = offline;
+    app.on_cached_dialogs(cached_dialogs);
+    if let Some(folders) = cache.as_ref().and_then(|cache| cache.load_folders().ok()) {
+        app.on_folders_loaded(folders);
+    }
+    if app.selected_dialog_id().is_some() {
+        request_messages_for_selected(&req_tx, &mut app, cache.as_ref()).await;
+    }
+
+    let mut images = ImageLayer::new(GraphicsProtocol::detect());
     let mut events = EventStream::new();
     let mut tick = interval(Duration::from_millis(120));
 
     while !app.should_quit {
+        if let Some((dialog_id, text)) = app.sync_compose_draft() {
+            request_save_draft(&req_tx, &mut app, dialog_id, text).await;
+        }
+        let mut viewport = app.message_viewport;
         terminal_guard
             .terminal_mut()
-            .draw(|f| draw(f, &app))
+            .draw(|f| viewport = draw(f, &app, &mut images))
             .context("failed to draw frame")?;
+        images
+            .flush(terminal_guard.terminal_mut().backend_mut())
+            .context("failed to draw images")?;
+        app.apply_message_viewport(viewport);
+        request_read_ack_if_needed(&req_tx, &mut app).await;
+        if images.enabled() {
+            request_image_previews(&req_tx, &mut app).await;
+        }
 
+        let mut open_external_editor = false;
         tokio::select! {
             _ = tick.tick() => {}
             maybe_evt = events.next() => {
                 if let Some(Ok(CrosstermEvent::Key(key))) = maybe_evt {
                     let selected_before = app.selected_dialog_id();
+                    app.status_message = None;
                     match map_key_event(key, app.ui_mode, app.focus) {
                         AppCommand::MoveUp => {
                             app.select_prev();
@@ -87,10 +151,40 @@ async fn main() -> anyhow::Result<()> {
                         }
                         AppCommand::ScrollMessagesUp => {
                             app.scroll_messages_up();
+                            request_older_messages_if_needed(&req_tx, &mut app).await;
                         }
                         AppCommand::ScrollMessagesDown => {
+                            if app.wants_latest_messages() {
+                                request_messages_for_selected(&req_tx, &mut app, cache.as_ref())
+                                    .await;
+                            }
                             app.scroll_messages_down();
                         }
+                        AppCommand::SelectPrevMessage => {
+                            app.select_prev_message();
+                        }
+                        AppCommand::SelectNextMessage => {
+                            app.select_next_message();
+                        }
+                        AppCommand::ReplyToMessage => {
+                            app.start_reply();
+                        }
+                        AppCommand::EditMessage => {
+                            app.start_edit();
+                        }
+                        AppCommand::DeleteMessage => {
+                            app.start_delete();
+                        }
+                        AppCommand::ConfirmDeleteForMe => {
+                            if app.can_delete_for_me() {
+                                request_delete_messages(&req_tx, &mut app, false).await;
+                            } else {
+                                app.last_error = Some(DELETE_FOR_ME_UNSUPPORTED.to_string());
+                            }
+                        }
+                        AppCommand::ConfirmDeleteForEveryone => {
+                            request_delete_messages(&req_tx, &mut app, true).await;
+                        }
                         AppCommand::FocusNext => {
                             app.focus_next();
                         }
@@ -103,7 +197,32 @@ async fn main() -> anyhow::Result<()> {
                         AppCommand::ExitComposeOrSearch => match app.ui_mode {
                             app::UiMode::Compose => app.exit_compose(),
                             app::UiMode::Search => app.exit_or_clear_search(),
-                            app::UiMode::Normal => {}
+                            app::UiMode::ConfirmDelete => app.cancel_delete(),
+                            app::UiMode::ConfirmPin => app.cancel_pin(),
+                            app::UiMode::MessageSearch => app.close_message_search(),
+                            app::UiMode::AttachFile => app.close_attach(),
+                            app::UiMode::PickReaction => app.close_reaction_picker(),
+                            app::UiMode::ForwardPicker => app.close_forward_picker(),
+                            app::UiMode::TopicPicker => app.close_topic_picker(),
+                            app::UiMode::SelectMessages => app.cancel_message_selection(),
+                            app::UiMode::Normal => {
+                                if app.editing_message_id.is_some() {
+                                    app.cancel_edit();
+                                } else if app.reply_to_message_id.is_some() {
+                                    app.cancel_reply();
+                                } else if app.selected_message_id.is_none() && app.close_thread() {
+                                    if app.selected_dialog_messages().is_empty() {
+                                        request_messages_for_selected(
+                                            &req_tx,
+                                            &mut app,
+                                            cache.as_ref(),
+                                        )
+                                        .await;
+                                    }
+                                } else {
+                                    app.clear_message_selection();
+                                }
+                            }
                         },
                         AppCommand::SubmitMessage => {
                             request_send_message(&req_tx, &mut app).await;
@@ -111,15 +230,184 @@ async fn main() -> anyhow::Result<()> {
                         AppCommand::StartSearch => {
                             app.start_search();
                         }
+                        AppCommand::StartMessageSearch => {
+                            app.start_message_search(false);
+                        }
+                        AppCommand::StartGlobalSearch => {
+                            app.start_message_search(true);
+                        }
+                        AppCommand::SubmitSearch => {
+                            // Opening a result loads history around the message instead of the
+                            // latest page, so the selection check below must not run; the
+                            // search asks for the chat's pins and topics itself.
+                            request_message_search(&req_tx, &mut app).await;
+                            continue;
+                        }
+                        AppCommand::PrevSearchResult => {
+                            app.select_prev_search_result();
+                        }
+                        AppCommand::NextSearchResult => {
+                            app.select_next_search_result();
+                        }
+                        AppCommand::ToggleSearchScope => {
+                            app.toggle_message_search_scope();
+                        }
                         AppCommand::ToggleSortMode => {
                             app.toggle_sort_mode();
                         }
+                        AppCommand::SelectFolder(index) => {
+                            app.select_folder(index);
+                        }
+                        AppCommand::PrevFolder => {
+                            app.select_prev_folder();
+                        }
+                        AppCommand::NextFolder => {
+                            app.select_next_folder();
+                        }
+                        AppCommand::DownloadMedia => {
+                            request_download(&req_tx, &mut app, &download_dir).await;
+                        }
+                        AppCommand::ToggleSpoilers => {
+                            app.toggle_spoilers();
+                        }
+                        AppCommand::StartReaction => {
+                            app.start_reaction_picker();
+                        }
+                        AppCommand::PrevReaction => {
+                            app.select_prev_reaction();
+                        }
+                        AppCommand::NextReaction => {
+                            app.select_next_reaction();
+                        }
+                        AppCommand::PickReaction(index) => {
+                            if app.pick_reaction(index) {
+                                request_send_reaction(&req_tx, &mut app).await;
+                            }
+                        }
+                        AppCommand::SubmitReaction => {
+                            request_send_reaction(&req_tx, &mut app).await;
+                        }
+                        AppCommand::ToggleMessageMark => {
+                            app.toggle_message_mark();
+                        }
+                        AppCommand::StartMessageSelection => {
+                            app.start_message_selection();
+                        }
+                        AppCommand::CopyMessages => {
+                            let messages = app.take_target_messages();
+                            if !messages.is_empty() {
+                                let text = tui::transcript(&messages);
+                                match tui::copy_to_clipboard(&mut io::stdout(), &text) {
+                                    Ok(()) => {
+                                        app.status_message =
+                                            Some(format!("Copied {}", message_count(messages.len())));
+                                    }
+                                    Err(err) => {
+                                        app.last_error = Some(format!("failed to copy: {err}"));
+                                    }
+                                }
+                            }
+                        }
+                        AppCommand::ExportMessages => {
+                            let messages = app.take_target_messages();
+                            if let Some(title) =
+                                app.selected_dialog().map(|dialog| dialog.title.clone())
+                                && !messages.is_empty()
+                            {
+                                match export_messages(&download_dir, &title, &messages).await {
+                                    Ok(path) => {
+                                        app.status_message = Some(format!(
+                                            "Exported {} to {}",
+                                            message_count(messages.len()),
+                                            path.display()
+                                        ));
+                                    }
+                                    Err(err) => {
+                                        app.last_error = Some(format!("Export failed: {err:#}"));
+                                    }
+                                }
+                            }
+                        }
+                        AppCommand::StartForward => {
+                            app.start_forward();
+                        }
+                        AppCommand::PrevForwardTarget => {
+                            app.select_prev_forward_target();
+                        }
+                        AppCommand::NextForwardTarget => {
+                            app.select_next_forward_target();
+                        }
+                        AppCommand::ToggleForwardDropAuthor => {
+                            app.toggle_forward_drop_author();
+                        }
+                        AppCommand::ToggleForwardDropCaptions => {
+                            app.toggle_forward_drop_captions();
+                        }
+                        AppCommand::SubmitForward => {
+                            request_forward_messages(&req_tx, &mut app).await;
+                        }
+                        AppCommand::OpenPinnedMessage => {
+                            request_open_pinned_message(&req_tx, &mut app).await;
+                        }
+                        AppCommand::StartPin => {
+                            app.start_pin();
+                        }
+                        AppCommand::TogglePinNotify => {
+                            app.toggle_pin_notify();
+                        }
+                        AppCommand::ConfirmPin => {
+                            request_pin_message(&req_tx, &mut app).await;
+                        }
+                        AppCommand::OpenTopics => {
+                            if let Some(dialog_id) = app.start_topic_picker() {
+                                request_topics(&req_tx, &mut app, dialog_id).await;
+                            }
+                        }
+                        AppCommand::OpenThread => {
+                            request_open_thread(&req_tx, &mut app).await;
+                        }
+                        AppCommand::PrevTopic => {
+                            app.select_prev_topic();
+                        }
+                        AppCommand::NextTopic => {
+                            app.select_next_topic();
+                        }
+                        AppCommand::SubmitTopic => {
+                            if app.submit_topic() {
+                                request_messages_for_selected(&req_tx, &mut app, cache.as_ref())
+                                    .await;
+                            }
+                        }
+                        AppCommand::CancelTransfer => {
+                            request_cancel_transfer(&req_tx, &mut app).await;
+                        }
+                        AppCommand::StartAttach => {
+                            app.start_attach();
+                        }
+                        AppCommand::ToggleComposePreview => {
+                            app.toggle_compose_preview();
+                        }
+                        AppCommand::CycleMarkupMode => {
+                            app.cycle_markup_mode();
+                        }
+                        AppCommand::OpenExternalEditor => {
+                            open_external_editor = true;
+                        }
+                        AppCommand::CompletePath => {
+                            app.complete_attach_path();
+                        }
+                        AppCommand::SubmitAttachment => {
+                            request_send_file(&req_tx, &mut app).await;
+                        }
                         AppCommand::Backspace => {
                             app.backspace();
                         }
                         AppCommand::InsertChar(ch) => {
                             app.insert_char(ch);
                         }
+                        AppCommand::Edit(action) => {
+                            app.edit_compose(action);
+                        }
                         AppCommand::Quit => {
                             app.should_quit = true;
                         }
@@ -127,11 +415,19 @@ async fn main() -> anyhow::Result<()> {
                     }
 
                     if selected_before != app.selected_dialog_id() {
-                        request_messages_for_selected(&req_tx, &mut app).await;
+                        request_messages_for_selected(&req_tx, &mut app, cache.as_ref()).await;
                     }
                 }
             }
             maybe_tele = event_rx.recv() => {
+                // Offline events are read from the cache in the first place.
+                if let (Some(cache), Some(event)) = (&cache, &maybe_tele)
+                    && !app.offline
+                    && let Err(err) = cache.record_event(event)
+                {
+                    warn!("failed to update message cache: {err:#}");
+                }
+
                 match maybe_tele {
                     Some(TelegramEvent::DialogsLoaded(dialogs)) => {
                         let selected_before = app.selected_dialog_id();
@@ -142,25 +438,190 @@ async fn main() -> anyhow::Result<()> {
                                 && (selected_before != selected_after
                                     || app.selected_dialog_messages().is_empty());
                         if should_request_messages {
-                            request_messages_for_selected(&req_tx, &mut app).await;
+                            request_messages_for_selected(&req_tx, &mut app, cache.as_ref()).await;
                         }
                     }
-                    Some(TelegramEvent::MessagesLoaded { dialog_id, messages }) => {
-                        if Some(dialog_id) == app.selected_dialog_id() {
+                    Some(TelegramEvent::FoldersLoaded(folders)) => {
+                        let selected_before = app.selected_dialog_id();
+                        app.on_folders_loaded(folders);
+                        if app.selected_dialog_id().is_some()
+                            && app.selected_dialog_id() != selected_before
+                        {
+                            request_messages_for_selected(&req_tx, &mut app, cache.as_ref()).await;
+                        }
+                    }
+                    Some(TelegramEvent::MessagesLoaded {
+                        dialog_id,
+                        thread_id,
+                        messages,
+                    }) => {
+                        if Some(dialog_id) == app.selected_dialog_id()
+                            && thread_id == app.selected_topic_id()
+                        {
                             app.on_messages_loaded(dialog_id, messages);
                         }
                     }
+                    Some(TelegramEvent::OlderMessagesLoaded {
+                        dialog_id,
+                        thread_id,
+                        messages,
+                    }) => {
+                        app.on_older_messages_loaded(dialog_id, thread_id, messages);
+                    }
+                    Some(TelegramEvent::TopicsLoaded { dialog_id, topics }) => {
+                        app.on_topics_loaded(dialog_id, topics);
+                    }
+                    Some(TelegramEvent::ThreadOpened {
+                        dialog_id,
+                        message_id,
+                        discussion_id,
+                        top_id,
+                    }) => {
+                        if app.on_thread_opened(dialog_id, message_id, discussion_id, top_id) {
+                            request_messages_for_selected(&req_tx, &mut app, cache.as_ref()).await;
+                        }
+                    }
+                    Some(TelegramEvent::ThreadRead {
+                        dialog_id,
+                        thread_id,
+                        max_id,
+                    }) => {
+                        app.on_thread_read(dialog_id, thread_id, max_id);
+                    }
                     Some(TelegramEvent::MessageSent { dialog_id, message }) => {
                         app.on_message_sent(dialog_id, message);
                     }
+                    Some(TelegramEvent::MessageEdited { dialog_id, message }) => {
+                        app.on_message_edited(dialog_id, message);
+                    }
+                    Some(TelegramEvent::MessagesDeleted { dialog_id, message_ids }) => {
+                        app.on_messages_deleted(dialog_id, &message_ids);
+                    }
                     Some(TelegramEvent::IncomingMessage { dialog_id, message }) => {
                         app.on_incoming_message(dialog_id, message);
                     }
+                    Some(TelegramEvent::OutgoingMessage { dialog_id, message }) => {
+                        app.on_outgoing_message(dialog_id, message);
+                    }
+                    Some(TelegramEvent::IncomingMessageEdited { dialog_id, message }) => {
+                        app.on_message_edited(dialog_id, message);
+                    }
+                    Some(TelegramEvent::IncomingMessagesDeleted {
+                        dialog_ids,
+                        message_ids,
+                    }) => {
+                        app.on_incoming_messages_deleted(&dialog_ids, &message_ids);
+                    }
+                    Some(TelegramEvent::InboxRead {
+                        dialog_id,
+                        max_id,
+                        still_unread_count,
+                    }) => {
+                        app.on_inbox_read(dialog_id, max_id, still_unread_count);
+                    }
+                    Some(TelegramEvent::OutboxRead { dialog_id, max_id }) => {
+                        app.on_outbox_read(dialog_id, max_id);
+                    }
+                    Some(TelegramEvent::SearchResultsLoaded { query, results }) => {
+                        app.on_search_results_loaded(query, results);
+                    }
+                    Some(TelegramEvent::MessagesAroundLoaded {
+                        dialog_id,
+                        message_id,
+                        messages,
+                        has_newer,
+                    }) => {
+                        app.on_messages_around_loaded(dialog_id, message_id, messages, has_newer);
+                    }
+                    Some(TelegramEvent::DownloadProgress {
+                        dialog_id,
+                        message_id,
+                        file_name,
+                        downloaded,
+                        total,
+                    }) => {
+                        app.on_download_progress(
+                            dialog_id, message_id, file_name, downloaded, total,
+                        );
+                    }
+                    Some(TelegramEvent::DownloadFinished {
+                        dialog_id,
+                        message_id,
+                        path,
+                    }) => {
+                        app.on_download_finished(dialog_id, message_id, &path);
+                    }
+                    Some(TelegramEvent::DownloadCancelled { dialog_id, message_id }) => {
+                        app.on_download_cancelled(dialog_id, message_id);
+                    }
+                    Some(TelegramEvent::DownloadFailed {
+                        dialog_id,
+                        message_id,
+                        error,
+                    }) => {
+                        app.on_download_failed(dialog_id, message_id, error);
+                    }
+                    Some(TelegramEvent::UploadProgress {
+                        dialog_id,
+                        uploaded,
+                        total,
+                    }) => {
+                        app.on_upload_progress(dialog_id, uploaded, total);
+                    }
+                    Some(TelegramEvent::UploadFinished { dialog_id, message }) => {
+                        app.on_upload_finished(dialog_id, message);
+                    }
+                    Some(TelegramEvent::UploadCancelled { dialog_id }) => {
+                        app.on_upload_cancelled(dialog_id);
+                    }
+                    Some(TelegramEvent::UploadFailed { dialog_id, error }) => {
+                        app.on_upload_failed(dialog_id, error);
+                    }
+                    Some(TelegramEvent::ImagePreviewLoaded {
+                        dialog_id,
+                        message_id,
+                        preview,
+                    }) => {
+                        app.on_image_preview_loaded(dialog_id, message_id, preview);
+                    }
+                    Some(TelegramEvent::DraftChanged { dialog_id, text }) => {
+                        app.on_draft_changed(dialog_id, text);
+                    }
+                    Some(TelegramEvent::MessagesForwarded {
+                        dialog_id,
+                        messages,
+                    }) => {
+                        app.on_messages_forwarded(dialog_id, messages);
+                    }
+                    Some(TelegramEvent::ReactionsChanged {
+                        dialog_id,
+                        message_id,
+                        reactions,
+                        chosen_known,
+                    }) => {
+                        app.on_reactions_changed(dialog_id, message_id, reactions, chosen_known);
+                    }
+                    Some(TelegramEvent::PinnedMessagesLoaded { dialog_id, messages }) => {
+                        app.on_pinned_messages_loaded(dialog_id, messages);
+                    }
+                    Some(TelegramEvent::PinnedMessagesChanged {
+                        dialog_id,
+                        message_ids,
+                        pinned,
+                    }) => {
+                        if app.on_pinned_messages_changed(dialog_id, &message_ids, pinned) {
+                            request_pinned_messages(&req_tx, &mut app, dialog_id).await;
+                        }
+                    }
                     Some(TelegramEvent::Error(err_msg)) => {
                         app.last_error = Some(err_msg);
                         app.is_loading_dialogs = false;
                         app.is_loading_messages = false;
+                        app.is_loading_older_messages = false;
                         app.is_sending_message = false;
+                        if let Some(search) = &mut app.message_search {
+                            search.is_searching = false;
+                        }
                     }
                     None => {
                         app.last_error = Some("telegram task exited".to_string());
@@ -169,8 +630,19 @@ async fn main() -> anyhow::Result<()> {
                 }
             }
         }
+
+        if open_external_editor {
+            // The event stream reads stdin in the background; the editor needs it to itself.
+            drop(events);
+            compose_in_external_editor(&mut terminal_guard, &mut app).await?;
+            images.invalidate();
+            events = EventStream::new();
+        }
     }
 
+    if let Some((dialog_id, text)) = app.unsaved_draft() {
+        request_save_draft(&req_tx, &mut app, dialog_id, text).await;
+    }
     let _ = req_tx.send(TelegramRequest::Shutdown).await;
 
     if let Err(join_err) = telegram_handle.await {
@@ -180,6 +652,64 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Runs `$VISUAL`/`$EDITOR` on the compose text with the terminal handed over to it.
+async fn compose_in_external_editor(
+    terminal_guard: &mut TerminalGuard,
+    app: &mut AppState,
+) -> anyhow::Result<()> {
+    let command = editor::editor_command(|name| env::var(name).ok());
+    let edited = match terminal_guard.suspend() {
+        Ok(()) => editor::edit_externally(&command, app.compose.text()).await,
+        Err(err) => Err(err.context("failed to suspend terminal")),
+    };
+    // The terminal comes back whether or not the editor succeeded.
+    terminal_guard
+        .resume()
+        .context("failed to restore terminal")?;
+
+    match edited {
+        Ok(text) if text != app.compose.text() => app.compose.replace_text(text),
+        Ok(_) => {}
+        Err(err) => app.last_error = Some(format!("External editor failed: {err:#}")),
+    }
+    Ok(())
+}
+
+fn open_cache() -> Option<MessageCache> {
+    match MessageCache::open(CACHE_FILE) {
+        Ok(cache) => Some(cache),
+        Err(err) => {
+            warn!("running without message cache: {err:#}");
+            None
+        }
+    }
+}
+
+/// Connects and checks the session; when that fails or takes longer than `CONNECT_TIMEOUT` and
+/// there is something cached to show, falls back to offline mode instead of exiting.
+async fn start_session(can_start_offline: bool) -> anyhow::Result<Startup> {
+    let connect = async {
+        let auth_flow = AuthFlow::connect_from_env()
+            .await
+            .context("failed to initialize Telegram client")?;
+        let status = auth_flow.current_status().await?;
+        anyhow::Ok((auth_flow, status))
+    };
+    let connected = timeout(CONNECT_TIMEOUT, connect)
+        .await
+        .context("timed out connecting to Telegram")
+        .and_then(|connected| connected);
+
+    match connected {
+        Ok((auth_flow, status)) => Ok(Startup::Online(Box::new(auth_flow), status)),
+        Err(err) if can_start_offline => {
+            warn!("starting in offline mode: {err:#}");
+            Ok(Startup::Offline)
+        }
+        Err(err) => Err(err),
+    }
+}
+
 async fn run_auth_loop(
     terminal: &mut tui::AppTerminal,
     auth_flow: &mut AuthFlow,
@@ -299,18 +829,138 @@ fn handle_auth_key(key: KeyEvent, input: &mut String) -> AuthKeyAction {
     }
 }
 
-async fn request_messages_for_selected(req_tx: &mpsc::Sender<TelegramRequest>, app: &mut AppState) {
+async fn request_messages_for_selected(
+    req_tx: &mpsc::Sender<TelegramRequest>,
+    app: &mut AppState,
+    cache: Option<&MessageCache>,
+) {
     if let Some(dialog_id) = app.selected_dialog_id() {
+        let thread_id = app.selected_topic_id();
+        if let Some(cache) = cache {
+            match cache.load_messages(dialog_id, 50, thread_id) {
+                Ok(messages) => app.on_cached_messages(dialog_id, messages),
+                Err(err) => warn!("failed to read cached messages: {err:#}"),
+            }
+        }
+
         app.is_loading_messages = true;
         if let Err(err) = req_tx
             .send(TelegramRequest::LoadMessages {
                 dialog_id,
                 limit: 50,
+                thread_id,
             })
             .await
         {
             app.last_error = Some(format!("failed to request messages: {err}"));
             app.is_loading_messages = false;
+            return;
+        }
+        request_chat_details(req_tx, app, dialog_id).await;
+    }
+}
+
+/// Pinned messages and, in forums, topics of a chat that has just been opened.
+async fn request_chat_details(
+    req_tx: &mpsc::Sender<TelegramRequest>,
+    app: &mut AppState,
+    dialog_id: i64,
+) {
+    request_pinned_messages(req_tx, app, dialog_id).await;
+    if app.selected_dialog().is_some_and(|dialog| dialog.forum) {
+        request_topics(req_tx, app, dialog_id).await;
+    }
+}
+
+async fn request_topics(
+    req_tx: &mpsc::Sender<TelegramRequest>,
+    app: &mut AppState,
+    dialog_id: i64,
+) {
+    if let Err(err) = req_tx.send(TelegramRequest::LoadTopics { dialog_id }).await {
+        app.last_error = Some(format!("failed to request topics: {err}"));
+    }
+}
+
+async fn request_pinned_messages(
+    req_tx: &mpsc::Sender<TelegramRequest>,
+    app: &mut AppState,
+    dialog_id: i64,
+) {
+    if let Err(err) = req_tx
+        .send(TelegramRequest::LoadPinnedMessages { dialog_id })
+        .await
+    {
+        app.last_error = Some(format!("failed to request pinned messages: {err}"));
+    }
+}
+
+async fn request_older_messages_if_needed(
+    req_tx: &mpsc::Sender<TelegramRequest>,
+    app: &mut AppState,
+) {
+    let Some((dialog_id, before_id)) = app.older_messages_anchor() else {
+        return;
+    };
+
+    app.is_loading_older_messages = true;
+    if let Err(err) = req_tx
+        .send(TelegramRequest::LoadOlderMessages {
+            dialog_id,
+            before_id,
+            thread_id: app.selected_topic_id(),
+        })
+        .await
+    {
+        app.last_error = Some(format!("failed to request older messages: {err}"));
+        app.is_loading_older_messages = false;
+    }
+}
+
+async fn request_read_ack_if_needed(req_tx: &mpsc::Sender<TelegramRequest>, app: &mut AppState) {
+    let Some((dialog_id, max_id)) = app.pending_read_ack() else {
+        return;
+    };
+
+    if let Err(err) = req_tx
+        .send(TelegramRequest::MarkRead {
+            dialog_id,
+            max_id,
+            thread_id: app.selected_topic_id(),
+        })
+        .await
+    {
+        app.last_error = Some(format!("failed to request read acknowledgement: {err}"));
+        return;
+    }
+    app.on_read_ack_sent(dialog_id, max_id);
+}
+
+async fn request_save_draft(
+    req_tx: &mpsc::Sender<TelegramRequest>,
+    app: &mut AppState,
+    dialog_id: i64,
+    text: String,
+) {
+    if let Err(err) = req_tx
+        .send(TelegramRequest::SaveDraft { dialog_id, text })
+        .await
+    {
+        app.last_error = Some(format!("failed to request draft save: {err}"));
+    }
+}
+
+async fn request_image_previews(req_tx: &mpsc::Sender<TelegramRequest>, app: &mut AppState) {
+    for (dialog_id, message_id) in app.previews_to_load() {
+        if req_tx
+            .send(TelegramRequest::LoadImagePreview {
+                dialog_id,
+                message_id,
+            })
+            .await
+            .is_err()
+        {
+            return;
         }
     }
 }
@@ -325,20 +975,264 @@ async fn request_send_message(req_tx: &mpsc::Sender<TelegramRequest>, app: &mut
         return;
     };
 
-    let text = app.compose_text.trim().to_string();
-    if text.is_empty() {
+    let text = app.formatted_compose();
+    if text.text.trim().is_empty() {
         app.last_error = Some("Message must not be empty".to_string());
         return;
     }
 
+    let request = match app.editing_message_id {
+        Some(message_id) => TelegramRequest::EditMessage {
+            dialog_id,
+            message_id,
+            text,
+        },
+        None => TelegramRequest::SendMessage {
+            dialog_id,
+            text,
+            reply_to: app.send_reply_to(),
+        },
+    };
+
     app.is_sending_message = true;
     app.last_error = None;
+    if let Err(err) = req_tx.send(request).await {
+        app.last_error = Some(format!("failed to request message send: {err}"));
+        app.is_sending_message = false;
+    }
+}
+
+async fn request_message_search(req_tx: &mpsc::Sender<TelegramRequest>, app: &mut AppState) {
+    let selected_before = app.selected_dialog_id();
+    let request = match app.submit_message_search() {
+        Some(SearchSubmission::Search { dialog_id, query }) => {
+            TelegramRequest::SearchMessages { dialog_id, query }
+        }
+        Some(SearchSubmission::Open {
+            dialog_id,
+            message_id,
+        }) => {
+            if !app.open_message(dialog_id, message_id) {
+                return;
+            }
+            TelegramRequest::LoadMessagesAround {
+                dialog_id,
+                message_id,
+            }
+        }
+        None => return,
+    };
+
+    if let Err(err) = req_tx.send(request).await {
+        app.last_error = Some(format!("failed to request message search: {err}"));
+        app.is_loading_messages = false;
+        if let Some(search) = &mut app.message_search {
+            search.is_searching = false;
+        }
+        return;
+    }
+    if let Some(dialog_id) = app.selected_dialog_id()
+        && selected_before != Some(dialog_id)
+    {
+        request_chat_details(req_tx, app, dialog_id).await;
+    }
+}
+
+async fn request_download(
+    req_tx: &mpsc::Sender<TelegramRequest>,
+    app: &mut AppState,
+    download_dir: &Path,
+) {
+    let Some((dialog_id, message_id)) = app.start_download() else {
+        return;
+    };
+
     if let Err(err) = req_tx
-        .send(TelegramRequest::SendMessage { dialog_id, text })
+        .send(TelegramRequest::DownloadMedia {
+            dialog_id,
+            message_id,
+            path: download_dir.to_path_buf(),
+        })
         .await
     {
-        app.last_error = Some(format!("failed to request message send: {err}"));
-        app.is_sending_message = false;
+        app.on_download_failed(dialog_id, message_id, err.to_string());
+    }
+}
+
+async fn request_cancel_transfer(req_tx: &mpsc::Sender<TelegramRequest>, app: &mut AppState) {
+    let request = match app.transfer_to_cancel() {
+        Some(Transfer::Download {
+            dialog_id,
+            message_id,
+        }) => TelegramRequest::CancelDownload {
+            dialog_id,
+            message_id,
+        },
+        Some(Transfer::Upload) => TelegramRequest::CancelUpload,
+        None => return,
+    };
+
+    if let Err(err) = req_tx.send(request).await {
+        app.last_error = Some(format!("failed to cancel transfer: {err}"));
+    }
+}
+
+async fn request_send_file(req_tx: &mpsc::Sender<TelegramRequest>, app: &mut AppState) {
+    let Some(attachment) = app.submit_attachment() else {
+        return;
+    };
+
+    if let Err(err) = req_tx
+        .send(TelegramRequest::SendFile {
+            dialog_id: attachment.dialog_id,
+            path: attachment.path,
+            caption: attachment.caption,
+            reply_to: attachment.reply_to,
+        })
+        .await
+    {
+        app.on_upload_failed(attachment.dialog_id, err.to_string());
+    }
+}
+
+async fn request_delete_messages(
+    req_tx: &mpsc::Sender<TelegramRequest>,
+    app: &mut AppState,
+    revoke: bool,
+) {
+    let Some((dialog_id, message_ids)) = app.take_pending_delete() else {
+        return;
+    };
+
+    if let Err(err) = req_tx
+        .send(TelegramRequest::DeleteMessages {
+            dialog_id,
+            message_ids,
+            revoke,
+        })
+        .await
+    {
+        app.last_error = Some(format!("failed to request message deletion: {err}"));
+    }
+}
+
+/// Writes a transcript of messages into `dir`, named after the chat and the message ids.
+async fn export_messages(
+    dir: &Path,
+    title: &str,
+    messages: &[MessageSummary],
+) -> anyhow::Result<PathBuf> {
+    let name: String = title
+        .chars()
+        .map(|ch| if ch.is_alphanumeric() { ch } else { '_' })
+        .collect();
+    let first = messages
+        .first()
+        .map(|message| message.id)
+        .unwrap_or_default();
+    let last = messages
+        .last()
+        .map(|message| message.id)
+        .unwrap_or_default();
+    let path = dir.join(format!("{name}-{first}-{last}.txt"));
+
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("create {}", dir.display()))?;
+    tokio::fs::write(&path, tui::transcript(messages) + "\n")
+        .await
+        .with_context(|| format!("write {}", path.display()))?;
+    Ok(path)
+}
+
+async fn request_forward_messages(req_tx: &mpsc::Sender<TelegramRequest>, app: &mut AppState) {
+    let Some(forward) = app.submit_forward() else {
+        return;
+    };
+
+    if let Err(err) = req_tx
+        .send(TelegramRequest::ForwardMessages {
+            from_dialog_id: forward.from_dialog_id,
+            message_ids: forward.message_ids,
+            to_dialog_id: forward.to_dialog_id,
+            drop_author: forward.drop_author,
+            drop_captions: forward.drop_captions,
+        })
+        .await
+    {
+        app.last_error = Some(format!("failed to request forward: {err}"));
+    }
+}
+
+async fn request_send_reaction(req_tx: &mpsc::Sender<TelegramRequest>, app: &mut AppState) {
+    let Some((dialog_id, message_id, emoji)) = app.submit_reaction() else {
+        return;
+    };
+
+    if let Err(err) = req_tx
+        .send(TelegramRequest::SendReaction {
+            dialog_id,
+            message_id,
+            emoji,
+        })
+        .await
+    {
+        app.last_error = Some(format!("failed to request reaction: {err}"));
+    }
+}
+
+async fn request_open_pinned_message(req_tx: &mpsc::Sender<TelegramRequest>, app: &mut AppState) {
+    let Some((dialog_id, message_id)) = app.open_pinned_message() else {
+        return;
+    };
+
+    if let Err(err) = req_tx
+        .send(TelegramRequest::LoadMessagesAround {
+            dialog_id,
+            message_id,
+        })
+        .await
+    {
+        app.last_error = Some(format!("failed to request pinned message: {err}"));
+        app.is_loading_messages = false;
+    }
+}
+
+async fn request_open_thread(req_tx: &mpsc::Sender<TelegramRequest>, app: &mut AppState) {
+    let Some((dialog_id, message_id)) = app.thread_target() else {
+        return;
+    };
+
+    if let Err(err) = req_tx
+        .send(TelegramRequest::OpenThread {
+            dialog_id,
+            message_id,
+        })
+        .await
+    {
+        app.last_error = Some(format!("failed to request comments: {err}"));
+    }
+}
+
+async fn request_pin_message(req_tx: &mpsc::Sender<TelegramRequest>, app: &mut AppState) {
+    let Some((dialog_id, pending)) = app.take_pending_pin() else {
+        return;
+    };
+
+    let request = if pending.unpin {
+        TelegramRequest::UnpinMessage {
+            dialog_id,
+            message_id: pending.message_id,
+        }
+    } else {
+        TelegramRequest::PinMessage {
+            dialog_id,
+            message_id: pending.message_id,
+            notify: pending.notify,
+        }
+    };
+    if let Err(err) = req_tx.send(request).await {
+        app.last_error = Some(format!("failed to request pin change: {err}"));
     }
 }
 
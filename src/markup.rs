@@ -0,0 +1,723 @@
+//! Compose-box markup: a Markdown subset or Telegram-style HTML, turned into message entities.
+
+use crate::telegram::{EntityKind, FormattedText, TextEntity};
+
+const MARKDOWN_ESCAPABLE: &[char] = &['\\', '*', '_', '`', '[', ']', '|'];
+/// Markers that wrap text on the same line, longest first.
+const MARKDOWN_INLINE: &[(&str, InlineMarker)] = &[
+    ("**", InlineMarker::Bold),
+    ("||", InlineMarker::Spoiler),
+    ("_", InlineMarker::Italic),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MarkupMode {
+    #[default]
+    Markdown,
+    Html,
+    /// Sends the text as typed.
+    Plain,
+}
+
+impl MarkupMode {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Markdown => Self::Html,
+            Self::Html => Self::Plain,
+            Self::Plain => Self::Markdown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InlineMarker {
+    Bold,
+    Spoiler,
+    Italic,
+}
+
+impl InlineMarker {
+    fn kind(self) -> EntityKind {
+        match self {
+            Self::Bold => EntityKind::Bold,
+            Self::Spoiler => EntityKind::Spoiler,
+            Self::Italic => EntityKind::Italic,
+        }
+    }
+}
+
+pub fn parse(input: &str, mode: MarkupMode) -> FormattedText {
+    match mode {
+        MarkupMode::Markdown => parse_markdown(input),
+        MarkupMode::Html => parse_html(input),
+        MarkupMode::Plain => FormattedText {
+            text: input.to_string(),
+            entities: Vec::new(),
+        },
+    }
+}
+
+/// An entity whose closing marker has not been reached yet.
+struct Open {
+    marker: &'static str,
+    kind: EntityKind,
+    start: usize,
+    /// Input index of the `]` that ends a link.
+    link_end: Option<usize>,
+}
+
+/// Parses `**bold**`, `_italic_`, `` `code` ``, fenced code blocks, `[text](url)` and
+/// `||spoiler||`. Markers without a partner are kept as typed, and `\` escapes a marker.
+fn parse_markdown(input: &str) -> FormattedText {
+    let mut text = String::new();
+    let mut entities = Vec::new();
+    let mut open: Vec<Open> = Vec::new();
+    let mut index = 0;
+
+    while index < input.len() {
+        let tail = &input[index..];
+        let at_line_start = index == 0 || input[..index].ends_with('\n');
+
+        if tail.starts_with("```") {
+            if at_line_start && let Some((language, body, consumed)) = fenced_block(tail) {
+                push_entity(&mut entities, EntityKind::Pre { language }, &mut text, body);
+                index += consumed;
+            } else {
+                text.push_str("```");
+                index += 3;
+            }
+            continue;
+        }
+
+        if let Some(escaped) = tail
+            .strip_prefix('\\')
+            .and_then(|rest| rest.chars().next())
+            .filter(|ch| MARKDOWN_ESCAPABLE.contains(ch))
+        {
+            text.push(escaped);
+            index += 1 + escaped.len_utf8();
+            continue;
+        }
+
+        if let Some(code) = tail.strip_prefix('`')
+            && let Some(end) = code.find('`').filter(|&end| end > 0)
+        {
+            push_entity(&mut entities, EntityKind::Code, &mut text, &code[..end]);
+            index += end + 2;
+            continue;
+        }
+
+        if let Some(position) = open.iter().rposition(|entry| entry.link_end == Some(index)) {
+            let link = open.remove(position);
+            close_entity(&mut entities, link.kind, link.start, text.len());
+            index = link_url_end(input, index);
+            continue;
+        }
+
+        if tail.starts_with('[')
+            && let Some((text_end, url)) = link_target(tail)
+        {
+            open.push(Open {
+                marker: "[",
+                kind: EntityKind::TextUrl { url },
+                start: text.len(),
+                link_end: Some(index + text_end),
+            });
+            index += 1;
+            continue;
+        }
+
+        if let Some(&(marker, tag)) = MARKDOWN_INLINE
+            .iter()
+            .find(|(marker, _)| tail.starts_with(marker))
+        {
+            let before = input[..index].chars().next_back();
+            let after = tail[marker.len()..].chars().next();
+            if let Some(position) = open.iter().rposition(|entry| entry.marker == marker)
+                && (tag != InlineMarker::Italic || !after.is_some_and(char::is_alphanumeric))
+            {
+                let entry = open.remove(position);
+                close_entity(&mut entities, entry.kind, entry.start, text.len());
+                index += marker.len();
+                continue;
+            }
+            let opens = tail[marker.len()..].contains(marker)
+                && after.is_some_and(|ch| !ch.is_whitespace())
+                && (tag != InlineMarker::Italic || !before.is_some_and(char::is_alphanumeric));
+            if opens {
+                open.push(Open {
+                    marker,
+                    kind: tag.kind(),
+                    start: text.len(),
+                    link_end: None,
+                });
+                index += marker.len();
+                continue;
+            }
+        }
+
+        let ch = tail.chars().next().unwrap_or_default();
+        text.push(ch);
+        index += ch.len_utf8();
+    }
+
+    // Openers whose partner was swallowed by code or a link go back into the text.
+    for entry in open.into_iter().rev() {
+        text.insert_str(entry.start, entry.marker);
+        for entity in &mut entities {
+            if entity.range.start >= entry.start {
+                entity.range.start += entry.marker.len();
+            }
+            if entity.range.end > entry.start {
+                entity.range.end += entry.marker.len();
+            }
+        }
+    }
+
+    entities.sort_by_key(|entity| entity.range.start);
+    FormattedText { text, entities }
+}
+
+/// Splits a block starting with ```` ``` ```` into its language, its body and the input length
+/// it spans, up to and including the closing fence.
+fn fenced_block(tail: &str) -> Option<(String, &str, usize)> {
+    let header_end = tail.find('\n')?;
+    let language = tail[3..header_end].trim().to_string();
+    let body_start = header_end + 1;
+    let body = &tail[body_start..];
+    if let Some(rest) = body.strip_prefix("```") {
+        return Some((language, "", body_start + body.len() - rest.len()));
+    }
+    let close = body.find("\n```")?;
+    Some((language, &body[..close], body_start + close + 4))
+}
+
+/// For a tail starting with `[`, the offset of the closing `]` and the URL in the `(...)`
+/// right after it. The link text ends at the first `]` and stays on one line.
+fn link_target(tail: &str) -> Option<(usize, String)> {
+    let text_end = tail[1..].find([']', '[', '\n'])? + 1;
+    if !tail[text_end..].starts_with("](") {
+        return None;
+    }
+    let url_start = text_end + 2;
+    let url_len = tail[url_start..].find(')')?;
+    let url = tail[url_start..url_start + url_len].trim();
+    if text_end == 1 || url.is_empty() || url.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((text_end, url.to_string()))
+}
+
+fn link_url_end(input: &str, text_end: usize) -> usize {
+    input[text_end..]
+        .find(')')
+        .map_or(input.len(), |close| text_end + close + 1)
+}
+
+fn push_entity(entities: &mut Vec<TextEntity>, kind: EntityKind, text: &mut String, body: &str) {
+    let start = text.len();
+    text.push_str(body);
+    close_entity(entities, kind, start, text.len());
+}
+
+fn close_entity(entities: &mut Vec<TextEntity>, kind: EntityKind, start: usize, end: usize) {
+    if start < end {
+        entities.push(TextEntity {
+            kind,
+            range: start..end,
+        });
+    }
+}
+
+/// Parses the HTML tags Telegram understands: `<b>`, `<i>`, `<u>`, `<s>`, `<code>`, `<pre>`,
+/// `<a href>`, `<tg-spoiler>` and `<blockquote>`, with their common aliases. Anything that is
+/// not one of these tags stays literal text; unclosed tags run to the end.
+fn parse_html(input: &str) -> FormattedText {
+    let mut text = String::new();
+    let mut entities = Vec::new();
+    // `None` marks a `<code>` inside `<pre>`, which only names the language.
+    let mut open: Vec<(String, Option<EntityKind>, usize)> = Vec::new();
+    let mut index = 0;
+
+    while index < input.len() {
+        let tail = &input[index..];
+
+        if let Some(tag) = html_tag(tail) {
+            index += tag.len;
+            if tag.closing {
+                if let Some(position) = open.iter().rposition(|(name, ..)| *name == tag.name) {
+                    let (_, kind, start) = open.remove(position);
+                    if let Some(kind) = kind {
+                        close_entity(&mut entities, kind, start, text.len());
+                    }
+                }
+                continue;
+            }
+
+            let kind = match tag.name.as_str() {
+                "code" => match open.last_mut() {
+                    Some((name, Some(EntityKind::Pre { language }), start))
+                        if name == "pre" && *start == text.len() =>
+                    {
+                        if let Some(class) = tag.attribute("class") {
+                            *language = class.trim_start_matches("language-").to_string();
+                        }
+                        None
+                    }
+                    _ => Some(EntityKind::Code),
+                },
+                "pre" => Some(EntityKind::Pre {
+                    language: String::new(),
+                }),
+                "a" => Some(EntityKind::TextUrl {
+                    url: tag.attribute("href").unwrap_or_default(),
+                }),
+                name => html_kind(name),
+            };
+            open.push((tag.name, kind, text.len()));
+            continue;
+        }
+
+        if let Some((ch, len)) = html_char_reference(tail) {
+            text.push(ch);
+            index += len;
+            continue;
+        }
+
+        let ch = tail.chars().next().unwrap_or_default();
+        text.push(ch);
+        index += ch.len_utf8();
+    }
+
+    for (_, kind, start) in open {
+        if let Some(kind) = kind {
+            close_entity(&mut entities, kind, start, text.len());
+        }
+    }
+
+    entities.sort_by_key(|entity| entity.range.start);
+    FormattedText { text, entities }
+}
+
+fn html_kind(name: &str) -> Option<EntityKind> {
+    match name {
+        "b" | "strong" => Some(EntityKind::Bold),
+        "i" | "em" => Some(EntityKind::Italic),
+        "u" | "ins" => Some(EntityKind::Underline),
+        "s" | "strike" | "del" => Some(EntityKind::Strikethrough),
+        "tg-spoiler" => Some(EntityKind::Spoiler),
+        "blockquote" => Some(EntityKind::Blockquote),
+        _ => None,
+    }
+}
+
+struct HtmlTag<'a> {
+    name: String,
+    closing: bool,
+    attributes: &'a str,
+    len: usize,
+}
+
+impl HtmlTag<'_> {
+    fn attribute(&self, name: &str) -> Option<String> {
+        let mut rest = self.attributes;
+        while let Some(eq) = rest.find('=') {
+            let key = rest[..eq].trim();
+            let value = rest[eq + 1..].trim_start();
+            let quote = value.chars().next()?;
+            let (value, after) = if quote == '"' || quote == '\'' {
+                let end = value[1..].find(quote)? + 1;
+                (&value[1..end], &value[end + 1..])
+            } else {
+                let end = value.find(char::is_whitespace).unwrap_or(value.len());
+                (&value[..end], &value[end..])
+            };
+            if key.eq_ignore_ascii_case(name) {
+                return Some(decode_html(value));
+            }
+            rest = after;
+        }
+        None
+    }
+}
+
+/// A supported tag at the start of `tail`; `<span class="tg-spoiler">` reads as `<tg-spoiler>`.
+fn html_tag(tail: &str) -> Option<HtmlTag<'_>> {
+    let inner = tail.strip_prefix('<')?;
+    let close = inner.find('>')?;
+    let (closing, inner) = match inner[..close].strip_prefix('/') {
+        Some(rest) => (true, rest),
+        None => (false, &inner[..close]),
+    };
+    let name_end = inner
+        .find(|ch: char| !(ch.is_ascii_alphanumeric() || ch == '-'))
+        .unwrap_or(inner.len());
+    let mut tag = HtmlTag {
+        name: inner[..name_end].to_ascii_lowercase(),
+        closing,
+        attributes: &inner[name_end..],
+        len: close + 2,
+    };
+    if tag.name == "span" && (closing || tag.attribute("class").as_deref() == Some("tg-spoiler")) {
+        tag.name = "tg-spoiler".to_string();
+    }
+    let supported =
+        matches!(tag.name.as_str(), "code" | "pre" | "a") || html_kind(&tag.name).is_some();
+    supported.then_some(tag)
+}
+
+/// Decodes `&lt;`, `&gt;`, `&amp;`, `&quot;`, `&apos;` and numeric references.
+fn html_char_reference(tail: &str) -> Option<(char, usize)> {
+    let body = tail.strip_prefix('&')?;
+    let end = body.find(';').filter(|&end| end <= 10)?;
+    let ch = match &body[..end] {
+        "lt" => '<',
+        "gt" => '>',
+        "amp" => '&',
+        "quot" => '"',
+        "apos" => '\'',
+        reference => {
+            let number = reference.strip_prefix('#')?;
+            let code = match number.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => number.parse().ok()?,
+            };
+            char::from_u32(code)?
+        }
+    };
+    Some((ch, end + 2))
+}
+
+fn decode_html(value: &str) -> String {
+    let mut decoded = String::with_capacity(value.len());
+    let mut index = 0;
+    while index < value.len() {
+        let tail = &value[index..];
+        if let Some((ch, len)) = html_char_reference(tail) {
+            decoded.push(ch);
+            index += len;
+        } else {
+            let ch = tail.chars().next().unwrap_or_default();
+            decoded.push(ch);
+            index += ch.len_utf8();
+        }
+    }
+    decoded
+}
+
+/// `mode` when it can write every entity, otherwise HTML, which writes them all. Links and
+/// mentions Telegram finds by itself need no markup.
+pub fn mode_for(entities: &[TextEntity], mode: MarkupMode) -> MarkupMode {
+    let writable = entities.iter().all(|entity| match (&entity.kind, mode) {
+        (EntityKind::Url | EntityKind::Mention, _) | (_, MarkupMode::Html) => true,
+        (_, MarkupMode::Plain) => false,
+        (kind, MarkupMode::Markdown) => markdown_markers(kind).is_some(),
+    });
+    if writable { mode } else { MarkupMode::Html }
+}
+
+/// Writes `text` with its entities as markup that [`parse`] reads back in `mode`. Entities the
+/// mode cannot write are left out; see [`mode_for`].
+pub fn render(text: &str, entities: &[TextEntity], mode: MarkupMode) -> String {
+    let mut spans: Vec<(usize, usize, String, String, bool)> = entities
+        .iter()
+        .filter(|entity| {
+            entity.range.start < entity.range.end
+                && entity.range.end <= text.len()
+                && text.is_char_boundary(entity.range.start)
+                && text.is_char_boundary(entity.range.end)
+        })
+        .filter_map(|entity| {
+            let (open, close) = match mode {
+                MarkupMode::Markdown => markdown_markers(&entity.kind)?,
+                MarkupMode::Html => html_markers(&entity.kind)?,
+                MarkupMode::Plain => return None,
+            };
+            let raw = matches!(entity.kind, EntityKind::Code | EntityKind::Pre { .. });
+            Some((entity.range.start, entity.range.end, open, close, raw))
+        })
+        .collect();
+    spans.sort_by_key(|&(start, end, ..)| (start, std::cmp::Reverse(end)));
+
+    let mut output = String::with_capacity(text.len());
+    // Open spans, innermost last; an overlapping span is cut short so that markers nest.
+    let mut open: Vec<(usize, String, bool)> = Vec::new();
+    let mut spans = spans.into_iter().peekable();
+    for (index, ch) in text.char_indices() {
+        while let Some((_, close, _)) = open.pop_if(|(end, ..)| *end <= index) {
+            output.push_str(&close);
+        }
+        while let Some((_, end, open_marker, close, raw)) =
+            spans.next_if(|(start, ..)| *start == index)
+        {
+            let end = open.last().map_or(end, |(outer, ..)| end.min(*outer));
+            output.push_str(&open_marker);
+            open.push((end, close, raw));
+        }
+
+        let raw = open.iter().any(|(.., raw)| *raw);
+        match mode {
+            MarkupMode::Markdown if !raw && MARKDOWN_ESCAPABLE.contains(&ch) => {
+                output.push('\\');
+                output.push(ch);
+            }
+            MarkupMode::Html if ch == '&' => output.push_str("&amp;"),
+            MarkupMode::Html if ch == '<' => output.push_str("&lt;"),
+            MarkupMode::Html if ch == '>' => output.push_str("&gt;"),
+            _ => output.push(ch),
+        }
+    }
+    for (_, close, _) in open.into_iter().rev() {
+        output.push_str(&close);
+    }
+    output
+}
+
+fn markdown_markers(kind: &EntityKind) -> Option<(String, String)> {
+    let (open, close) = match kind {
+        EntityKind::Bold => ("**".to_string(), "**"),
+        EntityKind::Italic => ("_".to_string(), "_"),
+        EntityKind::Spoiler => ("||".to_string(), "||"),
+        EntityKind::Code => ("`".to_string(), "`"),
+        EntityKind::Pre { language } => (format!("```{language}\n"), "\n```"),
+        EntityKind::TextUrl { url }
+            if !url.contains(|ch: char| ch == ')' || ch.is_whitespace()) =>
+        {
+            return Some(("[".to_string(), format!("]({url})")));
+        }
+        _ => return None,
+    };
+    Some((open, close.to_string()))
+}
+
+fn html_markers(kind: &EntityKind) -> Option<(String, String)> {
+    let tag = match kind {
+        EntityKind::Pre { language } if !language.is_empty() => {
+            let class = format!("language-{language}").replace('"', "&quot;");
+            return Some((
+                format!("<pre><code class=\"{class}\">"),
+                "</code></pre>".to_string(),
+            ));
+        }
+        EntityKind::TextUrl { url } => {
+            let href = url.replace('&', "&amp;").replace('"', "&quot;");
+            return Some((format!("<a href=\"{href}\">"), "</a>".to_string()));
+        }
+        EntityKind::Bold => "b",
+        EntityKind::Italic => "i",
+        EntityKind::Underline => "u",
+        EntityKind::Strikethrough => "s",
+        EntityKind::Spoiler => "tg-spoiler",
+        EntityKind::Code => "code",
+        EntityKind::Pre { .. } => "pre",
+        EntityKind::Blockquote => "blockquote",
+        EntityKind::Url | EntityKind::Mention => return None,
+    };
+    Some((format!("<{tag}>"), format!("</{tag}>")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MarkupMode, mode_for, parse, render};
+    use crate::telegram::{EntityKind, TextEntity};
+
+    fn entity(kind: EntityKind, range: std::ops::Range<usize>) -> TextEntity {
+        TextEntity { kind, range }
+    }
+
+    #[test]
+    fn markdown_inline_markers_become_entities() {
+        let parsed = parse(
+            "**bold** _it_ `x_y` ||boo|| [site](https://a.b)",
+            MarkupMode::Markdown,
+        );
+
+        assert_eq!(parsed.text, "bold it x_y boo site");
+        assert_eq!(
+            parsed.entities,
+            vec![
+                entity(EntityKind::Bold, 0..4),
+                entity(EntityKind::Italic, 5..7),
+                entity(EntityKind::Code, 8..11),
+                entity(EntityKind::Spoiler, 12..15),
+                entity(
+                    EntityKind::TextUrl {
+                        url: "https://a.b".to_string()
+                    },
+                    16..20
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn markdown_nests_markers_inside_links() {
+        let parsed = parse("[**go**](tg://x) now", MarkupMode::Markdown);
+
+        assert_eq!(parsed.text, "go now");
+        assert_eq!(
+            parsed.entities,
+            vec![
+                entity(EntityKind::Bold, 0..2),
+                entity(
+                    EntityKind::TextUrl {
+                        url: "tg://x".to_string()
+                    },
+                    0..2
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn markdown_link_text_ends_at_the_first_bracket() {
+        let parsed = parse("[x] todo [docs](https://a)", MarkupMode::Markdown);
+
+        assert_eq!(parsed.text, "[x] todo docs");
+        assert_eq!(
+            parsed.entities,
+            vec![entity(
+                EntityKind::TextUrl {
+                    url: "https://a".to_string()
+                },
+                9..13
+            )]
+        );
+        assert!(
+            parse("[a\nb](https://a)", MarkupMode::Markdown)
+                .entities
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn markdown_fenced_block_keeps_language_and_body() {
+        let parsed = parse(
+            "see\n```rust\nfn main() {}\n```\ndone",
+            MarkupMode::Markdown,
+        );
+
+        assert_eq!(parsed.text, "see\nfn main() {}\ndone");
+        assert_eq!(
+            parsed.entities,
+            vec![entity(
+                EntityKind::Pre {
+                    language: "rust".to_string()
+                },
+                4..16
+            )]
+        );
+    }
+
+    #[test]
+    fn markdown_leaves_unpaired_and_escaped_markers() {
+        let parsed = parse(r"snake_case 2*3 **open \_x\_ a||b", MarkupMode::Markdown);
+
+        assert_eq!(parsed.text, "snake_case 2*3 **open _x_ a||b");
+        assert!(parsed.entities.is_empty());
+    }
+
+    #[test]
+    fn markdown_restores_opener_whose_partner_was_in_code() {
+        let parsed = parse("**a `**` b", MarkupMode::Markdown);
+
+        assert_eq!(parsed.text, "**a ** b");
+        assert_eq!(parsed.entities, vec![entity(EntityKind::Code, 4..6)]);
+    }
+
+    #[test]
+    fn html_tags_become_entities() {
+        let parsed = parse(
+            "<b>bold</b> <a href=\"https://a.b?x=1&amp;y=2\">link</a> <span class=\"tg-spoiler\">s</span> &lt;3",
+            MarkupMode::Html,
+        );
+
+        assert_eq!(parsed.text, "bold link s <3");
+        assert_eq!(
+            parsed.entities,
+            vec![
+                entity(EntityKind::Bold, 0..4),
+                entity(
+                    EntityKind::TextUrl {
+                        url: "https://a.b?x=1&y=2".to_string()
+                    },
+                    5..9
+                ),
+                entity(EntityKind::Spoiler, 10..11),
+            ]
+        );
+    }
+
+    #[test]
+    fn html_pre_takes_language_from_inner_code() {
+        let parsed = parse(
+            "<pre><code class=\"language-go\">x := 1</code></pre>",
+            MarkupMode::Html,
+        );
+
+        assert_eq!(parsed.text, "x := 1");
+        assert_eq!(
+            parsed.entities,
+            vec![entity(
+                EntityKind::Pre {
+                    language: "go".to_string()
+                },
+                0..6
+            )]
+        );
+    }
+
+    #[test]
+    fn html_keeps_unknown_tags_and_closes_open_ones_at_end() {
+        let parsed = parse("a <script> b < c <i>tail", MarkupMode::Html);
+
+        assert_eq!(parsed.text, "a <script> b < c tail");
+        assert_eq!(parsed.entities, vec![entity(EntityKind::Italic, 17..21)]);
+    }
+
+    #[test]
+    fn plain_mode_sends_text_as_typed() {
+        let parsed = parse("**not bold**", MarkupMode::Plain);
+
+        assert_eq!(parsed.text, "**not bold**");
+        assert!(parsed.entities.is_empty());
+    }
+
+    #[test]
+    fn rendered_markup_parses_back_to_the_same_entities() {
+        let text = "a_b [x] link\nfn main() {}\nbold *in* it";
+        let entities = vec![
+            entity(
+                EntityKind::TextUrl {
+                    url: "https://a.b".to_string(),
+                },
+                8..12,
+            ),
+            entity(
+                EntityKind::Pre {
+                    language: "rust".to_string(),
+                },
+                13..25,
+            ),
+            entity(EntityKind::Bold, 26..38),
+            entity(EntityKind::Italic, 31..35),
+        ];
+
+        for mode in [MarkupMode::Markdown, MarkupMode::Html] {
+            let parsed = parse(&render(text, &entities, mode), mode);
+            assert_eq!(parsed.text, text);
+            assert_eq!(parsed.entities, entities);
+        }
+    }
+
+    #[test]
+    fn formatting_the_mode_cannot_write_falls_back_to_html() {
+        let bold = [entity(EntityKind::Bold, 0..1)];
+        let underline = [entity(EntityKind::Underline, 0..1)];
+
+        assert_eq!(mode_for(&bold, MarkupMode::Markdown), MarkupMode::Markdown);
+        assert_eq!(mode_for(&underline, MarkupMode::Markdown), MarkupMode::Html);
+        assert_eq!(mode_for(&bold, MarkupMode::Plain), MarkupMode::Html);
+        assert_eq!(mode_for(&[], MarkupMode::Plain), MarkupMode::Plain);
+    }
+}
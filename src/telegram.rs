@@ -1,58 +1,621 @@
-use std::{collections::HashMap, env, sync::Arc};
+use std::{
+    collections::HashMap,
+    env,
+    hash::{BuildHasher, RandomState},
+    ops::Range,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context as TaskContext, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{Context, anyhow};
 use grammers_client::{
-    Client, SignInError, Update, UpdatesConfiguration,
-    types::{LoginToken, Media, Message, PasswordToken},
+    Client, InputMessage, PeerMap, SignInError, Update, UpdatesConfiguration,
+    grammers_tl_types as tl,
+    types::{
+        InputReactions, LoginToken, Media, Message, PasswordToken, Peer, media::Document,
+        photo_sizes::PhotoSize,
+    },
 };
 use grammers_mtsender::SenderPool;
-use grammers_session::{defs::PeerRef, storages::SqliteSession};
+use grammers_session::{
+    defs::{PeerId, PeerKind, PeerRef},
+    storages::SqliteSession,
+};
 use tokio::{
-    sync::mpsc::{self, UnboundedReceiver},
+    fs,
+    io::{AsyncRead, AsyncWriteExt, ReadBuf},
+    sync::{
+        mpsc::{self, UnboundedReceiver},
+        oneshot,
+    },
     task::JoinHandle,
     time::{Duration, interval},
 };
+use tracing::warn;
 
 const SESSION_FILE: &str = "telegram.session";
+const DEFAULT_DOWNLOAD_DIR: &str = "downloads";
 const DIALOG_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+const UPLOAD_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+/// Larger images are sent as documents; Telegram rejects bigger photos.
+const MAX_PHOTO_UPLOAD_SIZE: u64 = 10 * 1024 * 1024;
+const PHOTO_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "webp"];
+/// Thumbnail size class of about 320px, big enough for a preview a few dozen cells wide.
+const PREVIEW_THUMB_TYPE: &str = "m";
+const MAX_PREVIEW_PIXELS: u32 = 320;
+pub const OLDER_MESSAGES_PAGE_SIZE: usize = 50;
+pub const SEARCH_RESULTS_LIMIT: usize = 50;
+/// Messages fetched on each side of a message opened from search results.
+pub const MESSAGES_AROUND_HALF_PAGE: usize = 25;
+const PINNED_MESSAGES_LIMIT: usize = 50;
+const TOPICS_LIMIT: i32 = 100;
+/// Archived chats are kept in this peer folder.
+const ARCHIVE_FOLDER_ID: i32 = 1;
+/// Forum messages outside any other topic belong to the General topic.
+pub const GENERAL_TOPIC_ID: i32 = 1;
+/// Channels and supergroups have no "delete for me".
+pub const DELETE_FOR_ME_UNSUPPORTED: &str =
+    "messages in channels and supergroups can only be deleted for everyone";
+/// Custom emoji are stickers; the terminal shows a placeholder instead.
+const CUSTOM_EMOJI_REACTION: &str = "◆";
+const PAID_REACTION: &str = "⭐";
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct DialogSummary {
     pub id: i64,
     pub title: String,
+    pub unread_count: usize,
+    pub unread_mentions_count: usize,
+    pub read_inbox_max_id: i32,
+    pub read_outbox_max_id: i32,
+    /// Cloud draft of the chat, empty when there is none.
+    pub draft: String,
+    /// Supergroup with topics enabled.
+    pub forum: bool,
+    pub kind: DialogKind,
+    /// A user in our contacts.
+    pub contact: bool,
+    pub muted: bool,
+    pub archived: bool,
+    /// Pinned at the top of the chat list.
+    pub pinned: bool,
+    pub last_message: Option<LastMessage>,
+}
+
+/// The newest message of a chat, previewed in the chat list.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LastMessage {
+    pub id: i32,
+    pub from: String,
+    pub text: String,
+    pub entities: Vec<TextEntity>,
+    pub media: Option<MediaInfo>,
+    pub date: String,
+    pub outgoing: bool,
+    pub mentioned: bool,
+}
+
+impl From<&MessageSummary> for LastMessage {
+    fn from(message: &MessageSummary) -> Self {
+        Self {
+            id: message.id,
+            from: message.from.clone(),
+            text: message.text.clone(),
+            entities: message.entities.clone(),
+            media: message.media.clone(),
+            date: message.date.clone(),
+            outgoing: message.outgoing,
+            mentioned: message.mentioned,
+        }
+    }
+}
+
+/// Who a chat is with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DialogKind {
+    #[default]
+    User,
+    Bot,
+    Group,
+    Supergroup,
+    Channel,
+}
+
+/// A chat folder of the user. Which chats it holds follows from these rules, since unread and
+/// muted chats come and go.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChatFolder {
+    pub id: i32,
+    pub title: String,
+    pub contacts: bool,
+    pub non_contacts: bool,
+    pub groups: bool,
+    pub broadcasts: bool,
+    pub bots: bool,
+    pub exclude_muted: bool,
+    pub exclude_read: bool,
+    pub exclude_archived: bool,
+    /// Chats in the folder whatever the rules say, pinned ones included.
+    pub include: Vec<i64>,
+    pub exclude: Vec<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicSummary {
+    pub id: i32,
+    pub title: String,
+    pub top_message: i32,
+    pub unread_count: usize,
+    pub unread_mentions_count: usize,
+    pub read_inbox_max_id: i32,
+}
+
+/// What a message carries besides its text; the text is then the caption.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MediaInfo {
+    Photo {
+        width: i32,
+        height: i32,
+        size: u64,
+    },
+    Video {
+        file_name: String,
+        duration: u32,
+        width: i32,
+        height: i32,
+        size: u64,
+        /// Round video message.
+        round: bool,
+    },
+    Animation {
+        file_name: String,
+        width: i32,
+        height: i32,
+        size: u64,
+    },
+    Audio {
+        title: String,
+        performer: String,
+        duration: u32,
+        size: u64,
+    },
+    Voice {
+        duration: u32,
+        size: u64,
+    },
+    Sticker {
+        emoji: String,
+        animated: bool,
+    },
+    Document {
+        file_name: String,
+        mime_type: String,
+        size: u64,
+    },
+    Poll {
+        question: String,
+        options: Vec<String>,
+        quiz: bool,
+        closed: bool,
+        total_voters: u32,
+    },
+    Location {
+        latitude: f64,
+        longitude: f64,
+        live: bool,
+    },
+    Venue {
+        title: String,
+        address: String,
+        latitude: f64,
+        longitude: f64,
+    },
+    Contact {
+        name: String,
+        phone: String,
+    },
+    Dice {
+        emoji: String,
+        value: i32,
+    },
+    WebPage {
+        url: String,
+        site_name: String,
+        title: String,
+    },
+    Unsupported,
+}
+
+impl MediaInfo {
+    /// Whether the media is a file that can be downloaded.
+    pub fn is_file(&self) -> bool {
+        matches!(
+            self,
+            Self::Photo { .. }
+                | Self::Video { .. }
+                | Self::Animation { .. }
+                | Self::Audio { .. }
+                | Self::Voice { .. }
+                | Self::Sticker { .. }
+                | Self::Document { .. }
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntityKind {
+    Bold,
+    Italic,
+    Underline,
+    Strikethrough,
+    Spoiler,
+    Code,
+    Pre {
+        language: String,
+    },
+    /// Link text pointing at a URL that is not part of the text.
+    TextUrl {
+        url: String,
+    },
+    /// URLs, e-mail addresses and phone numbers written out in the text.
+    Url,
+    /// User mentions, hashtags, cashtags and bot commands.
+    Mention,
+    Blockquote,
+}
+
+/// Formatting applied to `text[range]` of a message; `range` is in bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextEntity {
+    pub kind: EntityKind,
+    pub range: Range<usize>,
+}
+
+/// Text with its formatting, as sent to Telegram.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FormattedText {
+    pub text: String,
+    pub entities: Vec<TextEntity>,
+}
+
+/// How many people reacted to a message with one emoji.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReactionCount {
+    /// The emoji, or a placeholder for custom emoji and paid reactions.
+    pub emoji: String,
+    pub count: u32,
+    /// Whether one of the reactions is ours.
+    pub chosen: bool,
+}
+
+/// Comments under a channel post, which live in the linked discussion group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comments {
+    pub count: usize,
+    /// Names of the latest commenters; empty when the server did not send who they are.
+    pub recent: Vec<String>,
+}
+
+/// Copies our own choices from `previous` into `reactions`, for updates that leave them out.
+pub fn keep_chosen_reactions(previous: &[ReactionCount], reactions: &mut [ReactionCount]) {
+    for reaction in reactions {
+        reaction.chosen = previous
+            .iter()
+            .any(|old| old.chosen && old.emoji == reaction.emoji);
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct MessageSummary {
     pub id: i32,
     pub from: String,
+    /// Message text, or the caption when there is media.
     pub text: String,
+    pub entities: Vec<TextEntity>,
+    pub media: Option<MediaInfo>,
     pub date: String,
+    pub reply_to: Option<i32>,
+    /// Reply thread of the message: its forum topic, or the message its reply chain starts at.
+    /// `None` outside threads and in the General topic.
+    pub topic_id: Option<i32>,
+    pub outgoing: bool,
+    /// Mentions us or replies to one of our messages.
+    pub mentioned: bool,
+    pub edited: bool,
+    pub deleted: bool,
+    pub reactions: Vec<ReactionCount>,
+    /// Set on channel posts that can be commented on.
+    pub comments: Option<Comments>,
+}
+
+/// Decoded photo thumbnail shown inline in the message pane.
+#[derive(Debug, Clone)]
+pub struct ImagePreview {
+    pub width: u32,
+    pub height: u32,
+    /// Packed 8-bit RGB pixels, row by row.
+    pub rgb: Vec<u8>,
+    /// The thumbnail file as downloaded, for terminals that decode images themselves.
+    pub source: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageSearchResult {
+    pub dialog_id: i64,
+    pub dialog_title: String,
+    pub message: MessageSummary,
 }
 
 #[derive(Debug)]
 pub enum TelegramRequest {
     LoadDialogs,
-    LoadMessages { dialog_id: i64, limit: usize },
-    SendMessage { dialog_id: i64, text: String },
+    LoadFolders,
+    /// `thread_id` scopes the history to the reply thread of that message, such as a forum
+    /// topic.
+    LoadMessages {
+        dialog_id: i64,
+        limit: usize,
+        thread_id: Option<i32>,
+    },
+    LoadOlderMessages {
+        dialog_id: i64,
+        before_id: i32,
+        thread_id: Option<i32>,
+    },
+    SendMessage {
+        dialog_id: i64,
+        text: FormattedText,
+        reply_to: Option<i32>,
+    },
+    EditMessage {
+        dialog_id: i64,
+        message_id: i32,
+        text: FormattedText,
+    },
+    DeleteMessages {
+        dialog_id: i64,
+        message_ids: Vec<i32>,
+        revoke: bool,
+    },
+    MarkRead {
+        dialog_id: i64,
+        max_id: i32,
+        thread_id: Option<i32>,
+    },
+    /// Searches one chat, or every chat when `dialog_id` is `None`.
+    SearchMessages {
+        dialog_id: Option<i64>,
+        query: String,
+    },
+    LoadMessagesAround {
+        dialog_id: i64,
+        message_id: i32,
+    },
+    /// Saves the media of a message into the `path` directory; the file name comes from the media.
+    DownloadMedia {
+        dialog_id: i64,
+        message_id: i32,
+        path: PathBuf,
+    },
+    CancelDownload {
+        dialog_id: i64,
+        message_id: i32,
+    },
+    /// Uploads a local file and sends it as a photo when it is an image, else as a document.
+    SendFile {
+        dialog_id: i64,
+        path: PathBuf,
+        caption: FormattedText,
+        reply_to: Option<i32>,
+    },
+    CancelUpload,
+    /// Downloads and decodes a small thumbnail of a photo message.
+    LoadImagePreview {
+        dialog_id: i64,
+        message_id: i32,
+    },
+    /// Stores the draft of a chat in the cloud; an empty text clears it.
+    SaveDraft {
+        dialog_id: i64,
+        text: String,
+    },
+    /// `drop_author` sends copies without the "forwarded from" header; `drop_captions` also
+    /// strips media captions and implies `drop_author`.
+    ForwardMessages {
+        from_dialog_id: i64,
+        message_ids: Vec<i32>,
+        to_dialog_id: i64,
+        drop_author: bool,
+        drop_captions: bool,
+    },
+    /// Replaces our reaction to a message; `None` removes it.
+    SendReaction {
+        dialog_id: i64,
+        message_id: i32,
+        emoji: Option<String>,
+    },
+    LoadPinnedMessages {
+        dialog_id: i64,
+    },
+    LoadTopics {
+        dialog_id: i64,
+    },
+    /// Finds the comment thread of a channel post in the linked discussion group.
+    OpenThread {
+        dialog_id: i64,
+        message_id: i32,
+    },
+    /// `notify` alerts every member of the chat about the new pin.
+    PinMessage {
+        dialog_id: i64,
+        message_id: i32,
+        notify: bool,
+    },
+    UnpinMessage {
+        dialog_id: i64,
+        message_id: i32,
+    },
     Shutdown,
 }
 
 #[derive(Debug)]
 pub enum TelegramEvent {
     DialogsLoaded(Vec<DialogSummary>),
+    /// Folders in the order the user arranged them.
+    FoldersLoaded(Vec<ChatFolder>),
     MessagesLoaded {
         dialog_id: i64,
+        thread_id: Option<i32>,
+        messages: Vec<MessageSummary>,
+    },
+    OlderMessagesLoaded {
+        dialog_id: i64,
+        thread_id: Option<i32>,
         messages: Vec<MessageSummary>,
     },
     MessageSent {
         dialog_id: i64,
         message: MessageSummary,
     },
+    MessageEdited {
+        dialog_id: i64,
+        message: MessageSummary,
+    },
+    MessagesDeleted {
+        dialog_id: i64,
+        message_ids: Vec<i32>,
+    },
     IncomingMessage {
         dialog_id: i64,
         message: MessageSummary,
     },
+    /// A message we sent, possibly from another device or app.
+    OutgoingMessage {
+        dialog_id: i64,
+        message: MessageSummary,
+    },
+    IncomingMessageEdited {
+        dialog_id: i64,
+        message: MessageSummary,
+    },
+    /// Deletions outside channels do not say which chat they belong to, so `dialog_ids` lists
+    /// every chat the message ids may refer to.
+    IncomingMessagesDeleted {
+        dialog_ids: Vec<i64>,
+        message_ids: Vec<i32>,
+    },
+    InboxRead {
+        dialog_id: i64,
+        max_id: i32,
+        still_unread_count: usize,
+    },
+    OutboxRead {
+        dialog_id: i64,
+        max_id: i32,
+    },
+    SearchResultsLoaded {
+        query: String,
+        results: Vec<MessageSearchResult>,
+    },
+    /// `has_newer` is set when the chat has messages newer than the loaded window.
+    MessagesAroundLoaded {
+        dialog_id: i64,
+        message_id: i32,
+        messages: Vec<MessageSummary>,
+        has_newer: bool,
+    },
+    DownloadProgress {
+        dialog_id: i64,
+        message_id: i32,
+        file_name: String,
+        downloaded: u64,
+        total: Option<u64>,
+    },
+    DownloadFinished {
+        dialog_id: i64,
+        message_id: i32,
+        path: PathBuf,
+    },
+    DownloadCancelled {
+        dialog_id: i64,
+        message_id: i32,
+    },
+    DownloadFailed {
+        dialog_id: i64,
+        message_id: i32,
+        error: String,
+    },
+    UploadProgress {
+        dialog_id: i64,
+        uploaded: u64,
+        total: u64,
+    },
+    UploadFinished {
+        dialog_id: i64,
+        message: MessageSummary,
+    },
+    UploadCancelled {
+        dialog_id: i64,
+    },
+    UploadFailed {
+        dialog_id: i64,
+        error: String,
+    },
+    ImagePreviewLoaded {
+        dialog_id: i64,
+        message_id: i32,
+        preview: ImagePreview,
+    },
+    /// A cloud draft was saved by us or changed on another device.
+    DraftChanged {
+        dialog_id: i64,
+        text: String,
+    },
+    MessagesForwarded {
+        dialog_id: i64,
+        messages: Vec<MessageSummary>,
+    },
+    /// `chosen_known` is false when the update does not say which reactions are ours.
+    ReactionsChanged {
+        dialog_id: i64,
+        message_id: i32,
+        reactions: Vec<ReactionCount>,
+        chosen_known: bool,
+    },
+    /// Pinned messages of a chat, newest first.
+    PinnedMessagesLoaded {
+        dialog_id: i64,
+        messages: Vec<MessageSummary>,
+    },
+    TopicsLoaded {
+        dialog_id: i64,
+        topics: Vec<TopicSummary>,
+    },
+    /// Comments of the channel post `message_id` are the thread `top_id` of `discussion_id`.
+    ThreadOpened {
+        dialog_id: i64,
+        message_id: i32,
+        discussion_id: i64,
+        top_id: i32,
+    },
+    /// A reply thread, such as a forum topic, was read up to `max_id` here or elsewhere.
+    ThreadRead {
+        dialog_id: i64,
+        thread_id: i32,
+        max_id: i32,
+    },
+    /// Pinned or unpinned by us or by anyone else in the chat.
+    PinnedMessagesChanged {
+        dialog_id: i64,
+        message_ids: Vec<i32>,
+        pinned: bool,
+    },
     Error(String),
 }
 
@@ -183,6 +746,8 @@ async fn run_request_loop(
     event_tx: mpsc::Sender<TelegramEvent>,
 ) -> anyhow::Result<()> {
     let mut chat_map: HashMap<i64, PeerRef> = HashMap::new();
+    let mut downloads: HashMap<(i64, i32), oneshot::Sender<()>> = HashMap::new();
+    let mut upload: Option<oneshot::Sender<()>> = None;
     let mut dialogs_dirty = false;
     let mut updates = client.stream_updates(
         updates_rx,
@@ -212,13 +777,45 @@ async fn run_request_loop(
                             }
                         }
                     }
-                    TelegramRequest::LoadMessages { dialog_id, limit } => {
-                        let result = load_messages(&client, &chat_map, dialog_id, limit).await;
+                    TelegramRequest::LoadFolders => {
+                        let _ = event_tx.send(folders_event(&client).await).await;
+                    }
+                    TelegramRequest::LoadMessages {
+                        dialog_id,
+                        limit,
+                        thread_id,
+                    } => {
+                        let result =
+                            load_messages(&client, &chat_map, dialog_id, limit, thread_id).await;
                         match result {
                             Ok(messages) => {
                                 let _ = event_tx
                                     .send(TelegramEvent::MessagesLoaded {
                                         dialog_id,
+                                        thread_id,
+                                        messages,
+                                    })
+                                    .await;
+                            }
+                            Err(err) => {
+                                let _ = event_tx.send(TelegramEvent::Error(err.to_string())).await;
+                            }
+                        }
+                    }
+                    TelegramRequest::LoadOlderMessages {
+                        dialog_id,
+                        before_id,
+                        thread_id,
+                    } => {
+                        let result =
+                            load_older_messages(&client, &chat_map, dialog_id, before_id, thread_id)
+                                .await;
+                        match result {
+                            Ok(messages) => {
+                                let _ = event_tx
+                                    .send(TelegramEvent::OlderMessagesLoaded {
+                                        dialog_id,
+                                        thread_id,
                                         messages,
                                     })
                                     .await;
@@ -228,8 +825,12 @@ async fn run_request_loop(
                             }
                         }
                     }
-                    TelegramRequest::SendMessage { dialog_id, text } => {
-                        let result = send_message(&client, &chat_map, dialog_id, &text).await;
+                    TelegramRequest::SendMessage {
+                        dialog_id,
+                        text,
+                        reply_to,
+                    } => {
+                        let result = send_message(&client, &chat_map, dialog_id, text, reply_to).await;
                         match result {
                             Ok(message) => {
                                 let _ = event_tx
@@ -241,108 +842,1612 @@ async fn run_request_loop(
                             }
                         }
                     }
-                    TelegramRequest::Shutdown => break,
-                }
-            }
-            update_result = updates.next() => {
-                match update_result {
-                    Ok(Update::NewMessage(message)) if !message.outgoing() => {
-                        let dialog_id = message.peer_id().bot_api_dialog_id();
-                        let event = TelegramEvent::IncomingMessage {
-                            dialog_id,
-                            message: summarize_message(&message),
-                        };
-                        let _ = event_tx.send(event).await;
-                        dialogs_dirty = true;
+                    TelegramRequest::EditMessage {
+                        dialog_id,
+                        message_id,
+                        text,
+                    } => {
+                        let result = edit_message(&client, &chat_map, dialog_id, message_id, text).await;
+                        match result {
+                            Ok(message) => {
+                                let _ = event_tx
+                                    .send(TelegramEvent::MessageEdited { dialog_id, message })
+                                    .await;
+                            }
+                            Err(err) => {
+                                let _ = event_tx.send(TelegramEvent::Error(err.to_string())).await;
+                            }
+                        }
                     }
-                    Ok(_) => {}
-                    Err(err) => {
-                        let _ = event_tx.send(TelegramEvent::Error(err.to_string())).await;
-                        break;
+                    TelegramRequest::DeleteMessages {
+                        dialog_id,
+                        message_ids,
+                        revoke,
+                    } => {
+                        let result =
+                            delete_messages(&client, &chat_map, dialog_id, &message_ids, revoke).await;
+                        match result {
+                            Ok(()) => {
+                                let _ = event_tx
+                                    .send(TelegramEvent::MessagesDeleted {
+                                        dialog_id,
+                                        message_ids,
+                                    })
+                                    .await;
+                            }
+                            Err(err) => {
+                                let _ = event_tx.send(TelegramEvent::Error(err.to_string())).await;
+                            }
+                        }
                     }
-                }
-            }
-            _ = refresh_tick.tick() => {
-                if !dialogs_dirty {
-                    continue;
-                }
-
-                match load_dialogs(&client, &mut chat_map).await {
-                    Ok(dialogs) => {
-                        let _ = event_tx.send(TelegramEvent::DialogsLoaded(dialogs)).await;
-                        dialogs_dirty = false;
+                    TelegramRequest::MarkRead {
+                        dialog_id,
+                        max_id,
+                        thread_id,
+                    } => {
+                        if let Err(err) =
+                            mark_read(&client, &chat_map, dialog_id, max_id, thread_id).await
+                        {
+                            let _ = event_tx.send(TelegramEvent::Error(err.to_string())).await;
+                        }
+                        // Reading a thread does not report the new unread count of the chat.
+                        if thread_id.is_some() {
+                            dialogs_dirty = true;
+                        }
                     }
-                    Err(err) => {
-                        let _ = event_tx.send(TelegramEvent::Error(err.to_string())).await;
+                    TelegramRequest::SearchMessages { dialog_id, query } => {
+                        let result = search_messages(&client, &mut chat_map, dialog_id, &query).await;
+                        match result {
+                            Ok(results) => {
+                                let _ = event_tx
+                                    .send(TelegramEvent::SearchResultsLoaded { query, results })
+                                    .await;
+                            }
+                            Err(err) => {
+                                let _ = event_tx.send(TelegramEvent::Error(err.to_string())).await;
+                            }
+                        }
                     }
-                }
-            }
-        }
-    }
-
-    Ok(())
-}
-
-async fn load_dialogs(
-    client: &Client,
-    chat_map: &mut HashMap<i64, PeerRef>,
-) -> anyhow::Result<Vec<DialogSummary>> {
-    let mut dialogs = client.iter_dialogs();
-    let mut out = Vec::new();
-    chat_map.clear();
-
-    while let Some(dialog) = dialogs.next().await? {
-        let peer = dialog.peer().clone();
-        let dialog_id = peer.id().bot_api_dialog_id();
-        let title = peer.name().unwrap_or("Unknown").to_string();
-
-        chat_map.insert(dialog_id, PeerRef::from(&peer));
-        out.push(DialogSummary {
+                    TelegramRequest::LoadMessagesAround {
+                        dialog_id,
+                        message_id,
+                    } => {
+                        let result =
+                            load_messages_around(&client, &chat_map, dialog_id, message_id).await;
+                        match result {
+                            Ok((messages, has_newer)) => {
+                                let _ = event_tx
+                                    .send(TelegramEvent::MessagesAroundLoaded {
+                                        dialog_id,
+                                        message_id,
+                                        messages,
+                                        has_newer,
+                                    })
+                                    .await;
+                            }
+                            Err(err) => {
+                                let _ = event_tx.send(TelegramEvent::Error(err.to_string())).await;
+                            }
+                        }
+                    }
+                    TelegramRequest::DownloadMedia {
+                        dialog_id,
+                        message_id,
+                        path,
+                    } => {
+                        // Finished downloads drop their receiver.
+                        downloads.retain(|_, cancel| !cancel.is_closed());
+                        match fetch_media(&client, &chat_map, dialog_id, message_id).await {
+                            Ok(media) => {
+                                let (cancel_tx, cancel_rx) = oneshot::channel();
+                                downloads.insert((dialog_id, message_id), cancel_tx);
+                                tokio::spawn(run_download(
+                                    client.clone(),
+                                    media,
+                                    path,
+                                    (dialog_id, message_id),
+                                    cancel_rx,
+                                    event_tx.clone(),
+                                ));
+                            }
+                            Err(err) => {
+                                let _ = event_tx
+                                    .send(TelegramEvent::DownloadFailed {
+                                        dialog_id,
+                                        message_id,
+                                        error: err.to_string(),
+                                    })
+                                    .await;
+                            }
+                        }
+                    }
+                    TelegramRequest::CancelDownload {
+                        dialog_id,
+                        message_id,
+                    } => {
+                        if let Some(cancel) = downloads.remove(&(dialog_id, message_id)) {
+                            let _ = cancel.send(());
+                        }
+                    }
+                    TelegramRequest::SendFile {
+                        dialog_id,
+                        path,
+                        caption,
+                        reply_to,
+                    } => match chat_map.get(&dialog_id) {
+                        // Replacing the sender of a running upload would cancel it; a finished
+                        // upload drops its receiver.
+                        _ if upload.as_ref().is_some_and(|cancel| !cancel.is_closed()) => {
+                            let _ = event_tx
+                                .send(TelegramEvent::UploadFailed {
+                                    dialog_id,
+                                    error: "another file is still uploading".to_string(),
+                                })
+                                .await;
+                        }
+                        Some(peer) => {
+                            let (cancel_tx, cancel_rx) = oneshot::channel();
+                            upload = Some(cancel_tx);
+                            tokio::spawn(run_upload(
+                                client.clone(),
+                                *peer,
+                                dialog_id,
+                                (path, caption, reply_to),
+                                cancel_rx,
+                                event_tx.clone(),
+                            ));
+                        }
+                        None => {
+                            let _ = event_tx
+                                .send(TelegramEvent::UploadFailed {
+                                    dialog_id,
+                                    error: "selected chat is not available in cache".to_string(),
+                                })
+                                .await;
+                        }
+                    },
+                    TelegramRequest::CancelUpload => {
+                        if let Some(cancel) = upload.take() {
+                            let _ = cancel.send(());
+                        }
+                    }
+                    TelegramRequest::LoadImagePreview {
+                        dialog_id,
+                        message_id,
+                    } => {
+                        if let Some(peer) = chat_map.get(&dialog_id) {
+                            tokio::spawn(run_image_preview(
+                                client.clone(),
+                                *peer,
+                                (dialog_id, message_id),
+                                event_tx.clone(),
+                            ));
+                        }
+                    }
+                    TelegramRequest::SaveDraft { dialog_id, text } => {
+                        let event = match save_draft(&client, &chat_map, dialog_id, &text).await {
+                            Ok(()) => TelegramEvent::DraftChanged { dialog_id, text },
+                            Err(err) => TelegramEvent::Error(err.to_string()),
+                        };
+                        let _ = event_tx.send(event).await;
+                    }
+                    TelegramRequest::ForwardMessages {
+                        from_dialog_id,
+                        message_ids,
+                        to_dialog_id,
+                        drop_author,
+                        drop_captions,
+                    } => {
+                        let result = forward_messages(
+                            &client,
+                            &chat_map,
+                            (from_dialog_id, &message_ids),
+                            to_dialog_id,
+                            drop_author,
+                            drop_captions,
+                        )
+                        .await;
+                        let event = match result {
+                            Ok(messages) => TelegramEvent::MessagesForwarded {
+                                dialog_id: to_dialog_id,
+                                messages,
+                            },
+                            Err(err) => TelegramEvent::Error(err.to_string()),
+                        };
+                        let _ = event_tx.send(event).await;
+                        dialogs_dirty = true;
+                    }
+                    TelegramRequest::SendReaction {
+                        dialog_id,
+                        message_id,
+                        emoji,
+                    } => {
+                        let result =
+                            send_reaction(&client, &chat_map, dialog_id, message_id, emoji).await;
+                        let event = match result {
+                            Ok(reactions) => TelegramEvent::ReactionsChanged {
+                                dialog_id,
+                                message_id,
+                                reactions,
+                                chosen_known: true,
+                            },
+                            Err(err) => TelegramEvent::Error(err.to_string()),
+                        };
+                        let _ = event_tx.send(event).await;
+                    }
+                    TelegramRequest::LoadPinnedMessages { dialog_id } => {
+                        let event = match load_pinned_messages(&client, &chat_map, dialog_id).await {
+                            Ok(messages) => TelegramEvent::PinnedMessagesLoaded {
+                                dialog_id,
+                                messages,
+                            },
+                            Err(err) => TelegramEvent::Error(err.to_string()),
+                        };
+                        let _ = event_tx.send(event).await;
+                    }
+                    TelegramRequest::OpenThread {
+                        dialog_id,
+                        message_id,
+                    } => {
+                        let event =
+                            match open_thread(&client, &mut chat_map, dialog_id, message_id).await {
+                                Ok((discussion_id, top_id)) => TelegramEvent::ThreadOpened {
+                                    dialog_id,
+                                    message_id,
+                                    discussion_id,
+                                    top_id,
+                                },
+                                Err(err) => TelegramEvent::Error(err.to_string()),
+                            };
+                        let _ = event_tx.send(event).await;
+                    }
+                    TelegramRequest::LoadTopics { dialog_id } => {
+                        let event = match load_topics(&client, &chat_map, dialog_id).await {
+                            Ok(topics) => TelegramEvent::TopicsLoaded { dialog_id, topics },
+                            Err(err) => TelegramEvent::Error(err.to_string()),
+                        };
+                        let _ = event_tx.send(event).await;
+                    }
+                    TelegramRequest::PinMessage {
+                        dialog_id,
+                        message_id,
+                        notify,
+                    } => {
+                        let result =
+                            update_pinned(&client, &chat_map, dialog_id, message_id, Some(notify)).await;
+                        let event = match result {
+                            Ok(()) => TelegramEvent::PinnedMessagesChanged {
+                                dialog_id,
+                                message_ids: vec![message_id],
+                                pinned: true,
+                            },
+                            Err(err) => TelegramEvent::Error(err.to_string()),
+                        };
+                        let _ = event_tx.send(event).await;
+                    }
+                    TelegramRequest::UnpinMessage {
+                        dialog_id,
+                        message_id,
+                    } => {
+                        let result = update_pinned(&client, &chat_map, dialog_id, message_id, None).await;
+                        let event = match result {
+                            Ok(()) => TelegramEvent::PinnedMessagesChanged {
+                                dialog_id,
+                                message_ids: vec![message_id],
+                                pinned: false,
+                            },
+                            Err(err) => TelegramEvent::Error(err.to_string()),
+                        };
+                        let _ = event_tx.send(event).await;
+                    }
+                    TelegramRequest::Shutdown => break,
+                }
+            }
+            update_result = updates.next() => {
+                match update_result {
+                    Ok(Update::NewMessage(message)) => {
+                        let dialog_id = message.peer_id().bot_api_dialog_id();
+                        let summary = summarize_message(&message);
+                        let event = if message.outgoing() {
+                            TelegramEvent::OutgoingMessage {
+                                dialog_id,
+                                message: summary,
+                            }
+                        } else {
+                            TelegramEvent::IncomingMessage {
+                                dialog_id,
+                                message: summary,
+                            }
+                        };
+                        let _ = event_tx.send(event).await;
+                        dialogs_dirty = true;
+                    }
+                    Ok(Update::MessageEdited(message)) => {
+                        let dialog_id = message.peer_id().bot_api_dialog_id();
+                        let event = TelegramEvent::IncomingMessageEdited {
+                            dialog_id,
+                            message: summarize_message(&message),
+                        };
+                        let _ = event_tx.send(event).await;
+                    }
+                    Ok(Update::MessageDeleted(deletion)) => {
+                        let dialog_ids = match deletion.channel_id() {
+                            Some(channel_id) => vec![PeerId::channel(channel_id).bot_api_dialog_id()],
+                            None => chat_map
+                                .iter()
+                                .filter(|(_, peer)| peer.id.kind() != PeerKind::Channel)
+                                .map(|(dialog_id, _)| *dialog_id)
+                                .collect(),
+                        };
+                        let event = TelegramEvent::IncomingMessagesDeleted {
+                            dialog_ids,
+                            message_ids: deletion.into_messages(),
+                        };
+                        let _ = event_tx.send(event).await;
+                        dialogs_dirty = true;
+                    }
+                    Ok(Update::Raw(raw)) => {
+                        if is_folders_update(&raw.raw) {
+                            let _ = event_tx.send(folders_event(&client).await).await;
+                        } else if let Some(event) = read_state_event(&raw.raw)
+                            .or_else(|| thread_read_event(&raw.raw))
+                            .or_else(|| draft_event(&raw.raw))
+                            .or_else(|| reactions_event(&raw.raw))
+                            .or_else(|| pinned_event(&raw.raw))
+                        {
+                            let _ = event_tx.send(event).await;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        let _ = event_tx.send(TelegramEvent::Error(err.to_string())).await;
+                        break;
+                    }
+                }
+            }
+            _ = refresh_tick.tick() => {
+                if !dialogs_dirty {
+                    continue;
+                }
+
+                match load_dialogs(&client, &mut chat_map).await {
+                    Ok(dialogs) => {
+                        let _ = event_tx.send(TelegramEvent::DialogsLoaded(dialogs)).await;
+                        dialogs_dirty = false;
+                    }
+                    Err(err) => {
+                        let _ = event_tx.send(TelegramEvent::Error(err.to_string())).await;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn load_dialogs(
+    client: &Client,
+    chat_map: &mut HashMap<i64, PeerRef>,
+) -> anyhow::Result<Vec<DialogSummary>> {
+    let mut dialogs = client.iter_dialogs();
+    let mut out = Vec::new();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64);
+
+    while let Some(dialog) = dialogs.next().await? {
+        let peer = dialog.peer().clone();
+        let dialog_id = peer.id().bot_api_dialog_id();
+        let title = peer.name().unwrap_or("Unknown").to_string();
+
+        // Chats outside the list, such as discussion groups of open comments, stay reachable.
+        chat_map.insert(dialog_id, PeerRef::from(&peer));
+        let mut summary = DialogSummary {
             id: dialog_id,
             title,
+            forum: matches!(&peer, Peer::Group(group)
+                if matches!(&group.raw, tl::enums::Chat::Channel(channel) if channel.forum)),
+            kind: match &peer {
+                Peer::User(user) if user.is_bot() => DialogKind::Bot,
+                Peer::User(_) => DialogKind::User,
+                Peer::Group(group) if matches!(group.raw, tl::enums::Chat::Channel(_)) => {
+                    DialogKind::Supergroup
+                }
+                Peer::Group(_) => DialogKind::Group,
+                Peer::Channel(_) => DialogKind::Channel,
+            },
+            contact: matches!(&peer, Peer::User(user) if user.contact()),
+            last_message: dialog
+                .last_message
+                .as_ref()
+                .map(|message| LastMessage::from(&summarize_message(message))),
+            ..DialogSummary::default()
+        };
+        if let tl::enums::Dialog::Dialog(raw) = &dialog.raw {
+            let tl::enums::PeerNotifySettings::Settings(notify) = &raw.notify_settings;
+            summary.muted = notify
+                .mute_until
+                .is_some_and(|until| i64::from(until) > now);
+            summary.archived = raw.folder_id == Some(ARCHIVE_FOLDER_ID);
+            summary.pinned = raw.pinned;
+            summary.unread_count = usize::try_from(raw.unread_count).unwrap_or(0);
+            summary.unread_mentions_count = usize::try_from(raw.unread_mentions_count).unwrap_or(0);
+            summary.read_inbox_max_id = raw.read_inbox_max_id;
+            summary.read_outbox_max_id = raw.read_outbox_max_id;
+            summary.draft = raw.draft.as_ref().map(draft_text).unwrap_or_default();
+        }
+        out.push(summary);
+    }
+
+    Ok(out)
+}
+
+async fn folders_event(client: &Client) -> TelegramEvent {
+    match load_folders(client).await {
+        Ok(folders) => TelegramEvent::FoldersLoaded(folders),
+        Err(err) => TelegramEvent::Error(err.to_string()),
+    }
+}
+
+/// The "All chats" entry is left out; the app always shows it first.
+async fn load_folders(client: &Client) -> anyhow::Result<Vec<ChatFolder>> {
+    let tl::enums::messages::DialogFilters::Filters(filters) = client
+        .invoke(&tl::functions::messages::GetDialogFilters {})
+        .await
+        .context("load chat folders")?;
+
+    // Saved Messages can be in a folder; its chat id is our own user id.
+    let needs_self = filters.filters.iter().any(|filter| {
+        let peers = match filter {
+            tl::enums::DialogFilter::Filter(filter) => &filter.include_peers,
+            tl::enums::DialogFilter::Chatlist(filter) => &filter.include_peers,
+            tl::enums::DialogFilter::Default => return false,
+        };
+        peers
+            .iter()
+            .any(|peer| matches!(peer, tl::enums::InputPeer::PeerSelf))
+    });
+    let self_id = if needs_self {
+        let me = client.get_me().await.context("load own account")?;
+        Some(PeerId::user(me.bare_id()).bot_api_dialog_id())
+    } else {
+        None
+    };
+    let ids = |peers: &[tl::enums::InputPeer]| -> Vec<i64> {
+        peers
+            .iter()
+            .filter_map(|peer| input_peer_dialog_id(peer, self_id))
+            .collect()
+    };
+
+    Ok(filters
+        .filters
+        .into_iter()
+        .filter_map(|filter| match filter {
+            tl::enums::DialogFilter::Filter(filter) => {
+                let tl::enums::TextWithEntities::Entities(title) = filter.title;
+                Some(ChatFolder {
+                    id: filter.id,
+                    title: title.text,
+                    contacts: filter.contacts,
+                    non_contacts: filter.non_contacts,
+                    groups: filter.groups,
+                    broadcasts: filter.broadcasts,
+                    bots: filter.bots,
+                    exclude_muted: filter.exclude_muted,
+                    exclude_read: filter.exclude_read,
+                    exclude_archived: filter.exclude_archived,
+                    include: ids(&[filter.pinned_peers, filter.include_peers].concat()),
+                    exclude: ids(&filter.exclude_peers),
+                })
+            }
+            // Shared folders hold exactly the chats listed.
+            tl::enums::DialogFilter::Chatlist(filter) => {
+                let tl::enums::TextWithEntities::Entities(title) = filter.title;
+                Some(ChatFolder {
+                    id: filter.id,
+                    title: title.text,
+                    include: ids(&[filter.pinned_peers, filter.include_peers].concat()),
+                    ..ChatFolder::default()
+                })
+            }
+            tl::enums::DialogFilter::Default => None,
+        })
+        .collect())
+}
+
+fn input_peer_dialog_id(peer: &tl::enums::InputPeer, self_id: Option<i64>) -> Option<i64> {
+    let peer_id = match peer {
+        tl::enums::InputPeer::User(user) => PeerId::user(user.user_id),
+        tl::enums::InputPeer::UserFromMessage(user) => PeerId::user(user.user_id),
+        tl::enums::InputPeer::Chat(chat) => PeerId::chat(chat.chat_id),
+        tl::enums::InputPeer::Channel(channel) => PeerId::channel(channel.channel_id),
+        tl::enums::InputPeer::ChannelFromMessage(channel) => PeerId::channel(channel.channel_id),
+        tl::enums::InputPeer::PeerSelf => return self_id,
+        tl::enums::InputPeer::Empty => return None,
+    };
+    Some(peer_id.bot_api_dialog_id())
+}
+
+fn is_folders_update(update: &tl::enums::Update) -> bool {
+    matches!(
+        update,
+        tl::enums::Update::DialogFilter(_)
+            | tl::enums::Update::DialogFilterOrder(_)
+            | tl::enums::Update::DialogFilters
+    )
+}
+
+async fn load_messages(
+    client: &Client,
+    chat_map: &HashMap<i64, PeerRef>,
+    dialog_id: i64,
+    limit: usize,
+    thread_id: Option<i32>,
+) -> anyhow::Result<Vec<MessageSummary>> {
+    let peer = chat_map
+        .get(&dialog_id)
+        .ok_or_else(|| anyhow!("selected chat is not available in cache"))?;
+    match thread_id {
+        Some(thread_id) => load_thread_page(client, *peer, thread_id, 0, limit).await,
+        None => load_history_page(client, *peer, 0, limit).await,
+    }
+}
+
+async fn load_older_messages(
+    client: &Client,
+    chat_map: &HashMap<i64, PeerRef>,
+    dialog_id: i64,
+    before_id: i32,
+    thread_id: Option<i32>,
+) -> anyhow::Result<Vec<MessageSummary>> {
+    let peer = chat_map
+        .get(&dialog_id)
+        .ok_or_else(|| anyhow!("selected chat is not available in cache"))?;
+    match thread_id {
+        Some(thread_id) => {
+            load_thread_page(
+                client,
+                *peer,
+                thread_id,
+                before_id,
+                OLDER_MESSAGES_PAGE_SIZE,
+            )
+            .await
+        }
+        None => load_history_page(client, *peer, before_id, OLDER_MESSAGES_PAGE_SIZE).await,
+    }
+}
+
+async fn load_messages_around(
+    client: &Client,
+    chat_map: &HashMap<i64, PeerRef>,
+    dialog_id: i64,
+    message_id: i32,
+) -> anyhow::Result<(Vec<MessageSummary>, bool)> {
+    let peer = *chat_map
+        .get(&dialog_id)
+        .ok_or_else(|| anyhow!("selected chat is not available in cache"))?;
+
+    // A negative `add_offset` makes the server also return messages newer than `offset_id`.
+    let half_page = MESSAGES_AROUND_HALF_PAGE as i32;
+    let history = client
+        .invoke(&tl::functions::messages::GetHistory {
+            peer: peer.into(),
+            offset_id: message_id,
+            offset_date: 0,
+            add_offset: -half_page,
+            limit: half_page * 2,
+            max_id: 0,
+            min_id: 0,
+            hash: 0,
+        })
+        .await
+        .context("load messages around search result")?;
+    let messages = summarize_history(client, peer, history);
+
+    let newer_count = messages
+        .iter()
+        .filter(|message| message.id > message_id)
+        .count();
+    Ok((messages, newer_count + 1 >= MESSAGES_AROUND_HALF_PAGE))
+}
+
+async fn search_messages(
+    client: &Client,
+    chat_map: &mut HashMap<i64, PeerRef>,
+    dialog_id: Option<i64>,
+    query: &str,
+) -> anyhow::Result<Vec<MessageSearchResult>> {
+    let mut found = Vec::new();
+    match dialog_id {
+        Some(dialog_id) => {
+            let peer = chat_map
+                .get(&dialog_id)
+                .ok_or_else(|| anyhow!("selected chat is not available in cache"))?;
+            let mut iter = client
+                .search_messages(*peer)
+                .query(query)
+                .limit(SEARCH_RESULTS_LIMIT);
+            while let Some(message) = iter.next().await.context("search chat messages")? {
+                found.push(message);
+            }
+        }
+        None => {
+            let mut iter = client
+                .search_all_messages()
+                .query(query)
+                .limit(SEARCH_RESULTS_LIMIT);
+            while let Some(message) = iter.next().await.context("search all messages")? {
+                found.push(message);
+            }
+        }
+    }
+
+    let results = found
+        .iter()
+        .map(|message| {
+            let dialog_id = message.peer_id().bot_api_dialog_id();
+            let dialog_title = match message.peer() {
+                Ok(peer) => {
+                    // Global results may come from chats that were not part of the last dialog load.
+                    chat_map
+                        .entry(dialog_id)
+                        .or_insert_with(|| PeerRef::from(peer));
+                    peer.name().unwrap_or("Unknown").to_string()
+                }
+                Err(_) => "Unknown".to_string(),
+            };
+            MessageSearchResult {
+                dialog_id,
+                dialog_title,
+                message: summarize_message(message),
+            }
+        })
+        .collect();
+
+    Ok(results)
+}
+
+async fn fetch_media(
+    client: &Client,
+    chat_map: &HashMap<i64, PeerRef>,
+    dialog_id: i64,
+    message_id: i32,
+) -> anyhow::Result<Media> {
+    let peer = chat_map
+        .get(&dialog_id)
+        .ok_or_else(|| anyhow!("selected chat is not available in cache"))?;
+
+    client
+        .get_messages_by_id(*peer, &[message_id])
+        .await
+        .context("fetch message to download")?
+        .pop()
+        .flatten()
+        .ok_or_else(|| anyhow!("message is no longer available"))?
+        .media()
+        .filter(|media| media_file_name(media, message_id).is_some())
+        .ok_or_else(|| anyhow!("message has no downloadable media"))
+}
+
+/// Streams `media` into `dir` through a `.part` file, which is renamed once complete and removed
+/// when the download is cancelled or fails.
+async fn run_download(
+    client: Client,
+    media: Media,
+    dir: PathBuf,
+    (dialog_id, message_id): (i64, i32),
+    mut cancel_rx: oneshot::Receiver<()>,
+    event_tx: mpsc::Sender<TelegramEvent>,
+) {
+    let file_name = media_file_name(&media, message_id).unwrap_or_default();
+    let target = unique_download_path(&dir, &file_name);
+    let mut part_path = target.clone().into_os_string();
+    part_path.push(".part");
+    let part_path = PathBuf::from(part_path);
+
+    let result = tokio::select! {
+        result = write_download(
+            &client,
+            &media,
+            &part_path,
+            &file_name,
+            (dialog_id, message_id),
+            &event_tx,
+        ) => result,
+        // Also resolves when the request loop shuts down and drops the sender.
+        _ = &mut cancel_rx => {
+            let _ = fs::remove_file(&part_path).await;
+            let _ = event_tx
+                .send(TelegramEvent::DownloadCancelled {
+                    dialog_id,
+                    message_id,
+                })
+                .await;
+            return;
+        }
+    };
+
+    let result = match result {
+        Ok(()) => fs::rename(&part_path, &target)
+            .await
+            .context("move finished download into place"),
+        Err(err) => Err(err),
+    };
+    let event = match result {
+        Ok(()) => TelegramEvent::DownloadFinished {
+            dialog_id,
+            message_id,
+            path: target,
+        },
+        Err(err) => {
+            let _ = fs::remove_file(&part_path).await;
+            TelegramEvent::DownloadFailed {
+                dialog_id,
+                message_id,
+                error: format!("{err:#}"),
+            }
+        }
+    };
+    let _ = event_tx.send(event).await;
+}
+
+async fn write_download(
+    client: &Client,
+    media: &Media,
+    part_path: &Path,
+    file_name: &str,
+    (dialog_id, message_id): (i64, i32),
+    event_tx: &mpsc::Sender<TelegramEvent>,
+) -> anyhow::Result<()> {
+    let total = media_size(media);
+    let progress = |downloaded| TelegramEvent::DownloadProgress {
+        dialog_id,
+        message_id,
+        file_name: file_name.to_string(),
+        downloaded,
+        total,
+    };
+
+    if let Some(dir) = part_path.parent() {
+        fs::create_dir_all(dir)
+            .await
+            .context("create downloads directory")?;
+    }
+    let mut file = fs::File::create(part_path)
+        .await
+        .context("create download file")?;
+    let _ = event_tx.send(progress(0)).await;
+
+    let mut download = client.iter_download(media);
+    let mut downloaded = 0;
+    while let Some(chunk) = download.next().await.context("download media")? {
+        file.write_all(&chunk)
+            .await
+            .context("write download file")?;
+        downloaded += chunk.len() as u64;
+        let _ = event_tx.send(progress(downloaded)).await;
+    }
+    file.flush().await.context("write download file")?;
+
+    Ok(())
+}
+
+fn media_size(media: &Media) -> Option<u64> {
+    let size = match media {
+        Media::Photo(photo) => photo.size(),
+        Media::Document(document) => document.size(),
+        Media::Sticker(sticker) => sticker.document.size(),
+        _ => 0,
+    };
+    u64::try_from(size).ok().filter(|size| *size > 0)
+}
+
+/// File name to save a message's media under, or `None` when the media is not a file.
+fn media_file_name(media: &Media, message_id: i32) -> Option<String> {
+    let (name, mime_type) = match media {
+        Media::Photo(_) => return Some(format!("photo_{message_id}.jpg")),
+        Media::Document(document) => (document.name(), document.mime_type()),
+        Media::Sticker(sticker) => (sticker.document.name(), sticker.document.mime_type()),
+        _ => return None,
+    };
+    Some(download_file_name(name, mime_type, message_id))
+}
+
+/// The sender's file name, or one made of the message id and MIME type when it is unusable.
+/// Names come from the sender, so only their last path component is trusted, on any platform.
+fn download_file_name(name: &str, mime_type: Option<&str>, message_id: i32) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default().trim();
+    if !matches!(name, "" | "." | "..") {
+        return name.to_string();
+    }
+
+    let extension = match mime_type.unwrap_or_default() {
+        "image/jpeg" => ".jpg",
+        "image/png" => ".png",
+        "image/webp" => ".webp",
+        "image/gif" => ".gif",
+        "video/mp4" => ".mp4",
+        "video/webm" => ".webm",
+        "audio/ogg" => ".ogg",
+        "audio/mpeg" => ".mp3",
+        "application/pdf" => ".pdf",
+        "application/x-tgsticker" => ".tgs",
+        _ => "",
+    };
+    format!("file_{message_id}{extension}")
+}
+
+/// Picks `dir/name`, or `dir/name (N)` when a file or pending download with that name exists.
+fn unique_download_path(dir: &Path, name: &str) -> PathBuf {
+    let taken = |path: &Path| {
+        let mut part = path.as_os_str().to_owned();
+        part.push(".part");
+        path.exists() || Path::new(&part).exists()
+    };
+
+    let candidate = dir.join(name);
+    if !taken(&candidate) {
+        return candidate;
+    }
+
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{extension}")),
+        _ => (name, String::new()),
+    };
+    (1..)
+        .map(|n| dir.join(format!("{stem} ({n}){extension}")))
+        .find(|path| !taken(path))
+        .unwrap_or(candidate)
+}
+
+/// Previews are best effort: a failure is only logged and the message keeps its descriptor.
+async fn run_image_preview(
+    client: Client,
+    peer: PeerRef,
+    (dialog_id, message_id): (i64, i32),
+    event_tx: mpsc::Sender<TelegramEvent>,
+) {
+    match load_image_preview(&client, peer, message_id).await {
+        Ok(preview) => {
+            let _ = event_tx
+                .send(TelegramEvent::ImagePreviewLoaded {
+                    dialog_id,
+                    message_id,
+                    preview,
+                })
+                .await;
+        }
+        Err(err) => warn!("failed to load image preview: {err:#}"),
+    }
+}
+
+async fn load_image_preview(
+    client: &Client,
+    peer: PeerRef,
+    message_id: i32,
+) -> anyhow::Result<ImagePreview> {
+    let message = client
+        .get_messages_by_id(peer, &[message_id])
+        .await
+        .context("fetch message for preview")?
+        .pop()
+        .flatten()
+        .ok_or_else(|| anyhow!("message is no longer available"))?;
+    let Some(Media::Photo(photo)) = message.media() else {
+        return Err(anyhow!("message has no photo"));
+    };
+    let thumbs = photo.thumbs();
+    let thumb = thumbs
+        .iter()
+        .find(|thumb| thumb.photo_type() == PREVIEW_THUMB_TYPE)
+        .or_else(|| {
+            thumbs
+                .iter()
+                .filter(|thumb| matches!(thumb, PhotoSize::Size(_) | PhotoSize::Progressive(_)))
+                .min_by_key(|thumb| thumb.size())
+        })
+        .ok_or_else(|| anyhow!("photo has no thumbnail"))?;
+
+    let mut source = Vec::new();
+    let mut download = client.iter_download(thumb);
+    while let Some(chunk) = download.next().await.context("download thumbnail")? {
+        source.extend(chunk);
+    }
+
+    tokio::task::spawn_blocking(move || -> anyhow::Result<ImagePreview> {
+        let image = image::load_from_memory(&source)
+            .context("decode thumbnail")?
+            .thumbnail(MAX_PREVIEW_PIXELS, MAX_PREVIEW_PIXELS)
+            .into_rgb8();
+        Ok(ImagePreview {
+            width: image.width(),
+            height: image.height(),
+            rgb: image.into_raw(),
+            source,
+        })
+    })
+    .await
+    .context("join thumbnail decoder")?
+}
+
+/// Uploads and sends a file, reporting progress until it is sent, cancelled or fails.
+async fn run_upload(
+    client: Client,
+    peer: PeerRef,
+    dialog_id: i64,
+    (path, caption, reply_to): (PathBuf, FormattedText, Option<i32>),
+    mut cancel_rx: oneshot::Receiver<()>,
+    event_tx: mpsc::Sender<TelegramEvent>,
+) {
+    let sent = tokio::select! {
+        result = send_file(&client, peer, dialog_id, &path, caption, reply_to, &event_tx) => result,
+        // Also resolves when the request loop shuts down and drops the sender.
+        _ = &mut cancel_rx => {
+            let _ = event_tx.send(TelegramEvent::UploadCancelled { dialog_id }).await;
+            return;
+        }
+    };
+
+    let event = match sent {
+        Ok(message) => TelegramEvent::UploadFinished { dialog_id, message },
+        Err(err) => TelegramEvent::UploadFailed {
+            dialog_id,
+            error: format!("{err:#}"),
+        },
+    };
+    let _ = event_tx.send(event).await;
+}
+
+async fn send_file(
+    client: &Client,
+    peer: PeerRef,
+    dialog_id: i64,
+    path: &Path,
+    caption: FormattedText,
+    reply_to: Option<i32>,
+    event_tx: &mpsc::Sender<TelegramEvent>,
+) -> anyhow::Result<MessageSummary> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("{} is not a file", path.display()))?
+        .to_string();
+    let file = fs::File::open(path)
+        .await
+        .with_context(|| format!("open {}", path.display()))?;
+    let metadata = file.metadata().await.context("read file metadata")?;
+    if !metadata.is_file() {
+        return Err(anyhow!("{} is not a file", path.display()));
+    }
+    let total = metadata.len();
+
+    let uploaded = Arc::new(AtomicU64::new(0));
+    let mut reader = CountingReader {
+        inner: file,
+        read: Arc::clone(&uploaded),
+    };
+    let upload = client.upload_stream(&mut reader, total as usize, name);
+    tokio::pin!(upload);
+    let mut progress = interval(UPLOAD_PROGRESS_INTERVAL);
+    let uploaded_file = loop {
+        tokio::select! {
+            result = &mut upload => break result.context("upload file")?,
+            _ = progress.tick() => {
+                let _ = event_tx
+                    .send(TelegramEvent::UploadProgress {
+                        dialog_id,
+                        uploaded: uploaded.load(Ordering::Relaxed),
+                        total,
+                    })
+                    .await;
+            }
+        }
+    };
+
+    let input = input_message(caption).reply_to(reply_to);
+    let input = if is_photo_upload(path, total) {
+        input.photo(uploaded_file)
+    } else {
+        input.document(uploaded_file)
+    };
+    let sent = client
+        .send_message(peer, input)
+        .await
+        .context("send file")?;
+
+    Ok(summarize_message(&sent))
+}
+
+fn is_photo_upload(path: &Path, size: u64) -> bool {
+    let is_image = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            PHOTO_EXTENSIONS
+                .iter()
+                .any(|photo| photo.eq_ignore_ascii_case(extension))
         });
+    is_image && size <= MAX_PHOTO_UPLOAD_SIZE
+}
+
+/// Counts the bytes handed to the uploader, which reads the file one part at a time.
+struct CountingReader<R> {
+    inner: R,
+    read: Arc<AtomicU64>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        self.read.fetch_add(read as u64, Ordering::Relaxed);
+        poll
     }
+}
 
-    Ok(out)
+async fn send_message(
+    client: &Client,
+    chat_map: &HashMap<i64, PeerRef>,
+    dialog_id: i64,
+    text: FormattedText,
+    reply_to: Option<i32>,
+) -> anyhow::Result<MessageSummary> {
+    let peer = chat_map
+        .get(&dialog_id)
+        .ok_or_else(|| anyhow!("selected chat is not available in cache"))?;
+
+    let input = input_message(text).reply_to(reply_to);
+    let sent = client
+        .send_message(*peer, input)
+        .await
+        .context("send message")?;
+
+    Ok(summarize_message(&sent))
 }
 
-async fn load_messages(
+async fn edit_message(
     client: &Client,
     chat_map: &HashMap<i64, PeerRef>,
     dialog_id: i64,
-    limit: usize,
-) -> anyhow::Result<Vec<MessageSummary>> {
+    message_id: i32,
+    text: FormattedText,
+) -> anyhow::Result<MessageSummary> {
     let peer = chat_map
         .get(&dialog_id)
         .ok_or_else(|| anyhow!("selected chat is not available in cache"))?;
 
-    let mut iter = client.iter_messages(*peer).limit(limit);
-    let mut messages = Vec::new();
+    client
+        .edit_message(*peer, message_id, input_message(text))
+        .await
+        .context("edit message")?;
+
+    let edited = client
+        .get_messages_by_id(*peer, &[message_id])
+        .await
+        .context("fetch edited message")?
+        .pop()
+        .flatten()
+        .ok_or_else(|| anyhow!("edited message is no longer available"))?;
+
+    Ok(summarize_message(&edited))
+}
+
+async fn delete_messages(
+    client: &Client,
+    chat_map: &HashMap<i64, PeerRef>,
+    dialog_id: i64,
+    message_ids: &[i32],
+    revoke: bool,
+) -> anyhow::Result<()> {
+    let peer = chat_map
+        .get(&dialog_id)
+        .ok_or_else(|| anyhow!("selected chat is not available in cache"))?;
 
-    while let Some(msg) = iter.next().await? {
-        messages.push(summarize_message(&msg));
+    // The app only offers "for me" elsewhere.
+    if !revoke && peer.id.kind() == PeerKind::Channel {
+        return Err(anyhow!(DELETE_FOR_ME_UNSUPPORTED));
+    }
+    // `Client::delete_messages` always revokes.
+    if revoke {
+        client
+            .delete_messages(*peer, message_ids)
+            .await
+            .context("delete messages")?;
+    } else {
+        client
+            .invoke(&tl::functions::messages::DeleteMessages {
+                revoke: false,
+                id: message_ids.to_vec(),
+            })
+            .await
+            .context("delete messages")?;
     }
 
-    messages.reverse();
-    Ok(messages)
+    Ok(())
 }
 
-async fn send_message(
+async fn mark_read(
+    client: &Client,
+    chat_map: &HashMap<i64, PeerRef>,
+    dialog_id: i64,
+    max_id: i32,
+    thread_id: Option<i32>,
+) -> anyhow::Result<()> {
+    let peer = *chat_map
+        .get(&dialog_id)
+        .ok_or_else(|| anyhow!("selected chat is not available in cache"))?;
+
+    if let Some(thread_id) = thread_id {
+        client
+            .invoke(&tl::functions::messages::ReadDiscussion {
+                peer: peer.into(),
+                msg_id: thread_id,
+                read_max_id: max_id,
+            })
+            .await
+            .context("mark thread as read")?;
+    } else if peer.id.kind() == PeerKind::Channel {
+        client
+            .invoke(&tl::functions::channels::ReadHistory {
+                channel: peer.into(),
+                max_id,
+            })
+            .await
+            .context("mark channel history as read")?;
+    } else {
+        client
+            .invoke(&tl::functions::messages::ReadHistory {
+                peer: peer.into(),
+                max_id,
+            })
+            .await
+            .context("mark history as read")?;
+    }
+
+    Ok(())
+}
+
+async fn save_draft(
     client: &Client,
     chat_map: &HashMap<i64, PeerRef>,
     dialog_id: i64,
     text: &str,
-) -> anyhow::Result<MessageSummary> {
+) -> anyhow::Result<()> {
+    let peer = *chat_map
+        .get(&dialog_id)
+        .ok_or_else(|| anyhow!("chat of the draft is not available in cache"))?;
+
+    client
+        .invoke(&tl::functions::messages::SaveDraft {
+            no_webpage: false,
+            invert_media: false,
+            reply_to: None,
+            peer: peer.into(),
+            message: text.to_string(),
+            entities: None,
+            media: None,
+            effect: None,
+            suggested_post: None,
+        })
+        .await
+        .context("save draft")?;
+    Ok(())
+}
+
+/// Returns the new messages in the destination chat, oldest first.
+async fn forward_messages(
+    client: &Client,
+    chat_map: &HashMap<i64, PeerRef>,
+    (from_dialog_id, message_ids): (i64, &[i32]),
+    to_dialog_id: i64,
+    drop_author: bool,
+    drop_captions: bool,
+) -> anyhow::Result<Vec<MessageSummary>> {
+    let from_peer = *chat_map
+        .get(&from_dialog_id)
+        .ok_or_else(|| anyhow!("selected chat is not available in cache"))?;
+    let to_peer = *chat_map
+        .get(&to_dialog_id)
+        .ok_or_else(|| anyhow!("destination chat is not available in cache"))?;
+
+    let updates = client
+        .invoke(&tl::functions::messages::ForwardMessages {
+            silent: false,
+            background: false,
+            with_my_score: false,
+            drop_author: drop_author || drop_captions,
+            drop_media_captions: drop_captions,
+            noforwards: false,
+            allow_paid_floodskip: false,
+            from_peer: from_peer.into(),
+            id: message_ids.to_vec(),
+            random_id: random_ids(message_ids.len()),
+            to_peer: to_peer.into(),
+            top_msg_id: None,
+            reply_to: None,
+            schedule_date: None,
+            send_as: None,
+            quick_reply_shortcut: None,
+            video_timestamp: None,
+            allow_paid_stars: None,
+            suggested_post: None,
+        })
+        .await
+        .context("forward messages")?;
+
+    let (updates, users, chats) = match updates {
+        tl::enums::Updates::Updates(u) => (u.updates, u.users, u.chats),
+        tl::enums::Updates::Combined(u) => (u.updates, u.users, u.chats),
+        _ => (Vec::new(), Vec::new(), Vec::new()),
+    };
+    let peers = PeerMap::new(users, chats);
+    let mut messages: Vec<MessageSummary> = updates
+        .into_iter()
+        .filter_map(|update| match update {
+            tl::enums::Update::NewMessage(update) => Some(update.message),
+            tl::enums::Update::NewChannelMessage(update) => Some(update.message),
+            _ => None,
+        })
+        .map(|raw| summarize_message(&Message::from_raw(client, raw, Some(to_peer), &peers)))
+        .collect();
+    messages.sort_by_key(|message| message.id);
+    Ok(messages)
+}
+
+/// Telegram only needs these to be unique per sent message; a randomly keyed hasher will do.
+fn random_ids(count: usize) -> Vec<i64> {
+    let state = RandomState::new();
+    (0..count)
+        .map(|index| state.hash_one(index) as i64)
+        .collect()
+}
+
+async fn send_reaction(
+    client: &Client,
+    chat_map: &HashMap<i64, PeerRef>,
+    dialog_id: i64,
+    message_id: i32,
+    emoji: Option<String>,
+) -> anyhow::Result<Vec<ReactionCount>> {
+    let peer = *chat_map
+        .get(&dialog_id)
+        .ok_or_else(|| anyhow!("selected chat is not available in cache"))?;
+
+    let reactions = emoji.map_or_else(InputReactions::remove, InputReactions::emoticon);
+    client
+        .send_reactions(peer, message_id, reactions)
+        .await
+        .context("send reaction")?;
+
+    let message = client
+        .get_messages_by_id(peer, &[message_id])
+        .await
+        .context("fetch reacted message")?
+        .pop()
+        .flatten()
+        .ok_or_else(|| anyhow!("message is no longer available"))?;
+
+    Ok(summarize_message(&message).reactions)
+}
+
+/// Messages before `offset_id` (0 for the newest), oldest first. Fetched raw, unlike
+/// `iter_messages`, so the names of commenters can be looked up.
+async fn load_history_page(
+    client: &Client,
+    peer: PeerRef,
+    offset_id: i32,
+    limit: usize,
+) -> anyhow::Result<Vec<MessageSummary>> {
+    let history = client
+        .invoke(&tl::functions::messages::GetHistory {
+            peer: peer.into(),
+            offset_id,
+            offset_date: 0,
+            add_offset: 0,
+            limit: i32::try_from(limit).unwrap_or(i32::MAX),
+            max_id: 0,
+            min_id: 0,
+            hash: 0,
+        })
+        .await
+        .context("load messages")?;
+    Ok(summarize_history(client, peer, history))
+}
+
+/// Messages of a reply thread before `offset_id` (0 for the newest), oldest first.
+async fn load_thread_page(
+    client: &Client,
+    peer: PeerRef,
+    thread_id: i32,
+    offset_id: i32,
+    limit: usize,
+) -> anyhow::Result<Vec<MessageSummary>> {
+    let history = client
+        .invoke(&tl::functions::messages::GetReplies {
+            peer: peer.into(),
+            msg_id: thread_id,
+            offset_id,
+            offset_date: 0,
+            add_offset: 0,
+            limit: i32::try_from(limit).unwrap_or(i32::MAX),
+            max_id: 0,
+            min_id: 0,
+            hash: 0,
+        })
+        .await
+        .context("load thread messages")?;
+    Ok(summarize_history(client, peer, history))
+}
+
+/// Sorted oldest first.
+fn summarize_history(
+    client: &Client,
+    peer: PeerRef,
+    history: tl::enums::messages::Messages,
+) -> Vec<MessageSummary> {
+    let (raw_messages, users, chats) = match history {
+        tl::enums::messages::Messages::Messages(m) => (m.messages, m.users, m.chats),
+        tl::enums::messages::Messages::Slice(m) => (m.messages, m.users, m.chats),
+        tl::enums::messages::Messages::ChannelMessages(m) => (m.messages, m.users, m.chats),
+        tl::enums::messages::Messages::NotModified(_) => (Vec::new(), Vec::new(), Vec::new()),
+    };
+    let peers = PeerMap::new(users, chats);
+    let mut messages: Vec<MessageSummary> = raw_messages
+        .into_iter()
+        .filter(|raw| !matches!(raw, tl::enums::Message::Empty(_)))
+        .map(|raw| {
+            let recent = recent_commenters(&raw, &peers);
+            let mut summary =
+                summarize_message(&Message::from_raw(client, raw, Some(peer), &peers));
+            if let Some(comments) = &mut summary.comments {
+                comments.recent = recent;
+            }
+            summary
+        })
+        .collect();
+    messages.sort_by_key(|message| message.id);
+    messages
+}
+
+fn recent_commenters(raw: &tl::enums::Message, peers: &PeerMap) -> Vec<String> {
+    let tl::enums::Message::Message(tl::types::Message {
+        replies: Some(tl::enums::MessageReplies::Replies(replies)),
+        ..
+    }) = raw
+    else {
+        return Vec::new();
+    };
+    replies
+        .recent_repliers
+        .iter()
+        .flatten()
+        .filter_map(|peer| peers.get(PeerId::from(peer.clone()))?.name())
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// The discussion group copy of a channel post starts its comment thread. Returns the group,
+/// which is remembered so the thread can be loaded and answered, and the id of the copy.
+async fn open_thread(
+    client: &Client,
+    chat_map: &mut HashMap<i64, PeerRef>,
+    dialog_id: i64,
+    message_id: i32,
+) -> anyhow::Result<(i64, i32)> {
+    let peer = *chat_map
+        .get(&dialog_id)
+        .ok_or_else(|| anyhow!("selected chat is not available in cache"))?;
+
+    let tl::enums::messages::DiscussionMessage::Message(discussion) = client
+        .invoke(&tl::functions::messages::GetDiscussionMessage {
+            peer: peer.into(),
+            msg_id: message_id,
+        })
+        .await
+        .context("load comment thread")?;
+    // Albums come as several messages; the thread starts at the first one.
+    let (top_id, group_peer) = discussion
+        .messages
+        .iter()
+        .filter_map(|message| match message {
+            tl::enums::Message::Message(message) => Some((message.id, message.peer_id.clone())),
+            _ => None,
+        })
+        .min_by_key(|(id, _)| *id)
+        .ok_or_else(|| anyhow!("post has no comment thread"))?;
+    let peers = PeerMap::new(discussion.users, discussion.chats);
+    let group = peers
+        .get(PeerId::from(group_peer))
+        .ok_or_else(|| anyhow!("discussion group is not available"))?;
+    let discussion_id = group.id().bot_api_dialog_id();
+    chat_map
+        .entry(discussion_id)
+        .or_insert_with(|| PeerRef::from(group));
+    Ok((discussion_id, top_id))
+}
+
+async fn load_topics(
+    client: &Client,
+    chat_map: &HashMap<i64, PeerRef>,
+    dialog_id: i64,
+) -> anyhow::Result<Vec<TopicSummary>> {
+    let peer = *chat_map
+        .get(&dialog_id)
+        .ok_or_else(|| anyhow!("selected chat is not available in cache"))?;
+
+    let tl::enums::messages::ForumTopics::Topics(topics) = client
+        .invoke(&tl::functions::messages::GetForumTopics {
+            peer: peer.into(),
+            q: None,
+            offset_date: 0,
+            offset_id: 0,
+            offset_topic: 0,
+            limit: TOPICS_LIMIT,
+        })
+        .await
+        .context("load forum topics")?;
+    Ok(topics
+        .topics
+        .into_iter()
+        .filter_map(|topic| match topic {
+            tl::enums::ForumTopic::Topic(topic) => Some(TopicSummary {
+                id: topic.id,
+                title: topic.title,
+                top_message: topic.top_message,
+                unread_count: usize::try_from(topic.unread_count).unwrap_or(0),
+                unread_mentions_count: usize::try_from(topic.unread_mentions_count).unwrap_or(0),
+                read_inbox_max_id: topic.read_inbox_max_id,
+            }),
+            tl::enums::ForumTopic::Deleted(_) => None,
+        })
+        .collect())
+}
+
+async fn load_pinned_messages(
+    client: &Client,
+    chat_map: &HashMap<i64, PeerRef>,
+    dialog_id: i64,
+) -> anyhow::Result<Vec<MessageSummary>> {
     let peer = chat_map
         .get(&dialog_id)
         .ok_or_else(|| anyhow!("selected chat is not available in cache"))?;
 
-    let sent = client
-        .send_message(*peer, text)
+    let mut iter = client
+        .search_messages(*peer)
+        .filter(tl::enums::MessagesFilter::InputMessagesFilterPinned)
+        .limit(PINNED_MESSAGES_LIMIT);
+    let mut messages = Vec::new();
+    while let Some(message) = iter.next().await.context("load pinned messages")? {
+        messages.push(summarize_message(&message));
+    }
+    Ok(messages)
+}
+
+/// Pins the message when `notify` is set (to either value) and unpins it otherwise.
+async fn update_pinned(
+    client: &Client,
+    chat_map: &HashMap<i64, PeerRef>,
+    dialog_id: i64,
+    message_id: i32,
+    notify: Option<bool>,
+) -> anyhow::Result<()> {
+    let peer = *chat_map
+        .get(&dialog_id)
+        .ok_or_else(|| anyhow!("selected chat is not available in cache"))?;
+
+    client
+        .invoke(&tl::functions::messages::UpdatePinnedMessage {
+            silent: notify == Some(false),
+            unpin: notify.is_none(),
+            pm_oneside: false,
+            peer: peer.into(),
+            id: message_id,
+        })
         .await
-        .context("send message")?;
+        .context(if notify.is_some() {
+            "pin message"
+        } else {
+            "unpin message"
+        })?;
+    Ok(())
+}
 
-    Ok(summarize_message(&sent))
+fn draft_text(draft: &tl::enums::DraftMessage) -> String {
+    match draft {
+        tl::enums::DraftMessage::Message(draft) => draft.message.clone(),
+        tl::enums::DraftMessage::Empty(_) => String::new(),
+    }
+}
+
+/// Drafts of forum topics and saved-message chats are not per-chat drafts and are skipped.
+fn draft_event(update: &tl::enums::Update) -> Option<TelegramEvent> {
+    match update {
+        tl::enums::Update::DraftMessage(update)
+            if update.top_msg_id.is_none() && update.saved_peer_id.is_none() =>
+        {
+            Some(TelegramEvent::DraftChanged {
+                dialog_id: PeerId::from(update.peer.clone()).bot_api_dialog_id(),
+                text: draft_text(&update.draft),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Reactions inside forum topics and saved-message chats are applied like any other message.
+fn reactions_event(update: &tl::enums::Update) -> Option<TelegramEvent> {
+    let tl::enums::Update::MessageReactions(update) = update else {
+        return None;
+    };
+    let tl::enums::MessageReactions::Reactions(reactions) = &update.reactions;
+    Some(TelegramEvent::ReactionsChanged {
+        dialog_id: PeerId::from(update.peer.clone()).bot_api_dialog_id(),
+        message_id: update.msg_id,
+        reactions: summarize_reactions(&update.reactions),
+        chosen_known: !reactions.min,
+    })
+}
+
+fn pinned_event(update: &tl::enums::Update) -> Option<TelegramEvent> {
+    match update {
+        tl::enums::Update::PinnedMessages(update) => Some(TelegramEvent::PinnedMessagesChanged {
+            dialog_id: PeerId::from(update.peer.clone()).bot_api_dialog_id(),
+            message_ids: update.messages.clone(),
+            pinned: update.pinned,
+        }),
+        tl::enums::Update::PinnedChannelMessages(update) => {
+            Some(TelegramEvent::PinnedMessagesChanged {
+                dialog_id: PeerId::channel(update.channel_id).bot_api_dialog_id(),
+                message_ids: update.messages.clone(),
+                pinned: update.pinned,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Also sent for comment threads, where `channel_id` is the discussion group.
+fn thread_read_event(update: &tl::enums::Update) -> Option<TelegramEvent> {
+    match update {
+        tl::enums::Update::ReadChannelDiscussionInbox(update) => Some(TelegramEvent::ThreadRead {
+            dialog_id: PeerId::channel(update.channel_id).bot_api_dialog_id(),
+            thread_id: update.top_msg_id,
+            max_id: update.read_max_id,
+        }),
+        _ => None,
+    }
+}
+
+fn read_state_event(update: &tl::enums::Update) -> Option<TelegramEvent> {
+    match update {
+        tl::enums::Update::ReadHistoryInbox(read) => Some(TelegramEvent::InboxRead {
+            dialog_id: PeerId::from(read.peer.clone()).bot_api_dialog_id(),
+            max_id: read.max_id,
+            still_unread_count: usize::try_from(read.still_unread_count).unwrap_or(0),
+        }),
+        tl::enums::Update::ReadChannelInbox(read) => Some(TelegramEvent::InboxRead {
+            dialog_id: PeerId::channel(read.channel_id).bot_api_dialog_id(),
+            max_id: read.max_id,
+            still_unread_count: usize::try_from(read.still_unread_count).unwrap_or(0),
+        }),
+        tl::enums::Update::ReadHistoryOutbox(read) => Some(TelegramEvent::OutboxRead {
+            dialog_id: PeerId::from(read.peer.clone()).bot_api_dialog_id(),
+            max_id: read.max_id,
+        }),
+        tl::enums::Update::ReadChannelOutbox(read) => Some(TelegramEvent::OutboxRead {
+            dialog_id: PeerId::channel(read.channel_id).bot_api_dialog_id(),
+            max_id: read.max_id,
+        }),
+        _ => None,
+    }
 }
 
 fn summarize_message(message: &Message) -> MessageSummary {
@@ -350,39 +2455,386 @@ fn summarize_message(message: &Message) -> MessageSummary {
         .sender()
         .and_then(|sender| sender.name().map(ToOwned::to_owned))
         .unwrap_or_else(|| "Unknown".to_string());
+    let (reply_to, topic_id) = reply_and_topic(message);
 
     MessageSummary {
         id: message.id(),
         from,
-        text: summarize_message_text(message),
+        text: message.text().to_string(),
+        entities: message
+            .fmt_entities()
+            .map(|entities| summarize_entities(message.text(), entities))
+            .unwrap_or_default(),
+        media: message.media().map(|media| summarize_media(&media)),
         date: message.date().to_string(),
+        reply_to,
+        topic_id,
+        outgoing: message.outgoing(),
+        mentioned: message.mentioned(),
+        edited: message.edit_date().is_some() && !message.edit_hide(),
+        deleted: false,
+        reactions: match &message.raw {
+            tl::enums::Message::Message(raw) => raw
+                .reactions
+                .as_ref()
+                .map(summarize_reactions)
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        },
+        comments: summarize_comments(message),
     }
 }
 
-fn summarize_message_text(message: &Message) -> String {
-    if !message.text().trim().is_empty() {
-        return message.text().to_string();
+/// Commenters are filled in by the caller, which has the peers of the response.
+fn summarize_comments(message: &Message) -> Option<Comments> {
+    match &message.raw {
+        tl::enums::Message::Message(tl::types::Message {
+            replies: Some(tl::enums::MessageReplies::Replies(replies)),
+            ..
+        }) if replies.comments => Some(Comments {
+            count: usize::try_from(replies.replies).unwrap_or(0),
+            recent: Vec::new(),
+        }),
+        _ => None,
     }
+}
 
-    if let Some(media) = message.media() {
-        match media {
-            Media::Sticker(sticker) => {
-                let emoji = sticker.emoji().trim();
-                if !emoji.is_empty() {
-                    format!("sticker: {emoji}")
-                } else if sticker.is_animated() {
-                    "sticker: animated".to_string()
-                } else {
-                    "sticker: sticker".to_string()
-                }
+fn summarize_reactions(reactions: &tl::enums::MessageReactions) -> Vec<ReactionCount> {
+    let tl::enums::MessageReactions::Reactions(reactions) = reactions;
+    reactions
+        .results
+        .iter()
+        .filter_map(|result| {
+            let tl::enums::ReactionCount::Count(result) = result;
+            let emoji = match &result.reaction {
+                tl::enums::Reaction::Emoji(reaction) => reaction.emoticon.clone(),
+                tl::enums::Reaction::CustomEmoji(_) => CUSTOM_EMOJI_REACTION.to_string(),
+                tl::enums::Reaction::Paid => PAID_REACTION.to_string(),
+                tl::enums::Reaction::Empty => return None,
+            };
+            Some(ReactionCount {
+                emoji,
+                count: u32::try_from(result.count).unwrap_or(0),
+                chosen: result.chosen_order.is_some(),
+            })
+        })
+        .collect()
+}
+
+/// Splits the reply header into the replied message and the thread. Messages reply to the first
+/// message of a forum topic just to be placed in it, which is not shown as a reply.
+fn reply_and_topic(message: &Message) -> (Option<i32>, Option<i32>) {
+    let header = match &message.raw {
+        tl::enums::Message::Message(raw) => raw.reply_to.as_ref(),
+        tl::enums::Message::Service(raw) => {
+            if matches!(raw.action, tl::enums::MessageAction::TopicCreate(_)) {
+                return (None, Some(raw.id));
             }
-            _ => "[media]".to_string(),
+            raw.reply_to.as_ref()
         }
-    } else {
-        String::new()
+        tl::enums::Message::Empty(_) => None,
+    };
+    match header {
+        Some(tl::enums::MessageReplyHeader::Header(header)) if header.forum_topic => {
+            match header.reply_to_top_id {
+                Some(top_id) => (header.reply_to_msg_id, Some(top_id)),
+                None => (None, header.reply_to_msg_id),
+            }
+        }
+        Some(tl::enums::MessageReplyHeader::Header(header)) => (
+            message.reply_to_message_id(),
+            header.reply_to_top_id.or(header.reply_to_msg_id),
+        ),
+        _ => (None, None),
+    }
+}
+
+fn summarize_entities(text: &str, entities: &[tl::enums::MessageEntity]) -> Vec<TextEntity> {
+    use tl::enums::MessageEntity;
+
+    entities
+        .iter()
+        .filter_map(|entity| {
+            let (kind, offset, length) = match entity {
+                MessageEntity::Bold(e) => (EntityKind::Bold, e.offset, e.length),
+                MessageEntity::Italic(e) => (EntityKind::Italic, e.offset, e.length),
+                MessageEntity::Underline(e) => (EntityKind::Underline, e.offset, e.length),
+                MessageEntity::Strike(e) => (EntityKind::Strikethrough, e.offset, e.length),
+                MessageEntity::Spoiler(e) => (EntityKind::Spoiler, e.offset, e.length),
+                MessageEntity::Code(e) => (EntityKind::Code, e.offset, e.length),
+                MessageEntity::Pre(e) => (
+                    EntityKind::Pre {
+                        language: e.language.clone(),
+                    },
+                    e.offset,
+                    e.length,
+                ),
+                MessageEntity::TextUrl(e) => (
+                    EntityKind::TextUrl { url: e.url.clone() },
+                    e.offset,
+                    e.length,
+                ),
+                MessageEntity::Url(e) => (EntityKind::Url, e.offset, e.length),
+                MessageEntity::Email(e) => (EntityKind::Url, e.offset, e.length),
+                MessageEntity::Phone(e) => (EntityKind::Url, e.offset, e.length),
+                MessageEntity::Mention(e) => (EntityKind::Mention, e.offset, e.length),
+                MessageEntity::MentionName(e) => (EntityKind::Mention, e.offset, e.length),
+                MessageEntity::Hashtag(e) => (EntityKind::Mention, e.offset, e.length),
+                MessageEntity::Cashtag(e) => (EntityKind::Mention, e.offset, e.length),
+                MessageEntity::BotCommand(e) => (EntityKind::Mention, e.offset, e.length),
+                MessageEntity::Blockquote(e) => (EntityKind::Blockquote, e.offset, e.length),
+                _ => return None,
+            };
+            let range = utf16_range_to_bytes(text, offset, length)?;
+            Some(TextEntity { kind, range })
+        })
+        .collect()
+}
+
+fn input_message(text: FormattedText) -> InputMessage {
+    let entities = text
+        .entities
+        .iter()
+        .filter_map(|entity| input_entity(&text.text, entity))
+        .collect::<Vec<_>>();
+    InputMessage::new().text(text.text).fmt_entities(entities)
+}
+
+/// Entities Telegram detects on its own, such as plain URLs and mentions, are left out.
+fn input_entity(text: &str, entity: &TextEntity) -> Option<tl::enums::MessageEntity> {
+    use tl::{enums::MessageEntity, types};
+
+    let offset = utf16_len(text.get(..entity.range.start)?);
+    let length = utf16_len(text.get(entity.range.clone())?);
+    let entity = match &entity.kind {
+        EntityKind::Bold => MessageEntity::Bold(types::MessageEntityBold { offset, length }),
+        EntityKind::Italic => MessageEntity::Italic(types::MessageEntityItalic { offset, length }),
+        EntityKind::Underline => {
+            MessageEntity::Underline(types::MessageEntityUnderline { offset, length })
+        }
+        EntityKind::Strikethrough => {
+            MessageEntity::Strike(types::MessageEntityStrike { offset, length })
+        }
+        EntityKind::Spoiler => {
+            MessageEntity::Spoiler(types::MessageEntitySpoiler { offset, length })
+        }
+        EntityKind::Code => MessageEntity::Code(types::MessageEntityCode { offset, length }),
+        EntityKind::Pre { language } => MessageEntity::Pre(types::MessageEntityPre {
+            offset,
+            length,
+            language: language.clone(),
+        }),
+        EntityKind::TextUrl { url } => MessageEntity::TextUrl(types::MessageEntityTextUrl {
+            offset,
+            length,
+            url: url.clone(),
+        }),
+        EntityKind::Blockquote => MessageEntity::Blockquote(types::MessageEntityBlockquote {
+            collapsed: false,
+            offset,
+            length,
+        }),
+        EntityKind::Url | EntityKind::Mention => return None,
+    };
+    Some(entity)
+}
+
+fn utf16_len(text: &str) -> i32 {
+    text.encode_utf16().count() as i32
+}
+
+/// Converts a Telegram entity position, counted in UTF-16 code units, into a byte range of
+/// `text`. Positions outside the text or inside a character give `None`.
+fn utf16_range_to_bytes(text: &str, offset: i32, length: i32) -> Option<Range<usize>> {
+    let offset = usize::try_from(offset).ok()?;
+    let end = offset.checked_add(usize::try_from(length).ok()?)?;
+    let mut units = 0;
+    let mut start_byte = None;
+    for (index, ch) in text.char_indices().chain([(text.len(), '\0')]) {
+        if units == offset {
+            start_byte = Some(index);
+        }
+        if units == end {
+            return start_byte.map(|start| start..index);
+        }
+        if units > end {
+            return None;
+        }
+        units += ch.len_utf16();
+    }
+    None
+}
+
+fn summarize_media(media: &Media) -> MediaInfo {
+    match media {
+        Media::Photo(photo) => {
+            let (width, height) = match &photo.raw.photo {
+                Some(tl::enums::Photo::Photo(photo)) => photo
+                    .sizes
+                    .iter()
+                    .filter_map(|size| match size {
+                        tl::enums::PhotoSize::Size(size) => Some((size.w, size.h)),
+                        tl::enums::PhotoSize::Progressive(size) => Some((size.w, size.h)),
+                        tl::enums::PhotoSize::PhotoCachedSize(size) => Some((size.w, size.h)),
+                        _ => None,
+                    })
+                    .max_by_key(|(w, h)| i64::from(*w) * i64::from(*h))
+                    .unwrap_or_default(),
+                _ => (0, 0),
+            };
+            MediaInfo::Photo {
+                width,
+                height,
+                size: file_size(photo.size()),
+            }
+        }
+        Media::Sticker(sticker) => MediaInfo::Sticker {
+            emoji: sticker.emoji().trim().to_string(),
+            animated: sticker.is_animated(),
+        },
+        Media::Document(document) => summarize_document(document),
+        Media::Poll(poll) => {
+            let text = |text: &tl::enums::TextWithEntities| match text {
+                tl::enums::TextWithEntities::Entities(text) => text.text.clone(),
+            };
+            MediaInfo::Poll {
+                question: text(poll.question()),
+                options: poll
+                    .iter_answers()
+                    .map(|answer| text(&answer.text))
+                    .collect(),
+                quiz: poll.is_quiz(),
+                closed: poll.closed(),
+                total_voters: poll
+                    .total_voters()
+                    .and_then(|voters| u32::try_from(voters).ok())
+                    .unwrap_or_default(),
+            }
+        }
+        Media::Geo(geo) => MediaInfo::Location {
+            latitude: geo.latitue(),
+            longitude: geo.longitude(),
+            live: false,
+        },
+        Media::GeoLive(live) => MediaInfo::Location {
+            latitude: live
+                .geo
+                .as_ref()
+                .map(|geo| geo.latitue())
+                .unwrap_or_default(),
+            longitude: live
+                .geo
+                .as_ref()
+                .map(|geo| geo.longitude())
+                .unwrap_or_default(),
+            live: true,
+        },
+        Media::Venue(venue) => MediaInfo::Venue {
+            title: venue.title().to_string(),
+            address: venue.address().to_string(),
+            latitude: venue
+                .geo
+                .as_ref()
+                .map(|geo| geo.latitue())
+                .unwrap_or_default(),
+            longitude: venue
+                .geo
+                .as_ref()
+                .map(|geo| geo.longitude())
+                .unwrap_or_default(),
+        },
+        Media::Contact(contact) => MediaInfo::Contact {
+            name: format!("{} {}", contact.first_name(), contact.last_name())
+                .trim()
+                .to_string(),
+            phone: contact.phone_number().to_string(),
+        },
+        Media::Dice(dice) => MediaInfo::Dice {
+            emoji: dice.emoji().to_string(),
+            value: dice.value(),
+        },
+        Media::WebPage(webpage) => match &webpage.raw.webpage {
+            tl::enums::WebPage::Page(page) => MediaInfo::WebPage {
+                url: page.url.clone(),
+                site_name: page.site_name.clone().unwrap_or_default(),
+                title: page.title.clone().unwrap_or_default(),
+            },
+            tl::enums::WebPage::Empty(page) => MediaInfo::WebPage {
+                url: page.url.clone().unwrap_or_default(),
+                site_name: String::new(),
+                title: String::new(),
+            },
+            tl::enums::WebPage::Pending(page) => MediaInfo::WebPage {
+                url: page.url.clone().unwrap_or_default(),
+                site_name: String::new(),
+                title: String::new(),
+            },
+            tl::enums::WebPage::NotModified(_) => MediaInfo::Unsupported,
+        },
+        _ => MediaInfo::Unsupported,
+    }
+}
+
+/// Tells videos, GIFs, music and voice notes apart from other files by their attributes.
+fn summarize_document(document: &Document) -> MediaInfo {
+    let file_name = document.name().to_string();
+    let size = file_size(document.size());
+    let duration = document
+        .duration()
+        .map(|duration| duration.round() as u32)
+        .unwrap_or_default();
+    let (width, height) = document.resolution().unwrap_or_default();
+    let attributes = match &document.raw.document {
+        Some(tl::enums::Document::Document(raw)) => raw.attributes.as_slice(),
+        _ => &[],
+    };
+
+    for attribute in attributes {
+        match attribute {
+            tl::enums::DocumentAttribute::Audio(audio) if audio.voice => {
+                return MediaInfo::Voice { duration, size };
+            }
+            tl::enums::DocumentAttribute::Audio(audio) => {
+                return MediaInfo::Audio {
+                    title: audio.title.clone().unwrap_or_default(),
+                    performer: audio.performer.clone().unwrap_or_default(),
+                    duration,
+                    size,
+                };
+            }
+            tl::enums::DocumentAttribute::Video(video) if !document.is_animated() => {
+                return MediaInfo::Video {
+                    file_name,
+                    duration,
+                    width,
+                    height,
+                    size,
+                    round: video.round_message,
+                };
+            }
+            tl::enums::DocumentAttribute::Animated => {
+                return MediaInfo::Animation {
+                    file_name,
+                    width,
+                    height,
+                    size,
+                };
+            }
+            _ => {}
+        }
+    }
+
+    MediaInfo::Document {
+        file_name,
+        mime_type: document.mime_type().unwrap_or_default().to_string(),
+        size,
     }
 }
 
+fn file_size(size: i64) -> u64 {
+    u64::try_from(size).unwrap_or_default()
+}
+
 fn read_api_id() -> anyhow::Result<i32> {
     let raw = env::var("TELEGRAM_API_ID")
         .context("TELEGRAM_API_ID is not set. Export it before running the app")?;
@@ -394,3 +2846,77 @@ fn read_api_hash() -> anyhow::Result<String> {
     env::var("TELEGRAM_API_HASH")
         .context("TELEGRAM_API_HASH is not set. Export it before running the app")
 }
+
+/// Directory for downloaded media, `TELEGRAM_DOWNLOAD_DIR` or `downloads` by default.
+pub fn download_dir() -> PathBuf {
+    env::var("TELEGRAM_DOWNLOAD_DIR")
+        .ok()
+        .filter(|dir| !dir.trim().is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_DOWNLOAD_DIR))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{download_file_name, unique_download_path, utf16_range_to_bytes};
+    use std::{env, fs, path::PathBuf};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("tele-rust-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn download_name_keeps_only_the_last_path_component() {
+        assert_eq!(download_file_name("../../.bashrc", None, 7), ".bashrc");
+        assert_eq!(download_file_name("/etc/passwd", None, 7), "passwd");
+        assert_eq!(download_file_name("..\\..\\evil.exe", None, 7), "evil.exe");
+        assert_eq!(download_file_name("report.pdf", None, 7), "report.pdf");
+    }
+
+    #[test]
+    fn download_name_falls_back_to_message_id_and_mime_type() {
+        assert_eq!(download_file_name("", Some("video/mp4"), 7), "file_7.mp4");
+        assert_eq!(download_file_name("..", Some("image/png"), 7), "file_7.png");
+        assert_eq!(download_file_name("dir/", None, 7), "file_7");
+    }
+
+    #[test]
+    fn unique_download_path_numbers_taken_names() {
+        let dir = temp_dir("downloads");
+        assert_eq!(unique_download_path(&dir, "a.txt"), dir.join("a.txt"));
+
+        fs::write(dir.join("a.txt"), "").unwrap();
+        fs::write(dir.join("a (1).txt.part"), "").unwrap();
+        assert_eq!(unique_download_path(&dir, "a.txt"), dir.join("a (2).txt"));
+
+        fs::write(dir.join(".env"), "").unwrap();
+        assert_eq!(unique_download_path(&dir, ".env"), dir.join(".env (1)"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn utf16_entities_map_to_bytes_around_surrogate_pairs() {
+        let text = "hi 👋 𝐁old é";
+        assert_eq!(utf16_range_to_bytes(text, 3, 2), Some(3..7));
+        assert_eq!(&text[utf16_range_to_bytes(text, 6, 5).unwrap()], "𝐁old");
+        assert_eq!(&text[utf16_range_to_bytes(text, 12, 1).unwrap()], "é");
+        assert_eq!(utf16_range_to_bytes(text, 0, 13), Some(0..text.len()));
+    }
+
+    #[test]
+    fn utf16_entities_may_be_empty_but_not_outside_the_text() {
+        let text = "👋ab";
+        assert_eq!(utf16_range_to_bytes(text, 2, 0), Some(4..4));
+        assert_eq!(utf16_range_to_bytes(text, 4, 0), Some(6..6));
+        // Half of a surrogate pair is not a character boundary.
+        assert_eq!(utf16_range_to_bytes(text, 1, 1), None);
+        assert_eq!(utf16_range_to_bytes(text, 0, 1), None);
+        assert_eq!(utf16_range_to_bytes(text, 3, 2), None);
+        assert_eq!(utf16_range_to_bytes(text, 5, 0), None);
+        assert_eq!(utf16_range_to_bytes(text, -1, 2), None);
+        assert_eq!(utf16_range_to_bytes(text, 0, -1), None);
+    }
+}
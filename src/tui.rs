@@ -1,4 +1,9 @@
-use std::io;
+mod graphics;
+
+use std::{
+    io::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use crossterm::{
     ExecutableCommand, cursor,
@@ -10,13 +15,49 @@ use ratatui::{
     layout::{Alignment, Margin, Rect},
     layout::{Constraint, Direction, Layout},
     style::{Color, Modifier, Style},
+    text::{Line, Span},
     widgets::{
-        Block, Borders, List, ListItem, ListState, Paragraph, Scrollbar, ScrollbarOrientation,
-        ScrollbarState, Wrap,
+        Block, Borders, Clear, LineGauge, List, ListItem, ListState, Paragraph, Scrollbar,
+        ScrollbarOrientation, ScrollbarState, Wrap,
+    },
+};
+
+use crate::{
+    app::{
+        AppState, FocusArea, ForwardPicker, MessageSearch, MessageSearchScope, MessageViewport,
+        REACTION_CHOICES, ReactionPicker, SortMode, TopicPicker, UiMode,
+    },
+    markup::MarkupMode,
+    telegram::{
+        Comments, DialogKind, DialogSummary, EntityKind, ImagePreview, MediaInfo,
+        MessageSearchResult, MessageSummary, ReactionCount, TextEntity,
     },
 };
 
-use crate::app::{AppState, FocusArea, SortMode, UiMode};
+pub use graphics::{GraphicsProtocol, ImageLayer};
+
+const REPLY_SNIPPET_CHARS: usize = 48;
+const SEARCH_SNIPPET_CHARS: usize = 64;
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+const TRANSFER_GAUGE_WIDTH: u16 = 40;
+const TRANSFER_NAME_CHARS: usize = 20;
+const PREVIEW_INDENT: u16 = 2;
+const SPOILER_CHAR: char = '▒';
+const CODE_BACKGROUND: Color = Color::Indexed(236);
+const MAX_INPUT_ROWS: usize = 8;
+const DRAFT_MARKER: &str = " ✎";
+const FORUM_MARKER: &str = " #";
+const MUTED_MARKER: &str = " 🔕";
+const PINNED_MARKER: &str = " 📌";
+const MENTION_MARKER: &str = "@ ";
+/// Each chat list entry is a title row and a preview row.
+const CHAT_ITEM_ROWS: usize = 2;
+/// The preview row starts under the title, past the kind icon.
+const CHAT_PREVIEW_INDENT: usize = 3;
+const MARK_MARKER: &str = "● ";
+const SELECTION_BACKGROUND: Color = Color::Indexed(238);
 
 pub type AppTerminal = Terminal<CrosstermBackend<io::Stdout>>;
 
@@ -41,6 +82,23 @@ impl TerminalGuard {
     pub fn terminal_mut(&mut self) -> &mut AppTerminal {
         &mut self.terminal
     }
+
+    /// Hands the terminal over to another program, such as an external editor.
+    pub fn suspend(&mut self) -> anyhow::Result<()> {
+        terminal::disable_raw_mode()?;
+        self.terminal.backend_mut().execute(LeaveAlternateScreen)?;
+        self.terminal.backend_mut().execute(cursor::Show)?;
+        Ok(())
+    }
+
+    /// Takes the terminal back after `suspend`; the next frame is drawn from scratch.
+    pub fn resume(&mut self) -> anyhow::Result<()> {
+        terminal::enable_raw_mode()?;
+        self.terminal.backend_mut().execute(EnterAlternateScreen)?;
+        self.terminal.backend_mut().execute(cursor::Hide)?;
+        self.terminal.clear()?;
+        Ok(())
+    }
 }
 
 impl Drop for TerminalGuard {
@@ -51,12 +109,35 @@ impl Drop for TerminalGuard {
     }
 }
 
-pub fn draw(frame: &mut Frame<'_>, app: &AppState) {
+pub fn draw(frame: &mut Frame<'_>, app: &AppState, images: &mut ImageLayer) -> MessageViewport {
+    images.begin_frame(frame.area());
+    let mut viewport = MessageViewport {
+        scroll_from_bottom: app.message_scroll_from_bottom,
+        first_visible_message_id: None,
+        last_visible_message_id: None,
+        ..app.message_viewport
+    };
+
+    let compose_banner = compose_banner(app);
+    let (compose_lines, (cursor_row, cursor_column)) = compose_rows(
+        app.compose.text(),
+        app.compose.cursor(),
+        usize::from(frame.area().width.saturating_sub(2)),
+    );
+    // The input pane grows with the compose text, up to a limit.
+    let text_rows = if app.attach.is_some() {
+        1
+    } else {
+        compose_lines.len().clamp(1, MAX_INPUT_ROWS)
+    };
+    let banner_rows = usize::from(compose_banner.is_some());
+    let input_height = to_u16_saturating(text_rows + banner_rows + 2);
+
     let outer = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
             Constraint::Min(0),
-            Constraint::Length(3),
+            Constraint::Length(input_height),
             Constraint::Length(1),
         ])
         .split(frame.area());
@@ -66,7 +147,34 @@ pub fn draw(frame: &mut Frame<'_>, app: &AppState) {
         .constraints([Constraint::Percentage(30), Constraint::Percentage(70)])
         .split(outer[0]);
 
-    let chats_title = if app.search_query.is_empty() {
+    let folder_tabs = folder_tabs_line(app);
+    let chats_area = if let Some(tabs) = folder_tabs {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(1), Constraint::Min(0)])
+            .split(panes[0]);
+        frame.render_widget(Paragraph::new(tabs), rows[0]);
+        rows[1]
+    } else {
+        panes[0]
+    };
+
+    // Overlays on the message pane; images under them are not drawn.
+    let overlay =
+        if app.attach.as_ref().is_some_and(|picker| {
+            app.ui_mode == UiMode::AttachFile && !picker.completions.is_empty()
+        }) || app.forward.is_some()
+        {
+            Some(panes[1])
+        } else if app.compose_preview && app.ui_mode == UiMode::Compose {
+            Some(compose_preview_area(app, panes[1]))
+        } else if app.reaction_picker.is_some() {
+            Some(reaction_picker_area(panes[1]))
+        } else {
+            None
+        };
+
+    let mut chats_title = if app.search_query.is_empty() {
         format!("Chats [{}]", sort_label(app.sort_mode))
     } else {
         format!(
@@ -75,6 +183,11 @@ pub fn draw(frame: &mut Frame<'_>, app: &AppState) {
             app.search_query
         )
     };
+    if app.offline {
+        chats_title.push_str(" (offline)");
+    } else if app.is_loading_dialogs && !app.dialogs.is_empty() {
+        chats_title.push_str(" (syncing...)");
+    }
 
     let chats_block = Block::default()
         .borders(Borders::ALL)
@@ -82,16 +195,12 @@ pub fn draw(frame: &mut Frame<'_>, app: &AppState) {
         .border_style(focus_style(app, FocusArea::Chats));
 
     let visible_dialogs = app.visible_dialogs();
+    let today = today_utc();
+    // The highlight symbol takes two columns of every row.
+    let chat_width = list_inner_width(chats_area).saturating_sub(2);
     let chat_items: Vec<ListItem<'_>> = visible_dialogs
         .iter()
-        .map(|dialog| {
-            let badge = app.dialog_new_message_count(dialog.id);
-            if badge > 0 {
-                ListItem::new(format!("{} [{}]", dialog.title, badge))
-            } else {
-                ListItem::new(dialog.title.clone())
-            }
-        })
+        .map(|dialog| ListItem::new(chat_lines(app, dialog, chat_width, &today).to_vec()))
         .collect();
     let has_chat_items = !chat_items.is_empty();
 
@@ -108,26 +217,34 @@ pub fn draw(frame: &mut Frame<'_>, app: &AppState) {
     if has_chat_items {
         list_state.select(app.selected_visible_index());
     }
-    frame.render_stateful_widget(chats, panes[0], &mut list_state);
+    frame.render_stateful_widget(chats, chats_area, &mut list_state);
     maybe_render_scrollbar(
         frame,
-        panes[0],
-        visible_dialogs.len(),
-        list_state.offset(),
-        list_inner_height(panes[0]),
+        chats_area,
+        visible_dialogs.len() * CHAT_ITEM_ROWS,
+        list_state.offset() * CHAT_ITEM_ROWS,
+        list_inner_height(chats_area),
     );
 
-    let title = app
-        .selected_dialog()
-        .map(|d| {
+    let title = thread_title(app)
+        .or_else(|| {
+            app.selected_dialog()
+                .map(|d| format!("Messages - {}{}", d.title, topic_suffix(app, d.id)))
+        })
+        .map(|mut title| {
             if app.pending_new_messages_for_selected > 0 {
-                format!(
-                    "Messages - {} ({} new)",
-                    d.title, app.pending_new_messages_for_selected
-                )
-            } else {
-                format!("Messages - {}", d.title)
+                title.push_str(&format!(" ({} new)", app.pending_new_messages_for_selected));
+            }
+            if app.is_loading_older_messages {
+                title.push_str(" (loading history...)");
             }
+            if app
+                .selected_dialog_id()
+                .is_some_and(|id| app.detached_history_dialogs.contains(&id))
+            {
+                title.push_str(" (older history, Down at bottom for latest)");
+            }
+            title
         })
         .unwrap_or_else(|| "Messages".to_string());
 
@@ -136,56 +253,158 @@ pub fn draw(frame: &mut Frame<'_>, app: &AppState) {
         .title(title)
         .border_style(focus_style(app, FocusArea::Messages));
 
-    if app.is_loading_dialogs {
+    // The pinned strip takes the top of the message pane while the chat has pins.
+    let message_pane = match app.shown_pin() {
+        Some((index, pin)) if app.ui_mode != UiMode::MessageSearch => {
+            let rows = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Length(3), Constraint::Min(0)])
+                .split(panes[1]);
+            draw_pinned_strip(frame, app, (index, pin), rows[0]);
+            rows[1]
+        }
+        _ => panes[1],
+    };
+
+    // Cached chats and messages stay on screen while the server copy is loading.
+    if let Some(search) = app
+        .message_search
+        .as_ref()
+        .filter(|_| app.ui_mode == UiMode::MessageSearch)
+    {
+        draw_message_search(frame, app, search, panes[1]);
+    } else if app.is_loading_dialogs && app.dialogs.is_empty() {
         let paragraph = Paragraph::new("Loading chats...".to_string())
             .block(right_block)
             .wrap(Wrap { trim: false });
-        frame.render_widget(paragraph, panes[1]);
-    } else if app.is_loading_messages {
+        frame.render_widget(paragraph, message_pane);
+    } else if app.is_loading_messages && app.selected_dialog_messages().is_empty() {
         let paragraph = Paragraph::new("Loading messages...".to_string())
             .block(right_block)
             .wrap(Wrap { trim: false });
-        frame.render_widget(paragraph, panes[1]);
+        frame.render_widget(paragraph, message_pane);
     } else if let Some(err) = &app.last_error {
         let paragraph = Paragraph::new(format!("Error: {err}"))
             .block(right_block)
             .wrap(Wrap { trim: false });
-        frame.render_widget(paragraph, panes[1]);
+        frame.render_widget(paragraph, message_pane);
     } else if visible_dialogs.is_empty() {
         let paragraph = Paragraph::new("No chats match search.".to_string())
             .block(right_block)
             .wrap(Wrap { trim: false });
-        frame.render_widget(paragraph, panes[1]);
+        frame.render_widget(paragraph, message_pane);
     } else {
-        let lines: Vec<String> = app
-            .selected_dialog_messages()
-            .iter()
-            .map(|message| format!("[{}] {}: {}", message.date, message.from, message.text))
-            .collect();
+        let messages = app.selected_dialog_messages();
 
-        if lines.is_empty() {
+        if messages.is_empty() {
             let paragraph = Paragraph::new("No messages for selected chat.".to_string())
                 .block(right_block)
                 .wrap(Wrap { trim: false });
-            frame.render_widget(paragraph, panes[1]);
+            frame.render_widget(paragraph, message_pane);
         } else {
-            let viewport_height = list_inner_height(panes[1]);
-            let viewport_width = list_inner_width(panes[1]);
-            let content_lines = total_wrapped_line_count(&lines, viewport_width);
-            let message_top_offset = message_top_offset(
+            let viewport_height = list_inner_height(message_pane);
+            let viewport_width = list_inner_width(message_pane);
+            let preview_columns =
+                to_u16_saturating(viewport_width).saturating_sub(PREVIEW_INDENT + 1);
+            let previews: Vec<Option<(&ImagePreview, (u16, u16))>> = messages
+                .iter()
+                .map(|message| {
+                    let preview = app
+                        .selected_dialog_id
+                        .and_then(|dialog_id| app.image_previews.get(&(dialog_id, message.id)))
+                        .filter(|_| images.enabled() && !message.deleted)?;
+                    let cells =
+                        graphics::preview_cells(preview.width, preview.height, preview_columns);
+                    Some((preview, cells))
+                })
+                .collect();
+            // Previews sit below the message body, on rows kept blank for them.
+            let blocks: Vec<Vec<Line<'static>>> = messages
+                .iter()
+                .zip(&previews)
+                .map(|(message, preview)| {
+                    let mut block = message_block(app, messages, message);
+                    if let Some((_, (_, rows))) = preview {
+                        block.extend((0..*rows).map(|_| Line::default()));
+                    }
+                    block
+                })
+                .collect();
+            let block_heights: Vec<usize> = blocks
+                .iter()
+                .map(|block| {
+                    let texts: Vec<String> = block.iter().map(line_text).collect();
+                    total_wrapped_line_count(&texts, viewport_width)
+                })
+                .collect();
+            let content_lines = block_heights.iter().sum::<usize>();
+            let mut scroll_from_bottom = app
+                .message_scroll_from_bottom
+                .min(content_lines.saturating_sub(viewport_height));
+            if app.reveal_selected_message
+                && let Some(index) = app.selected_message_index()
+            {
+                scroll_from_bottom = scroll_to_reveal(
+                    content_lines,
+                    viewport_height,
+                    scroll_from_bottom,
+                    block_heights[..index].iter().sum(),
+                    block_heights[index],
+                );
+            }
+            let message_top_offset =
+                message_top_offset(content_lines, viewport_height, scroll_from_bottom);
+            viewport = MessageViewport {
                 content_lines,
-                viewport_height,
-                app.message_scroll_from_bottom,
-            );
-            let body = lines.join("\n");
-            let paragraph = Paragraph::new(body)
+                height: viewport_height,
+                scroll_from_bottom,
+                first_visible_message_id: first_visible_block(&block_heights, message_top_offset)
+                    .map(|index| messages[index].id),
+                last_visible_message_id: last_visible_block(
+                    &block_heights,
+                    message_top_offset,
+                    viewport_height,
+                )
+                .map(|index| messages[index].id),
+            };
+            let lines: Vec<Line<'_>> = blocks.into_iter().flatten().collect();
+            let paragraph = Paragraph::new(lines)
                 .block(right_block)
                 .scroll((to_u16_saturating(message_top_offset), 0))
                 .wrap(Wrap { trim: false });
-            frame.render_widget(paragraph, panes[1]);
+            frame.render_widget(paragraph, message_pane);
+
+            let inner = message_pane.inner(Margin {
+                vertical: 1,
+                horizontal: 1,
+            });
+            let mut block_start = 0;
+            for ((message, preview), height) in messages.iter().zip(&previews).zip(&block_heights) {
+                if let (Some(dialog_id), Some((preview, (columns, rows)))) =
+                    (app.selected_dialog_id, preview)
+                    && let Some(row) = preview_row(
+                        block_start + height,
+                        usize::from(*rows),
+                        message_top_offset,
+                        viewport_height,
+                    )
+                {
+                    let area = Rect::new(
+                        inner.x + PREVIEW_INDENT,
+                        inner.y + to_u16_saturating(row),
+                        *columns,
+                        *rows,
+                    );
+                    if !overlay.is_some_and(|overlay| overlay.intersects(area)) {
+                        images.render(frame.buffer_mut(), (dialog_id, message.id), preview, area);
+                    }
+                }
+                block_start += height;
+            }
+
             maybe_render_scrollbar(
                 frame,
-                panes[1],
+                message_pane,
                 content_lines,
                 message_top_offset,
                 viewport_height,
@@ -193,33 +412,1114 @@ pub fn draw(frame: &mut Frame<'_>, app: &AppState) {
         }
     }
 
-    let input_title = if app.is_sending_message {
-        "Input (sending...)"
-    } else {
-        "Input"
+    if let Some(picker) = app
+        .attach
+        .as_ref()
+        .filter(|picker| app.ui_mode == UiMode::AttachFile && !picker.completions.is_empty())
+    {
+        draw_path_completions(frame, &picker.completions, panes[1]);
+    } else if let Some(picker) = &app.forward {
+        draw_forward_picker(frame, app, picker, panes[1]);
+    } else if let Some(picker) = &app.topic_picker {
+        draw_topic_picker(frame, app, picker, panes[0]);
+    } else if let (Some(picker), Some(area)) = (&app.reaction_picker, overlay) {
+        draw_reaction_picker(frame, app, picker, area);
+    } else if let Some(area) = overlay {
+        draw_compose_preview(frame, app, area);
+    }
+
+    let input_title = match &app.attach {
+        Some(_) if app.compose.text().trim().is_empty() => "Attach file".to_string(),
+        Some(_) => format!(
+            "Attach file with caption: {}",
+            snippet(app.compose.text(), REPLY_SNIPPET_CHARS)
+        ),
+        None if app.is_sending_message => {
+            format!("Input [{}] (sending...)", markup_label(app.markup_mode))
+        }
+        None => format!("Input [{}]", markup_label(app.markup_mode)),
     };
     let input_block = Block::default()
         .borders(Borders::ALL)
         .title(input_title)
         .border_style(focus_style(app, FocusArea::Input));
-    let input_text = if app.compose_text.is_empty() {
-        "Press i to start typing".to_string()
-    } else {
-        app.compose_text.clone()
+    // Scrolled so the cursor row stays visible.
+    let first_row = cursor_row.saturating_sub(text_rows - 1);
+    let (input_text, is_placeholder) = match &app.attach {
+        Some(picker) if picker.path.is_empty() => {
+            (vec!["Type a file path, Tab completes".to_string()], true)
+        }
+        Some(picker) => (vec![picker.path.clone()], false),
+        None if app.compose.is_empty() && app.ui_mode != UiMode::Compose => {
+            (vec!["Press i to start typing".to_string()], true)
+        }
+        None => (
+            compose_lines
+                .into_iter()
+                .skip(first_row)
+                .take(text_rows)
+                .collect(),
+            false,
+        ),
     };
-    let input_style = if app.compose_text.is_empty() {
+    let input_style = if is_placeholder {
         Style::default().fg(Color::DarkGray)
     } else {
         Style::default()
     };
-    let input = Paragraph::new(input_text)
-        .style(input_style)
-        .block(input_block)
-        .wrap(Wrap { trim: false });
+    if app.attach.is_none() && app.ui_mode == UiMode::Compose {
+        frame.set_cursor_position((
+            outer[1].x + 1 + to_u16_saturating(cursor_column),
+            outer[1].y + 1 + to_u16_saturating(banner_rows + cursor_row - first_row),
+        ));
+    }
+    let mut input_lines = Vec::new();
+    if let Some(banner) = compose_banner {
+        input_lines.push(Line::styled(
+            banner,
+            Style::default()
+                .fg(Color::Cyan)
+                .add_modifier(Modifier::ITALIC),
+        ));
+    }
+    input_lines.extend(
+        input_text
+            .into_iter()
+            .map(|row| Line::styled(row, input_style)),
+    );
+    let input = Paragraph::new(input_lines).block(input_block);
     frame.render_widget(input, outer[1]);
 
-    let help = Paragraph::new(hotkeys_text(app)).style(Style::default().fg(Color::DarkGray));
-    frame.render_widget(help, outer[2]);
+    let transfer = match (&app.upload, app.downloads.last()) {
+        (Some(upload), _) => Some((
+            upload.ratio(),
+            transfer_label(
+                &format!("Sending {}", upload.file_name),
+                upload.uploaded,
+                upload.ratio(),
+                app.downloads.len(),
+            ),
+        )),
+        (None, Some(download)) => Some((
+            download.ratio(),
+            transfer_label(
+                &download.file_name,
+                download.downloaded,
+                download.ratio(),
+                app.downloads.len() - 1,
+            ),
+        )),
+        (None, None) => None,
+    };
+    let status_area = match transfer {
+        Some((ratio, label)) => {
+            let status = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Min(0), Constraint::Length(TRANSFER_GAUGE_WIDTH)])
+                .split(outer[2]);
+            let gauge = LineGauge::default()
+                .ratio(ratio.unwrap_or_default())
+                .label(label)
+                .filled_style(Style::default().fg(Color::Cyan))
+                .unfilled_style(Style::default().fg(Color::DarkGray));
+            frame.render_widget(gauge, status[1]);
+            status[0]
+        }
+        None => outer[2],
+    };
+    let help = match &app.status_message {
+        Some(message) => Paragraph::new(message.as_str()).style(Style::default().fg(Color::Green)),
+        None => Paragraph::new(hotkeys_text(app)).style(Style::default().fg(Color::DarkGray)),
+    };
+    frame.render_widget(help, status_area);
+
+    viewport
+}
+
+fn message_block(
+    app: &AppState,
+    messages: &[MessageSummary],
+    message: &MessageSummary,
+) -> Vec<Line<'static>> {
+    let mut block = Vec::new();
+
+    // Replies to the first message of a thread just place the message in it.
+    if let Some(reply_id) = message
+        .reply_to
+        .filter(|id| Some(*id) != app.selected_topic_id())
+    {
+        let quote = match messages.iter().find(|original| original.id == reply_id) {
+            Some(original) => format!(
+                "  | {}: {}",
+                original.from,
+                snippet(&message_preview(original), REPLY_SNIPPET_CHARS)
+            ),
+            None => "  | (reply to an earlier message)".to_string(),
+        };
+        block.push(Line::styled(quote, Style::default().fg(Color::DarkGray)));
+    }
+
+    let mut body_style = if app.selected_message_id == Some(message.id) {
+        Style::default()
+            .fg(Color::Yellow)
+            .add_modifier(Modifier::BOLD)
+    } else {
+        Style::default()
+    };
+    if app.is_message_selected(message.id) {
+        body_style = body_style.bg(SELECTION_BACKGROUND);
+    }
+    let mut suffix = String::new();
+    if message.deleted {
+        body_style = body_style
+            .fg(Color::DarkGray)
+            .add_modifier(Modifier::CROSSED_OUT);
+        suffix.push_str(" (deleted)");
+    } else {
+        if message.outgoing {
+            let read_outbox_max_id = app
+                .selected_dialog()
+                .map(|dialog| dialog.read_outbox_max_id)
+                .unwrap_or_default();
+            suffix.push_str(if message.id <= read_outbox_max_id {
+                " ✓✓"
+            } else {
+                " ✓"
+            });
+        }
+        if message.edited {
+            suffix.push_str(" (edited)");
+        }
+        if app.is_pinned(message.id) {
+            suffix.push_str(" (pinned)");
+        }
+    }
+
+    let mark = if app.marked_message_ids.contains(&message.id) {
+        MARK_MARKER
+    } else {
+        ""
+    };
+    let header = Span::styled(
+        format!("{mark}[{}] {}: ", message.date, message.from),
+        body_style,
+    );
+    let text = styled_text(
+        &message.text,
+        &message.entities,
+        body_style,
+        app.spoilers_revealed(message.id),
+    );
+    // With media, the descriptor takes the first line and the caption follows below it.
+    match &message.media {
+        Some(media) => {
+            block.push(Line::from(vec![
+                header,
+                Span::styled(format!("{}{suffix}", media_descriptor(media)), body_style),
+            ]));
+            if !message.text.trim().is_empty() {
+                block.extend(text);
+            }
+        }
+        None => {
+            let start = block.len();
+            block.extend(text);
+            block[start].spans.insert(0, header);
+            if let Some(last) = block.last_mut()
+                && !suffix.is_empty()
+            {
+                last.push_span(Span::styled(suffix, body_style));
+            }
+        }
+    }
+
+    if !message.deleted && !message.reactions.is_empty() {
+        block.push(reaction_line(&message.reactions));
+    }
+    if let Some(comments) = message.comments.as_ref().filter(|_| !message.deleted) {
+        block.push(Line::styled(
+            comments_line(comments),
+            Style::default().fg(Color::DarkGray),
+        ));
+    }
+
+    block
+}
+
+fn comments_line(comments: &Comments) -> String {
+    let indent = " ".repeat(usize::from(PREVIEW_INDENT));
+    let noun = if comments.count == 1 {
+        "comment"
+    } else {
+        "comments"
+    };
+    if comments.recent.is_empty() {
+        format!("{indent}💬 {} {noun}", comments.count)
+    } else {
+        format!(
+            "{indent}💬 {} {noun} - {}",
+            comments.count,
+            comments.recent.join(", ")
+        )
+    }
+}
+
+/// Our own reactions are highlighted.
+fn reaction_line(reactions: &[ReactionCount]) -> Line<'static> {
+    let mut spans = vec![Span::raw(" ".repeat(usize::from(PREVIEW_INDENT)))];
+    for (index, reaction) in reactions.iter().enumerate() {
+        if index > 0 {
+            spans.push(Span::raw(" "));
+        }
+        let style = if reaction.chosen {
+            Style::default()
+                .fg(Color::Cyan)
+                .add_modifier(Modifier::BOLD)
+        } else {
+            Style::default().fg(Color::DarkGray)
+        };
+        spans.push(Span::styled(
+            format!("{} {}", reaction.emoji, reaction.count),
+            style,
+        ));
+    }
+    Line::from(spans)
+}
+
+/// Splits `text` into lines of spans styled by its entities on top of `base`.
+fn styled_text(
+    text: &str,
+    entities: &[TextEntity],
+    base: Style,
+    reveal_spoilers: bool,
+) -> Vec<Line<'static>> {
+    let entities: Vec<&TextEntity> = entities
+        .iter()
+        .filter(|entity| text.get(entity.range.clone()).is_some())
+        .collect();
+    let mut bounds: Vec<usize> = entities
+        .iter()
+        .flat_map(|entity| [entity.range.start, entity.range.end])
+        .chain([0, text.len()])
+        .collect();
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    let mut lines = vec![Line::default()];
+    for window in bounds.windows(2) {
+        let (start, end) = (window[0], window[1]);
+        let mut style = base;
+        let mut hidden = false;
+        for entity in entities
+            .iter()
+            .filter(|entity| entity.range.start <= start && end <= entity.range.end)
+        {
+            hidden |= entity.kind == EntityKind::Spoiler && !reveal_spoilers;
+            style = entity_style(&entity.kind, style);
+        }
+        if hidden {
+            style = style.fg(Color::DarkGray);
+        }
+
+        for (index, part) in text[start..end].split('\n').enumerate() {
+            if index > 0 {
+                lines.push(Line::default());
+            }
+            if part.is_empty() {
+                continue;
+            }
+            let content = if hidden {
+                part.chars().map(|_| SPOILER_CHAR).collect()
+            } else {
+                part.to_string()
+            };
+            if let Some(line) = lines.last_mut() {
+                line.push_span(Span::styled(content, style));
+            }
+        }
+    }
+    lines
+}
+
+fn entity_style(kind: &EntityKind, style: Style) -> Style {
+    match kind {
+        EntityKind::Bold => style.add_modifier(Modifier::BOLD),
+        EntityKind::Italic => style.add_modifier(Modifier::ITALIC),
+        EntityKind::Underline => style.add_modifier(Modifier::UNDERLINED),
+        EntityKind::Strikethrough => style.add_modifier(Modifier::CROSSED_OUT),
+        EntityKind::Code | EntityKind::Pre { .. } => style.bg(CODE_BACKGROUND),
+        EntityKind::TextUrl { .. } | EntityKind::Url => {
+            style.fg(Color::Cyan).add_modifier(Modifier::UNDERLINED)
+        }
+        EntityKind::Mention => style.fg(Color::LightBlue),
+        EntityKind::Blockquote => style.fg(Color::Gray).add_modifier(Modifier::ITALIC),
+        EntityKind::Spoiler => style,
+    }
+}
+
+/// `text` with the characters of every spoiler masked.
+fn hide_spoilers(text: &str, entities: &[TextEntity]) -> String {
+    let spoilers: Vec<&TextEntity> = entities
+        .iter()
+        .filter(|entity| entity.kind == EntityKind::Spoiler)
+        .collect();
+    text.char_indices()
+        .map(|(index, ch)| {
+            let hidden = !ch.is_whitespace()
+                && spoilers
+                    .iter()
+                    .any(|spoiler| spoiler.range.contains(&index));
+            if hidden { SPOILER_CHAR } else { ch }
+        })
+        .collect()
+}
+
+/// Plain-text copy of messages for the clipboard or an export file, spoilers included.
+pub fn transcript(messages: &[MessageSummary]) -> String {
+    messages
+        .iter()
+        .map(|message| {
+            let mut entry = format!("[{}] {}: ", message.date, message.from);
+            if let Some(media) = &message.media {
+                entry.push_str(&media_descriptor(media));
+                if !message.text.trim().is_empty() {
+                    entry.push('\n');
+                }
+            }
+            entry.push_str(&message.text);
+            entry
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Sets the system clipboard through the terminal with an OSC 52 sequence, which also works
+/// over SSH; terminals that do not support it ignore the sequence.
+pub fn copy_to_clipboard(out: &mut impl Write, text: &str) -> io::Result<()> {
+    write!(out, "\x1b]52;c;{}\x07", graphics::base64(text.as_bytes()))?;
+    out.flush()
+}
+
+/// Text standing in for a message in one-line snippets.
+fn message_preview(message: &MessageSummary) -> String {
+    preview_text(&message.text, &message.entities, message.media.as_ref())
+}
+
+fn preview_text(text: &str, entities: &[TextEntity], media: Option<&MediaInfo>) -> String {
+    match media {
+        Some(media) if text.trim().is_empty() => media_descriptor(media),
+        _ => hide_spoilers(text, entities),
+    }
+}
+
+fn media_descriptor(media: &MediaInfo) -> String {
+    let (kind, details) = match media {
+        MediaInfo::Photo {
+            width,
+            height,
+            size,
+        } => ("photo", vec![resolution(*width, *height), file_size(*size)]),
+        MediaInfo::Video {
+            file_name,
+            duration,
+            width,
+            height,
+            size,
+            round,
+        } => (
+            if *round { "video message" } else { "video" },
+            vec![
+                file_name.clone(),
+                format_duration(*duration),
+                resolution(*width, *height),
+                file_size(*size),
+            ],
+        ),
+        MediaInfo::Animation {
+            file_name,
+            width,
+            height,
+            size,
+        } => (
+            "GIF",
+            vec![
+                file_name.clone(),
+                resolution(*width, *height),
+                file_size(*size),
+            ],
+        ),
+        MediaInfo::Audio {
+            title,
+            performer,
+            duration,
+            size,
+        } => {
+            let name = match (performer.is_empty(), title.is_empty()) {
+                (false, false) => format!("{performer} - {title}"),
+                (true, _) => title.clone(),
+                (false, true) => performer.clone(),
+            };
+            (
+                "audio",
+                vec![name, format_duration(*duration), file_size(*size)],
+            )
+        }
+        MediaInfo::Voice { duration, size } => (
+            "voice message",
+            vec![format_duration(*duration), file_size(*size)],
+        ),
+        MediaInfo::Sticker { emoji, animated } => (
+            if *animated {
+                "animated sticker"
+            } else {
+                "sticker"
+            },
+            vec![emoji.clone()],
+        ),
+        MediaInfo::Document {
+            file_name,
+            mime_type,
+            size,
+        } => (
+            "file",
+            vec![file_name.clone(), mime_type.clone(), file_size(*size)],
+        ),
+        MediaInfo::Poll {
+            question,
+            options,
+            quiz,
+            closed,
+            total_voters,
+        } => (
+            if *quiz { "quiz" } else { "poll" },
+            vec![
+                question.clone(),
+                options.join(" / "),
+                format!("{total_voters} votes"),
+                if *closed {
+                    "closed".to_string()
+                } else {
+                    String::new()
+                },
+            ],
+        ),
+        MediaInfo::Location {
+            latitude,
+            longitude,
+            live,
+        } => (
+            if *live { "live location" } else { "location" },
+            vec![coordinates(*latitude, *longitude)],
+        ),
+        MediaInfo::Venue {
+            title,
+            address,
+            latitude,
+            longitude,
+        } => (
+            "venue",
+            vec![
+                title.clone(),
+                address.clone(),
+                coordinates(*latitude, *longitude),
+            ],
+        ),
+        MediaInfo::Contact { name, phone } => ("contact", vec![name.clone(), phone.clone()]),
+        MediaInfo::Dice { emoji, value } => ("dice", vec![format!("{emoji} {value}")]),
+        MediaInfo::WebPage {
+            url,
+            site_name,
+            title,
+        } => ("link", vec![site_name.clone(), title.clone(), url.clone()]),
+        MediaInfo::Unsupported => ("unsupported media", Vec::new()),
+    };
+
+    let details: Vec<String> = details
+        .into_iter()
+        .filter(|detail| !detail.trim().is_empty())
+        .collect();
+    if details.is_empty() {
+        format!("[{kind}]")
+    } else {
+        format!("[{kind}: {}]", details.join(", "))
+    }
+}
+
+fn resolution(width: i32, height: i32) -> String {
+    if width > 0 && height > 0 {
+        format!("{width}x{height}")
+    } else {
+        String::new()
+    }
+}
+
+fn file_size(size: u64) -> String {
+    if size > 0 {
+        format_bytes(size)
+    } else {
+        String::new()
+    }
+}
+
+fn format_duration(seconds: u32) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{seconds:02}")
+    } else {
+        format!("{minutes}:{seconds:02}")
+    }
+}
+
+fn coordinates(latitude: f64, longitude: f64) -> String {
+    format!("{latitude:.5}, {longitude:.5}")
+}
+
+fn draw_message_search(frame: &mut Frame<'_>, app: &AppState, search: &MessageSearch, area: Rect) {
+    let scope = match search.scope {
+        MessageSearchScope::Chat(dialog_id) => {
+            let title = app
+                .dialogs
+                .iter()
+                .find(|dialog| dialog.id == dialog_id)
+                .map(|dialog| dialog.title.as_str())
+                .unwrap_or("chat");
+            format!("Search in {title}")
+        }
+        MessageSearchScope::AllChats => "Search all chats".to_string(),
+    };
+    let mut title = format!("{scope}: {}", search.query);
+    if search.is_searching {
+        title.push_str(" (searching...)");
+    }
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(title)
+        .border_style(Style::default().fg(Color::Cyan));
+
+    let placeholder = if search.results_query.is_none() {
+        Some("Type a query and press Enter. Tab switches between this chat and all chats.")
+    } else if search.results.is_empty() {
+        Some("No messages found.")
+    } else {
+        None
+    };
+    if let Some(placeholder) = placeholder {
+        let paragraph = Paragraph::new(placeholder)
+            .block(block)
+            .wrap(Wrap { trim: false });
+        frame.render_widget(paragraph, area);
+        return;
+    }
+
+    let items: Vec<ListItem<'_>> = search
+        .results
+        .iter()
+        .map(|result| ListItem::new(search_result_line(result)))
+        .collect();
+    let list = List::new(items)
+        .block(block)
+        .highlight_style(
+            Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD),
+        )
+        .highlight_symbol("> ");
+    let mut list_state = ListState::default();
+    list_state.select(Some(search.selected));
+    frame.render_stateful_widget(list, area, &mut list_state);
+    maybe_render_scrollbar(
+        frame,
+        area,
+        search.results.len(),
+        list_state.offset(),
+        list_inner_height(area),
+    );
+}
+
+fn search_result_line(result: &MessageSearchResult) -> String {
+    format!(
+        "{} / {} / {} / {}",
+        result.dialog_title,
+        result.message.from,
+        result.message.date,
+        snippet(&message_preview(&result.message), SEARCH_SNIPPET_CHARS)
+    )
+}
+
+fn draw_path_completions(frame: &mut Frame<'_>, completions: &[String], area: Rect) {
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(format!("{} matches", completions.len()))
+        .border_style(Style::default().fg(Color::Cyan));
+    let items: Vec<ListItem<'_>> = completions
+        .iter()
+        .map(|name| ListItem::new(name.as_str()))
+        .collect();
+    frame.render_widget(Clear, area);
+    frame.render_widget(List::new(items).block(block), area);
+}
+
+/// Bottom part of the message pane, tall enough for the preview but at most half the pane.
+fn compose_preview_area(app: &AppState, pane: Rect) -> Rect {
+    let text = app.formatted_compose();
+    let lines: Vec<String> = text.text.split('\n').map(str::to_string).collect();
+    let content = total_wrapped_line_count(&lines, list_inner_width(pane)).max(1);
+    let height = to_u16_saturating(content + 2)
+        .min((pane.height / 2).max(3))
+        .min(pane.height);
+    Rect::new(pane.x, pane.bottom() - height, pane.width, height)
+}
+
+fn draw_compose_preview(frame: &mut Frame<'_>, app: &AppState, area: Rect) {
+    let text = app.formatted_compose();
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(format!("Preview ({})", markup_label(app.markup_mode)))
+        .border_style(Style::default().fg(Color::Cyan));
+    let paragraph = if text.text.is_empty() {
+        Paragraph::new("Nothing to send yet").style(Style::default().fg(Color::DarkGray))
+    } else {
+        Paragraph::new(styled_text(
+            &text.text,
+            &text.entities,
+            Style::default(),
+            true,
+        ))
+    };
+    frame.render_widget(Clear, area);
+    frame.render_widget(paragraph.block(block).wrap(Wrap { trim: false }), area);
+}
+
+fn draw_forward_picker(frame: &mut Frame<'_>, app: &AppState, picker: &ForwardPicker, area: Rect) {
+    let count = picker.message_ids.len();
+    let noun = if count == 1 { "message" } else { "messages" };
+    let mut title = format!("Forward {count} {noun} to: {}", picker.query);
+    if picker.drop_captions {
+        title.push_str(" [copy without captions]");
+    } else if picker.drop_author {
+        title.push_str(" [copy]");
+    }
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(title)
+        .border_style(Style::default().fg(Color::Cyan));
+    let candidates = app.forward_candidates();
+    let items: Vec<ListItem<'_>> = candidates
+        .iter()
+        .map(|dialog| ListItem::new(dialog.title.as_str()))
+        .collect();
+    let list = List::new(items)
+        .block(block)
+        .highlight_style(
+            Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD),
+        )
+        .highlight_symbol("> ");
+    let mut state = ListState::default();
+    if !candidates.is_empty() {
+        state.select(Some(picker.selected));
+    }
+    frame.render_widget(Clear, area);
+    frame.render_stateful_widget(list, area, &mut state);
+}
+
+/// Lists the topics of a forum chat over the chat list, after an entry for all of them.
+fn draw_topic_picker(frame: &mut Frame<'_>, app: &AppState, picker: &TopicPicker, area: Rect) {
+    let title = app
+        .dialogs
+        .iter()
+        .find(|dialog| dialog.id == picker.dialog_id)
+        .map_or_else(|| "Topics".to_string(), |d| format!("Topics - {}", d.title));
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(title)
+        .border_style(Style::default().fg(Color::Cyan));
+    let items: Vec<ListItem<'_>> = std::iter::once(ListItem::new("All topics"))
+        .chain(app.topic_choices(picker.dialog_id).iter().map(|topic| {
+            let mention = if topic.unread_mentions_count > 0 {
+                " @"
+            } else {
+                ""
+            };
+            if topic.unread_count > 0 {
+                ListItem::new(format!("{} [{}{mention}]", topic.title, topic.unread_count))
+            } else {
+                ListItem::new(topic.title.as_str())
+            }
+        }))
+        .collect();
+    let list = List::new(items)
+        .block(block)
+        .highlight_style(
+            Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD),
+        )
+        .highlight_symbol("> ");
+    let mut state = ListState::default();
+    state.select(Some(picker.selected));
+    frame.render_widget(Clear, area);
+    frame.render_stateful_widget(list, area, &mut state);
+}
+
+/// Breadcrumb of an open comment thread: the channel, then the comments of the post.
+fn thread_title(app: &AppState) -> Option<String> {
+    let thread = app.thread?;
+    let channel = app
+        .dialogs
+        .iter()
+        .find(|dialog| dialog.id == thread.channel_id)
+        .map_or("Channel", |dialog| dialog.title.as_str());
+    Some(format!("Messages - {channel} › Comments"))
+}
+
+/// A chat list entry: kind icon, title and markers with the date of the last message on the
+/// right, then a preview of that message with the unread badge.
+fn chat_lines(
+    app: &AppState,
+    dialog: &DialogSummary,
+    width: usize,
+    today: &str,
+) -> [Line<'static>; CHAT_ITEM_ROWS] {
+    let mut title = format!(
+        "{} {}{}{}",
+        kind_icon(dialog.kind),
+        dialog.title,
+        if dialog.forum { FORUM_MARKER } else { "" },
+        topic_suffix(app, dialog.id)
+    );
+    if app.has_draft(dialog.id) {
+        title.push_str(DRAFT_MARKER);
+    }
+    if dialog.muted {
+        title.push_str(MUTED_MARKER);
+    }
+    if dialog.pinned {
+        title.push_str(PINNED_MARKER);
+    }
+    let date = dialog
+        .last_message
+        .as_ref()
+        .map(|last| chat_list_date(&last.date, today))
+        .unwrap_or_default();
+    let title_width = Line::from(title.as_str()).width();
+    let gap = width
+        .saturating_sub(title_width + date.chars().count())
+        .max(1);
+    let title_line = Line::from(vec![
+        Span::raw(title),
+        Span::raw(" ".repeat(gap)),
+        Span::styled(date, Style::default().fg(Color::DarkGray)),
+    ]);
+
+    let unread = app.dialog_unread_count(dialog.id);
+    let badge = match (unread, dialog.unread_mentions_count) {
+        (0, _) => String::new(),
+        (unread, 0) => format!(" [{unread}]"),
+        (unread, _) => format!(" [{unread} @]"),
+    };
+    // Muted chats get a dim badge, as in other Telegram apps.
+    let badge_style = if dialog.muted {
+        Style::default().fg(Color::DarkGray)
+    } else {
+        Style::default().fg(Color::Cyan)
+    };
+    let mut preview = vec![Span::raw(" ".repeat(CHAT_PREVIEW_INDENT))];
+    if let Some(last) = &dialog.last_message {
+        let mut room = width.saturating_sub(CHAT_PREVIEW_INDENT + badge.chars().count() + 3);
+        if last.mentioned {
+            preview.push(Span::styled(
+                MENTION_MARKER,
+                Style::default().fg(Color::Yellow),
+            ));
+            room = room.saturating_sub(MENTION_MARKER.len());
+        }
+        let sender = if last.outgoing {
+            "You: ".to_string()
+        } else if matches!(dialog.kind, DialogKind::Group | DialogKind::Supergroup) {
+            format!("{}: ", last.from)
+        } else {
+            String::new()
+        };
+        let text = preview_text(&last.text, &last.entities, last.media.as_ref());
+        preview.push(Span::styled(
+            snippet(&format!("{sender}{text}"), room),
+            Style::default().fg(Color::Gray),
+        ));
+    }
+    preview.push(Span::styled(badge, badge_style));
+
+    [title_line, Line::from(preview)]
+}
+
+fn kind_icon(kind: DialogKind) -> &'static str {
+    match kind {
+        DialogKind::User => "👤",
+        DialogKind::Bot => "🤖",
+        DialogKind::Group | DialogKind::Supergroup => "👥",
+        DialogKind::Channel => "📢",
+    }
+}
+
+/// Message dates are UTC like "2024-01-31 18:05:00 UTC": the time for today's messages, the
+/// day otherwise.
+fn chat_list_date(date: &str, today: &str) -> String {
+    match (date.get(..10), date.get(11..16)) {
+        (Some(day), Some(time)) if day == today => time.to_string(),
+        (Some(day), _) if day.get(..4) == today.get(..4) => {
+            let month = day
+                .get(5..7)
+                .and_then(|month| month.parse::<usize>().ok())
+                .and_then(|month| MONTHS.get(month.wrapping_sub(1)));
+            match (month, day.get(8..10)) {
+                (Some(month), Some(day_of_month)) => format!("{month} {day_of_month}"),
+                _ => day.to_string(),
+            }
+        }
+        (Some(day), _) => day.to_string(),
+        _ => date.to_string(),
+    }
+}
+
+/// Today's date in UTC as "YYYY-MM-DD", the same form as message dates.
+fn today_utc() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() / 86_400) as i64;
+    // Civil date from days since 1970-01-01, after Howard Hinnant's `civil_from_days`.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Folder tabs with their unread counts, or `None` when there is only "All".
+fn folder_tabs_line(app: &AppState) -> Option<Line<'static>> {
+    let tabs = app.folder_tabs();
+    if tabs.len() < 2 {
+        return None;
+    }
+
+    let mut spans = Vec::new();
+    for (index, tab) in tabs.into_iter().enumerate() {
+        if index > 0 {
+            spans.push(Span::styled("|", Style::default().fg(Color::DarkGray)));
+        }
+        let unread = app.folder_unread_count(tab);
+        let label = if unread > 0 {
+            format!(" {} {unread} ", app.folder_title(tab))
+        } else {
+            format!(" {} ", app.folder_title(tab))
+        };
+        let style = if tab == app.active_folder {
+            Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD | Modifier::REVERSED)
+        } else {
+            Style::default()
+        };
+        spans.push(Span::styled(label, style));
+    }
+    Some(Line::from(spans))
+}
+
+/// Names the open topic of a forum chat, if any.
+fn topic_suffix(app: &AppState, dialog_id: i64) -> String {
+    app.topic_by_dialog
+        .get(&dialog_id)
+        .and_then(|topic_id| {
+            app.topic_choices(dialog_id)
+                .iter()
+                .find(|topic| topic.id == *topic_id)
+        })
+        .map(|topic| format!(" › {}", topic.title))
+        .unwrap_or_default()
+}
+
+/// Shows one pin of the selected chat; the title counts the pins when there are several.
+fn draw_pinned_strip(
+    frame: &mut Frame<'_>,
+    app: &AppState,
+    (index, pin): (usize, &MessageSummary),
+    area: Rect,
+) {
+    let count = app.pinned_messages().len();
+    let title = if count > 1 {
+        format!("Pinned {}/{count}", index + 1)
+    } else {
+        "Pinned".to_string()
+    };
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(title)
+        .border_style(Style::default().fg(Color::Cyan));
+    let line = Line::from(vec![
+        Span::styled(
+            format!("{}: ", pin.from),
+            Style::default().add_modifier(Modifier::BOLD),
+        ),
+        Span::raw(snippet(
+            &message_preview(pin),
+            usize::from(area.width.saturating_sub(4)),
+        )),
+    ]);
+    frame.render_widget(Paragraph::new(line).block(block), area);
+}
+
+fn reaction_picker_area(pane: Rect) -> Rect {
+    let height = 3.min(pane.height);
+    Rect::new(pane.x, pane.bottom() - height, pane.width, height)
+}
+
+/// Choices are numbered for the digit hotkeys; the ones we already left are highlighted.
+fn draw_reaction_picker(
+    frame: &mut Frame<'_>,
+    app: &AppState,
+    picker: &ReactionPicker,
+    area: Rect,
+) {
+    let chosen: Vec<&str> = app
+        .reaction_target()
+        .map(|message| {
+            message
+                .reactions
+                .iter()
+                .filter(|reaction| reaction.chosen)
+                .map(|reaction| reaction.emoji.as_str())
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut spans = Vec::new();
+    for (index, emoji) in REACTION_CHOICES.iter().enumerate() {
+        let mut style = if chosen.contains(emoji) {
+            Style::default().fg(Color::Cyan)
+        } else {
+            Style::default()
+        };
+        if index == picker.selected {
+            style = style.add_modifier(Modifier::REVERSED);
+        }
+        if index > 0 {
+            spans.push(Span::raw(" "));
+        }
+        let number = if index < 9 {
+            format!("{}:", index + 1)
+        } else {
+            String::new()
+        };
+        spans.push(Span::styled(format!("{number}{emoji}"), style));
+    }
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title("React")
+        .border_style(Style::default().fg(Color::Cyan));
+    frame.render_widget(Clear, area);
+    frame.render_widget(Paragraph::new(Line::from(spans)).block(block), area);
+}
+
+/// Gauge label for a transfer; `others` counts the transfers not shown.
+fn transfer_label(name: &str, transferred: u64, ratio: Option<f64>, others: usize) -> String {
+    let name = if name.is_empty() { "media" } else { name };
+    let progress = match ratio {
+        Some(ratio) => format!("{}%", (ratio * 100.0).round() as u64),
+        None => format_bytes(transferred),
+    };
+    let more = if others > 0 {
+        format!(" +{others}")
+    } else {
+        String::new()
+    };
+    format!("{} {progress}{more}", snippet(name, TRANSFER_NAME_CHARS))
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+fn compose_banner(app: &AppState) -> Option<String> {
+    if let Some(pending) = app.pending_pin {
+        let preview = app
+            .pin_target()
+            .map(|target| snippet(&message_preview(target), REPLY_SNIPPET_CHARS))
+            .unwrap_or_default();
+        return Some(if pending.unpin {
+            format!("Unpin: {preview}?")
+        } else if pending.notify {
+            format!("Pin and notify all members: {preview}?")
+        } else {
+            format!("Pin silently: {preview}?")
+        });
+    }
+
+    if let Some(target) = app.reaction_target() {
+        return Some(format!(
+            "React to {}: {}",
+            target.from,
+            snippet(&message_preview(target), REPLY_SNIPPET_CHARS)
+        ));
+    }
+
+    match app.delete_targets().as_slice() {
+        [] => {}
+        [target] => {
+            return Some(format!(
+                "Delete {}: {}?",
+                target.from,
+                snippet(&message_preview(target), REPLY_SNIPPET_CHARS)
+            ));
+        }
+        targets => return Some(format!("Delete {} messages?", targets.len())),
+    }
+
+    if let Some(target) = app.editing_target() {
+        return Some(format!(
+            "Editing: {}",
+            snippet(&message_preview(target), REPLY_SNIPPET_CHARS)
+        ));
+    }
+
+    app.reply_target().map(|target| {
+        format!(
+            "Replying to {}: {}",
+            target.from,
+            snippet(&message_preview(target), REPLY_SNIPPET_CHARS)
+        )
+    })
+}
+
+fn snippet(text: &str, max_chars: usize) -> String {
+    let first_line = text.lines().next().unwrap_or_default().trim();
+    if first_line.chars().count() > max_chars {
+        let truncated: String = first_line.chars().take(max_chars).collect();
+        format!("{truncated}...")
+    } else if text.trim().lines().nth(1).is_some() {
+        format!("{first_line}...")
+    } else {
+        first_line.to_string()
+    }
 }
 
 fn sort_label(sort_mode: SortMode) -> &'static str {
@@ -229,6 +1529,14 @@ fn sort_label(sort_mode: SortMode) -> &'static str {
     }
 }
 
+fn markup_label(mode: MarkupMode) -> &'static str {
+    match mode {
+        MarkupMode::Markdown => "Markdown",
+        MarkupMode::Html => "HTML",
+        MarkupMode::Plain => "Plain",
+    }
+}
+
 fn focus_style(app: &AppState, area: FocusArea) -> Style {
     if app.focus == area {
         Style::default().fg(Color::Yellow)
@@ -240,23 +1548,51 @@ fn focus_style(app: &AppState, area: FocusArea) -> Style {
 fn hotkeys_text(app: &AppState) -> &'static str {
     match app.ui_mode {
         UiMode::Compose => {
-            "Type message | Enter send | Esc stop compose | Tab/Shift+Tab focus | q/й quit"
+            "Type message | Enter send | Alt+Enter newline | Up/Down history | Ctrl+Z/Ctrl+Y undo/redo | Ctrl+E/Ctrl+у $EDITOR | Ctrl+O/Ctrl+щ attach file | Ctrl+P/Ctrl+з preview | Ctrl+T/Ctrl+е markup | Esc stop compose | Tab/Shift+Tab focus"
+        }
+        UiMode::AttachFile => {
+            "Attach file | Type path | Tab complete | Enter send with compose text as caption | Esc back"
+        }
+        UiMode::ConfirmDelete if app.can_delete_for_me() => {
+            "Delete message | m/ь for me | e/у for everyone | Esc cancel"
+        }
+        UiMode::ConfirmDelete => "Delete message for everyone | e/у confirm | Esc cancel",
+        UiMode::ConfirmPin => "Pin message | Enter confirm | n/т toggle notification | Esc cancel",
+        UiMode::TopicPicker => "Topics | Up/Down select | Enter open | Esc cancel",
+        UiMode::SelectMessages => {
+            "Select messages | k/л j/о extend | m/ь or Space mark | d/в delete | f/а forward | y/н copy | w/ц export | Esc cancel"
+        }
+        UiMode::ForwardPicker => {
+            "Forward | Type to find a chat | Up/Down select | Enter forward | Ctrl+A/Ctrl+ф copy without author | Ctrl+D/Ctrl+в also drop captions | Esc cancel"
+        }
+        UiMode::PickReaction => {
+            "React | Left/Right choose | 1-9 pick | Enter send, or remove if already yours | Esc cancel"
+        }
+        UiMode::MessageSearch => {
+            "Search messages | Type query | Enter search/open | Up/Down select | Tab chat/all chats | Esc close"
         }
         UiMode::Search => {
             "Search chats | Type to filter | Backspace edit | Esc clear/exit | Up/Down select | q/й quit"
         }
         UiMode::Normal => match app.focus {
             FocusArea::Chats => {
-                "Tab/Shift+Tab focus | Up/Down select chat | i/ш compose | / or . search | s/ы sort | q/й quit"
+                "Tab/Shift+Tab focus | Up/Down select chat | i/ш compose | / or . search | 1-9 or [/х ]/ъ folders | o/щ open pin | t/е topics | ?/, find | g/п find all | s/ы sort | q/й quit"
             }
             FocusArea::Messages => {
-                "Tab/Shift+Tab focus | Up/Down scroll messages | i/ш compose | / or . search | q/й quit"
+                "Tab/Shift+Tab focus | Up/Down scroll | k/л j/о select | r/к reply | e/у edit | d/в delete | a/ф react | V/М select | m/ь mark | f/а forward | y/н copy | w/ц export | p/з pin | o/щ open pin | c/с comments | t/е topics | s/ы save media | v/м spoilers | x/ч cancel transfer | ?/, find | Esc clear/back | i/ш compose | q/й quit"
             }
             FocusArea::Input => "Tab/Shift+Tab focus | i/ш compose | / or . search | q/й quit",
         },
     }
 }
 
+fn line_text(line: &Line<'_>) -> String {
+    line.spans
+        .iter()
+        .map(|span| span.content.as_ref())
+        .collect()
+}
+
 fn list_inner_height(area: Rect) -> usize {
     usize::from(area.height.saturating_sub(2))
 }
@@ -265,17 +1601,45 @@ fn list_inner_width(area: Rect) -> usize {
     usize::from(area.width.saturating_sub(2))
 }
 
-fn total_wrapped_line_count(lines: &[String], width: usize) -> usize {
+fn total_wrapped_line_count<S: AsRef<str>>(lines: &[S], width: usize) -> usize {
     if width == 0 {
         return 0;
     }
 
     lines
         .iter()
-        .map(|line| wrapped_line_count(line, width))
+        .map(|line| wrapped_line_count(line.as_ref(), width))
         .sum::<usize>()
 }
 
+/// Splits compose text into rows of at most `width` characters, returning them with the cursor's
+/// row and column.
+fn compose_rows(text: &str, cursor: usize, width: usize) -> (Vec<String>, (usize, usize)) {
+    let width = width.max(1);
+    let mut rows = Vec::new();
+    let mut position = (0, 0);
+    let mut line_start = 0;
+    for line in text.split('\n') {
+        let chars: Vec<char> = line.chars().collect();
+        let first_row = rows.len();
+        rows.extend(
+            chars
+                .chunks(width)
+                .map(|chunk| chunk.iter().collect::<String>()),
+        );
+        if (line_start..=line_start + line.len()).contains(&cursor) {
+            let column = line[..cursor - line_start].chars().count();
+            position = (first_row + column / width, column % width);
+        }
+        // An empty line, or a full last row with the cursor after it, still needs a row.
+        while rows.len() <= position.0.max(first_row) {
+            rows.push(String::new());
+        }
+        line_start += line.len() + 1;
+    }
+    (rows, position)
+}
+
 fn wrapped_line_count(line: &str, width: usize) -> usize {
     if width == 0 {
         return 0;
@@ -299,6 +1663,66 @@ fn message_top_offset(
     max_top_offset.saturating_sub(scroll_from_bottom.min(max_top_offset))
 }
 
+/// Adjusts a bottom-relative scroll so the block at `start..start + height` is on screen.
+fn scroll_to_reveal(
+    total_lines: usize,
+    viewport_height: usize,
+    scroll_from_bottom: usize,
+    start: usize,
+    height: usize,
+) -> usize {
+    let max_top_offset = total_lines.saturating_sub(viewport_height);
+    let mut top = message_top_offset(total_lines, viewport_height, scroll_from_bottom);
+    if start < top {
+        top = start;
+    } else if start + height > top + viewport_height {
+        top = (start + height).saturating_sub(viewport_height).min(start);
+    }
+    max_top_offset - top.min(max_top_offset)
+}
+
+/// Index of the first block that has at least one line inside the viewport.
+fn first_visible_block(heights: &[usize], top_offset: usize) -> Option<usize> {
+    let mut end = 0;
+    heights.iter().position(|height| {
+        end += height;
+        end > top_offset
+    })
+}
+
+/// Viewport row of a preview taking the last `rows` lines of a block ending at `block_end`, if
+/// the whole preview is on screen.
+fn preview_row(
+    block_end: usize,
+    rows: usize,
+    top_offset: usize,
+    viewport_height: usize,
+) -> Option<usize> {
+    let start = block_end.checked_sub(rows)?;
+    (start >= top_offset && block_end <= top_offset + viewport_height).then(|| start - top_offset)
+}
+
+/// Index of the last block that has at least one line inside the viewport.
+fn last_visible_block(
+    heights: &[usize],
+    top_offset: usize,
+    viewport_height: usize,
+) -> Option<usize> {
+    let bottom = top_offset + viewport_height;
+    let mut start = 0;
+    let mut last = None;
+    for (index, height) in heights.iter().enumerate() {
+        if start >= bottom {
+            break;
+        }
+        if start + height > top_offset {
+            last = Some(index);
+        }
+        start += height;
+    }
+    last
+}
+
 fn maybe_render_scrollbar(
     frame: &mut Frame<'_>,
     area: Rect,
@@ -391,7 +1815,27 @@ pub fn draw_auth(frame: &mut Frame<'_>, view: &AuthView<'_>) {
 
 #[cfg(test)]
 mod tests {
-    use super::{message_top_offset, total_wrapped_line_count, wrapped_line_count};
+    use ratatui::{
+        Terminal,
+        backend::TestBackend,
+        style::{Color, Modifier, Style},
+    };
+
+    use super::{
+        CODE_BACKGROUND, Comments, EntityKind, GraphicsProtocol, ImageLayer, ImagePreview,
+        MediaInfo, MessageSearchResult, MessageSummary, SPOILER_CHAR, TextEntity, chat_lines,
+        chat_list_date, comments_line, compose_rows, draw, first_visible_block, folder_tabs_line,
+        format_bytes, format_duration, hide_spoilers, last_visible_block, line_text,
+        media_descriptor, message_preview, message_top_offset, preview_row, reaction_line,
+        scroll_to_reveal, search_result_line, snippet, styled_text, total_wrapped_line_count,
+        transcript, transfer_label, wrapped_line_count,
+    };
+    use crate::{
+        app::AppState,
+        telegram::{
+            ChatFolder, DialogKind, DialogSummary, LastMessage, ReactionCount, TopicSummary,
+        },
+    };
 
     #[test]
     fn message_offset_is_bottom_aligned_by_default() {
@@ -413,6 +1857,38 @@ mod tests {
         assert_eq!(message_top_offset(3, 10, 0), 0);
     }
 
+    #[test]
+    fn compose_rows_wrap_lines_and_track_the_cursor() {
+        let rows = |text: &str, cursor| compose_rows(text, cursor, 3);
+
+        assert_eq!(rows("", 0), (vec![String::new()], (0, 0)));
+        assert_eq!(
+            rows("abcdé\n\nxy", 4),
+            (
+                vec![
+                    "abc".to_string(),
+                    "dé".to_string(),
+                    String::new(),
+                    "xy".to_string()
+                ],
+                (1, 1)
+            )
+        );
+        assert_eq!(rows("abcdé\n\nxy", 7), (rows("abcdé\n\nxy", 0).0, (2, 0)));
+        // The cursor after a full row moves onto a row of its own.
+        assert_eq!(
+            rows("abc", 3),
+            (vec!["abc".to_string(), String::new()], (1, 0))
+        );
+        assert_eq!(
+            rows("abc\nd", 3),
+            (
+                vec!["abc".to_string(), String::new(), "d".to_string()],
+                (1, 0)
+            )
+        );
+    }
+
     #[test]
     fn wrapped_line_count_respects_width() {
         assert_eq!(wrapped_line_count("abcd", 2), 2);
@@ -428,4 +1904,646 @@ mod tests {
         let lines = vec!["abc".to_string(), "defgh".to_string()];
         assert_eq!(total_wrapped_line_count(&lines, 2), 5);
     }
+
+    #[test]
+    fn reveal_scrolls_up_to_message_above_viewport() {
+        // 10 lines, viewport 4 at the bottom shows lines 6..10.
+        assert_eq!(scroll_to_reveal(10, 4, 0, 2, 1), 4);
+    }
+
+    #[test]
+    fn reveal_scrolls_down_to_message_below_viewport() {
+        // Viewport at the top shows lines 0..4; reveal lines 7..9.
+        assert_eq!(scroll_to_reveal(10, 4, 6, 7, 2), 1);
+    }
+
+    #[test]
+    fn reveal_keeps_scroll_when_message_is_visible() {
+        assert_eq!(scroll_to_reveal(10, 4, 2, 5, 1), 2);
+    }
+
+    #[test]
+    fn snippet_truncates_long_and_multiline_text() {
+        assert_eq!(snippet("hello world", 5), "hello...");
+        assert_eq!(snippet("first\nsecond", 20), "first...");
+        assert_eq!(snippet("short", 20), "short");
+    }
+
+    #[test]
+    fn last_visible_block_is_bottom_of_viewport() {
+        let heights = [2, 3, 1, 4];
+        assert_eq!(last_visible_block(&heights, 6, 4), Some(3));
+        assert_eq!(last_visible_block(&heights, 0, 4), Some(1));
+        assert_eq!(last_visible_block(&heights, 0, 5), Some(1));
+        assert_eq!(last_visible_block(&heights, 0, 6), Some(2));
+        assert_eq!(last_visible_block(&[], 0, 4), None);
+    }
+
+    #[test]
+    fn first_visible_block_is_top_of_viewport() {
+        let heights = [2, 3, 1, 4];
+        assert_eq!(first_visible_block(&heights, 0), Some(0));
+        assert_eq!(first_visible_block(&heights, 2), Some(1));
+        assert_eq!(first_visible_block(&heights, 5), Some(2));
+        assert_eq!(first_visible_block(&heights, 10), None);
+    }
+
+    #[test]
+    fn preview_row_requires_whole_preview_on_screen() {
+        assert_eq!(preview_row(10, 4, 5, 5), Some(1));
+        assert_eq!(preview_row(10, 4, 7, 5), None);
+        assert_eq!(preview_row(10, 4, 2, 5), None);
+    }
+
+    #[test]
+    fn photo_preview_is_drawn_below_message() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(vec![DialogSummary {
+            id: 1,
+            title: "chat".to_string(),
+            ..DialogSummary::default()
+        }]);
+        app.on_messages_loaded(
+            1,
+            vec![MessageSummary {
+                id: 7,
+                from: "Alice".to_string(),
+                text: String::new(),
+                entities: Vec::new(),
+                date: "12:00".to_string(),
+                reply_to: None,
+                topic_id: None,
+                outgoing: false,
+                mentioned: false,
+                edited: false,
+                deleted: false,
+                reactions: Vec::new(),
+                comments: None,
+                media: Some(MediaInfo::Photo {
+                    width: 2,
+                    height: 2,
+                    size: 100,
+                }),
+            }],
+        );
+        app.image_previews.insert(
+            (1, 7),
+            ImagePreview {
+                width: 1,
+                height: 1,
+                rgb: vec![255, 0, 0],
+                source: Vec::new(),
+            },
+        );
+        let mut images = ImageLayer::new(GraphicsProtocol::HalfBlocks);
+        let mut terminal = Terminal::new(TestBackend::new(60, 30)).expect("create terminal");
+
+        let mut viewport = None;
+        terminal
+            .draw(|frame| viewport = Some(draw(frame, &app, &mut images)))
+            .expect("draw frame");
+
+        let viewport = viewport.expect("viewport");
+        assert_eq!(viewport.first_visible_message_id, Some(7));
+        let buffer = terminal.backend().buffer();
+        let blocks: Vec<(u16, u16)> = buffer
+            .area
+            .positions()
+            .filter(|&position| buffer[position].symbol() == "▀")
+            .map(|position| (position.x, position.y))
+            .collect();
+        assert!(!blocks.is_empty());
+        assert!(
+            blocks
+                .iter()
+                .all(|&position| buffer[position].fg == Color::Rgb(255, 0, 0))
+        );
+        // Messages start at column 19 of the 70% pane; the preview is indented two cells.
+        assert_eq!(blocks.first(), Some(&(21, 2)));
+    }
+
+    #[test]
+    fn compose_preview_shows_formatted_text_over_messages() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(vec![DialogSummary {
+            id: 1,
+            title: "chat".to_string(),
+            ..DialogSummary::default()
+        }]);
+        app.enter_compose();
+        app.compose.set_text("**hi** there");
+        app.compose_preview = true;
+        let mut images = ImageLayer::new(GraphicsProtocol::Off);
+        let mut terminal = Terminal::new(TestBackend::new(60, 20)).expect("create terminal");
+
+        terminal
+            .draw(|frame| {
+                draw(frame, &app, &mut images);
+            })
+            .expect("draw frame");
+
+        let buffer = terminal.backend().buffer();
+        let row = |y: u16| -> String { (0..60).map(|x| buffer[(x, y)].symbol()).collect() };
+        // The 16-row message pane keeps its bottom three rows for the preview.
+        assert!(row(13).contains("Preview (Markdown)"));
+        assert!(row(14).contains("│hi there"));
+        assert!(buffer[(19, 14)].modifier.contains(Modifier::BOLD));
+        assert!(!buffer[(22, 14)].modifier.contains(Modifier::BOLD));
+        assert!(row(16).contains("Input [Markdown]"));
+    }
+
+    #[test]
+    fn pinned_strip_sits_above_the_messages() {
+        let pin = |id: i32, text: &str| MessageSummary {
+            id,
+            from: "Alice".to_string(),
+            text: text.to_string(),
+            entities: Vec::new(),
+            date: "now".to_string(),
+            reply_to: None,
+            topic_id: None,
+            outgoing: false,
+            mentioned: false,
+            edited: false,
+            deleted: false,
+            reactions: Vec::new(),
+            comments: None,
+            media: None,
+        };
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(vec![DialogSummary {
+            id: 1,
+            title: "chat".to_string(),
+            ..DialogSummary::default()
+        }]);
+        app.on_messages_loaded(1, vec![pin(7, "rules")]);
+        app.on_pinned_messages_loaded(1, vec![pin(7, "rules"), pin(3, "welcome")]);
+        let mut images = ImageLayer::new(GraphicsProtocol::Off);
+        let mut terminal = Terminal::new(TestBackend::new(60, 20)).expect("create terminal");
+
+        terminal
+            .draw(|frame| {
+                draw(frame, &app, &mut images);
+            })
+            .expect("draw frame");
+
+        let buffer = terminal.backend().buffer();
+        let row = |y: u16| -> String { (0..60).map(|x| buffer[(x, y)].symbol()).collect() };
+        assert!(row(0).contains("Pinned 1/2"));
+        assert!(row(1).contains("Alice: rules"));
+        assert!(row(3).contains("Messages - chat"));
+        assert!(row(4).contains("rules (pinned)"));
+    }
+
+    #[test]
+    fn forum_chats_name_the_open_topic_and_list_topics() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(vec![DialogSummary {
+            id: 1,
+            title: "forum".to_string(),
+            forum: true,
+            ..DialogSummary::default()
+        }]);
+        app.on_topics_loaded(
+            1,
+            vec![TopicSummary {
+                id: 7,
+                title: "Releases".to_string(),
+                top_message: 9,
+                unread_count: 2,
+                unread_mentions_count: 1,
+                read_inbox_max_id: 7,
+            }],
+        );
+        app.topic_by_dialog.insert(1, 7);
+        let mut images = ImageLayer::new(GraphicsProtocol::Off);
+        let mut terminal = Terminal::new(TestBackend::new(100, 20)).expect("create terminal");
+
+        terminal
+            .draw(|frame| {
+                draw(frame, &app, &mut images);
+            })
+            .expect("draw frame");
+        let buffer = terminal.backend().buffer();
+        let row = |y: u16| -> String { (0..100).map(|x| buffer[(x, y)].symbol()).collect() };
+        assert!(row(0).contains("Messages - forum › Releases"));
+        assert!(row(1).contains("> 👤") && row(1).contains("forum # › Releases"));
+
+        app.start_topic_picker();
+        terminal
+            .draw(|frame| {
+                draw(frame, &app, &mut images);
+            })
+            .expect("draw frame");
+        let buffer = terminal.backend().buffer();
+        let row = |y: u16| -> String { (0..100).map(|x| buffer[(x, y)].symbol()).collect() };
+        assert!(row(0).contains("Topics - forum"));
+        assert!(row(1).contains("  All topics"));
+        assert!(row(2).contains("> Releases [2 @]"));
+    }
+
+    #[test]
+    fn folder_tabs_sit_above_the_chat_list_with_unread_counts() {
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(vec![
+            DialogSummary {
+                id: 1,
+                title: "work chat".to_string(),
+                unread_count: 3,
+                ..DialogSummary::default()
+            },
+            DialogSummary {
+                id: 2,
+                title: "friends".to_string(),
+                ..DialogSummary::default()
+            },
+        ]);
+        assert!(folder_tabs_line(&app).is_none());
+
+        app.on_folders_loaded(vec![ChatFolder {
+            id: 2,
+            title: "Work".to_string(),
+            include: vec![1],
+            ..ChatFolder::default()
+        }]);
+        app.select_folder(1);
+        let line = folder_tabs_line(&app).expect("folder tabs");
+        assert_eq!(line_text(&line), " All 3 | Work 3 ");
+
+        let mut images = ImageLayer::new(GraphicsProtocol::Off);
+        let mut terminal = Terminal::new(TestBackend::new(100, 20)).expect("create terminal");
+        terminal
+            .draw(|frame| {
+                draw(frame, &app, &mut images);
+            })
+            .expect("draw frame");
+        let buffer = terminal.backend().buffer();
+        let row = |y: u16| -> String { (0..30).map(|x| buffer[(x, y)].symbol()).collect() };
+        assert!(row(0).starts_with(" All 3 | Work 3"));
+        assert!(row(1).contains("Chats"));
+        assert!(row(2).contains("> 👤") && row(2).contains("work chat"));
+        assert!(row(3).contains("[3]"));
+        assert!(!row(4).contains("friends"));
+    }
+
+    #[test]
+    fn chat_entries_show_kind_flags_and_last_message() {
+        let mut app = AppState::new();
+        let team = DialogSummary {
+            id: 1,
+            title: "team".to_string(),
+            kind: DialogKind::Supergroup,
+            muted: true,
+            pinned: true,
+            unread_count: 2,
+            unread_mentions_count: 1,
+            last_message: Some(LastMessage {
+                from: "Alice".to_string(),
+                text: "ping @me\nmore".to_string(),
+                date: "2024-03-05 09:30:00 UTC".to_string(),
+                mentioned: true,
+                ..LastMessage::default()
+            }),
+            ..DialogSummary::default()
+        };
+        let bot = DialogSummary {
+            id: 2,
+            title: "helper".to_string(),
+            kind: DialogKind::Bot,
+            last_message: Some(LastMessage {
+                media: Some(MediaInfo::Sticker {
+                    emoji: "👋".to_string(),
+                    animated: false,
+                }),
+                date: "2023-12-31 23:00:00 UTC".to_string(),
+                outgoing: true,
+                ..LastMessage::default()
+            }),
+            ..DialogSummary::default()
+        };
+        app.on_dialogs_loaded(vec![team.clone(), bot.clone()]);
+
+        let [title, preview] = chat_lines(&app, &team, 30, "2024-03-05");
+        assert_eq!(line_text(&title), "👥 team 🔕 📌            09:30");
+        assert_eq!(line_text(&preview), "   @ Alice: ping @me... [2 @]");
+        assert_eq!(preview.spans[3].style.fg, Some(Color::DarkGray));
+
+        let [title, preview] = chat_lines(&app, &bot, 30, "2024-03-05");
+        assert!(line_text(&title).starts_with("🤖 helper "));
+        assert!(line_text(&title).ends_with(" 2023-12-31"));
+        assert_eq!(line_text(&preview), "   You: [sticker: 👋]");
+
+        assert_eq!(
+            chat_list_date("2024-01-31 18:05:00 UTC", "2024-03-05"),
+            "Jan 31"
+        );
+        assert_eq!(chat_list_date("now", "2024-03-05"), "now");
+    }
+
+    #[test]
+    fn search_result_line_lists_chat_sender_date_and_snippet() {
+        let result = MessageSearchResult {
+            dialog_id: 1,
+            dialog_title: "Team".to_string(),
+            message: MessageSummary {
+                id: 7,
+                from: "Alice".to_string(),
+                text: "release today\nsee notes".to_string(),
+                entities: Vec::new(),
+                date: "2024-01-01".to_string(),
+                reply_to: None,
+                topic_id: None,
+                outgoing: false,
+                mentioned: false,
+                edited: false,
+                deleted: false,
+                reactions: Vec::new(),
+                comments: None,
+                media: None,
+            },
+        };
+
+        assert_eq!(
+            search_result_line(&result),
+            "Team / Alice / 2024-01-01 / release today..."
+        );
+    }
+
+    #[test]
+    fn transfer_label_shows_percent_or_bytes() {
+        assert_eq!(
+            transfer_label("report.pdf", 512, Some(0.25), 0),
+            "report.pdf 25%"
+        );
+        assert_eq!(
+            transfer_label("report.pdf", 512, Some(0.25), 2),
+            "report.pdf 25% +2"
+        );
+        assert_eq!(
+            transfer_label("report.pdf", 3 * 1024 * 1024 / 2, None, 0),
+            "report.pdf 1.5 MB"
+        );
+        assert_eq!(transfer_label("", 0, None, 0), "media 0 B");
+    }
+
+    #[test]
+    fn format_bytes_picks_unit() {
+        assert_eq!(format_bytes(900), "900 B");
+        assert_eq!(format_bytes(2048), "2.0 KB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024), "5.0 GB");
+    }
+
+    #[test]
+    fn media_descriptors_skip_unknown_details() {
+        assert_eq!(
+            media_descriptor(&MediaInfo::Video {
+                file_name: "clip.mp4".to_string(),
+                duration: 65,
+                width: 1920,
+                height: 1080,
+                size: 3 * 1024 * 1024,
+                round: false,
+            }),
+            "[video: clip.mp4, 1:05, 1920x1080, 3.0 MB]"
+        );
+        assert_eq!(
+            media_descriptor(&MediaInfo::Voice {
+                duration: 7,
+                size: 0,
+            }),
+            "[voice message: 0:07]"
+        );
+        assert_eq!(
+            media_descriptor(&MediaInfo::Poll {
+                question: "Lunch?".to_string(),
+                options: vec!["Pizza".to_string(), "Sushi".to_string()],
+                quiz: false,
+                closed: true,
+                total_voters: 3,
+            }),
+            "[poll: Lunch?, Pizza / Sushi, 3 votes, closed]"
+        );
+        assert_eq!(
+            media_descriptor(&MediaInfo::Location {
+                latitude: 55.75583,
+                longitude: 37.6173,
+                live: false,
+            }),
+            "[location: 55.75583, 37.61730]"
+        );
+        assert_eq!(
+            media_descriptor(&MediaInfo::Unsupported),
+            "[unsupported media]"
+        );
+    }
+
+    #[test]
+    fn format_duration_adds_hours_when_needed() {
+        assert_eq!(format_duration(0), "0:00");
+        assert_eq!(format_duration(3725), "1:02:05");
+    }
+
+    #[test]
+    fn preview_prefers_caption_over_media() {
+        let mut message = MessageSummary {
+            id: 1,
+            from: "Alice".to_string(),
+            text: String::new(),
+            entities: Vec::new(),
+            date: "2024-01-01".to_string(),
+            reply_to: None,
+            topic_id: None,
+            outgoing: false,
+            mentioned: false,
+            edited: false,
+            deleted: false,
+            reactions: Vec::new(),
+            comments: None,
+            media: Some(MediaInfo::Sticker {
+                emoji: "👍".to_string(),
+                animated: false,
+            }),
+        };
+        assert_eq!(message_preview(&message), "[sticker: 👍]");
+
+        message.text = "nice".to_string();
+        assert_eq!(message_preview(&message), "nice");
+    }
+
+    #[test]
+    fn entities_become_styled_spans_split_on_newlines() {
+        let entities = vec![
+            TextEntity {
+                kind: EntityKind::Bold,
+                range: 0..4,
+            },
+            TextEntity {
+                kind: EntityKind::Code,
+                range: 5..12,
+            },
+            TextEntity {
+                kind: EntityKind::Italic,
+                range: 2..7,
+            },
+        ];
+
+        let lines = styled_text("bold code\nmore", &entities, Style::default(), false);
+
+        assert_eq!(lines.len(), 2);
+        let spans: Vec<(&str, Style)> = lines[0]
+            .spans
+            .iter()
+            .map(|span| (span.content.as_ref(), span.style))
+            .collect();
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        assert_eq!(
+            spans,
+            vec![
+                ("bo", bold),
+                ("ld", bold.add_modifier(Modifier::ITALIC)),
+                (" ", Style::default().add_modifier(Modifier::ITALIC)),
+                (
+                    "co",
+                    Style::default()
+                        .bg(CODE_BACKGROUND)
+                        .add_modifier(Modifier::ITALIC)
+                ),
+                ("de", Style::default().bg(CODE_BACKGROUND)),
+            ]
+        );
+        assert_eq!(line_text(&lines[1]), "more");
+        assert_eq!(
+            lines[1].spans[0].style,
+            Style::default().bg(CODE_BACKGROUND)
+        );
+    }
+
+    #[test]
+    fn spoilers_are_masked_until_revealed() {
+        let entities = vec![TextEntity {
+            kind: EntityKind::Spoiler,
+            range: 4..10,
+        }];
+
+        let hidden = styled_text("the end is", &entities, Style::default(), false);
+        let shown = styled_text("the end is", &entities, Style::default(), true);
+
+        let mask: String = std::iter::repeat_n(SPOILER_CHAR, 6).collect();
+        assert_eq!(line_text(&hidden[0]), format!("the {mask}"));
+        assert_eq!(line_text(&shown[0]), "the end is");
+        assert_eq!(
+            hide_spoilers("the end is", &entities),
+            format!("the {SPOILER_CHAR}{SPOILER_CHAR}{SPOILER_CHAR} {SPOILER_CHAR}{SPOILER_CHAR}")
+        );
+    }
+
+    #[test]
+    fn comments_show_under_posts_and_the_thread_names_its_channel() {
+        assert_eq!(
+            comments_line(&Comments {
+                count: 1,
+                recent: Vec::new(),
+            }),
+            "  💬 1 comment"
+        );
+        assert_eq!(
+            comments_line(&Comments {
+                count: 4,
+                recent: vec!["Alice".to_string(), "Bob".to_string()],
+            }),
+            "  💬 4 comments - Alice, Bob"
+        );
+
+        let mut app = AppState::new();
+        app.on_dialogs_loaded(vec![DialogSummary {
+            id: 1,
+            title: "news".to_string(),
+            ..DialogSummary::default()
+        }]);
+        app.on_thread_opened(1, 5, -100, 70);
+        let mut images = ImageLayer::new(GraphicsProtocol::Off);
+        let mut terminal = Terminal::new(TestBackend::new(80, 20)).expect("create terminal");
+
+        terminal
+            .draw(|frame| {
+                draw(frame, &app, &mut images);
+            })
+            .expect("draw frame");
+        let buffer = terminal.backend().buffer();
+        let row = |y: u16| -> String { (0..80).map(|x| buffer[(x, y)].symbol()).collect() };
+        assert!(row(0).contains("Messages - news › Comments"));
+        assert!(row(1).contains("> 👤") && row(1).contains("news"));
+    }
+
+    #[test]
+    fn reaction_line_highlights_our_reactions() {
+        let line = reaction_line(&[
+            ReactionCount {
+                emoji: "👍".to_string(),
+                count: 3,
+                chosen: true,
+            },
+            ReactionCount {
+                emoji: "🔥".to_string(),
+                count: 1,
+                chosen: false,
+            },
+        ]);
+
+        assert_eq!(line_text(&line), "  👍 3 🔥 1");
+        assert_eq!(line.spans[1].style.fg, Some(Color::Cyan));
+        assert_eq!(line.spans[3].style.fg, Some(Color::DarkGray));
+    }
+
+    #[test]
+    fn transcript_lists_media_and_full_text() {
+        let text = MessageSummary {
+            id: 1,
+            from: "Alice".to_string(),
+            text: "hello".to_string(),
+            entities: Vec::new(),
+            media: None,
+            date: "now".to_string(),
+            reply_to: None,
+            topic_id: None,
+            outgoing: false,
+            mentioned: false,
+            edited: false,
+            deleted: false,
+            reactions: Vec::new(),
+            comments: None,
+        };
+        let photo = MessageSummary {
+            id: 2,
+            text: "secret".to_string(),
+            entities: vec![TextEntity {
+                kind: EntityKind::Spoiler,
+                range: 0..6,
+            }],
+            media: Some(MediaInfo::Photo {
+                width: 800,
+                height: 600,
+                size: 0,
+            }),
+            ..text.clone()
+        };
+
+        assert_eq!(
+            transcript(&[text, photo]),
+            "[now] Alice: hello\n[now] Alice: [photo: 800x600]\nsecret"
+        );
+    }
+
+    #[test]
+    fn entities_outside_the_text_are_ignored() {
+        let entities = vec![TextEntity {
+            kind: EntityKind::Bold,
+            range: 1..40,
+        }];
+
+        let lines = styled_text("ёж", &entities, Style::default(), false);
+
+        assert_eq!(lines[0].spans.len(), 1);
+        assert_eq!(lines[0].spans[0].style, Style::default());
+    }
 }
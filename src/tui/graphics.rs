@@ -0,0 +1,627 @@
+//! Inline image previews for the message pane.
+//!
+//! Half-block art is drawn straight into the ratatui buffer. Kitty previews are Unicode
+//! placeholder cells in the buffer plus a one-off image upload. Sixel and iTerm2 images bypass
+//! the buffer: their cells are marked as skipped and the image is written out after the frame.
+
+use std::{
+    collections::HashMap,
+    env,
+    io::{self, Write},
+};
+
+use crossterm::{QueueableCommand, cursor::MoveTo};
+use ratatui::{buffer::Buffer, layout::Rect, style::Color};
+
+use crate::telegram::ImagePreview;
+
+pub const MAX_PREVIEW_COLUMNS: u16 = 40;
+pub const MAX_PREVIEW_ROWS: u16 = 12;
+const PROTOCOL_ENV: &str = "TELEGRAM_IMAGE_PROTOCOL";
+const DEFAULT_CELL_SIZE: (u16, u16) = (8, 16);
+const KITTY_PLACEHOLDER: char = '\u{10EEEE}';
+const KITTY_CHUNK_SIZE: usize = 4096;
+const MAX_KITTY_IMAGE_ID: u32 = 0xFF_FFFF;
+const MAX_CACHED_SIXELS: usize = 64;
+const SIXEL_LEVELS: u32 = 6;
+/// Row and column numbers of Kitty placeholder cells, as defined by the Kitty graphics protocol.
+const KITTY_DIACRITICS: [char; 40] = [
+    '\u{0305}', '\u{030D}', '\u{030E}', '\u{0310}', '\u{0312}', '\u{033D}', '\u{033E}', '\u{033F}',
+    '\u{0346}', '\u{034A}', '\u{034B}', '\u{034C}', '\u{0350}', '\u{0351}', '\u{0352}', '\u{0357}',
+    '\u{035B}', '\u{0363}', '\u{0364}', '\u{0365}', '\u{0366}', '\u{0367}', '\u{0368}', '\u{0369}',
+    '\u{036A}', '\u{036B}', '\u{036C}', '\u{036D}', '\u{036E}', '\u{036F}', '\u{0483}', '\u{0484}',
+    '\u{0485}', '\u{0486}', '\u{0487}', '\u{0592}', '\u{0593}', '\u{0594}', '\u{0595}', '\u{0597}',
+];
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Dialog and message id of the photo a preview belongs to.
+pub type PreviewKey = (i64, i32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphicsProtocol {
+    Kitty,
+    Sixel,
+    Iterm2,
+    HalfBlocks,
+    Off,
+}
+
+impl GraphicsProtocol {
+    /// Picks a protocol from `TELEGRAM_IMAGE_PROTOCOL`, falling back to what the terminal
+    /// advertises through its environment.
+    pub fn detect() -> Self {
+        Self::from_env(|name| env::var(name).ok())
+    }
+
+    fn from_env(var: impl Fn(&str) -> Option<String>) -> Self {
+        if let Some(protocol) = var(PROTOCOL_ENV).as_deref().and_then(Self::parse) {
+            return protocol;
+        }
+
+        let term = var("TERM").unwrap_or_default();
+        let term_program = var("TERM_PROGRAM").unwrap_or_default();
+        // tmux swallows graphics escapes unless passthrough is configured.
+        if var("TMUX").is_some() || term.starts_with("screen") || term.starts_with("tmux") {
+            return Self::HalfBlocks;
+        }
+        if var("KITTY_WINDOW_ID").is_some() || term == "xterm-kitty" || term == "xterm-ghostty" {
+            Self::Kitty
+        } else if matches!(term_program.as_str(), "iTerm.app" | "WezTerm")
+            || var("LC_TERMINAL").as_deref() == Some("iTerm2")
+        {
+            Self::Iterm2
+        } else if term.contains("sixel")
+            || term.starts_with("foot")
+            || term.starts_with("mlterm")
+            || var("WT_SESSION").is_some()
+            || var("KONSOLE_VERSION").is_some()
+        {
+            Self::Sixel
+        } else {
+            Self::HalfBlocks
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "kitty" => Some(Self::Kitty),
+            "sixel" => Some(Self::Sixel),
+            "iterm2" | "iterm" => Some(Self::Iterm2),
+            "halfblocks" | "blocks" => Some(Self::HalfBlocks),
+            "off" | "none" => Some(Self::Off),
+            _ => None,
+        }
+    }
+}
+
+/// Cell columns and rows a `width` x `height` image takes, assuming cells twice as tall as wide.
+pub fn preview_cells(width: u32, height: u32, max_columns: u16) -> (u16, u16) {
+    let max_columns = u64::from(max_columns.min(MAX_PREVIEW_COLUMNS));
+    if width == 0 || height == 0 || max_columns == 0 {
+        return (0, 0);
+    }
+    let (width, height) = (u64::from(width), u64::from(height));
+
+    let mut columns = max_columns;
+    let mut rows = (columns * height).div_ceil(width * 2);
+    if rows > u64::from(MAX_PREVIEW_ROWS) {
+        rows = u64::from(MAX_PREVIEW_ROWS);
+        columns = (rows * 2 * width / height).clamp(1, max_columns);
+    }
+    (columns as u16, rows.max(1) as u16)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Placement {
+    key: PreviewKey,
+    area: Rect,
+}
+
+pub struct ImageLayer {
+    protocol: GraphicsProtocol,
+    /// Pixel size of one terminal cell, used to scale sixel images.
+    cell_size: (u16, u16),
+    screen: Rect,
+    placements: Vec<Placement>,
+    shown: Vec<Placement>,
+    /// Escape sequences to write once the frame has been drawn.
+    pending: Vec<(Option<Rect>, Vec<u8>)>,
+    kitty_ids: HashMap<PreviewKey, u32>,
+    kitty_uploads: HashMap<u32, (u16, u16)>,
+    sixels: HashMap<(PreviewKey, u16, u16), Vec<u8>>,
+}
+
+impl ImageLayer {
+    pub fn new(protocol: GraphicsProtocol) -> Self {
+        let cell_size = crossterm::terminal::window_size()
+            .ok()
+            .filter(|size| size.width > 0 && size.height > 0 && size.columns > 0 && size.rows > 0)
+            .map(|size| (size.width / size.columns, size.height / size.rows))
+            .unwrap_or(DEFAULT_CELL_SIZE);
+        Self::with_cell_size(protocol, cell_size)
+    }
+
+    fn with_cell_size(protocol: GraphicsProtocol, cell_size: (u16, u16)) -> Self {
+        Self {
+            protocol,
+            cell_size,
+            screen: Rect::default(),
+            placements: Vec::new(),
+            shown: Vec::new(),
+            pending: Vec::new(),
+            kitty_ids: HashMap::new(),
+            kitty_uploads: HashMap::new(),
+            sixels: HashMap::new(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.protocol != GraphicsProtocol::Off
+    }
+
+    /// Starts a new frame covering `screen`. A resized screen has been cleared, so every image
+    /// is written again.
+    pub fn begin_frame(&mut self, screen: Rect) {
+        if screen != self.screen {
+            self.screen = screen;
+            self.shown.clear();
+        }
+        self.placements.clear();
+    }
+
+    /// The terminal was cleared behind our back: upload and place every image again.
+    pub fn invalidate(&mut self) {
+        self.shown.clear();
+        self.kitty_uploads.clear();
+    }
+
+    pub fn render(
+        &mut self,
+        buf: &mut Buffer,
+        key: PreviewKey,
+        preview: &ImagePreview,
+        area: Rect,
+    ) {
+        let area = area.intersection(buf.area);
+        if area.is_empty() || preview.width == 0 || preview.height == 0 {
+            return;
+        }
+
+        match self.protocol {
+            GraphicsProtocol::Off => {}
+            GraphicsProtocol::HalfBlocks => render_half_blocks(buf, preview, area),
+            GraphicsProtocol::Kitty => self.render_kitty(buf, key, preview, area),
+            GraphicsProtocol::Sixel | GraphicsProtocol::Iterm2 => {
+                // Skipped cells keep whatever the image put on screen; once the image moves they
+                // differ from the previous frame again and get repainted.
+                for y in area.top()..area.bottom() {
+                    for x in area.left()..area.right() {
+                        if let Some(cell) = buf.cell_mut((x, y)) {
+                            cell.reset();
+                            cell.set_skip(true);
+                        }
+                    }
+                }
+                let placement = Placement { key, area };
+                if !self.shown.contains(&placement) {
+                    let bytes = if self.protocol == GraphicsProtocol::Sixel {
+                        self.sixel(key, preview, area)
+                    } else {
+                        encode_iterm2(preview, area)
+                    };
+                    self.pending.push((Some(area), bytes));
+                }
+                self.placements.push(placement);
+            }
+        }
+    }
+
+    /// Writes the images queued while drawing the last frame.
+    pub fn flush(&mut self, out: &mut impl Write) -> io::Result<()> {
+        self.shown = std::mem::take(&mut self.placements);
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        for (area, bytes) in self.pending.drain(..) {
+            if let Some(area) = area {
+                out.queue(MoveTo(area.x, area.y))?;
+            }
+            out.write_all(&bytes)?;
+        }
+        out.flush()
+    }
+
+    fn render_kitty(
+        &mut self,
+        buf: &mut Buffer,
+        key: PreviewKey,
+        preview: &ImagePreview,
+        area: Rect,
+    ) {
+        let next_id = self.kitty_ids.len() as u32 % MAX_KITTY_IMAGE_ID + 1;
+        let id = *self.kitty_ids.entry(key).or_insert(next_id);
+        let size = (area.width, area.height);
+        if self.kitty_uploads.get(&id) != Some(&size) {
+            let replaced = self.kitty_uploads.insert(id, size).is_some();
+            self.pending
+                .push((None, encode_kitty(preview, id, size, replaced)));
+        }
+
+        let color = Color::Rgb((id >> 16) as u8, (id >> 8) as u8, id as u8);
+        for row in 0..area.height {
+            for column in 0..area.width {
+                let Some(cell) = buf.cell_mut((area.x + column, area.y + row)) else {
+                    continue;
+                };
+                let symbol = format!(
+                    "{KITTY_PLACEHOLDER}{}{}",
+                    KITTY_DIACRITICS[usize::from(row)],
+                    KITTY_DIACRITICS[usize::from(column)]
+                );
+                cell.reset();
+                cell.set_symbol(&symbol).set_fg(color);
+            }
+        }
+    }
+
+    fn sixel(&mut self, key: PreviewKey, preview: &ImagePreview, area: Rect) -> Vec<u8> {
+        if self.sixels.len() >= MAX_CACHED_SIXELS {
+            self.sixels.clear();
+        }
+        let (cell_width, cell_height) = self.cell_size;
+        self.sixels
+            .entry((key, area.width, area.height))
+            .or_insert_with(|| {
+                encode_sixel(
+                    preview,
+                    u32::from(area.width) * u32::from(cell_width),
+                    u32::from(area.height) * u32::from(cell_height),
+                )
+            })
+            .clone()
+    }
+}
+
+fn render_half_blocks(buf: &mut Buffer, preview: &ImagePreview, area: Rect) {
+    let width = u32::from(area.width);
+    let height = u32::from(area.height) * 2;
+    for row in 0..area.height {
+        for column in 0..area.width {
+            let Some(cell) = buf.cell_mut((area.x + column, area.y + row)) else {
+                continue;
+            };
+            let (top, bottom) = (u32::from(row) * 2, u32::from(row) * 2 + 1);
+            let [r, g, b] = sample(preview, u32::from(column), top, width, height);
+            let [br, bg, bb] = sample(preview, u32::from(column), bottom, width, height);
+            cell.reset();
+            cell.set_symbol("▀")
+                .set_fg(Color::Rgb(r, g, b))
+                .set_bg(Color::Rgb(br, bg, bb));
+        }
+    }
+}
+
+/// Nearest-neighbour pixel of `preview` scaled to `width` x `height`.
+fn sample(preview: &ImagePreview, x: u32, y: u32, width: u32, height: u32) -> [u8; 3] {
+    let source_x = (u64::from(x) * u64::from(preview.width) / u64::from(width.max(1))) as usize;
+    let source_y = (u64::from(y) * u64::from(preview.height) / u64::from(height.max(1))) as usize;
+    let index = (source_y * preview.width as usize + source_x) * 3;
+    match preview.rgb.get(index..index + 3) {
+        Some(&[r, g, b]) => [r, g, b],
+        _ => [0, 0, 0],
+    }
+}
+
+fn encode_kitty(
+    preview: &ImagePreview,
+    id: u32,
+    (columns, rows): (u16, u16),
+    replace: bool,
+) -> Vec<u8> {
+    let mut out = Vec::new();
+    if replace {
+        out.extend_from_slice(format!("\x1b_Ga=d,d=I,i={id},q=2\x1b\\").as_bytes());
+    }
+    let data = base64(&preview.rgb);
+    let chunks: Vec<&[u8]> = data.as_bytes().chunks(KITTY_CHUNK_SIZE).collect();
+    for (index, chunk) in chunks.iter().enumerate() {
+        let more = u8::from(index + 1 < chunks.len());
+        out.extend_from_slice(b"\x1b_G");
+        if index == 0 {
+            out.extend_from_slice(
+                format!(
+                    "a=T,U=1,f=24,s={},v={},i={id},c={columns},r={rows},q=2,",
+                    preview.width, preview.height
+                )
+                .as_bytes(),
+            );
+        }
+        out.extend_from_slice(format!("m={more};").as_bytes());
+        out.extend_from_slice(chunk);
+        out.extend_from_slice(b"\x1b\\");
+    }
+    out
+}
+
+fn encode_iterm2(preview: &ImagePreview, area: Rect) -> Vec<u8> {
+    format!(
+        "\x1b]1337;File=inline=1;size={};width={};height={};preserveAspectRatio=0:{}\x07",
+        preview.source.len(),
+        area.width,
+        area.height,
+        base64(&preview.source)
+    )
+    .into_bytes()
+}
+
+/// Encodes `preview` scaled to `width` x `height` pixels as a sixel image using a fixed
+/// 6x6x6 colour cube.
+fn encode_sixel(preview: &ImagePreview, width: u32, height: u32) -> Vec<u8> {
+    let mut out = format!("\x1bP0;1;0q\"1;1;{width};{height}").into_bytes();
+    let pixels: Vec<u8> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let [r, g, b] = sample(preview, x, y, width, height);
+            let level = |value: u8| u32::from(value) * (SIXEL_LEVELS - 1) / 255;
+            (level(r) * SIXEL_LEVELS * SIXEL_LEVELS + level(g) * SIXEL_LEVELS + level(b)) as u8
+        })
+        .collect();
+
+    let mut used = [false; (SIXEL_LEVELS * SIXEL_LEVELS * SIXEL_LEVELS) as usize];
+    for &color in &pixels {
+        used[usize::from(color)] = true;
+    }
+    for (color, _) in used.iter().enumerate().filter(|(_, used)| **used) {
+        let color = color as u32;
+        let percent = |level: u32| level * 100 / (SIXEL_LEVELS - 1);
+        out.extend_from_slice(
+            format!(
+                "#{color};2;{};{};{}",
+                percent(color / (SIXEL_LEVELS * SIXEL_LEVELS)),
+                percent(color / SIXEL_LEVELS % SIXEL_LEVELS),
+                percent(color % SIXEL_LEVELS)
+            )
+            .as_bytes(),
+        );
+    }
+
+    let width = width as usize;
+    for (band, rows) in pixels.chunks(width * 6).enumerate() {
+        if band > 0 {
+            out.push(b'-');
+        }
+        let mut colors: Vec<u8> = rows.to_vec();
+        colors.sort_unstable();
+        colors.dedup();
+        for (index, &color) in colors.iter().enumerate() {
+            if index > 0 {
+                out.push(b'$');
+            }
+            out.extend_from_slice(format!("#{color}").as_bytes());
+            let sixels: Vec<u8> = (0..width)
+                .map(|x| {
+                    let bits = (0..6)
+                        .filter(|row| rows.get(row * width + x) == Some(&color))
+                        .fold(0, |bits, row| bits | 1 << row);
+                    b'?' + bits
+                })
+                .collect();
+            push_sixel_runs(&mut out, &sixels);
+        }
+    }
+    out.extend_from_slice(b"\x1b\\");
+    out
+}
+
+fn push_sixel_runs(out: &mut Vec<u8>, sixels: &[u8]) {
+    let mut rest = sixels;
+    while let Some(&sixel) = rest.first() {
+        let run = rest.iter().take_while(|&&next| next == sixel).count();
+        if run > 3 {
+            out.extend_from_slice(format!("!{run}").as_bytes());
+            out.push(sixel);
+        } else {
+            out.extend(std::iter::repeat_n(sixel, run));
+        }
+        rest = &rest[run..];
+    }
+}
+
+pub(super) fn base64(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let group = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+        for index in 0..4 {
+            if index <= chunk.len() {
+                let digit = (group >> (18 - index * 6)) & 0x3f;
+                out.push(char::from(BASE64_ALPHABET[digit as usize]));
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use ratatui::{Terminal, backend::TestBackend, layout::Rect, style::Color};
+
+    use super::{
+        GraphicsProtocol, ImageLayer, KITTY_PLACEHOLDER, base64, encode_sixel, preview_cells,
+        push_sixel_runs,
+    };
+    use crate::telegram::ImagePreview;
+
+    /// A 2x2 image: red and green on top, blue and white below.
+    fn preview() -> ImagePreview {
+        ImagePreview {
+            width: 2,
+            height: 2,
+            rgb: vec![255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255],
+            source: b"jpeg".to_vec(),
+        }
+    }
+
+    fn draw(terminal: &mut Terminal<TestBackend>, layer: &mut ImageLayer, area: Rect) {
+        let preview = preview();
+        terminal
+            .draw(|frame| {
+                // Stands in for the message text underneath the image.
+                let screen = frame.area();
+                for position in screen.positions() {
+                    frame.buffer_mut()[position].set_symbol("x");
+                }
+                layer.begin_frame(screen);
+                layer.render(frame.buffer_mut(), (1, 10), &preview, area);
+            })
+            .expect("draw frame");
+    }
+
+    fn detect(vars: &[(&str, &str)]) -> GraphicsProtocol {
+        let vars: HashMap<&str, &str> = vars.iter().copied().collect();
+        GraphicsProtocol::from_env(|name| vars.get(name).map(|value| value.to_string()))
+    }
+
+    #[test]
+    fn detects_protocol_from_terminal_environment() {
+        assert_eq!(detect(&[("KITTY_WINDOW_ID", "1")]), GraphicsProtocol::Kitty);
+        assert_eq!(
+            detect(&[("TERM_PROGRAM", "iTerm.app")]),
+            GraphicsProtocol::Iterm2
+        );
+        assert_eq!(detect(&[("TERM", "foot")]), GraphicsProtocol::Sixel);
+        assert_eq!(
+            detect(&[("TERM", "xterm-256color")]),
+            GraphicsProtocol::HalfBlocks
+        );
+        assert_eq!(
+            detect(&[("TERM", "xterm-kitty"), ("TMUX", "/tmp/tmux")]),
+            GraphicsProtocol::HalfBlocks
+        );
+        assert_eq!(
+            detect(&[("TERM", "xterm-kitty"), ("TELEGRAM_IMAGE_PROTOCOL", "off")]),
+            GraphicsProtocol::Off
+        );
+    }
+
+    #[test]
+    fn preview_cells_keep_aspect_ratio_within_limits() {
+        assert_eq!(preview_cells(320, 160, 80), (40, 10));
+        assert_eq!(preview_cells(320, 320, 80), (24, 12));
+        assert_eq!(preview_cells(320, 160, 20), (20, 5));
+        assert_eq!(preview_cells(0, 160, 20), (0, 0));
+    }
+
+    #[test]
+    fn half_blocks_draw_top_and_bottom_pixels() {
+        let mut terminal = Terminal::new(TestBackend::new(4, 2)).expect("create terminal");
+        let mut layer = ImageLayer::with_cell_size(GraphicsProtocol::HalfBlocks, (8, 16));
+
+        draw(&mut terminal, &mut layer, Rect::new(1, 0, 2, 1));
+
+        let buffer = terminal.backend().buffer();
+        let left = &buffer[(1, 0)];
+        assert_eq!(left.symbol(), "▀");
+        assert_eq!(left.fg, Color::Rgb(255, 0, 0));
+        assert_eq!(left.bg, Color::Rgb(0, 0, 255));
+        let right = &buffer[(2, 0)];
+        assert_eq!(right.fg, Color::Rgb(0, 255, 0));
+        assert_eq!(right.bg, Color::Rgb(255, 255, 255));
+        assert_eq!(buffer[(0, 0)].symbol(), "x");
+    }
+
+    #[test]
+    fn kitty_uploads_once_and_fills_placeholders() {
+        let mut terminal = Terminal::new(TestBackend::new(4, 2)).expect("create terminal");
+        let mut layer = ImageLayer::with_cell_size(GraphicsProtocol::Kitty, (8, 16));
+
+        draw(&mut terminal, &mut layer, Rect::new(0, 0, 2, 2));
+        let mut out = Vec::new();
+        layer.flush(&mut out).expect("flush images");
+        let out = String::from_utf8(out).expect("utf8 escapes");
+        assert!(out.starts_with("\x1b_Ga=T,U=1,f=24,s=2,v=2,i=1,c=2,r=2,q=2,m=0;"));
+
+        let buffer = terminal.backend().buffer();
+        assert!(buffer[(1, 1)].symbol().starts_with(KITTY_PLACEHOLDER));
+        assert_eq!(buffer[(1, 1)].fg, Color::Rgb(0, 0, 1));
+        assert_ne!(buffer[(0, 1)].symbol(), buffer[(1, 1)].symbol());
+
+        draw(&mut terminal, &mut layer, Rect::new(0, 0, 2, 2));
+        let mut out = Vec::new();
+        layer.flush(&mut out).expect("flush images");
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn sixel_images_skip_their_cells_and_are_written_once_per_position() {
+        let mut terminal = Terminal::new(TestBackend::new(4, 3)).expect("create terminal");
+        let mut layer = ImageLayer::with_cell_size(GraphicsProtocol::Sixel, (2, 3));
+
+        draw(&mut terminal, &mut layer, Rect::new(1, 1, 2, 1));
+        assert_eq!(terminal.backend().buffer()[(1, 1)].symbol(), " ");
+        assert_eq!(terminal.backend().buffer()[(0, 1)].symbol(), "x");
+        let mut out = Vec::new();
+        layer.flush(&mut out).expect("flush images");
+        let out = String::from_utf8(out).expect("utf8 escapes");
+        assert!(out.starts_with("\x1b[2;2H\x1bP0;1;0q\"1;1;4;3"));
+        assert!(out.ends_with("\x1b\\"));
+
+        draw(&mut terminal, &mut layer, Rect::new(1, 1, 2, 1));
+        let mut out = Vec::new();
+        layer.flush(&mut out).expect("flush images");
+        assert!(out.is_empty());
+
+        draw(&mut terminal, &mut layer, Rect::new(1, 0, 2, 1));
+        let mut out = Vec::new();
+        layer.flush(&mut out).expect("flush images");
+        assert!(!out.is_empty());
+        // The old image area is repainted with the text below it.
+        assert_eq!(terminal.backend().buffer()[(1, 1)].symbol(), "x");
+    }
+
+    #[test]
+    fn iterm2_images_embed_the_source_file() {
+        let mut terminal = Terminal::new(TestBackend::new(4, 2)).expect("create terminal");
+        let mut layer = ImageLayer::with_cell_size(GraphicsProtocol::Iterm2, (8, 16));
+
+        draw(&mut terminal, &mut layer, Rect::new(0, 0, 3, 2));
+        let mut out = Vec::new();
+        layer.flush(&mut out).expect("flush images");
+        let out = String::from_utf8(out).expect("utf8 escapes");
+        assert!(out.ends_with(
+            "\x1b]1337;File=inline=1;size=4;width=3;height=2;preserveAspectRatio=0:anBlZw==\x07"
+        ));
+    }
+
+    #[test]
+    fn sixel_encoding_uses_palette_and_run_lengths() {
+        let preview = ImagePreview {
+            width: 1,
+            height: 1,
+            rgb: vec![255, 0, 0],
+            source: Vec::new(),
+        };
+        let encoded = String::from_utf8(encode_sixel(&preview, 5, 6)).expect("utf8 sixel");
+        assert_eq!(encoded, "\x1bP0;1;0q\"1;1;5;6#180;2;100;0;0#180!5~\x1b\\");
+
+        let mut out = Vec::new();
+        push_sixel_runs(&mut out, b"??~~~~@");
+        assert_eq!(out, b"??!4~@");
+    }
+
+    #[test]
+    fn base64_pads_partial_groups() {
+        assert_eq!(base64(b"jpeg"), "anBlZw==");
+        assert_eq!(base64(b"abc"), "YWJj");
+        assert_eq!(base64(b"ab"), "YWI=");
+    }
+}